@@ -1,10 +1,18 @@
+use std::env;
 use std::fs;
 use std::path::Path;
 
+const LOCALES_PATH: &str = "data/locales.tsv";
+const LANGUAGES_PATH: &str = "data/languages.tsv";
+
 fn main() {
-    // Rerun only when Cargo.toml changes (i.e., when the version is bumped).
-    // This avoids unnecessary rebuilds while still syncing README.md on version changes.
+    // Rerun when Cargo.toml changes (i.e., when the version is bumped) to sync
+    // README.md, or when the locale data changes to regenerate the catalog.
     println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed={}", LOCALES_PATH);
+    println!("cargo:rerun-if-changed={}", LANGUAGES_PATH);
+
+    generate_locale_catalog();
 
     let version = env!("CARGO_PKG_VERSION");
 
@@ -21,6 +29,86 @@ fn main() {
     }
 }
 
+// Generates $OUT_DIR/locale_catalog.rs from data/locales.tsv and
+// data/languages.tsv. The catalog is included by src/locale.rs.
+fn generate_locale_catalog() {
+    let content = fs::read_to_string(LOCALES_PATH).expect("Failed to read data/locales.tsv");
+    let mut catalog = locale_catalog_source(&content).unwrap_or_else(|e| panic!("{}: {}", LOCALES_PATH, e));
+
+    let content = fs::read_to_string(LANGUAGES_PATH).expect("Failed to read data/languages.tsv");
+    catalog.push_str(&native_names_source(&content).unwrap_or_else(|e| panic!("{}: {}", LANGUAGES_PATH, e)));
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is not set");
+    fs::write(Path::new(&out_dir).join("locale_catalog.rs"), catalog).expect("Failed to write locale catalog");
+}
+
+// Rust source for the locale table. Rows are
+// `lang_id  bcp47  language  region  name  code`, tab separated.
+fn locale_catalog_source(content: &str) -> Result<String, String> {
+    let mut source = String::from("pub static LOCALES: &[Locale] = &[\n");
+    let mut previous_id = None;
+    let mut codes = Vec::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [lang_id, tag, language, region, name, code] = fields[..] else {
+            return Err(format!("line {}: expected 6 fields, found {}", number + 1, fields.len()));
+        };
+
+        let lang_id = u16::from_str_radix(lang_id.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("line {}: invalid LANGID '{}'", number + 1, lang_id))?;
+        if previous_id.is_some_and(|previous| previous >= lang_id) {
+            return Err(format!("line {}: LANGID 0x{:04x} is out of order", number + 1, lang_id));
+        }
+        if code.is_empty() || codes.contains(&code) {
+            return Err(format!("line {}: code '{}' is empty or not unique", number + 1, code));
+        }
+        previous_id = Some(lang_id);
+        codes.push(code);
+
+        source.push_str(&format!(
+            "    Locale {{ lang_id: 0x{:04x}, tag: {:?}, language: {:?}, region: {:?}, name: {:?}, code: {:?} }},\n",
+            lang_id, tag, language, region, name, code
+        ));
+    }
+
+    source.push_str("];\n");
+    Ok(source)
+}
+
+// Rust source for the native language names. Rows are
+// `language  native name`, tab separated, sorted by language.
+fn native_names_source(content: &str) -> Result<String, String> {
+    let mut source = String::from("pub static NATIVE_NAMES: &[(&str, &str)] = &[\n");
+    let mut previous = None;
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [language, native] = fields[..] else {
+            return Err(format!("line {}: expected 2 fields, found {}", number + 1, fields.len()));
+        };
+        if native.is_empty() || previous.is_some_and(|previous| previous >= language) {
+            return Err(format!("line {}: language '{}' has no name or is out of order", number + 1, language));
+        }
+        previous = Some(language);
+
+        source.push_str(&format!("    ({:?}, {:?}),\n", language, native));
+    }
+
+    source.push_str("];\n");
+    Ok(source)
+}
+
 // Replaces every occurrence of `prefix + <old_version>` with `prefix + new_version`.
 // The old version is detected as the longest run of ASCII digits and dots that follows
 // the prefix.  All other content (including CHANGELOG history) is left untouched.
@@ -59,6 +147,28 @@ fn sync_version(content: &str, version: &str) -> String {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_locale_catalog_source() {
+        let data = "# comment\n0x0407\tde-DE\tde\tDE\tGerman (Germany)\tde\n0x0409\ten-US\ten\tUS\tEnglish (United States)\tus\n";
+        let source = locale_catalog_source(data).unwrap();
+        assert!(source.contains("Locale { lang_id: 0x0407, tag: \"de-DE\", language: \"de\", region: \"DE\", name: \"German (Germany)\", code: \"de\" },"));
+    }
+
+    #[test]
+    fn test_locale_catalog_rejects_bad_rows() {
+        assert!(locale_catalog_source("0x0409\ten-US\ten\tUS\tEnglish\n").is_err(), "Missing code column");
+        assert!(locale_catalog_source("0x0409\ten-US\ten\tUS\tA\tus\n0x0407\tde-DE\tde\tDE\tB\tde\n").is_err(), "Unsorted");
+        assert!(locale_catalog_source("0x0407\tde-DE\tde\tDE\tA\tde\n0x0409\ten-US\ten\tUS\tB\tde\n").is_err(), "Duplicate code");
+    }
+
+    #[test]
+    fn test_native_names_source() {
+        let source = native_names_source("# comment\nde\tDeutsch\nuk\tукраїнська\n").unwrap();
+        assert!(source.contains("(\"de\", \"Deutsch\"),\n    (\"uk\", \"українська\"),"));
+        assert!(native_names_source("uk\tукраїнська\nde\tDeutsch\n").is_err(), "Unsorted");
+        assert!(native_names_source("de\n").is_err(), "Missing name");
+    }
+
     #[test]
     fn test_replace_title_version() {
         let input = "# CCaps Layout Switcher v0.8.2\n\nSome text";
@@ -0,0 +1,323 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use crate::config;
+
+// Selects the startup mechanism used by -start: "xdg" (default) or "systemd"
+pub const AUTOSTART_ENV_VAR: &str = "CCAPS_AUTOSTART";
+
+const DESKTOP_FILE_NAME: &str = "ccaps.desktop";
+const UNIT_FILE_NAME: &str = "ccaps.service";
+const APP_NAME: &str = "CCaps Layout Switcher";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutostartKind {
+    // ~/.config/autostart/ccaps.desktop, started by the desktop session
+    Xdg,
+    // ~/.config/systemd/user/ccaps.service, enabled for default.target
+    Systemd,
+}
+
+impl AutostartKind {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.to_lowercase().as_str() {
+            "xdg" | "desktop" => Ok(AutostartKind::Xdg),
+            "systemd" => Ok(AutostartKind::Systemd),
+            _ => Err(format!("Invalid {} value '{}' (expected xdg or systemd)", AUTOSTART_ENV_VAR, value)),
+        }
+    }
+}
+
+// Quote an argument for a desktop file Exec key or a systemd ExecStart line
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\$`".contains(c)) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        if "\"\\$`".contains(c) {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Command line stored in the startup entry, same arguments as the Run key
+pub fn startup_command(exe_path: &Path, country_codes: &[String], config_path: Option<&Path>) -> String {
+    let mut command = format!("{} --background", quote_arg(&exe_path.to_string_lossy()));
+    if let Some(path) = config_path {
+        command.push_str(&format!(" --config {}", quote_arg(&path.to_string_lossy())));
+    }
+    for code in country_codes {
+        command.push_str(&format!(" -{}", code));
+    }
+    command
+}
+
+// Value of the first `key=` line in a desktop file or unit file
+fn read_key(content: &str, key: &str) -> Option<String> {
+    content.lines()
+        .find_map(|line| line.trim().strip_prefix(key)?.strip_prefix('='))
+        .map(|value| value.trim().to_string())
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Startup entries under an XDG config directory
+pub struct Autostart {
+    config_home: PathBuf,
+    kind: AutostartKind,
+}
+
+impl Autostart {
+    pub fn new(config_home: PathBuf, kind: AutostartKind) -> Self {
+        Autostart { config_home, kind }
+    }
+
+    // $XDG_CONFIG_HOME (or ~/.config) and the mechanism from CCAPS_AUTOSTART
+    pub fn from_env() -> Result<Self, String> {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok_or_else(|| "Cannot determine the config directory (HOME is not set)".to_string())?;
+
+        let kind = match env::var(AUTOSTART_ENV_VAR) {
+            Ok(value) => AutostartKind::parse(&value)?,
+            Err(_) => AutostartKind::Xdg,
+        };
+
+        Ok(Self::new(config_home, kind))
+    }
+
+    pub fn desktop_file_path(&self) -> PathBuf {
+        self.config_home.join("autostart").join(DESKTOP_FILE_NAME)
+    }
+
+    pub fn unit_path(&self) -> PathBuf {
+        self.config_home.join("systemd").join("user").join(UNIT_FILE_NAME)
+    }
+
+    // The symlink `systemctl --user enable` would create
+    fn wants_link_path(&self) -> PathBuf {
+        self.config_home.join("systemd").join("user").join("default.target.wants").join(UNIT_FILE_NAME)
+    }
+
+    // Command of the installed entry, if it is enabled
+    fn entry_command(&self, kind: AutostartKind) -> Option<String> {
+        match kind {
+            AutostartKind::Xdg => {
+                let content = fs::read_to_string(self.desktop_file_path()).ok()?;
+                if read_key(&content, "Hidden").as_deref() == Some("true")
+                    || read_key(&content, "X-GNOME-Autostart-enabled").as_deref() == Some("false")
+                {
+                    return None;
+                }
+                read_key(&content, "Exec")
+            },
+            AutostartKind::Systemd => {
+                // A unit only starts at login once it is enabled
+                fs::symlink_metadata(self.wants_link_path()).ok()?;
+                read_key(&fs::read_to_string(self.unit_path()).ok()?, "ExecStart")
+            },
+        }
+    }
+
+    // Configured mechanism first, so -status shows what -start would write
+    fn installed_command(&self) -> Option<String> {
+        let other = match self.kind {
+            AutostartKind::Xdg => AutostartKind::Systemd,
+            AutostartKind::Systemd => AutostartKind::Xdg,
+        };
+        self.entry_command(self.kind).or_else(|| self.entry_command(other))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.installed_command().is_some()
+    }
+
+    pub fn command(&self) -> Result<String, String> {
+        self.installed_command().ok_or_else(|| "No startup entry found".to_string())
+    }
+
+    // Write the entry for the configured mechanism and drop the other one,
+    // so there is never more than one way CCaps gets started
+    pub fn install(&self, exe_path: &Path, country_codes: &[String]) -> Result<(), String> {
+        let command = startup_command(exe_path, country_codes, config::config_override().as_deref());
+
+        match self.kind {
+            AutostartKind::Xdg => {
+                let content = format!(
+                    "[Desktop Entry]\nType=Application\nName={}\nComment=Caps Lock keyboard layout switcher\n\
+                     Exec={}\nTerminal=false\nNoDisplay=true\nX-GNOME-Autostart-enabled=true\n",
+                    APP_NAME, command
+                );
+                write_file(&self.desktop_file_path(), &content)?;
+                self.remove_systemd_unit()
+            },
+            AutostartKind::Systemd => {
+                let content = format!(
+                    "[Unit]\nDescription={}\n\n[Service]\nExecStart={}\nRestart=on-failure\n\n\
+                     [Install]\nWantedBy=default.target\n",
+                    APP_NAME, command
+                );
+                write_file(&self.unit_path(), &content)?;
+
+                let link = self.wants_link_path();
+                if fs::symlink_metadata(&link).is_err() {
+                    create_parent(&link)?;
+                    symlink(Path::new("..").join(UNIT_FILE_NAME), &link)
+                        .map_err(|e| format!("Cannot enable {}: {}", UNIT_FILE_NAME, e))?;
+                }
+                self.remove_desktop_file()
+            },
+        }
+    }
+
+    pub fn remove(&self) -> Result<(), String> {
+        self.remove_desktop_file()?;
+        self.remove_systemd_unit()
+    }
+
+    fn remove_desktop_file(&self) -> Result<(), String> {
+        remove_if_exists(&self.desktop_file_path())
+            .map_err(|e| format!("Cannot remove autostart file: {}", e))
+    }
+
+    fn remove_systemd_unit(&self) -> Result<(), String> {
+        remove_if_exists(&self.wants_link_path())
+            .and_then(|_| remove_if_exists(&self.unit_path()))
+            .map_err(|e| format!("Cannot remove systemd unit: {}", e))
+    }
+}
+
+fn create_parent(path: &Path) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Cannot create directory {}: {}", dir.display(), e))?;
+    }
+    Ok(())
+}
+
+fn write_file(path: &Path, content: &str) -> Result<(), String> {
+    create_parent(path)?;
+    fs::write(path, content).map_err(|e| format!("Cannot write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Temporary XDG_CONFIG_HOME, removed on drop
+    struct TempConfigHome(PathBuf);
+
+    impl TempConfigHome {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("ccaps-test-xdg-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempConfigHome(dir)
+        }
+    }
+
+    impl Drop for TempConfigHome {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn codes(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    const EXE: &str = "/usr/local/bin/ccaps";
+
+    #[test]
+    fn test_startup_command_quotes_paths() {
+        assert_eq!(startup_command(Path::new(EXE), &codes(&["de", "ru"]), None), "/usr/local/bin/ccaps --background -de -ru");
+        assert_eq!(startup_command(Path::new("/opt/my apps/ccaps"), &[], None), "\"/opt/my apps/ccaps\" --background");
+        assert_eq!(startup_command(Path::new(EXE), &codes(&["de"]), Some(Path::new("/home/u/my ccaps.json"))),
+                   "/usr/local/bin/ccaps --background --config \"/home/u/my ccaps.json\" -de");
+    }
+
+    #[test]
+    fn test_xdg_desktop_file_round_trip() {
+        let home = TempConfigHome::new("desktop");
+        let autostart = Autostart::new(home.0.clone(), AutostartKind::Xdg);
+        assert!(!autostart.is_enabled());
+
+        autostart.install(Path::new(EXE), &codes(&["de"])).unwrap();
+        let content = fs::read_to_string(home.0.join("autostart/ccaps.desktop")).unwrap();
+        assert!(content.starts_with("[Desktop Entry]\n"));
+        assert!(autostart.is_enabled());
+        assert_eq!(autostart.command().unwrap(), "/usr/local/bin/ccaps --background -de");
+
+        autostart.remove().unwrap();
+        assert!(!autostart.is_enabled());
+        assert!(autostart.command().is_err());
+    }
+
+    #[test]
+    fn test_disabled_desktop_file_is_not_enabled() {
+        let home = TempConfigHome::new("hidden");
+        let autostart = Autostart::new(home.0.clone(), AutostartKind::Xdg);
+        write_file(&autostart.desktop_file_path(), "[Desktop Entry]\nExec=ccaps --background\nHidden=true\n").unwrap();
+        assert!(!autostart.is_enabled());
+    }
+
+    #[test]
+    fn test_systemd_unit_round_trip() {
+        let home = TempConfigHome::new("systemd");
+        let autostart = Autostart::new(home.0.clone(), AutostartKind::Systemd);
+
+        autostart.install(Path::new(EXE), &codes(&["de", "fr"])).unwrap();
+        let unit = fs::read_to_string(home.0.join("systemd/user/ccaps.service")).unwrap();
+        assert!(unit.contains("ExecStart=/usr/local/bin/ccaps --background -de -fr\n"));
+        assert!(unit.contains("WantedBy=default.target"));
+        let link = home.0.join("systemd/user/default.target.wants/ccaps.service");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../ccaps.service"));
+        assert_eq!(autostart.command().unwrap(), "/usr/local/bin/ccaps --background -de -fr");
+
+        // Reinstalling updates the arguments in place
+        autostart.install(Path::new(EXE), &codes(&["ru"])).unwrap();
+        assert_eq!(autostart.command().unwrap(), "/usr/local/bin/ccaps --background -ru");
+
+        autostart.remove().unwrap();
+        assert!(!autostart.is_enabled());
+        assert!(fs::symlink_metadata(&link).is_err());
+    }
+
+    #[test]
+    fn test_switching_mechanism_replaces_entry() {
+        let home = TempConfigHome::new("switch");
+        Autostart::new(home.0.clone(), AutostartKind::Systemd).install(Path::new(EXE), &codes(&["de"])).unwrap();
+
+        let xdg = Autostart::new(home.0.clone(), AutostartKind::Xdg);
+        assert!(xdg.is_enabled(), "-status should also see entries of the other mechanism");
+
+        xdg.install(Path::new(EXE), &codes(&["de"])).unwrap();
+        assert!(!xdg.unit_path().exists());
+        assert!(xdg.desktop_file_path().exists());
+    }
+
+    #[test]
+    fn test_from_env_uses_xdg_config_home() {
+        let home = TempConfigHome::new("env");
+        env::set_var("XDG_CONFIG_HOME", &home.0);
+        let autostart = Autostart::from_env();
+        env::remove_var("XDG_CONFIG_HOME");
+
+        assert_eq!(autostart.unwrap().desktop_file_path(), home.0.join("autostart").join("ccaps.desktop"));
+        assert_eq!(AutostartKind::parse("systemd"), Ok(AutostartKind::Systemd));
+        assert!(AutostartKind::parse("cron").is_err());
+    }
+}
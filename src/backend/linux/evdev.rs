@@ -0,0 +1,633 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::raw::{c_int, c_ulong};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::backend::{self, LockKey};
+use crate::backend::linux::led;
+use crate::bindings::{Key, KeyCode, Modifiers};
+use crate::keyboard_hook::{self, CCAPS_EXTRA_INFO};
+
+// Event types and codes from <linux/input-event-codes.h>
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_LED: u16 = 0x11;
+const EV_MAX: u16 = 0x1f;
+
+const KEY_ESC: u16 = 1;
+const KEY_TAB: u16 = 15;
+const KEY_ENTER: u16 = 28;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_A: u16 = 30;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_LEFTALT: u16 = 56;
+const KEY_SPACE: u16 = 57;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_F1: u16 = 59;
+const KEY_F10: u16 = 68;
+const KEY_SCROLLLOCK: u16 = 70;
+const KEY_F11: u16 = 87;
+const KEY_F12: u16 = 88;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_UP: u16 = 103;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_DOWN: u16 = 108;
+const KEY_PAUSE: u16 = 119;
+const KEY_LEFTMETA: u16 = 125;
+const KEY_RIGHTMETA: u16 = 126;
+const KEY_COMPOSE: u16 = 127;
+const KEY_F13: u16 = 183;
+const KEY_F24: u16 = 194;
+const KEY_MAX: u16 = 0x2ff;
+
+// Letter and digit keys by their row on the keyboard
+const LETTER_ROWS: [(u16, &str); 3] = [(16, "QWERTYUIOP"), (30, "ASDFGHJKL"), (44, "ZXCVBNM")];
+const DIGIT_KEYS: (u16, &str) = (2, "1234567890");
+
+const MODIFIER_KEYS: [(u16, Key); 8] = [
+    (KEY_LEFTSHIFT, Key::LeftShift),
+    (KEY_RIGHTSHIFT, Key::RightShift),
+    (KEY_LEFTCTRL, Key::LeftCtrl),
+    (KEY_RIGHTCTRL, Key::RightCtrl),
+    (KEY_LEFTALT, Key::LeftAlt),
+    (KEY_RIGHTALT, Key::RightAlt),
+    (KEY_LEFTMETA, Key::LeftWin),
+    (KEY_RIGHTMETA, Key::RightWin),
+];
+
+pub const LED_CAPSL: u16 = 0x01;
+pub const LED_SCROLLL: u16 = 0x02;
+const LED_MAX: u16 = 0x0f;
+
+// Key event values
+const KEY_RELEASE: i32 = 0;
+const KEY_PRESS: i32 = 1;
+
+// Virtual device identity. Vendor and product are taken from CCAPS_EXTRA_INFO,
+// so the virtual keyboard carries the same marker as CCaps's injected input on
+// Windows and is never grabbed back by the hook.
+const VIRTUAL_DEVICE_NAME: &str = "CCaps virtual keyboard";
+const BUS_VIRTUAL: u16 = 0x06;
+pub const VIRTUAL_VENDOR: u16 = (CCAPS_EXTRA_INFO >> 16) as u16;
+pub const VIRTUAL_PRODUCT: u16 = (CCAPS_EXTRA_INFO & 0xFFFF) as u16;
+
+const INPUT_DIR: &str = "/dev/input";
+const UINPUT_PATH: &str = "/dev/uinput";
+
+// ioctl request encoding from <asm-generic/ioctl.h>
+const IOC_NONE: c_ulong = 0;
+const IOC_WRITE: c_ulong = 1;
+const IOC_READ: c_ulong = 2;
+
+const fn ioc(dir: c_ulong, kind: u8, nr: u8, size: usize) -> c_ulong {
+    (dir << 30) | ((size as c_ulong) << 16) | ((kind as c_ulong) << 8) | nr as c_ulong
+}
+
+const EVIOCGID: c_ulong = ioc(IOC_READ, b'E', 0x02, mem::size_of::<libc::input_id>());
+const EVIOCGRAB: c_ulong = ioc(IOC_WRITE, b'E', 0x90, mem::size_of::<c_int>());
+
+const fn eviocgname(len: usize) -> c_ulong {
+    ioc(IOC_READ, b'E', 0x06, len)
+}
+
+const fn eviocgkey(len: usize) -> c_ulong {
+    ioc(IOC_READ, b'E', 0x18, len)
+}
+
+const fn eviocgled(len: usize) -> c_ulong {
+    ioc(IOC_READ, b'E', 0x19, len)
+}
+
+const fn eviocgbit(ev: u16, len: usize) -> c_ulong {
+    ioc(IOC_READ, b'E', 0x20 + ev as u8, len)
+}
+
+const UI_DEV_CREATE: c_ulong = ioc(IOC_NONE, b'U', 1, 0);
+const UI_DEV_DESTROY: c_ulong = ioc(IOC_NONE, b'U', 2, 0);
+const UI_DEV_SETUP: c_ulong = ioc(IOC_WRITE, b'U', 3, mem::size_of::<libc::uinput_setup>());
+const UI_SET_EVBIT: c_ulong = ioc(IOC_WRITE, b'U', 100, mem::size_of::<c_int>());
+const UI_SET_KEYBIT: c_ulong = ioc(IOC_WRITE, b'U', 101, mem::size_of::<c_int>());
+const UI_SET_LEDBIT: c_ulong = ioc(IOC_WRITE, b'U', 105, mem::size_of::<c_int>());
+
+// Named key of an evdev key code. The key code doubles as the scancode;
+// for the main keyboard block it equals the PC scancode.
+pub fn key_code(code: u16) -> KeyCode {
+    KeyCode { key: key_from_code(code), scancode: code }
+}
+
+fn key_from_code(code: u16) -> Key {
+    if let Some((_, key)) = MODIFIER_KEYS.iter().find(|(modifier, _)| *modifier == code) {
+        return *key;
+    }
+
+    match code {
+        KEY_CAPSLOCK => Key::CapsLock,
+        KEY_COMPOSE => Key::Menu,
+        KEY_SCROLLLOCK => Key::ScrollLock,
+        KEY_PAUSE => Key::Pause,
+        KEY_SPACE => Key::Space,
+        KEY_TAB => Key::Tab,
+        KEY_ESC => Key::Escape,
+        KEY_ENTER => Key::Enter,
+        KEY_LEFT => Key::Left,
+        KEY_RIGHT => Key::Right,
+        KEY_UP => Key::Up,
+        KEY_DOWN => Key::Down,
+        KEY_F1..=KEY_F10 => Key::F((code - KEY_F1 + 1) as u8),
+        KEY_F11 => Key::F(11),
+        KEY_F12 => Key::F(12),
+        KEY_F13..=KEY_F24 => Key::F((code - KEY_F13 + 13) as u8),
+        _ => LETTER_ROWS.iter()
+            .chain([DIGIT_KEYS].iter())
+            .find_map(|(first, keys)| keys.chars().nth(code.checked_sub(*first)? as usize))
+            .map(Key::Char)
+            .unwrap_or(Key::Other),
+    }
+}
+
+// Key code of a named key, for keys injected without a scancode
+fn code_for_key(key: Key) -> Option<u16> {
+    if key == Key::Other {
+        return None;
+    }
+    (0..=KEY_MAX).find(|code| key_from_code(*code) == key)
+}
+
+// Modifier keys held down in a key state bitmask
+pub fn held_modifiers(key_state: &[u8]) -> Modifiers {
+    MODIFIER_KEYS.iter()
+        .filter(|(code, _)| test_bit(key_state, *code))
+        .fold(Modifiers::NONE, |held, (_, key)| held.with(*key))
+}
+
+// Check whether a device is CCaps's own virtual keyboard
+pub fn is_ccaps_device(vendor: u16, product: u16) -> bool {
+    vendor == VIRTUAL_VENDOR && product == VIRTUAL_PRODUCT
+}
+
+// Test a bit in a kernel bitmask as returned by EVIOCGBIT/EVIOCGKEY/EVIOCGLED
+pub fn test_bit(bits: &[u8], bit: u16) -> bool {
+    let byte = (bit / 8) as usize;
+    byte < bits.len() && (bits[byte] & (1 << (bit % 8))) != 0
+}
+
+fn bitmask_len(max: u16) -> usize {
+    max as usize / 8 + 1
+}
+
+fn last_os_error(context: &str) -> String {
+    format!("{}: {}", context, io::Error::last_os_error())
+}
+
+// A grabbed physical keyboard
+struct Keyboard {
+    fd: c_int,
+    path: String,
+    name: String,
+}
+
+impl Keyboard {
+    fn key_state(&self) -> Vec<u8> {
+        let mut bits = vec![0u8; bitmask_len(KEY_MAX)];
+        unsafe {
+            libc::ioctl(self.fd, eviocgkey(bits.len()), bits.as_mut_ptr());
+        }
+        bits
+    }
+
+    // Event node name ("event3"), used to select devices
+    fn id(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+
+    fn led_state(&self) -> Vec<u8> {
+        let mut bits = vec![0u8; bitmask_len(LED_MAX)];
+        unsafe {
+            libc::ioctl(self.fd, eviocgled(bits.len()), bits.as_mut_ptr());
+        }
+        bits
+    }
+}
+
+// Grabbed keyboards plus the virtual keyboard used to re-emit their events
+pub struct EvdevHook {
+    keyboards: Vec<Keyboard>,
+    uinput_fd: c_int,
+    // Pipe used to wake up and stop the event loop
+    stop_pipe: [c_int; 2],
+    stopped: AtomicBool,
+}
+
+impl EvdevHook {
+    // Grab all physical keyboards and create the virtual keyboard
+    pub fn open() -> Result<Self, String> {
+        let uinput_fd = create_virtual_keyboard()?;
+
+        let mut keyboards = Vec::new();
+        for path in keyboard_device_paths() {
+            match open_and_grab(&path) {
+                Ok(Some(fd)) => keyboards.push(Keyboard { fd, name: device_name(fd), path }),
+                Ok(None) => {},
+                Err(e) => eprintln!("Warning: {}", e),
+            }
+        }
+
+        if keyboards.is_empty() {
+            destroy_virtual_keyboard(uinput_fd);
+            return Err(format!(
+                "No keyboard could be grabbed in {} (is the user in the 'input' group?)",
+                INPUT_DIR
+            ));
+        }
+
+        let mut stop_pipe: [c_int; 2] = [-1, -1];
+        unsafe {
+            if libc::pipe2(stop_pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) != 0 {
+                let error = last_os_error("Failed to create pipe");
+                for keyboard in &keyboards {
+                    release_keyboard(keyboard.fd);
+                }
+                destroy_virtual_keyboard(uinput_fd);
+                return Err(error);
+            }
+        }
+
+        Ok(EvdevHook {
+            keyboards,
+            uinput_fd,
+            stop_pipe,
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    // Read events from the grabbed keyboards until `stop` is called
+    pub fn run(&self) {
+        let mut poll_fds: Vec<libc::pollfd> = self.keyboards
+            .iter()
+            .map(|k| libc::pollfd { fd: k.fd, events: libc::POLLIN, revents: 0 })
+            .collect();
+        poll_fds.push(libc::pollfd { fd: self.stop_pipe[0], events: libc::POLLIN, revents: 0 });
+
+        while !self.stopped.load(Ordering::SeqCst) {
+            let result = unsafe {
+                libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1)
+            };
+            if result < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                break;
+            }
+
+            for (i, poll_fd) in poll_fds.iter_mut().enumerate() {
+                if poll_fd.revents == 0 || i >= self.keyboards.len() {
+                    continue;
+                }
+
+                if poll_fd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                    // Device was unplugged, stop polling it
+                    eprintln!("Keyboard {} was removed", self.keyboards[i].path);
+                    poll_fd.fd = -1;
+                    continue;
+                }
+
+                self.process_events(&self.keyboards[i]);
+            }
+        }
+    }
+
+    fn process_events(&self, keyboard: &Keyboard) {
+        let mut events: [libc::input_event; 64] = unsafe { mem::zeroed() };
+        let size = unsafe {
+            libc::read(
+                keyboard.fd,
+                events.as_mut_ptr() as *mut libc::c_void,
+                mem::size_of_val(&events),
+            )
+        };
+        if size <= 0 {
+            return;
+        }
+
+        let count = size as usize / mem::size_of::<libc::input_event>();
+        for event in &events[..count] {
+            if event.type_ != EV_KEY {
+                self.write_event(event);
+                continue;
+            }
+
+            // Check real-time modifier state, like GetAsyncKeyState in the
+            // Windows hook. Autorepeat counts as a press.
+            let held = held_modifiers(&keyboard.key_state());
+            let pressed = event.value != KEY_RELEASE;
+            let time_ms = event.time.tv_sec as u64 * 1000 + event.time.tv_usec as u64 / 1000;
+            if !keyboard_hook::handle_key_event(backend::current(), key_code(event.code), held, pressed, time_ms) {
+                self.write_event(event);
+            }
+        }
+    }
+
+    fn write_event(&self, event: &libc::input_event) {
+        unsafe {
+            libc::write(
+                self.uinput_fd,
+                event as *const libc::input_event as *const libc::c_void,
+                mem::size_of::<libc::input_event>(),
+            );
+        }
+    }
+
+    fn emit(&self, event_type: u16, code: u16, value: i32) {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        event.type_ = event_type;
+        event.code = code;
+        event.value = value;
+        self.write_event(&event);
+    }
+
+    // Inject a press and release of a lock key through the virtual keyboard.
+    // The virtual keyboard is never grabbed, so the key reaches the system.
+    pub fn tap_key(&self, key: LockKey) {
+        let code = match key {
+            LockKey::CapsLock => KEY_CAPSLOCK,
+            LockKey::ScrollLock => KEY_SCROLLLOCK,
+        };
+
+        self.emit(EV_KEY, code, KEY_PRESS);
+        self.emit(EV_SYN, 0, 0);
+        self.emit(EV_KEY, code, KEY_RELEASE);
+        self.emit(EV_SYN, 0, 0);
+    }
+
+    // Inject a key press or release through the virtual keyboard; the
+    // scancode is the key code
+    pub fn inject_key(&self, key: KeyCode, pressed: bool) {
+        let code = match key.scancode {
+            0 => match code_for_key(key.key) {
+                Some(code) => code,
+                None => return,
+            },
+            code => code,
+        };
+
+        self.emit(EV_KEY, code, if pressed { KEY_PRESS } else { KEY_RELEASE });
+        self.emit(EV_SYN, 0, 0);
+    }
+
+    // Lock key state as shown by the keyboard LEDs
+    pub fn lock_key_state(&self, key: LockKey) -> bool {
+        let led = match key {
+            LockKey::CapsLock => LED_CAPSL,
+            LockKey::ScrollLock => LED_SCROLLL,
+        };
+
+        self.keyboards.first()
+            .map(|k| test_bit(&k.led_state(), led))
+            .unwrap_or(false)
+    }
+
+    // Set an LED on the grabbed keyboards (or only the selected one) by
+    // writing EV_LED to the device. No key event is generated, so the lock
+    // key state seen by applications does not change.
+    pub fn set_led(&self, led: u16, on: bool, device: Option<&str>) -> bool {
+        let mut written = false;
+
+        for keyboard in &self.keyboards {
+            if let Some(filter) = device {
+                if !led::device_matches(filter, keyboard.id(), &keyboard.name) {
+                    continue;
+                }
+            }
+
+            let mut events: [libc::input_event; 2] = unsafe { mem::zeroed() };
+            events[0].type_ = EV_LED;
+            events[0].code = led;
+            events[0].value = on as i32;
+            events[1].type_ = EV_SYN;
+
+            let size = unsafe {
+                libc::write(
+                    keyboard.fd,
+                    events.as_ptr() as *const libc::c_void,
+                    mem::size_of_val(&events),
+                )
+            };
+            written |= size > 0;
+        }
+
+        written
+    }
+
+    // Wake up the event loop and make it return
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        unsafe {
+            libc::write(self.stop_pipe[1], b"x".as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+impl Drop for EvdevHook {
+    fn drop(&mut self) {
+        for keyboard in &self.keyboards {
+            release_keyboard(keyboard.fd);
+        }
+        destroy_virtual_keyboard(self.uinput_fd);
+        unsafe {
+            libc::close(self.stop_pipe[0]);
+            libc::close(self.stop_pipe[1]);
+        }
+    }
+}
+
+// List /dev/input/event* device nodes
+fn keyboard_device_paths() -> Vec<String> {
+    let mut paths: Vec<String> = match fs::read_dir(INPUT_DIR) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| n.starts_with("event"))
+                .unwrap_or(false))
+            .map(|p| p.to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+// Open a device and grab it if it is a physical keyboard with Caps Lock.
+// Returns Ok(None) for devices that are not keyboards or are CCaps's own.
+fn open_and_grab(path: &str) -> Result<Option<c_int>, String> {
+    let c_path = CString::new(path).map_err(|_| format!("Invalid device path: {}", path))?;
+
+    unsafe {
+        // Write access is only needed for EV_LED, so fall back to read-only
+        let mut fd = libc::open(c_path.as_ptr(), libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC);
+        if fd < 0 {
+            fd = libc::open(c_path.as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK | libc::O_CLOEXEC);
+        }
+        if fd < 0 {
+            return Err(last_os_error(&format!("Cannot open {}", path)));
+        }
+
+        let mut id: libc::input_id = mem::zeroed();
+        libc::ioctl(fd, EVIOCGID, &mut id as *mut libc::input_id);
+
+        let mut ev_bits = vec![0u8; bitmask_len(EV_MAX)];
+        libc::ioctl(fd, eviocgbit(0, ev_bits.len()), ev_bits.as_mut_ptr());
+
+        let mut key_bits = vec![0u8; bitmask_len(KEY_MAX)];
+        libc::ioctl(fd, eviocgbit(EV_KEY, key_bits.len()), key_bits.as_mut_ptr());
+
+        let is_keyboard = test_bit(&ev_bits, EV_KEY)
+            && test_bit(&key_bits, KEY_CAPSLOCK)
+            && test_bit(&key_bits, KEY_A);
+
+        if !is_keyboard || is_ccaps_device(id.vendor, id.product) {
+            libc::close(fd);
+            return Ok(None);
+        }
+
+        if libc::ioctl(fd, EVIOCGRAB, 1 as c_int) != 0 {
+            let error = last_os_error(&format!("Cannot grab {}", path));
+            libc::close(fd);
+            return Err(error);
+        }
+
+        Ok(Some(fd))
+    }
+}
+
+fn device_name(fd: c_int) -> String {
+    let mut name = [0u8; 256];
+    let length = unsafe { libc::ioctl(fd, eviocgname(name.len()), name.as_mut_ptr()) };
+    if length <= 0 {
+        return String::new();
+    }
+
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..end]).to_string()
+}
+
+fn release_keyboard(fd: c_int) {
+    unsafe {
+        libc::ioctl(fd, EVIOCGRAB, 0 as c_int);
+        libc::close(fd);
+    }
+}
+
+// Create the uinput virtual keyboard that re-emits all forwarded events
+fn create_virtual_keyboard() -> Result<c_int, String> {
+    let c_path = CString::new(UINPUT_PATH).unwrap();
+
+    unsafe {
+        let fd = libc::open(c_path.as_ptr(), libc::O_WRONLY | libc::O_NONBLOCK | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(last_os_error(&format!("Cannot open {}", UINPUT_PATH)));
+        }
+
+        libc::ioctl(fd, UI_SET_EVBIT, EV_KEY as c_int);
+        libc::ioctl(fd, UI_SET_EVBIT, EV_SYN as c_int);
+        libc::ioctl(fd, UI_SET_EVBIT, EV_LED as c_int);
+        for key in 1..=KEY_MAX {
+            libc::ioctl(fd, UI_SET_KEYBIT, key as c_int);
+        }
+        for led in [LED_CAPSL, LED_SCROLLL] {
+            libc::ioctl(fd, UI_SET_LEDBIT, led as c_int);
+        }
+
+        let mut setup: libc::uinput_setup = mem::zeroed();
+        setup.id.bustype = BUS_VIRTUAL;
+        setup.id.vendor = VIRTUAL_VENDOR;
+        setup.id.product = VIRTUAL_PRODUCT;
+        setup.id.version = 1;
+        for (dst, src) in setup.name.iter_mut().zip(VIRTUAL_DEVICE_NAME.bytes()) {
+            *dst = src as libc::c_char;
+        }
+
+        if libc::ioctl(fd, UI_DEV_SETUP, &setup as *const libc::uinput_setup) != 0
+            || libc::ioctl(fd, UI_DEV_CREATE) != 0
+        {
+            let error = last_os_error("Cannot create virtual keyboard");
+            libc::close(fd);
+            return Err(error);
+        }
+
+        Ok(fd)
+    }
+}
+
+fn destroy_virtual_keyboard(fd: c_int) {
+    unsafe {
+        libc::ioctl(fd, UI_DEV_DESTROY);
+        libc::close(fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_codes() {
+        assert_eq!(key_code(KEY_CAPSLOCK), KeyCode { key: Key::CapsLock, scancode: 0x3A });
+        assert_eq!(key_code(KEY_RIGHTALT).key, Key::RightAlt);
+        assert_eq!(key_code(KEY_COMPOSE).key, Key::Menu);
+        assert_eq!(key_code(KEY_A).key, Key::Char('A'));
+        assert_eq!(key_code(50).key, Key::Char('M'));
+        assert_eq!(key_code(2).key, Key::Char('1'));
+        assert_eq!(key_code(11).key, Key::Char('0'));
+        assert_eq!(key_code(KEY_F10).key, Key::F(10));
+        assert_eq!(key_code(KEY_F12).key, Key::F(12));
+        assert_eq!(key_code(KEY_F13).key, Key::F(13));
+        assert_eq!(key_code(KEY_MAX).key, Key::Other);
+        assert_eq!(key_code(KEY_ESC).key, Key::Escape);
+        assert_eq!(key_code(KEY_DOWN).key, Key::Down);
+
+        assert_eq!(code_for_key(Key::LeftCtrl), Some(KEY_LEFTCTRL));
+        assert_eq!(code_for_key(Key::RightWin), Some(KEY_RIGHTMETA));
+        assert_eq!(code_for_key(Key::Other), None);
+        assert_eq!(code_for_key(Key::Layer), None);
+    }
+
+    #[test]
+    fn test_virtual_device_carries_ccaps_marker() {
+        assert_eq!(((VIRTUAL_VENDOR as usize) << 16) | VIRTUAL_PRODUCT as usize, CCAPS_EXTRA_INFO);
+        assert!(is_ccaps_device(VIRTUAL_VENDOR, VIRTUAL_PRODUCT), "Own virtual keyboard must not be grabbed");
+        assert!(!is_ccaps_device(0x046d, 0xc31c), "Physical keyboards must be grabbed");
+        assert!(!is_ccaps_device(VIRTUAL_VENDOR, 0), "Only an exact marker match is CCaps's device");
+    }
+
+    #[test]
+    fn test_modifiers_from_key_state() {
+        let mut key_state = vec![0u8; bitmask_len(KEY_MAX)];
+        assert_eq!(held_modifiers(&key_state), Modifiers::NONE);
+
+        key_state[(KEY_RIGHTSHIFT / 8) as usize] |= 1 << (KEY_RIGHTSHIFT % 8);
+        key_state[(KEY_LEFTCTRL / 8) as usize] |= 1 << (KEY_LEFTCTRL % 8);
+        key_state[(KEY_A / 8) as usize] |= 1 << (KEY_A % 8);
+        assert_eq!(held_modifiers(&key_state), Modifiers::NONE.with(Key::RightShift).with(Key::LeftCtrl),
+                   "Only modifier keys count, each with its side");
+    }
+
+    #[test]
+    fn test_bit_out_of_range() {
+        assert!(!test_bit(&[0xFF], 8), "Bits past the end of the mask are unset");
+    }
+
+    #[test]
+    fn test_ioctl_numbers_match_kernel_headers() {
+        assert_eq!(EVIOCGRAB, 0x40044590);
+        assert_eq!(UI_DEV_CREATE, 0x5501);
+        assert_eq!(UI_SET_EVBIT, 0x40045564);
+        assert_eq!(eviocgbit(EV_KEY, 96), 0x80604521);
+        assert_eq!(eviocgname(256), 0x81004506);
+    }
+}
@@ -0,0 +1,222 @@
+use std::thread;
+use std::time::Duration;
+use zbus::blocking::fdo::DBusProxy;
+use zbus::blocking::{Connection, ConnectionBuilder, Proxy};
+use zbus::names::BusName;
+use crate::backend::linux::{handle_index, input_method_lang_id, input_method_variant, layout_handle, LayoutChangeCallback, LayoutSource};
+
+const FCITX_SERVICE: &str = "org.fcitx.Fcitx5";
+const FCITX_PATH: &str = "/controller";
+const FCITX_INTERFACE: &str = "org.fcitx.Fcitx.Controller1";
+
+// The controller interface has no signal for input method changes,
+// so external changes are picked up by polling
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+fn fcitx_proxy(connection: &Connection) -> zbus::Result<Proxy<'static>> {
+    Proxy::new(connection, FCITX_SERVICE, FCITX_PATH, FCITX_INTERFACE)
+}
+
+fn current_input_method(connection: &Connection) -> Option<String> {
+    fcitx_proxy(connection).ok()?.call("CurrentInputMethod", &()).ok()
+}
+
+// Input methods of the current Fcitx5 group, in group order
+fn group_input_methods(connection: &Connection) -> zbus::Result<Vec<String>> {
+    let proxy = fcitx_proxy(connection)?;
+    let group: String = proxy.call("CurrentInputMethodGroup", &())?;
+    let (_layout, items): (String, Vec<(String, String)>) = proxy.call("InputMethodGroupInfo", &(group,))?;
+    Ok(items.into_iter().map(|(name, _layout)| name).collect())
+}
+
+fn input_method_handle(input_methods: &[String], name: &str) -> Option<usize> {
+    input_methods.iter()
+        .position(|input_method| input_method == name)
+        .map(|index| layout_handle(index, input_method_lang_id(name)))
+}
+
+// Layout switching through Fcitx5 input methods (KDE and CJK setups).
+// The cycle goes through the input methods of the current group.
+pub struct FcitxLayoutSource {
+    connection: Connection,
+}
+
+impl FcitxLayoutSource {
+    // Connect to the given bus address, or to the session bus
+    pub fn open(address: Option<&str>) -> Result<Self, String> {
+        let connection = match address {
+            Some(address) => ConnectionBuilder::address(address).and_then(|builder| builder.build()),
+            None => Connection::session(),
+        }
+        .map_err(|e| format!("Cannot connect to the session bus: {}", e))?;
+
+        // Don't let a layout query start Fcitx5 through bus activation
+        let running = DBusProxy::new(&connection)
+            .and_then(|dbus| Ok(dbus.name_has_owner(BusName::try_from(FCITX_SERVICE)?)?))
+            .unwrap_or(false);
+        if !running {
+            return Err("Fcitx5 is not running".to_string());
+        }
+
+        Ok(FcitxLayoutSource { connection })
+    }
+
+    pub fn input_methods(&self) -> Vec<String> {
+        group_input_methods(&self.connection).unwrap_or_default()
+    }
+
+    pub fn current_input_method(&self) -> Option<String> {
+        current_input_method(&self.connection)
+    }
+}
+
+impl LayoutSource for FcitxLayoutSource {
+    fn layout_handles(&self) -> Vec<usize> {
+        self.input_methods()
+            .iter()
+            .enumerate()
+            .map(|(index, name)| layout_handle(index, input_method_lang_id(name)))
+            .collect()
+    }
+
+    fn current_layout(&self) -> Option<usize> {
+        input_method_handle(&self.input_methods(), &self.current_input_method()?)
+    }
+
+    fn layout_variant(&self, handle: usize) -> Option<String> {
+        input_method_variant(self.input_methods().get(handle_index(handle)?)?)
+    }
+
+    fn activate_layout(&self, handle: usize) -> Result<(), String> {
+        let input_methods = self.input_methods();
+        let Some(name) = handle_index(handle).and_then(|index| input_methods.get(index)) else {
+            return Err(format!("No Fcitx5 input method for layout handle {:08X}", handle));
+        };
+
+        fcitx_proxy(&self.connection)
+            .and_then(|proxy| proxy.call_method("SetCurrentIM", &(name.as_str(),)))
+            .map(|_| ())
+            .map_err(|e| format!("Cannot switch Fcitx5 input method: {}", e))
+    }
+
+    fn watch_layout_changes(&self, on_change: LayoutChangeCallback) {
+        let connection = self.connection.clone();
+        let mut last = current_input_method(&connection);
+
+        thread::spawn(move || loop {
+            thread::sleep(POLL_INTERVAL);
+
+            let current = current_input_method(&connection);
+            if current == last {
+                continue;
+            }
+
+            if let Some(name) = &current {
+                let input_methods = group_input_methods(&connection).unwrap_or_default();
+                if let Some(handle) = input_method_handle(&input_methods, name) {
+                    on_change(handle);
+                }
+            }
+            last = current;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::linux::testing::PrivateBus;
+    use std::sync::{mpsc, Mutex};
+    use zbus::dbus_interface;
+
+    const INPUT_METHODS: [&str; 3] = ["keyboard-us", "keyboard-de", "mozc"];
+
+    // Mock of the Fcitx5 controller interface used by CCaps
+    struct MockFcitx {
+        current: String,
+    }
+
+    #[dbus_interface(name = "org.fcitx.Fcitx.Controller1")]
+    impl MockFcitx {
+        fn current_input_method_group(&self) -> String {
+            "Default".to_string()
+        }
+
+        fn input_method_group_info(&self, _group: String) -> (String, Vec<(String, String)>) {
+            let items = INPUT_METHODS.iter()
+                .map(|name| (name.to_string(), String::new()))
+                .collect();
+            ("keyboard-us".to_string(), items)
+        }
+
+        fn current_input_method(&self) -> String {
+            self.current.clone()
+        }
+
+        #[dbus_interface(name = "SetCurrentIM")]
+        fn set_current_im(&mut self, name: String) {
+            self.current = name;
+        }
+    }
+
+    fn start_mock(bus: &PrivateBus) -> Connection {
+        let mock = MockFcitx { current: INPUT_METHODS[0].to_string() };
+
+        ConnectionBuilder::address(bus.address.as_str())
+            .and_then(|builder| builder.name(FCITX_SERVICE))
+            .and_then(|builder| builder.serve_at(FCITX_PATH, mock))
+            .and_then(|builder| builder.build())
+            .expect("Failed to start mock Fcitx5 service")
+    }
+
+    #[test]
+    fn test_input_methods_map_to_cli_codes() {
+        use crate::layout_manager::LayoutInfo;
+
+        let cases = [("keyboard-us", "us"), ("keyboard-de", "de"), ("keyboard-ru-phonetic", "ru-phonetic"),
+                     ("keyboard-us-dvorak", "us-dvorak"), ("mozc", "jp"), ("pinyin", "cn")];
+        for (input_method, code) in cases {
+            let handle = layout_handle(0, input_method_lang_id(input_method));
+            let layout = LayoutInfo::with_variant(handle, input_method_variant(input_method).as_deref());
+            assert_eq!(layout.short_code, code, "Input method '{}' should map to code '{}'", input_method, code);
+        }
+    }
+
+    #[test]
+    fn test_not_running_without_service() {
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        assert!(FcitxLayoutSource::open(Some(&bus.address)).is_err());
+    }
+
+    #[test]
+    fn test_mock_fcitx_switching_and_changes() {
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        let _service = start_mock(&bus);
+
+        let source = FcitxLayoutSource::open(Some(&bus.address)).unwrap();
+        let handles = source.layout_handles();
+        assert_eq!(handles.iter().map(|h| h & 0xFFFF).collect::<Vec<_>>(), vec![0x0409, 0x0407, 0x0411]);
+        assert_eq!(source.current_layout(), Some(handles[0]));
+
+        source.activate_layout(handles[1]).unwrap();
+        assert_eq!(source.current_input_method().as_deref(), Some("keyboard-de"));
+        assert_eq!(source.current_layout(), Some(handles[1]));
+
+        // An input method change made by another client is reported to the watcher
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        source.watch_layout_changes(Box::new(move |handle| {
+            let _ = sender.lock().unwrap().send(handle);
+        }));
+
+        let other_client = FcitxLayoutSource::open(Some(&bus.address)).unwrap();
+        other_client.activate_layout(handles[2]).unwrap();
+
+        let reported = receiver.recv_timeout(Duration::from_secs(5)).expect("No input method change detected");
+        assert_eq!(reported, handles[2]);
+    }
+}
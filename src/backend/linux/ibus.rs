@@ -0,0 +1,288 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use zbus::blocking::{Connection, ConnectionBuilder, Proxy, ProxyBuilder};
+use zbus::zvariant::{OwnedValue, Value};
+use zbus::CacheProperties;
+use crate::backend::linux::{handle_index, input_method_lang_id, input_method_variant, layout_handle, LayoutChangeCallback, LayoutSource};
+
+const IBUS_SERVICE: &str = "org.freedesktop.IBus";
+const IBUS_PATH: &str = "/org/freedesktop/IBus";
+const IBUS_INTERFACE: &str = "org.freedesktop.IBus";
+
+// Environment variable pointing at the IBus bus, set by ibus-daemon
+pub const IBUS_ADDRESS_ENV_VAR: &str = "IBUS_ADDRESS";
+
+// Address of the running ibus-daemon. IBus runs its own bus instead of
+// the session bus; the address is also written to ~/.config/ibus/bus/.
+fn ibus_address() -> Option<String> {
+    if let Ok(address) = env::var(IBUS_ADDRESS_ENV_VAR) {
+        return Some(address);
+    }
+
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    // One file per display; the most recently written one is the live daemon
+    let newest = fs::read_dir(config_dir.join("ibus").join("bus")).ok()?
+        .flatten()
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max()?;
+
+    parse_address_file(&fs::read_to_string(newest.1).ok()?)
+}
+
+pub fn parse_address_file(content: &str) -> Option<String> {
+    content.lines()
+        .find_map(|line| line.strip_prefix("IBUS_ADDRESS="))
+        .map(|address| address.trim().to_string())
+        .filter(|address| !address.is_empty())
+}
+
+// Engine name from a serialized IBusEngineDesc, a structure whose third
+// field is the engine name
+pub fn engine_desc_name(value: &Value) -> Option<String> {
+    match value {
+        Value::Value(inner) => engine_desc_name(inner),
+        Value::Structure(desc) => match desc.fields().get(2)? {
+            Value::Str(name) => Some(name.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn ibus_proxy(connection: &Connection) -> zbus::Result<Proxy<'static>> {
+    ProxyBuilder::new_bare(connection)
+        .destination(IBUS_SERVICE)?
+        .path(IBUS_PATH)?
+        .interface(IBUS_INTERFACE)?
+        .cache_properties(CacheProperties::No)
+        .build()
+}
+
+// Layout switching through IBus engines (GNOME and other IBus desktops).
+// The cycle goes through the preloaded engines, the ones configured in the
+// IBus preferences.
+pub struct IbusLayoutSource {
+    connection: Connection,
+}
+
+impl IbusLayoutSource {
+    // Connect to the given bus address, or find the running ibus-daemon
+    pub fn open(address: Option<&str>) -> Result<Self, String> {
+        let address = match address {
+            Some(address) => address.to_string(),
+            None => ibus_address().ok_or_else(|| "IBus is not running".to_string())?,
+        };
+
+        let connection = ConnectionBuilder::address(address.as_str())
+            .and_then(|builder| builder.build())
+            .map_err(|e| format!("Cannot connect to IBus: {}", e))?;
+
+        let source = IbusLayoutSource { connection };
+        source.engines().map_err(|e| format!("IBus is not available: {}", e))?;
+        Ok(source)
+    }
+
+    pub fn engines(&self) -> zbus::Result<Vec<String>> {
+        ibus_proxy(&self.connection)?.get_property("PreloadEngines")
+    }
+
+    pub fn global_engine(&self) -> Option<String> {
+        let value: OwnedValue = ibus_proxy(&self.connection).ok()?
+            .get_property("GlobalEngine")
+            .ok()?;
+        engine_desc_name(&value)
+    }
+
+    fn engine_handle(engines: &[String], name: &str) -> Option<usize> {
+        engines.iter()
+            .position(|engine| engine == name)
+            .map(|index| layout_handle(index, input_method_lang_id(name)))
+    }
+}
+
+impl LayoutSource for IbusLayoutSource {
+    fn layout_handles(&self) -> Vec<usize> {
+        self.engines()
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .map(|(index, name)| layout_handle(index, input_method_lang_id(name)))
+            .collect()
+    }
+
+    fn current_layout(&self) -> Option<usize> {
+        let engines = self.engines().ok()?;
+        Self::engine_handle(&engines, &self.global_engine()?)
+    }
+
+    fn layout_variant(&self, handle: usize) -> Option<String> {
+        input_method_variant(self.engines().ok()?.get(handle_index(handle)?)?)
+    }
+
+    fn activate_layout(&self, handle: usize) -> Result<(), String> {
+        let engines = self.engines().unwrap_or_default();
+        let Some(name) = handle_index(handle).and_then(|index| engines.get(index)) else {
+            return Err(format!("No IBus engine for layout handle {:08X}", handle));
+        };
+
+        ibus_proxy(&self.connection)
+            .and_then(|proxy| proxy.call_method("SetGlobalEngine", &(name.as_str(),)))
+            .map(|_| ())
+            .map_err(|e| format!("Cannot switch IBus engine: {}", e))
+    }
+
+    fn watch_layout_changes(&self, on_change: LayoutChangeCallback) {
+        let signals = ibus_proxy(&self.connection)
+            .and_then(|proxy| proxy.receive_signal("GlobalEngineChanged"));
+        let signals = match signals {
+            Ok(signals) => signals,
+            Err(e) => {
+                eprintln!("Warning: Cannot watch IBus engine changes: {}", e);
+                return;
+            }
+        };
+
+        let connection = self.connection.clone();
+        thread::spawn(move || {
+            for signal in signals {
+                let Ok(name) = signal.body::<String>() else {
+                    continue;
+                };
+                let engines = ibus_proxy(&connection)
+                    .and_then(|proxy| proxy.get_property::<Vec<String>>("PreloadEngines"))
+                    .unwrap_or_default();
+                if let Some(handle) = Self::engine_handle(&engines, &name) {
+                    on_change(handle);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::linux::testing::PrivateBus;
+    use std::collections::HashMap;
+    use std::sync::{mpsc, Mutex};
+    use std::time::Duration;
+    use zbus::zvariant::StructureBuilder;
+    use zbus::{dbus_interface, SignalContext};
+
+    const ENGINES: [&str; 3] = ["xkb:us::eng", "xkb:de::ger", "xkb:ru:phonetic:rus"];
+
+    fn engine_desc(name: &str) -> Value<'static> {
+        StructureBuilder::new()
+            .add_field("IBusEngineDesc")
+            .add_field(HashMap::<String, Value>::new())
+            .add_field(name.to_string())
+            .add_field("Long name")
+            .build()
+            .into()
+    }
+
+    // Mock of the ibus-daemon interface used by CCaps
+    struct MockIbus {
+        engines: Vec<String>,
+        global_engine: String,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.IBus")]
+    impl MockIbus {
+        #[dbus_interface(property)]
+        fn preload_engines(&self) -> Vec<String> {
+            self.engines.clone()
+        }
+
+        #[dbus_interface(property)]
+        fn global_engine(&self) -> OwnedValue {
+            engine_desc(&self.global_engine).into()
+        }
+
+        async fn set_global_engine(&mut self, name: String, #[zbus(signal_context)] context: SignalContext<'_>) {
+            self.global_engine = name;
+            let _ = Self::engine_changed(&context, &self.global_engine).await;
+        }
+
+        #[dbus_interface(signal, name = "GlobalEngineChanged")]
+        async fn engine_changed(context: &SignalContext<'_>, name: &str) -> zbus::Result<()>;
+    }
+
+    fn start_mock(bus: &PrivateBus) -> Connection {
+        let mock = MockIbus {
+            engines: ENGINES.iter().map(|s| s.to_string()).collect(),
+            global_engine: ENGINES[0].to_string(),
+        };
+
+        ConnectionBuilder::address(bus.address.as_str())
+            .and_then(|builder| builder.name(IBUS_SERVICE))
+            .and_then(|builder| builder.serve_at(IBUS_PATH, mock))
+            .and_then(|builder| builder.build())
+            .expect("Failed to start mock IBus service")
+    }
+
+    #[test]
+    fn test_parse_address_file() {
+        let content = "# This file is created by ibus-daemon, please do not modify it.\n\
+                       IBUS_ADDRESS=unix:abstract=/home/user/.cache/ibus/dbus-abc,guid=123\n\
+                       IBUS_DAEMON_PID=1234\n";
+        assert_eq!(parse_address_file(content).as_deref(), Some("unix:abstract=/home/user/.cache/ibus/dbus-abc,guid=123"));
+        assert_eq!(parse_address_file("IBUS_DAEMON_PID=1234\n"), None);
+    }
+
+    #[test]
+    fn test_engine_desc_name() {
+        let desc = engine_desc("xkb:de::ger");
+        assert_eq!(engine_desc_name(&desc).as_deref(), Some("xkb:de::ger"));
+        assert_eq!(engine_desc_name(&Value::Value(Box::new(desc))).as_deref(), Some("xkb:de::ger"));
+        assert_eq!(engine_desc_name(&Value::from("xkb:de::ger")), None);
+    }
+
+    #[test]
+    fn test_engines_map_to_cli_codes() {
+        use crate::layout_manager::LayoutInfo;
+
+        let cases = [("xkb:us::eng", "us"), ("xkb:gb:extd:eng", "gb-extd"), ("xkb:ru:phonetic:rus", "ru-phonetic"),
+                     ("anthy", "jp"), ("hangul", "kr"), ("libpinyin", "cn")];
+        for (engine, code) in cases {
+            let handle = layout_handle(0, input_method_lang_id(engine));
+            let layout = LayoutInfo::with_variant(handle, input_method_variant(engine).as_deref());
+            assert_eq!(layout.short_code, code, "Engine '{}' should map to code '{}'", engine, code);
+        }
+    }
+
+    #[test]
+    fn test_mock_ibus_switching_and_events() {
+        let Some(bus) = PrivateBus::start() else {
+            return;
+        };
+        let _service = start_mock(&bus);
+
+        let source = IbusLayoutSource::open(Some(&bus.address)).unwrap();
+        let handles = source.layout_handles();
+        assert_eq!(handles.iter().map(|h| h & 0xFFFF).collect::<Vec<_>>(), vec![0x0409, 0x0407, 0x0419]);
+        assert_eq!(source.current_layout(), Some(handles[0]));
+
+        source.activate_layout(handles[1]).unwrap();
+        assert_eq!(source.global_engine().as_deref(), Some("xkb:de::ger"));
+        assert_eq!(source.current_layout(), Some(handles[1]));
+
+        // An engine change made by another client is reported to the watcher
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        source.watch_layout_changes(Box::new(move |handle| {
+            let _ = sender.lock().unwrap().send(handle);
+        }));
+
+        let other_client = IbusLayoutSource::open(Some(&bus.address)).unwrap();
+        other_client.activate_layout(handles[2]).unwrap();
+
+        let reported = receiver.recv_timeout(Duration::from_secs(5)).expect("No engine change signal");
+        assert_eq!(reported, handles[2]);
+    }
+}
@@ -0,0 +1,214 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+// Selects how the layout indicator LED is driven, e.g. "sysfs:input3"
+pub const LED_ENV_VAR: &str = "CCAPS_LED";
+
+pub const SYSFS_LEDS_DIR: &str = "/sys/class/leds";
+const SCROLL_LOCK_SUFFIX: &str = "::scrolllock";
+
+// Indicator driver chosen with CCAPS_LED. Sysfs and evdev drivers take an
+// optional device: an id ("input3" for sysfs, "event3" for evdev) or part
+// of the device name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LedSelection {
+    // Layout source LED (virtual console), then sysfs, then evdev
+    Auto,
+    // Toggle Scroll Lock with key events, as on Windows
+    Keys,
+    Sysfs(Option<String>),
+    Evdev(Option<String>),
+}
+
+impl LedSelection {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (driver, device) = match value.split_once(':') {
+            Some((driver, device)) if !device.is_empty() => (driver, Some(device.to_string())),
+            Some((driver, _)) => (driver, None),
+            None => (value, None),
+        };
+
+        match (driver.to_lowercase().as_str(), device) {
+            ("auto", None) => Ok(LedSelection::Auto),
+            ("keys", None) => Ok(LedSelection::Keys),
+            ("sysfs", device) => Ok(LedSelection::Sysfs(device)),
+            ("evdev", device) => Ok(LedSelection::Evdev(device)),
+            _ => Err(format!("Invalid {} value '{}' (expected auto, keys, sysfs[:device] or evdev[:device])",
+                             LED_ENV_VAR, value)),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        match env::var(LED_ENV_VAR) {
+            Ok(value) => Self::parse(&value).unwrap_or_else(|e| {
+                eprintln!("Warning: {}", e);
+                LedSelection::Auto
+            }),
+            Err(_) => LedSelection::Auto,
+        }
+    }
+
+    // Device filter for the sysfs driver; Auto uses every device
+    pub fn sysfs_device(&self) -> Option<Option<&str>> {
+        match self {
+            LedSelection::Auto => Some(None),
+            LedSelection::Sysfs(device) => Some(device.as_deref()),
+            _ => None,
+        }
+    }
+}
+
+// Whether a device selected by `filter` is the one with the given id or name
+pub fn device_matches(filter: &str, id: &str, name: &str) -> bool {
+    filter == id || (!name.is_empty() && name.to_lowercase().contains(&filter.to_lowercase()))
+}
+
+// Scroll Lock LEDs exposed by the kernel under /sys/class/leds
+#[derive(Default)]
+pub struct SysfsLeds {
+    brightness: Vec<PathBuf>,
+}
+
+impl SysfsLeds {
+    // Find writable "<device>::scrolllock" LEDs under `root`
+    pub fn find(root: &Path, device: Option<&str>) -> Self {
+        let mut brightness = Vec::new();
+
+        let mut entries: Vec<PathBuf> = match fs::read_dir(root) {
+            Ok(entries) => entries.flatten().map(|e| e.path()).collect(),
+            Err(_) => Vec::new(),
+        };
+        entries.sort();
+
+        for led_dir in entries {
+            let Some(entry_name) = led_dir.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(id) = entry_name.strip_suffix(SCROLL_LOCK_SUFFIX) else {
+                continue;
+            };
+
+            if let Some(filter) = device {
+                let name = fs::read_to_string(led_dir.join("device").join("name")).unwrap_or_default();
+                if !device_matches(filter, id, name.trim()) {
+                    continue;
+                }
+            }
+
+            let path = led_dir.join("brightness");
+            if OpenOptions::new().write(true).open(&path).is_err() {
+                continue;
+            }
+
+            // Detach the kbd-scrolllock trigger so the kernel does not
+            // overwrite the LED with the Scroll Lock state
+            let _ = fs::write(led_dir.join("trigger"), "none");
+            brightness.push(path);
+        }
+
+        SysfsLeds { brightness }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.brightness.is_empty()
+    }
+
+    pub fn set(&self, on: bool) -> bool {
+        let value = if on { "1" } else { "0" };
+        self.brightness.iter()
+            .filter(|path| fs::write(path, value).is_ok())
+            .count() > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fake /sys/class/leds tree, removed on drop
+    struct FakeSysfs {
+        root: PathBuf,
+    }
+
+    impl FakeSysfs {
+        fn new(name: &str) -> Self {
+            let root = env::temp_dir().join(format!("ccaps-test-leds-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+
+            for (led, device_name) in [("input3::scrolllock", "AT Translated Set 2 keyboard"),
+                                       ("input3::capslock", "AT Translated Set 2 keyboard"),
+                                       ("input7::scrolllock", "Logitech USB Keyboard")] {
+                let led_dir = root.join(led);
+                fs::create_dir_all(led_dir.join("device")).unwrap();
+                fs::write(led_dir.join("brightness"), "0").unwrap();
+                fs::write(led_dir.join("trigger"), "none [kbd-scrolllock]").unwrap();
+                fs::write(led_dir.join("device").join("name"), format!("{}\n", device_name)).unwrap();
+            }
+
+            FakeSysfs { root }
+        }
+
+        fn read(&self, led: &str, file: &str) -> String {
+            fs::read_to_string(self.root.join(led).join(file)).unwrap()
+        }
+    }
+
+    impl Drop for FakeSysfs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(LedSelection::parse("auto"), Ok(LedSelection::Auto));
+        assert_eq!(LedSelection::parse("keys"), Ok(LedSelection::Keys));
+        assert_eq!(LedSelection::parse("sysfs"), Ok(LedSelection::Sysfs(None)));
+        assert_eq!(LedSelection::parse("sysfs:input3"), Ok(LedSelection::Sysfs(Some("input3".to_string()))));
+        assert_eq!(LedSelection::parse("EVDEV:Logitech"), Ok(LedSelection::Evdev(Some("Logitech".to_string()))));
+        assert!(LedSelection::parse("keys:input3").is_err());
+        assert!(LedSelection::parse("blink").is_err());
+    }
+
+    #[test]
+    fn test_device_matches() {
+        assert!(device_matches("event3", "event3", "AT Translated Set 2 keyboard"));
+        assert!(device_matches("logitech", "event7", "Logitech USB Keyboard"));
+        assert!(!device_matches("event3", "event30", "Other"));
+        assert!(!device_matches("x", "event3", ""));
+    }
+
+    #[test]
+    fn test_sysfs_finds_scroll_lock_leds_only() {
+        let sysfs = FakeSysfs::new("all");
+        let leds = SysfsLeds::find(&sysfs.root, None);
+
+        assert!(leds.set(true));
+        assert_eq!(sysfs.read("input3::scrolllock", "brightness"), "1");
+        assert_eq!(sysfs.read("input7::scrolllock", "brightness"), "1");
+        assert_eq!(sysfs.read("input3::capslock", "brightness"), "0", "Caps Lock LED must not change");
+        assert_eq!(sysfs.read("input3::scrolllock", "trigger"), "none", "Keyboard trigger should be detached");
+
+        assert!(leds.set(false));
+        assert_eq!(sysfs.read("input7::scrolllock", "brightness"), "0");
+    }
+
+    #[test]
+    fn test_sysfs_selects_device() {
+        let sysfs = FakeSysfs::new("device");
+
+        let by_id = SysfsLeds::find(&sysfs.root, Some("input7"));
+        assert!(by_id.set(true));
+        assert_eq!(sysfs.read("input7::scrolllock", "brightness"), "1");
+        assert_eq!(sysfs.read("input3::scrolllock", "brightness"), "0");
+
+        let by_name = SysfsLeds::find(&sysfs.root, Some("AT Translated"));
+        assert!(by_name.set(true));
+        assert_eq!(sysfs.read("input3::scrolllock", "brightness"), "1");
+
+        let missing = SysfsLeds::find(&sysfs.root, Some("input9"));
+        assert!(missing.is_empty());
+        assert!(!missing.set(true));
+    }
+}
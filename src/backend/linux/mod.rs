@@ -0,0 +1,564 @@
+// Native Linux backend.
+//
+// Keyboard capture works the same on every Linux session: physical keyboards
+// are grabbed through evdev and everything except Caps Lock is re-emitted
+// through a uinput virtual keyboard. Layout switching depends on the session
+// (X11, Wayland compositor, input method framework, virtual console) and is
+// delegated to a `LayoutSource`.
+
+pub mod autostart;
+pub mod evdev;
+pub mod fcitx;
+pub mod ibus;
+pub mod led;
+pub mod sway;
+pub mod tty;
+pub mod x11;
+
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::c_int;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::backend::{self, Backend, LocalTime, LockKey, LockStatus};
+use crate::bindings::KeyCode;
+use crate::instance::InstanceInfo;
+use crate::keyboard_hook;
+use autostart::Autostart;
+use evdev::EvdevHook;
+use led::{LedSelection, SysfsLeds};
+
+const LOCK_FILE_NAME: &str = "ccaps.lock";
+
+// Called with the new layout handle when the layout changes outside CCaps
+pub type LayoutChangeCallback = Box<dyn Fn(usize) + Send + 'static>;
+
+// Session-specific layout switching
+pub trait LayoutSource: Send + Sync {
+    // Layout handles: low 16 bits hold the Windows language ID, the rest
+    // identifies the layout within the source
+    fn layout_handles(&self) -> Vec<usize>;
+    fn current_layout(&self) -> Option<usize>;
+    fn activate_layout(&self, handle: usize) -> Result<(), String>;
+
+    // Variant of a layout whose language has other layouts in the source
+    fn layout_variant(&self, _handle: usize) -> Option<String> {
+        None
+    }
+
+    // Add the default layout of a language, or remove a layout, in the
+    // session's keyboard configuration
+    fn add_layout(&self, _lang_id: u16) -> Result<usize, String> {
+        Err("Layouts cannot be added in this session; use the desktop's keyboard settings".to_string())
+    }
+    fn remove_layout(&self, _handle: usize) -> Result<(), String> {
+        Err("Layouts cannot be removed in this session; use the desktop's keyboard settings".to_string())
+    }
+
+    // Start reporting layout changes, for sources that can notify them
+    fn watch_layout_changes(&self, _on_change: LayoutChangeCallback) {}
+
+    // Drive the indicator LED directly; false if the source has no way to
+    fn set_indicator_led(&self, _on: bool) -> bool {
+        false
+    }
+}
+
+// Layout handle for the layout at `index` within its source: index in the
+// high word, language ID in the low word
+pub fn layout_handle(index: usize, lang_id: u32) -> usize {
+    ((index + 1) << 16) | (lang_id & 0xFFFF) as usize
+}
+
+// Index within the source for a handle built by `layout_handle`
+pub fn handle_index(handle: usize) -> Option<usize> {
+    (handle >> 16).checked_sub(1)
+}
+
+// XKB "layout(variant)" name of a keyboard layout input method: IBus
+// engines carry it as "xkb:ru:phonetic:rus", Fcitx5 as "keyboard-ru-phonetic"
+fn input_method_symbols_name(name: &str) -> Option<String> {
+    let (layout, variant) = if let Some(rest) = name.strip_prefix("xkb:") {
+        let mut parts = rest.split(':');
+        (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+    } else {
+        let rest = name.strip_prefix("keyboard-")?;
+        rest.split_once('-').unwrap_or((rest, ""))
+    };
+
+    Some(if variant.is_empty() {
+        layout.to_string()
+    } else {
+        format!("{}({})", layout, variant)
+    })
+}
+
+// Map an input method name to a Windows language ID. Keyboard layout
+// engines map through their XKB layout; other engines are matched by name.
+pub fn input_method_lang_id(name: &str) -> u32 {
+    if let Some(symbols_name) = input_method_symbols_name(name) {
+        return x11::xkb_layout_lang_id(&symbols_name);
+    }
+
+    match name {
+        "anthy" | "mozc" | "mozc-jp" | "kkc" | "skk" => 0x0411,
+        "hangul" => 0x0412,
+        "chewing" | "zhuyin" => 0x0404,
+        "pinyin" | "libpinyin" | "shuangpin" | "rime" | "sunpinyin" | "wubi" | "table:wubi" => 0x0804,
+        "unikey" | "bamboo" => 0x042a,
+        _ => 0,
+    }
+}
+
+// XKB variant of a keyboard layout input method ("phonetic" for "keyboard-ru-phonetic")
+pub fn input_method_variant(name: &str) -> Option<String> {
+    x11::xkb_variant(&input_method_symbols_name(name)?)
+}
+
+// Used when no supported layout switching mechanism was detected
+pub struct NoLayoutSource;
+
+impl LayoutSource for NoLayoutSource {
+    fn layout_handles(&self) -> Vec<usize> {
+        Vec::new()
+    }
+
+    fn current_layout(&self) -> Option<usize> {
+        None
+    }
+
+    fn activate_layout(&self, _handle: usize) -> Result<(), String> {
+        Err("No supported layout switching mechanism".to_string())
+    }
+}
+
+pub struct LinuxBackend {
+    layouts: Box<dyn LayoutSource>,
+    led: LedSelection,
+    sysfs_leds: SysfsLeds,
+    hook: Mutex<Option<Arc<EvdevHook>>>,
+    lock_fd: Mutex<Option<c_int>>,
+    // Bumped by every scheduled switch check, so only the latest one runs
+    switch_check: Arc<AtomicU64>,
+}
+
+impl LinuxBackend {
+    pub fn new() -> Self {
+        let led = LedSelection::from_env();
+        let sysfs_leds = match led.sysfs_device() {
+            Some(device) => SysfsLeds::find(Path::new(led::SYSFS_LEDS_DIR), device),
+            None => SysfsLeds::default(),
+        };
+        if matches!(led, LedSelection::Sysfs(_)) && sysfs_leds.is_empty() {
+            eprintln!("Warning: No writable Scroll Lock LED found in {}", led::SYSFS_LEDS_DIR);
+        }
+
+        LinuxBackend {
+            layouts: detect_layout_source(),
+            led,
+            sysfs_leds,
+            hook: Mutex::new(None),
+            lock_fd: Mutex::new(None),
+            switch_check: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn current_hook(&self) -> Option<Arc<EvdevHook>> {
+        self.hook.lock().unwrap().clone()
+    }
+}
+
+impl Default for LinuxBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Pick the layout source for the current session
+fn detect_layout_source() -> Box<dyn LayoutSource> {
+    // Input method frameworks switch layouts themselves and override the
+    // compositor or X server setting, so they take precedence
+    if let Ok(source) = fcitx::FcitxLayoutSource::open(None) {
+        return Box::new(source);
+    }
+    if let Ok(source) = ibus::IbusLayoutSource::open(None) {
+        return Box::new(source);
+    }
+
+    // sway and compatible wlroots compositors
+    if env::var_os(sway::SWAYSOCK_ENV_VAR).is_some() {
+        match sway::SwayLayoutSource::open(None) {
+            Ok(source) => return Box::new(source),
+            Err(e) => eprintln!("Warning: Sway layout switching unavailable: {}", e),
+        }
+    }
+
+    // X11 session (XWayland also sets DISPLAY, but its groups do not
+    // affect native Wayland clients)
+    if env::var_os("DISPLAY").is_some() && env::var_os("WAYLAND_DISPLAY").is_none() {
+        match x11::XkbLayoutSource::open(None) {
+            Ok(source) => return Box::new(source),
+            Err(e) => eprintln!("Warning: X11 layout switching unavailable: {}", e),
+        }
+    }
+
+    // Virtual console without a display server, if keymaps are configured
+    if env::var_os("DISPLAY").is_none() && env::var_os("WAYLAND_DISPLAY").is_none()
+        && !tty::configured_keymaps().is_empty()
+    {
+        match tty::TtyLayoutSource::open() {
+            Ok(source) => return Box::new(source),
+            Err(e) => eprintln!("Warning: Console layout switching unavailable: {}", e),
+        }
+    }
+
+    Box::new(NoLayoutSource)
+}
+
+// `ccaps -layouts add/remove` asks the running instance to re-read its
+// layouts with SIGUSR1
+extern "C" fn on_refresh_signal(_signal: c_int) {
+    keyboard_hook::request_refresh();
+}
+
+// Directory for the instance lock file
+fn runtime_dir() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+}
+
+fn lock_file_path() -> PathBuf {
+    runtime_dir().join(LOCK_FILE_NAME)
+}
+
+// Open the lock file, creating it if needed
+fn open_lock_file() -> Result<c_int, String> {
+    let path = lock_file_path();
+    let c_path = CString::new(path.to_string_lossy().as_bytes())
+        .map_err(|_| "Invalid lock file path".to_string())?;
+
+    let fd = unsafe {
+        libc::open(c_path.as_ptr(), libc::O_RDWR | libc::O_CREAT | libc::O_CLOEXEC, 0o600)
+    };
+    if fd < 0 {
+        return Err(format!("Cannot open lock file {}: {}", path.display(), io::Error::last_os_error()));
+    }
+    Ok(fd)
+}
+
+// Contents of the lock file while another process holds the lock
+fn held_lock_file_content() -> Option<String> {
+    let fd = open_lock_file().ok()?;
+    let held_elsewhere = unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } != 0;
+    unsafe {
+        if !held_elsewhere {
+            libc::flock(fd, libc::LOCK_UN);
+        }
+        libc::close(fd);
+    }
+
+    if !held_elsewhere {
+        return None;
+    }
+
+    fs::read_to_string(lock_file_path()).ok()
+}
+
+// PID of the process holding the instance lock, if any.
+// Older versions wrote only the PID to the lock file.
+fn running_instance_pid() -> Option<i32> {
+    let content = held_lock_file_content()?;
+    match InstanceInfo::from_json(&content) {
+        Some(info) => i32::try_from(info.pid).ok(),
+        None => content.trim().parse().ok(),
+    }
+}
+
+impl Backend for LinuxBackend {
+    fn name(&self) -> &'static str {
+        "linux"
+    }
+
+    fn layout_handles(&self) -> Vec<usize> {
+        self.layouts.layout_handles()
+    }
+
+    fn foreground_layout(&self) -> Option<usize> {
+        self.layouts.current_layout()
+    }
+
+    // Every source has a single way of switching, so retries repeat it
+    fn activate_layout(&self, handle: usize, _attempt: usize) -> Result<(), String> {
+        self.layouts.activate_layout(handle)
+    }
+
+    fn layout_variant(&self, handle: usize) -> Option<String> {
+        self.layouts.layout_variant(handle)
+    }
+
+    fn add_layout(&self, lang_id: u16) -> Result<usize, String> {
+        self.layouts.add_layout(lang_id)
+    }
+
+    fn remove_layout(&self, handle: usize) -> Result<(), String> {
+        self.layouts.remove_layout(handle)
+    }
+
+    fn set_indicator_led(&self, on: bool) -> bool {
+        let evdev = |device: Option<&str>| {
+            self.current_hook()
+                .map(|hook| hook.set_led(evdev::LED_SCROLLL, on, device))
+                .unwrap_or(false)
+        };
+
+        match &self.led {
+            LedSelection::Auto => {
+                self.layouts.set_indicator_led(on)
+                    || self.sysfs_leds.set(on)
+                    || evdev(None)
+            },
+            LedSelection::Keys => false,
+            LedSelection::Sysfs(_) => self.sysfs_leds.set(on),
+            LedSelection::Evdev(device) => evdev(device.as_deref()),
+        }
+    }
+
+    fn lock_key_state(&self, key: LockKey) -> bool {
+        self.current_hook()
+            .map(|hook| hook.lock_key_state(key))
+            .unwrap_or(false)
+    }
+
+    fn inject_key_tap(&self, key: LockKey) {
+        if let Some(hook) = self.current_hook() {
+            hook.tap_key(key);
+        }
+    }
+
+    fn inject_key(&self, key: KeyCode, pressed: bool) {
+        if let Some(hook) = self.current_hook() {
+            hook.inject_key(key, pressed);
+        }
+    }
+
+    // The evdev loop has no timers, so the check waits on its own thread
+    fn schedule_switch_check(&self, delay: Duration) {
+        let generation = self.switch_check.fetch_add(1, Ordering::SeqCst) + 1;
+        let latest = Arc::clone(&self.switch_check);
+        thread::spawn(move || {
+            thread::sleep(delay);
+            if latest.load(Ordering::SeqCst) == generation {
+                keyboard_hook::check_pending_switch(backend::current());
+            }
+        });
+    }
+
+    fn install_hook(&self) -> Result<(), String> {
+        let hook = EvdevHook::open()?;
+        *self.hook.lock().unwrap() = Some(Arc::new(hook));
+
+        unsafe {
+            libc::signal(libc::SIGUSR1, on_refresh_signal as extern "C" fn(c_int) as *const () as libc::sighandler_t);
+        }
+
+        // Keep the cycle index in sync with layout changes made elsewhere
+        self.layouts.watch_layout_changes(Box::new(|handle| {
+            keyboard_hook::sync_current_layout(backend::current(), handle);
+        }));
+        Ok(())
+    }
+
+    fn uninstall_hook(&self) {
+        // Devices are released when the last reference is dropped
+        if let Some(hook) = self.hook.lock().unwrap().take() {
+            hook.stop();
+        }
+    }
+
+    fn run_message_loop(&self) {
+        if let Some(hook) = self.current_hook() {
+            hook.run();
+        }
+    }
+
+    fn local_time(&self, unix_time: u64) -> Option<LocalTime> {
+        let time = libc::time_t::try_from(unix_time).ok()?;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return None;
+        }
+
+        Some(LocalTime {
+            year: tm.tm_year + 1900,
+            month: (tm.tm_mon + 1) as u32,
+            day: tm.tm_mday as u32,
+            hour: tm.tm_hour as u32,
+            minute: tm.tm_min as u32,
+        })
+    }
+
+    fn acquire_instance_lock(&self, info: &InstanceInfo) -> Result<LockStatus, String> {
+        let fd = open_lock_file()?;
+
+        unsafe {
+            if libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) != 0 {
+                libc::close(fd);
+                return Ok(LockStatus::AlreadyRunning);
+            }
+        }
+
+        // Record the owner so other invocations can report and stop us
+        if let Err(e) = fs::write(lock_file_path(), format!("{}\n", info.to_json())) {
+            eprintln!("Warning: Could not write lock file: {}", e);
+        }
+
+        *self.lock_fd.lock().unwrap() = Some(fd);
+        Ok(LockStatus::Acquired)
+    }
+
+    fn release_instance_lock(&self) {
+        if let Some(fd) = self.lock_fd.lock().unwrap().take() {
+            let _ = fs::write(lock_file_path(), "");
+            unsafe {
+                libc::flock(fd, libc::LOCK_UN);
+                libc::close(fd);
+            }
+        }
+    }
+
+    fn is_instance_running(&self) -> bool {
+        running_instance_pid().is_some()
+    }
+
+    fn running_instance(&self) -> Option<InstanceInfo> {
+        InstanceInfo::from_json(&held_lock_file_content()?)
+    }
+
+    fn update_instance_info(&self, info: &InstanceInfo) -> bool {
+        self.lock_fd.lock().unwrap().is_some()
+            && fs::write(lock_file_path(), format!("{}\n", info.to_json())).is_ok()
+    }
+
+    fn stop_running_instance(&self) -> bool {
+        match running_instance_pid() {
+            Some(pid) => unsafe { libc::kill(pid, libc::SIGTERM) == 0 },
+            None => false,
+        }
+    }
+
+    fn refresh_running_instance(&self) -> bool {
+        // Instances that wrote only their PID predate the SIGUSR1 handler,
+        // for them the signal would be fatal
+        match (self.running_instance(), running_instance_pid()) {
+            (Some(_), Some(pid)) => unsafe { libc::kill(pid, libc::SIGUSR1) == 0 },
+            _ => false,
+        }
+    }
+
+    fn spawn_background(&self, country_codes: &[String]) -> Result<(), String> {
+        let exe_path = env::current_exe()
+            .map_err(|_| "Failed to get executable path")?;
+
+        let mut command = Command::new(&exe_path);
+        command.arg("--background");
+
+        // Add country codes to the background process
+        for code in country_codes {
+            command.arg(format!("-{}", code));
+        }
+
+        // Detach from the terminal: own process group, no inherited stdio
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0);
+
+        command.spawn()
+            .map(|_| ())
+            .map_err(|e| format!("Failed to start process: {}", e))
+    }
+
+    fn is_in_startup(&self) -> bool {
+        Autostart::from_env()
+            .map(|autostart| autostart.is_enabled())
+            .unwrap_or(false)
+    }
+
+    fn get_startup_command(&self) -> Result<String, String> {
+        Autostart::from_env()?.command()
+    }
+
+    fn add_to_startup(&self, country_codes: &[String]) -> Result<(), String> {
+        let exe_path = env::current_exe()
+            .map_err(|_| "Failed to get executable path")?;
+        Autostart::from_env()?.install(&exe_path, country_codes)
+    }
+
+    fn remove_from_startup(&self) -> Result<(), String> {
+        Autostart::from_env()?.remove()
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    pub fn command_exists(name: &str) -> bool {
+        Command::new("sh")
+            .args(["-c", &format!("command -v {}", name)])
+            .stdout(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false)
+    }
+
+    // Private dbus-daemon for tests that talk to mock D-Bus services
+    pub struct PrivateBus {
+        daemon: Child,
+        pub address: String,
+    }
+
+    impl PrivateBus {
+        // None when dbus-daemon is not installed
+        pub fn start() -> Option<Self> {
+            if !command_exists("dbus-daemon") {
+                eprintln!("Skipping: dbus-daemon not installed");
+                return None;
+            }
+
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Failed to start dbus-daemon");
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .expect("Failed to read dbus-daemon address");
+
+            Some(PrivateBus {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use std::env;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use serde_json::Value;
+use crate::backend::linux::{handle_index, layout_handle, LayoutChangeCallback, LayoutSource};
+
+// i3-ipc framing: magic, payload length, message type, payload
+const IPC_MAGIC: &[u8; 6] = b"i3-ipc";
+const IPC_HEADER_LEN: usize = 14;
+
+// Message types used by CCaps
+pub const IPC_RUN_COMMAND: u32 = 0;
+pub const IPC_SUBSCRIBE: u32 = 2;
+pub const IPC_GET_INPUTS: u32 = 100;
+
+// Events have the high bit set in the message type
+pub const IPC_EVENT_INPUT: u32 = 0x8000_0015;
+
+// Environment variable sway sets to its IPC socket path
+pub const SWAYSOCK_ENV_VAR: &str = "SWAYSOCK";
+
+// Descriptions whose "(…)" part names a language of its own rather than
+// a variant
+fn regional_description_lang_id(description: &str) -> Option<u32> {
+    match description {
+        "English (US)" => Some(0x0409),
+        "English (UK)" => Some(0x0809),
+        "English (Australian)" => Some(0x0c09),
+        "English (New Zealand)" => Some(0x1409),
+        "English (South Africa)" => Some(0x1c09),
+        "Portuguese (Brazil)" => Some(0x0416),
+        "Serbian (Latin)" => Some(0x081a),
+        _ => None,
+    }
+}
+
+// Variant of an XKB layout description, e.g. "dvorak" for "English (Dvorak)".
+// Descriptions that map to their own language have none.
+pub fn layout_description_variant(description: &str) -> Option<String> {
+    if regional_description_lang_id(description).is_some() {
+        return None;
+    }
+    let (_, variant) = description.split_once(" (")?;
+    let variant = variant.trim_end_matches(')')
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+        .to_lowercase();
+    (!variant.is_empty()).then_some(variant)
+}
+
+// Map an XKB layout description (as reported by sway in `xkb_layout_names`)
+// to a Windows language ID. A full "Language (Variant)" entry takes
+// precedence over the bare language.
+pub fn layout_description_lang_id(description: &str) -> u32 {
+    if let Some(lang_id) = regional_description_lang_id(description) {
+        return lang_id;
+    }
+
+    let language = description.split(" (").next().unwrap_or(description);
+    match language {
+        "English" => 0x0409,
+        "Irish" => 0x1809,
+
+        // Cyrillic languages
+        "Russian" => 0x0419,
+        "Ukrainian" => 0x0422,
+        "Belarusian" => 0x0423,
+        "Bulgarian" => 0x0402,
+        "Croatian" => 0x041a,
+        "Czech" => 0x0405,
+        "Serbian" => 0x0c1a,
+        "Turkish" => 0x041f,
+
+        // Western European
+        "German" => 0x0407,
+        "French" => 0x040c,
+        "Italian" => 0x0410,
+        "Spanish" => 0x040a,
+        "Dutch" => 0x0413,
+        "Norwegian" => 0x0414,
+        "Swedish" => 0x041d,
+        "Danish" => 0x0406,
+        "Finnish" => 0x040b,
+        "Portuguese" => 0x0816,
+
+        // Eastern European
+        "Polish" => 0x0415,
+        "Hungarian" => 0x040e,
+        "Romanian" => 0x0418,
+        "Slovak" => 0x041b,
+        "Slovenian" => 0x0424,
+        "Estonian" => 0x0425,
+        "Latvian" => 0x0426,
+        "Lithuanian" => 0x0427,
+
+        // Asian languages
+        "Japanese" => 0x0411,
+        "Korean" => 0x0412,
+        "Taiwanese" => 0x0404,
+        "Chinese" => 0x0804,
+        "Thai" => 0x041e,
+        "Vietnamese" => 0x042a,
+
+        // Middle Eastern
+        "Hebrew" => 0x040d,
+        "Arabic" => 0x0401,
+        "Persian" => 0x0429,
+
+        // Other
+        "Icelandic" => 0x040f,
+        "Greek" => 0x0408,
+        "Albanian" => 0x041c,
+        "Macedonian" => 0x042f,
+
+        _ => 0,
+    }
+}
+
+pub fn encode_message(message_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(IPC_HEADER_LEN + payload.len());
+    message.extend_from_slice(IPC_MAGIC);
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&message_type.to_ne_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+pub fn read_message(stream: &mut impl Read) -> io::Result<(u32, Vec<u8>)> {
+    let mut header = [0u8; IPC_HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != IPC_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid i3-ipc magic"));
+    }
+
+    let length = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+    let message_type = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload)?;
+    Ok((message_type, payload))
+}
+
+// Layout names and active index of the first keyboard that has layouts
+pub fn keyboard_layouts(inputs: &Value) -> Option<(Vec<String>, usize)> {
+    inputs.as_array()?.iter().find_map(keyboard_input_layouts)
+}
+
+fn keyboard_input_layouts(input: &Value) -> Option<(Vec<String>, usize)> {
+    if input.get("type")?.as_str()? != "keyboard" {
+        return None;
+    }
+
+    let names: Vec<String> = input.get("xkb_layout_names")?
+        .as_array()?
+        .iter()
+        .filter_map(|name| name.as_str().map(|s| s.to_string()))
+        .collect();
+    if names.is_empty() {
+        return None;
+    }
+
+    let active = input.get("xkb_active_layout_index")
+        .and_then(|index| index.as_u64())
+        .unwrap_or(0) as usize;
+    Some((names, active))
+}
+
+// Handle of the newly active layout from an `input` event, if the event
+// is a layout change
+pub fn layout_change_handle(event: &Value) -> Option<usize> {
+    match event.get("change")?.as_str()? {
+        "xkb_layout" | "xkb_keymap" => {},
+        _ => return None,
+    }
+
+    let (names, active) = keyboard_input_layouts(event.get("input")?)?;
+    names.get(active).map(|name| layout_handle(active, layout_description_lang_id(name)))
+}
+
+// Layout switching through the sway (and compatible wlroots compositor)
+// IPC socket
+pub struct SwayLayoutSource {
+    socket_path: PathBuf,
+    stream: Mutex<UnixStream>,
+}
+
+impl SwayLayoutSource {
+    // Connect to the given socket, or to $SWAYSOCK if none is given
+    pub fn open(socket_path: Option<&Path>) -> Result<Self, String> {
+        let socket_path = match socket_path {
+            Some(path) => path.to_path_buf(),
+            None => env::var_os(SWAYSOCK_ENV_VAR)
+                .map(PathBuf::from)
+                .ok_or_else(|| format!("{} is not set", SWAYSOCK_ENV_VAR))?,
+        };
+
+        let stream = UnixStream::connect(&socket_path)
+            .map_err(|e| format!("Cannot connect to sway IPC socket {}: {}", socket_path.display(), e))?;
+
+        Ok(SwayLayoutSource {
+            socket_path,
+            stream: Mutex::new(stream),
+        })
+    }
+
+    // Send a request and wait for its reply
+    fn request(&self, message_type: u32, payload: &str) -> Result<Value, String> {
+        let mut stream = self.stream.lock().unwrap();
+
+        stream.write_all(&encode_message(message_type, payload.as_bytes()))
+            .map_err(|e| format!("Sway IPC write failed: {}", e))?;
+
+        let (reply_type, reply) = read_message(&mut *stream)
+            .map_err(|e| format!("Sway IPC read failed: {}", e))?;
+        if reply_type != message_type {
+            return Err(format!("Unexpected sway IPC reply type {}", reply_type));
+        }
+
+        serde_json::from_slice(&reply).map_err(|e| format!("Invalid sway IPC reply: {}", e))
+    }
+
+    fn layouts(&self) -> Option<(Vec<String>, usize)> {
+        let inputs = self.request(IPC_GET_INPUTS, "").ok()?;
+        keyboard_layouts(&inputs)
+    }
+
+    // Read `input` events from a subscribed connection until it closes
+    fn watch_events(mut stream: UnixStream, on_change: LayoutChangeCallback) {
+        while let Ok((message_type, payload)) = read_message(&mut stream) {
+            if message_type != IPC_EVENT_INPUT {
+                continue;
+            }
+
+            let handle = serde_json::from_slice::<Value>(&payload)
+                .ok()
+                .and_then(|event| layout_change_handle(&event));
+            if let Some(handle) = handle {
+                on_change(handle);
+            }
+        }
+    }
+}
+
+impl LayoutSource for SwayLayoutSource {
+    fn layout_handles(&self) -> Vec<usize> {
+        match self.layouts() {
+            Some((names, _)) => names.iter()
+                .enumerate()
+                .map(|(index, name)| layout_handle(index, layout_description_lang_id(name)))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn current_layout(&self) -> Option<usize> {
+        let (names, active) = self.layouts()?;
+        names.get(active).map(|name| layout_handle(active, layout_description_lang_id(name)))
+    }
+
+    fn layout_variant(&self, handle: usize) -> Option<String> {
+        let (names, _) = self.layouts()?;
+        layout_description_variant(names.get(handle_index(handle)?)?)
+    }
+
+    fn activate_layout(&self, handle: usize) -> Result<(), String> {
+        let index = handle_index(handle).ok_or_else(|| format!("Invalid layout handle {:08X}", handle))?;
+        let command = format!("input * xkb_switch_layout {}", index);
+        let reply = self.request(IPC_RUN_COMMAND, &command)?;
+
+        // The reply holds one result per command
+        match reply.get(0) {
+            Some(result) if result.get("success").and_then(|s| s.as_bool()) == Some(false) => {
+                let error = result.get("error").and_then(|e| e.as_str()).unwrap_or("unknown error");
+                Err(format!("Sway cannot switch layout: {}", error))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn watch_layout_changes(&self, on_change: LayoutChangeCallback) {
+        // Events arrive on a separate connection so they never interleave
+        // with request replies
+        let mut stream = match UnixStream::connect(&self.socket_path) {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Warning: Cannot watch sway layout changes: {}", e);
+                return;
+            }
+        };
+
+        let subscribed = stream.write_all(&encode_message(IPC_SUBSCRIBE, br#"["input"]"#)).is_ok()
+            && matches!(read_message(&mut stream), Ok((IPC_SUBSCRIBE, _)));
+        if !subscribed {
+            eprintln!("Warning: Cannot subscribe to sway input events");
+            return;
+        }
+
+        thread::spawn(move || Self::watch_events(stream, on_change));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout_manager::LayoutInfo;
+    use serde_json::json;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    const LAYOUT_NAMES: [&str; 4] = ["English (US)", "German", "Russian (phonetic)", "English (Dvorak)"];
+
+    #[test]
+    fn test_message_round_trip() {
+        let message = encode_message(IPC_GET_INPUTS, b"{}");
+        assert_eq!(&message[..6], b"i3-ipc");
+        assert_eq!(message.len(), IPC_HEADER_LEN + 2);
+
+        let (message_type, payload) = read_message(&mut message.as_slice()).unwrap();
+        assert_eq!(message_type, IPC_GET_INPUTS);
+        assert_eq!(payload, b"{}");
+    }
+
+    #[test]
+    fn test_read_message_rejects_bad_magic() {
+        let mut message = encode_message(IPC_GET_INPUTS, b"");
+        message[0] = b'x';
+        assert!(read_message(&mut message.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_descriptions_map_to_cli_codes() {
+        let cases = [("English (US)", "us"), ("English (UK)", "gb"), ("German", "de"),
+                     ("Russian (phonetic)", "ru"), ("Ukrainian", "ua"), ("Swedish", "se")];
+        for (description, code) in cases {
+            let layout = LayoutInfo::new(layout_handle(0, layout_description_lang_id(description)));
+            assert_eq!(layout.short_code, code, "'{}' should map to code '{}'", description, code);
+        }
+    }
+
+    #[test]
+    fn test_keyboard_layouts_skips_other_inputs() {
+        let inputs = json!([
+            {"identifier": "1:1:Mouse", "type": "pointer"},
+            {"identifier": "0:0:Power", "type": "keyboard", "xkb_layout_names": []},
+            {"identifier": "1:1:Keyboard", "type": "keyboard",
+             "xkb_layout_names": LAYOUT_NAMES, "xkb_active_layout_index": 1},
+        ]);
+
+        let (names, active) = keyboard_layouts(&inputs).unwrap();
+        assert_eq!(names, LAYOUT_NAMES);
+        assert_eq!(active, 1);
+    }
+
+    #[test]
+    fn test_layout_change_handle() {
+        let input = json!({"type": "keyboard", "xkb_layout_names": LAYOUT_NAMES, "xkb_active_layout_index": 2});
+
+        let handle = layout_change_handle(&json!({"change": "xkb_layout", "input": input})).unwrap();
+        assert_eq!(handle_index(handle), Some(2));
+        assert_eq!(handle & 0xFFFF, 0x0419);
+
+        assert_eq!(layout_change_handle(&json!({"change": "added", "input": input})), None);
+    }
+
+    // Minimal sway IPC server: one keyboard, layout switching and
+    // `input` event subscriptions
+    struct FakeSway {
+        path: PathBuf,
+    }
+
+    impl FakeSway {
+        fn start(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("ccaps-test-{}-{}.sock", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path).expect("Failed to bind fake sway socket");
+
+            let active = Arc::new(Mutex::new(0usize));
+            let subscribers: Arc<Mutex<Vec<UnixStream>>> = Arc::new(Mutex::new(Vec::new()));
+
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let active = Arc::clone(&active);
+                    let subscribers = Arc::clone(&subscribers);
+                    thread::spawn(move || Self::serve(stream, active, subscribers));
+                }
+            });
+
+            FakeSway { path }
+        }
+
+        fn keyboard(active: usize) -> Value {
+            json!({"identifier": "1:1:Keyboard", "type": "keyboard",
+                   "xkb_layout_names": LAYOUT_NAMES, "xkb_active_layout_index": active})
+        }
+
+        fn serve(mut stream: UnixStream, active: Arc<Mutex<usize>>, subscribers: Arc<Mutex<Vec<UnixStream>>>) {
+            while let Ok((message_type, payload)) = read_message(&mut stream) {
+                let reply = match message_type {
+                    IPC_GET_INPUTS => json!([{"type": "pointer"}, Self::keyboard(*active.lock().unwrap())]),
+                    IPC_RUN_COMMAND => {
+                        let command = String::from_utf8_lossy(&payload).to_string();
+                        let index = command.strip_prefix("input * xkb_switch_layout ")
+                            .and_then(|index| index.parse::<usize>().ok())
+                            .filter(|index| *index < LAYOUT_NAMES.len());
+                        if let Some(index) = index {
+                            *active.lock().unwrap() = index;
+                            let event = json!({"change": "xkb_layout", "input": Self::keyboard(index)}).to_string();
+                            for subscriber in subscribers.lock().unwrap().iter_mut() {
+                                let _ = subscriber.write_all(&encode_message(IPC_EVENT_INPUT, event.as_bytes()));
+                            }
+                        }
+                        json!([{"success": index.is_some()}])
+                    },
+                    IPC_SUBSCRIBE => {
+                        subscribers.lock().unwrap().push(stream.try_clone().unwrap());
+                        json!({"success": true})
+                    },
+                    _ => json!({"success": false}),
+                };
+
+                let reply = reply.to_string();
+                if stream.write_all(&encode_message(message_type, reply.as_bytes())).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    impl Drop for FakeSway {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_description_variants() {
+        assert_eq!(layout_description_variant("English (Dvorak)").as_deref(), Some("dvorak"));
+        assert_eq!(layout_description_variant("English (US, intl., with dead keys)").as_deref(), Some("us-intl-with-dead-keys"));
+        assert_eq!(layout_description_variant("English (UK)"), None, "Regional layouts are no variants");
+        assert_eq!(layout_description_variant("German"), None);
+    }
+
+    #[test]
+    fn test_fake_sway_switching() {
+        let sway = FakeSway::start("switch");
+        let source = SwayLayoutSource::open(Some(&sway.path)).unwrap();
+
+        let handles = source.layout_handles();
+        assert_eq!(handles.len(), 4);
+        assert_eq!(handles.iter().map(|h| h & 0xFFFF).collect::<Vec<_>>(), vec![0x0409, 0x0407, 0x0419, 0x0409]);
+        assert_eq!(source.current_layout(), Some(handles[0]));
+
+        // Layouts sharing a language are told apart by their variant
+        let variants: Vec<Option<String>> = handles.iter().map(|h| source.layout_variant(*h)).collect();
+        assert_eq!(variants, vec![None, None, Some("phonetic".to_string()), Some("dvorak".to_string())]);
+        let dvorak = LayoutInfo::with_variant(handles[3], variants[3].as_deref());
+        assert_eq!(dvorak.short_code, "us-dvorak");
+
+        source.activate_layout(handles[2]).unwrap();
+        assert_eq!(source.current_layout(), Some(handles[2]));
+    }
+
+    #[test]
+    fn test_fake_sway_layout_events() {
+        let sway = FakeSway::start("events");
+        let source = SwayLayoutSource::open(Some(&sway.path)).unwrap();
+        let handles = source.layout_handles();
+
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        source.watch_layout_changes(Box::new(move |handle| {
+            let _ = sender.lock().unwrap().send(handle);
+        }));
+
+        // A change made by another IPC client is reported to the watcher
+        let other_client = SwayLayoutSource::open(Some(&sway.path)).unwrap();
+        other_client.activate_layout(handles[1]).unwrap();
+
+        let reported = receiver.recv_timeout(Duration::from_secs(5)).expect("No layout change event");
+        assert_eq!(reported, handles[1]);
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::raw::{c_int, c_ulong};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use crate::backend::linux::{handle_index, layout_handle, x11, LayoutSource};
+
+// Console ioctls from <linux/kd.h>
+const KDGETLED: c_ulong = 0x4B31;
+const KDSETLED: c_ulong = 0x4B32;
+const KDGKBTYPE: c_ulong = 0x4B33;
+const KDGKBENT: c_ulong = 0x4B46;
+const KDSKBENT: c_ulong = 0x4B47;
+const KDGKBLED: c_ulong = 0x4B64;
+
+// LED bits for KDSETLED
+pub const LED_SCR: u8 = 0x01;
+pub const LED_NUM: u8 = 0x02;
+pub const LED_CAP: u8 = 0x04;
+
+// Keymap tables (modifier combinations) and keys per table
+const MAX_NR_KEYMAPS: usize = 256;
+const NR_KEYS: usize = 256;
+
+// Entry value of a table that is not allocated; writing it to key 0 frees the table
+pub const K_NOSUCHMAP: u16 = 0x027f;
+
+const CONSOLE_PATHS: [&str; 2] = ["/dev/tty0", "/dev/console"];
+const VCONSOLE_CONF: &str = "/etc/vconsole.conf";
+
+// Comma-separated keymaps to cycle through, e.g. "us,ru"
+pub const TTY_KEYMAPS_ENV_VAR: &str = "CCAPS_TTY_KEYMAPS";
+
+#[repr(C)]
+struct KbEntry {
+    kb_table: u8,
+    kb_index: u8,
+    kb_value: u16,
+}
+
+// Access to the kernel keyboard translation tables and LEDs
+pub trait Console: Send {
+    fn entry(&self, table: u8, index: u8) -> io::Result<u16>;
+    fn set_entry(&mut self, table: u8, index: u8, value: u16) -> io::Result<()>;
+    // Lock flags (not lights) in KDSETLED bit order
+    fn lock_flags(&self) -> u8;
+    fn set_leds(&mut self, leds: u8) -> io::Result<()>;
+}
+
+// The virtual console, through the KD ioctls
+pub struct VirtualConsole {
+    fd: c_int,
+    path: &'static str,
+}
+
+impl VirtualConsole {
+    pub fn open() -> Result<Self, String> {
+        for path in CONSOLE_PATHS {
+            let c_path = CString::new(path).unwrap();
+            let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+            if fd < 0 {
+                continue;
+            }
+
+            // Only a real virtual console answers KDGKBTYPE
+            let mut kb_type: u8 = 0;
+            if unsafe { libc::ioctl(fd, KDGKBTYPE, &mut kb_type) } == 0 {
+                return Ok(VirtualConsole { fd, path });
+            }
+            unsafe { libc::close(fd) };
+        }
+
+        Err("No accessible virtual console".to_string())
+    }
+}
+
+impl Drop for VirtualConsole {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl Console for VirtualConsole {
+    fn entry(&self, table: u8, index: u8) -> io::Result<u16> {
+        let mut entry = KbEntry { kb_table: table, kb_index: index, kb_value: 0 };
+        if unsafe { libc::ioctl(self.fd, KDGKBENT, &mut entry) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(entry.kb_value)
+    }
+
+    fn set_entry(&mut self, table: u8, index: u8, value: u16) -> io::Result<()> {
+        let entry = KbEntry { kb_table: table, kb_index: index, kb_value: value };
+        if unsafe { libc::ioctl(self.fd, KDSKBENT, &entry) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn lock_flags(&self) -> u8 {
+        let mut flags: u8 = 0;
+        unsafe {
+            if libc::ioctl(self.fd, KDGKBLED, &mut flags) != 0
+                && libc::ioctl(self.fd, KDGETLED, &mut flags) != 0
+            {
+                return 0;
+            }
+        }
+        flags & 0x07
+    }
+
+    fn set_leds(&mut self, leds: u8) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.fd, KDSETLED, leds as c_ulong) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Snapshot of a kernel keymap: every allocated table with all its entries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    pub name: String,
+    pub tables: BTreeMap<u8, Vec<u16>>,
+}
+
+impl Keymap {
+    pub fn read(name: &str, console: &dyn Console) -> io::Result<Self> {
+        let mut tables = BTreeMap::new();
+        for table in 0..MAX_NR_KEYMAPS {
+            let table = table as u8;
+            if console.entry(table, 0)? == K_NOSUCHMAP {
+                continue;
+            }
+
+            let entries = (0..NR_KEYS)
+                .map(|index| console.entry(table, index as u8))
+                .collect::<io::Result<Vec<u16>>>()?;
+            tables.insert(table, entries);
+        }
+
+        Ok(Keymap { name: name.to_string(), tables })
+    }
+
+    // Write this keymap to the console, freeing tables it does not use
+    pub fn apply(&self, console: &mut dyn Console, previous: Option<&Keymap>) -> io::Result<()> {
+        if let Some(previous) = previous {
+            for table in previous.tables.keys().filter(|t| !self.tables.contains_key(t)) {
+                console.set_entry(*table, 0, K_NOSUCHMAP)?;
+            }
+        }
+
+        for (table, entries) in &self.tables {
+            for (index, value) in entries.iter().enumerate() {
+                console.set_entry(*table, index as u8, *value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Map a console keymap name (as in `loadkeys`) to a Windows language ID,
+// e.g. "de-latin1" -> German, "ruwin_alt-UTF-8" -> Russian
+pub fn keymap_lang_id(name: &str) -> u32 {
+    let base = name
+        .split(['-', '_', '.'])
+        .next()
+        .unwrap_or(name)
+        .trim_end_matches(|c: char| c.is_ascii_digit());
+
+    match base {
+        "uk" => 0x0809,
+        "sv" => 0x041d,
+        "jp" => 0x0411,
+        "sr" => 0x0c1a,
+        "slovene" => 0x0424,
+        "et" => 0x0425,
+        "ruwin" => 0x0419,
+        "cf" => 0x0c0c,
+        _ => x11::xkb_layout_lang_id(base),
+    }
+}
+
+// KEYMAP and KEYMAP_TOGGLE from systemd's vconsole.conf
+pub fn parse_vconsole_keymaps(content: &str) -> Vec<String> {
+    let mut keymap = None;
+    let mut toggle = None;
+
+    for line in content.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"').trim_matches('\'').to_string();
+        match key.trim() {
+            "KEYMAP" => keymap = Some(value),
+            "KEYMAP_TOGGLE" => toggle = Some(value),
+            _ => {},
+        }
+    }
+
+    let mut keymaps = Vec::new();
+    for name in [keymap, toggle].into_iter().flatten() {
+        if !name.is_empty() && !keymaps.contains(&name) {
+            keymaps.push(name);
+        }
+    }
+    keymaps
+}
+
+// Keymaps to cycle through: $CCAPS_TTY_KEYMAPS, or the vconsole.conf keymaps
+pub fn configured_keymaps() -> Vec<String> {
+    if let Ok(value) = env::var(TTY_KEYMAPS_ENV_VAR) {
+        return value.split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+    }
+
+    fs::read_to_string(VCONSOLE_CONF)
+        .map(|content| parse_vconsole_keymaps(&content))
+        .unwrap_or_default()
+}
+
+fn load_keymap(console_path: &str, name: &str) -> Result<(), String> {
+    let status = Command::new("loadkeys")
+        .args(["-q", "-C", console_path, name])
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|e| format!("Cannot run loadkeys: {}", e))?;
+
+    if !status.success() {
+        return Err(format!("loadkeys failed for keymap '{}'", name));
+    }
+    Ok(())
+}
+
+struct TtyState {
+    console: Box<dyn Console>,
+    current: usize,
+}
+
+// Layout switching on the Linux virtual console. Every keymap is loaded
+// once with `loadkeys` and snapshotted; switching writes the snapshot back
+// with KDSKBENT, and the indicator is driven with KDSETLED, which changes
+// the LED without toggling Scroll Lock (that would pause console output).
+pub struct TtyLayoutSource {
+    keymaps: Vec<Keymap>,
+    state: Mutex<TtyState>,
+}
+
+impl TtyLayoutSource {
+    pub fn open() -> Result<Self, String> {
+        let names = configured_keymaps();
+        if names.is_empty() {
+            return Err(format!("No console keymaps configured (set {} or KEYMAP_TOGGLE in {})",
+                               TTY_KEYMAPS_ENV_VAR, VCONSOLE_CONF));
+        }
+
+        let console = VirtualConsole::open()?;
+        let console_path = console.path;
+
+        let mut keymaps = Vec::new();
+        for name in &names {
+            load_keymap(console_path, name)?;
+            let keymap = Keymap::read(name, &console)
+                .map_err(|e| format!("Cannot read keymap '{}': {}", name, e))?;
+            keymaps.push(keymap);
+        }
+
+        let source = Self::with_console(Box::new(console), keymaps);
+
+        // Start with the first keymap, the one the system boots with
+        if let Some(first) = source.keymaps.first() {
+            let mut state = source.state.lock().unwrap();
+            let previous = source.keymaps.last();
+            first.apply(state.console.as_mut(), previous)
+                .map_err(|e| format!("Cannot restore keymap '{}': {}", first.name, e))?;
+            state.current = 0;
+        }
+        Ok(source)
+    }
+
+    pub fn with_console(console: Box<dyn Console>, keymaps: Vec<Keymap>) -> Self {
+        TtyLayoutSource {
+            keymaps,
+            state: Mutex::new(TtyState { console, current: 0 }),
+        }
+    }
+
+    fn keymap_handle(&self, index: usize) -> usize {
+        layout_handle(index, keymap_lang_id(&self.keymaps[index].name))
+    }
+}
+
+impl LayoutSource for TtyLayoutSource {
+    fn layout_handles(&self) -> Vec<usize> {
+        (0..self.keymaps.len()).map(|index| self.keymap_handle(index)).collect()
+    }
+
+    fn current_layout(&self) -> Option<usize> {
+        let current = self.state.lock().unwrap().current;
+        (current < self.keymaps.len()).then(|| self.keymap_handle(current))
+    }
+
+    fn activate_layout(&self, handle: usize) -> Result<(), String> {
+        let Some(keymap) = handle_index(handle).and_then(|index| self.keymaps.get(index)) else {
+            return Err(format!("No keymap for layout handle {:08X}", handle));
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let previous = self.keymaps.get(state.current);
+        keymap.apply(state.console.as_mut(), previous)
+            .map_err(|e| format!("Cannot load keymap '{}': {}", keymap.name, e))?;
+        state.current = handle_index(handle).unwrap();
+        Ok(())
+    }
+
+    fn set_indicator_led(&self, on: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        // Keep Num Lock and Caps Lock lights showing their lock state
+        let mut leds = state.console.lock_flags() & (LED_NUM | LED_CAP);
+        if on {
+            leds |= LED_SCR;
+        }
+        state.console.set_leds(leds).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const KEY_Q: u8 = 16;
+
+    // In-memory console: allocated tables and the LED state
+    #[derive(Default)]
+    struct FakeConsoleState {
+        tables: BTreeMap<u8, Vec<u16>>,
+        leds: u8,
+        lock_flags: u8,
+    }
+
+    #[derive(Clone, Default)]
+    struct FakeConsole(Arc<Mutex<FakeConsoleState>>);
+
+    impl Console for FakeConsole {
+        fn entry(&self, table: u8, index: u8) -> io::Result<u16> {
+            let state = self.0.lock().unwrap();
+            Ok(state.tables.get(&table).map_or(K_NOSUCHMAP, |t| t[index as usize]))
+        }
+
+        fn set_entry(&mut self, table: u8, index: u8, value: u16) -> io::Result<()> {
+            let mut state = self.0.lock().unwrap();
+            if index == 0 && value == K_NOSUCHMAP {
+                state.tables.remove(&table);
+            } else {
+                state.tables.entry(table).or_insert_with(|| vec![0; NR_KEYS])[index as usize] = value;
+            }
+            Ok(())
+        }
+
+        fn lock_flags(&self) -> u8 {
+            self.0.lock().unwrap().lock_flags
+        }
+
+        fn set_leds(&mut self, leds: u8) -> io::Result<()> {
+            self.0.lock().unwrap().leds = leds;
+            Ok(())
+        }
+    }
+
+    // Keymap whose Q key produces `q_value` in the given tables
+    fn keymap(name: &str, tables: &[u8], q_value: u16) -> Keymap {
+        let tables = tables.iter()
+            .map(|table| {
+                let mut entries = vec![0; NR_KEYS];
+                entries[KEY_Q as usize] = q_value;
+                (*table, entries)
+            })
+            .collect();
+        Keymap { name: name.to_string(), tables }
+    }
+
+    #[test]
+    fn test_keymap_names_map_to_cli_codes() {
+        use crate::layout_manager::LayoutInfo;
+
+        let cases = [("us", "us"), ("uk", "gb"), ("de-latin1", "de"), ("ru", "ru"),
+                     ("ruwin_alt-UTF-8", "ru"), ("ua-utf", "ua"), ("pl2", "pl"), ("sv-latin1", "se")];
+        for (name, code) in cases {
+            let layout = LayoutInfo::new(layout_handle(0, keymap_lang_id(name)));
+            assert_eq!(layout.short_code, code, "Keymap '{}' should map to code '{}'", name, code);
+        }
+    }
+
+    #[test]
+    fn test_parse_vconsole_keymaps() {
+        let content = "KEYMAP=ru\nFONT=cyr-sun16\nKEYMAP_TOGGLE=\"us\"\n";
+        assert_eq!(parse_vconsole_keymaps(content), vec!["ru", "us"]);
+        assert_eq!(parse_vconsole_keymaps("KEYMAP=de\nKEYMAP_TOGGLE=de\n"), vec!["de"]);
+        assert!(parse_vconsole_keymaps("FONT=lat2-16\n").is_empty());
+    }
+
+    #[test]
+    fn test_keymap_snapshot_round_trip() {
+        let console = FakeConsole::default();
+        let mut writer = console.clone();
+        keymap("us", &[0, 1], 0x0b71).apply(&mut writer, None).unwrap();
+
+        let snapshot = Keymap::read("us", &console).unwrap();
+        assert_eq!(snapshot, keymap("us", &[0, 1], 0x0b71));
+    }
+
+    #[test]
+    fn test_switching_swaps_keymaps() {
+        let console = FakeConsole::default();
+        let us = keymap("us", &[0, 1], 0x0b71);
+        // Cyrillic maps use an extra table for the Latin/Cyrillic toggle
+        let ru = keymap("ru", &[0, 1, 8], 0x0b39);
+        let source = TtyLayoutSource::with_console(Box::new(console.clone()), vec![us, ru]);
+
+        let handles = source.layout_handles();
+        assert_eq!(handles.iter().map(|h| h & 0xFFFF).collect::<Vec<_>>(), vec![0x0409, 0x0419]);
+        assert_eq!(source.current_layout(), Some(handles[0]));
+
+        source.activate_layout(handles[1]).unwrap();
+        assert_eq!(source.current_layout(), Some(handles[1]));
+        assert_eq!(console.entry(0, KEY_Q).unwrap(), 0x0b39);
+        assert_eq!(console.entry(8, KEY_Q).unwrap(), 0x0b39);
+
+        source.activate_layout(handles[0]).unwrap();
+        assert_eq!(console.entry(0, KEY_Q).unwrap(), 0x0b71);
+        assert_eq!(console.entry(8, 0).unwrap(), K_NOSUCHMAP, "Tables of the previous keymap should be freed");
+    }
+
+    #[test]
+    fn test_indicator_uses_kdsetled_bits() {
+        let console = FakeConsole::default();
+        console.0.lock().unwrap().lock_flags = LED_NUM;
+        let source = TtyLayoutSource::with_console(Box::new(console.clone()), vec![keymap("us", &[0], 0)]);
+
+        assert!(source.set_indicator_led(true));
+        assert_eq!(console.0.lock().unwrap().leds, LED_NUM | LED_SCR, "Num Lock light should be kept");
+
+        assert!(source.set_indicator_led(false));
+        assert_eq!(console.0.lock().unwrap().leds, LED_NUM);
+    }
+}
@@ -0,0 +1,505 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_int, c_uint};
+use std::process::Command;
+use std::ptr;
+use std::sync::Mutex;
+use x11_dl::xlib::{self, Display, Xlib};
+use crate::backend::linux::{handle_index, layout_handle, LayoutSource};
+
+// Constants from <X11/extensions/XKB.h>
+const XKB_USE_CORE_KBD: c_uint = 0x0100;
+const XKB_SYMBOLS_NAME_MASK: c_uint = 1 << 2;
+const XKB_MAJOR_VERSION: c_int = 1;
+const XKB_MINOR_VERSION: c_int = 0;
+
+// Components of the XKB symbols name that are not layouts
+const NON_LAYOUT_SYMBOLS: &[&str] = &[
+    "pc", "inet", "group", "compose", "level3", "level5", "lv3", "lv5",
+    "ctrl", "capslock", "altwin", "terminate", "keypad", "kpdl", "nbsp",
+    "eurosign", "shift", "srvr_ctrl", "rupeesign", "typo", "apl", "numpad",
+];
+
+// XKB layout names (as in `setxkbmap -layout`) and their Windows language
+// IDs, so X11 layouts get the same names and short codes as on Windows
+const XKB_LAYOUTS: &[(&str, u32)] = &[
+    // English variants
+    ("us", 0x0409),
+    ("gb", 0x0809),
+    ("au", 0x0c09),
+    ("nz", 0x1409),
+    ("ie", 0x1809),
+    ("za", 0x1c09),
+
+    // Cyrillic languages
+    ("ru", 0x0419),
+    ("ua", 0x0422),
+    ("by", 0x0423),
+    ("bg", 0x0402),
+    ("hr", 0x041a),
+    ("cz", 0x0405),
+    ("rs", 0x0c1a),
+    ("tr", 0x041f),
+    ("kz", 0x043f),
+
+    // Western European
+    ("de", 0x0407),
+    ("fr", 0x040c),
+    ("it", 0x0410),
+    ("es", 0x040a),
+    ("nl", 0x0413),
+    ("no", 0x0414),
+    ("se", 0x041d),
+    ("dk", 0x0406),
+    ("fi", 0x040b),
+    ("pt", 0x0816),
+    ("br", 0x0416),
+    ("ch", 0x0807),
+    ("at", 0x0c07),
+    ("be", 0x080c),
+    ("ca", 0x0c0c),
+
+    // Eastern European
+    ("pl", 0x0415),
+    ("hu", 0x040e),
+    ("ro", 0x0418),
+    ("sk", 0x041b),
+    ("si", 0x0424),
+    ("ee", 0x0425),
+    ("lv", 0x0426),
+    ("lt", 0x0427),
+
+    // Asian languages
+    ("jp", 0x0411),
+    ("kr", 0x0412),
+    ("tw", 0x0404),
+    ("cn", 0x0804),
+    ("th", 0x041e),
+    ("vn", 0x042a),
+
+    // Middle Eastern
+    ("il", 0x040d),
+    ("ara", 0x0401),
+    ("ir", 0x0429),
+
+    // Other
+    ("is", 0x040f),
+    ("gr", 0x0408),
+    ("al", 0x041c),
+    ("mk", 0x042f),
+    ("ge", 0x0437),
+    ("am", 0x042b),
+];
+
+// At most four XKB groups can be configured
+const XKB_MAX_GROUPS: usize = 4;
+
+// Map an XKB layout name to a Windows language ID.
+// A full "layout(variant)" entry takes precedence over the bare layout.
+pub fn xkb_layout_lang_id(symbols_name: &str) -> u32 {
+    if let Some(lang_id) = variant_lang_id(symbols_name) {
+        return lang_id;
+    }
+
+    let layout = symbols_name.split('(').next().unwrap_or(symbols_name);
+    XKB_LAYOUTS.iter()
+        .find(|(name, _)| *name == layout)
+        .map(|(_, lang_id)| *lang_id)
+        .unwrap_or(0)
+}
+
+// XKB layout for a Windows language ID, the reverse of `xkb_layout_lang_id`
+pub fn xkb_layout_for_lang_id(lang_id: u32) -> Option<&'static str> {
+    XKB_LAYOUTS.iter()
+        .find(|(_, id)| *id == lang_id)
+        .map(|(name, _)| *name)
+}
+
+// `setxkbmap` arguments configuring the given groups,
+// e.g. ["us", "ru(phonetic)"] -> -layout us,ru -variant ,phonetic
+pub fn setxkbmap_args(layouts: &[String]) -> Vec<String> {
+    let (names, variants): (Vec<&str>, Vec<&str>) = layouts.iter()
+        .map(|layout| match layout.split_once('(') {
+            Some((name, variant)) => (name, variant.trim_end_matches(')')),
+            None => (layout.as_str(), ""),
+        })
+        .unzip();
+
+    vec!["-layout".to_string(), names.join(","), "-variant".to_string(), variants.join(",")]
+}
+
+// Variants that select a different language rather than a different layout
+fn variant_lang_id(symbols_name: &str) -> Option<u32> {
+    match symbols_name {
+        "rs(latin)" => Some(0x081a),
+        "br(nativo)" => Some(0x0416),
+        _ => None,
+    }
+}
+
+// Variant of an XKB "layout(variant)" name, e.g. "dvorak" for "us(dvorak)".
+// Variants that already map to their own language have none.
+pub fn xkb_variant(symbols_name: &str) -> Option<String> {
+    if variant_lang_id(symbols_name).is_some() {
+        return None;
+    }
+    let (_, variant) = symbols_name.split_once('(')?;
+    let variant = variant.trim_end_matches(')');
+    (!variant.is_empty()).then(|| variant.to_string())
+}
+
+// Extract the layout of every XKB group from the keymap symbols name,
+// e.g. "pc+us+de:2+ru(phonetic):3+inet(evdev)" -> ["us", "de", "ru(phonetic)"]
+pub fn parse_symbols_layouts(symbols: &str) -> Vec<String> {
+    let mut groups: Vec<Option<String>> = Vec::new();
+
+    for token in symbols.split('+') {
+        let (name, group) = match token.split_once(':') {
+            Some((name, index)) => match index.parse::<usize>() {
+                Ok(index) if index >= 1 => (name, Some(index - 1)),
+                _ => continue,
+            },
+            None => (token, None),
+        };
+
+        let base = name.split('(').next().unwrap_or(name);
+        if name.is_empty() || NON_LAYOUT_SYMBOLS.contains(&base) {
+            continue;
+        }
+
+        // A layout without an explicit group index belongs to the first group
+        let group = match group {
+            Some(group) => group,
+            None if groups.first().map(|g| g.is_none()).unwrap_or(true) => 0,
+            None => continue,
+        };
+
+        if groups.len() <= group {
+            groups.resize(group + 1, None);
+        }
+        if groups[group].is_none() {
+            groups[group] = Some(name.to_string());
+        }
+    }
+
+    groups.into_iter().flatten().collect()
+}
+
+// Layout handle for an XKB group
+pub fn group_handle(group: usize, symbols_name: &str) -> usize {
+    layout_handle(group, xkb_layout_lang_id(symbols_name))
+}
+
+struct DisplayHandle(*mut Display);
+
+// The display is only ever used while holding the mutex
+unsafe impl Send for DisplayHandle {}
+
+// Layout switching through XKB groups, the X11 equivalent of
+// ActivateKeyboardLayout
+pub struct XkbLayoutSource {
+    xlib: Xlib,
+    display: Mutex<DisplayHandle>,
+    display_name: Option<String>,
+}
+
+impl XkbLayoutSource {
+    // Connect to the given X display, or to $DISPLAY if none is given
+    pub fn open(display_name: Option<&str>) -> Result<Self, String> {
+        let xlib = Xlib::open().map_err(|e| format!("Cannot load libX11: {}", e))?;
+
+        let c_name = match display_name {
+            Some(name) => Some(CString::new(name).map_err(|_| "Invalid display name".to_string())?),
+            None => None,
+        };
+
+        unsafe {
+            let display = (xlib.XOpenDisplay)(c_name.as_ref().map_or(ptr::null(), |n| n.as_ptr()));
+            if display.is_null() {
+                return Err("Cannot open X display".to_string());
+            }
+
+            let (mut opcode, mut event, mut error) = (0, 0, 0);
+            let (mut major, mut minor) = (XKB_MAJOR_VERSION, XKB_MINOR_VERSION);
+            if (xlib.XkbQueryExtension)(display, &mut opcode, &mut event, &mut error, &mut major, &mut minor) == 0 {
+                (xlib.XCloseDisplay)(display);
+                return Err("X server does not support the XKB extension".to_string());
+            }
+
+            Ok(XkbLayoutSource {
+                xlib,
+                display: Mutex::new(DisplayHandle(display)),
+                display_name: display_name.map(str::to_string),
+            })
+        }
+    }
+
+    // Layout names of the configured XKB groups
+    pub fn group_layouts(&self) -> Vec<String> {
+        let display = self.display.lock().unwrap();
+
+        unsafe {
+            let desc = (self.xlib.XkbAllocKeyboard)();
+            if desc.is_null() {
+                return Vec::new();
+            }
+
+            let mut layouts = Vec::new();
+            if (self.xlib.XkbGetNames)(display.0, XKB_SYMBOLS_NAME_MASK, desc) == 0
+                && !(*desc).names.is_null()
+            {
+                let atom_name = (self.xlib.XGetAtomName)(display.0, (*(*desc).names).symbols);
+                if !atom_name.is_null() {
+                    let symbols = CStr::from_ptr(atom_name).to_string_lossy().to_string();
+                    layouts = parse_symbols_layouts(&symbols);
+                    (self.xlib.XFree)(atom_name as *mut _);
+                }
+            }
+
+            (self.xlib.XkbFreeKeyboard)(desc, 0, xlib::True);
+            layouts
+        }
+    }
+
+    // Currently active XKB group
+    pub fn current_group(&self) -> Option<usize> {
+        let display = self.display.lock().unwrap();
+
+        unsafe {
+            let mut state: xlib::XkbStateRec = std::mem::zeroed();
+            if (self.xlib.XkbGetState)(display.0, XKB_USE_CORE_KBD, &mut state) != 0 {
+                return None;
+            }
+            Some(state.group as usize)
+        }
+    }
+
+    // Configure the XKB groups with setxkbmap; layouts is the full list of groups
+    pub fn set_group_layouts(&self, layouts: &[String]) -> Result<(), String> {
+        let mut command = Command::new("setxkbmap");
+        if let Some(display) = &self.display_name {
+            command.args(["-display", display]);
+        }
+
+        let output = command.args(setxkbmap_args(layouts))
+            .output()
+            .map_err(|e| format!("Cannot run setxkbmap: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("setxkbmap failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+
+    // Lock the keyboard to the given XKB group
+    pub fn lock_group(&self, group: usize) {
+        let display = self.display.lock().unwrap();
+
+        unsafe {
+            (self.xlib.XkbLockGroup)(display.0, XKB_USE_CORE_KBD, group as c_uint);
+            (self.xlib.XSync)(display.0, xlib::False);
+        }
+    }
+}
+
+impl Drop for XkbLayoutSource {
+    fn drop(&mut self) {
+        let display = self.display.lock().unwrap();
+        unsafe {
+            (self.xlib.XCloseDisplay)(display.0);
+        }
+    }
+}
+
+impl LayoutSource for XkbLayoutSource {
+    fn layout_handles(&self) -> Vec<usize> {
+        self.group_layouts()
+            .iter()
+            .enumerate()
+            .map(|(group, name)| group_handle(group, name))
+            .collect()
+    }
+
+    fn current_layout(&self) -> Option<usize> {
+        let group = self.current_group()?;
+        let layouts = self.group_layouts();
+        layouts.get(group).map(|name| group_handle(group, name))
+    }
+
+    fn activate_layout(&self, handle: usize) -> Result<(), String> {
+        let group = handle_index(handle).ok_or_else(|| format!("Invalid layout handle {:08X}", handle))?;
+        self.lock_group(group);
+        Ok(())
+    }
+
+    fn layout_variant(&self, handle: usize) -> Option<String> {
+        let layouts = self.group_layouts();
+        xkb_variant(layouts.get(handle_index(handle)?)?)
+    }
+
+    fn add_layout(&self, lang_id: u16) -> Result<usize, String> {
+        let name = xkb_layout_for_lang_id(u32::from(lang_id))
+            .ok_or_else(|| format!("No XKB layout is known for language 0x{:04X}", lang_id))?;
+
+        let mut layouts = self.group_layouts();
+        if layouts.len() >= XKB_MAX_GROUPS {
+            return Err(format!("X11 supports at most {} layouts; remove one first", XKB_MAX_GROUPS));
+        }
+        layouts.push(name.to_string());
+        self.set_group_layouts(&layouts)?;
+        Ok(group_handle(layouts.len() - 1, name))
+    }
+
+    fn remove_layout(&self, handle: usize) -> Result<(), String> {
+        let mut layouts = self.group_layouts();
+        let group = handle_index(handle)
+            .filter(|&group| group < layouts.len())
+            .ok_or_else(|| "The layout is not an XKB group".to_string())?;
+        if layouts.len() == 1 {
+            return Err("Cannot remove the only layout".to_string());
+        }
+        layouts.remove(group);
+        self.set_group_layouts(&layouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::linux::testing::command_exists;
+    use std::process::{Child, Command, Stdio};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_symbols_with_variants() {
+        let layouts = parse_symbols_layouts("pc+us+de:2+ru(phonetic):3+inet(evdev)");
+        assert_eq!(layouts, vec!["us", "de", "ru(phonetic)"]);
+    }
+
+    #[test]
+    fn test_parse_symbols_skips_options() {
+        let layouts = parse_symbols_layouts("pc+us+ru:2+inet(evdev)+group(alt_shift_toggle)+level3(ralt_switch)");
+        assert_eq!(layouts, vec!["us", "ru"]);
+    }
+
+    #[test]
+    fn test_parse_symbols_single_layout() {
+        assert_eq!(parse_symbols_layouts("pc+de+inet(evdev)"), vec!["de"]);
+        assert!(parse_symbols_layouts("pc+inet(evdev)").is_empty());
+        assert!(parse_symbols_layouts("").is_empty());
+    }
+
+    #[test]
+    fn test_xkb_names_map_to_cli_codes() {
+        use crate::layout_manager::LayoutInfo;
+
+        let cases = [("us", "us"), ("gb", "gb"), ("de", "de"), ("ru(phonetic)", "ru"), ("ua", "ua"), ("se", "se")];
+        for (xkb_name, code) in cases {
+            let layout = LayoutInfo::new(group_handle(0, xkb_name));
+            assert_eq!(layout.short_code, code, "XKB layout '{}' should map to code '{}'", xkb_name, code);
+        }
+    }
+
+    #[test]
+    fn test_variant_specific_mapping() {
+        assert_eq!(xkb_layout_lang_id("rs"), 0x0c1a);
+        assert_eq!(xkb_layout_lang_id("rs(latin)"), 0x081a);
+        assert_eq!(xkb_layout_lang_id("xx"), 0);
+    }
+
+    #[test]
+    fn test_xkb_layout_for_lang_id() {
+        assert_eq!(xkb_layout_for_lang_id(0x0407), Some("de"));
+        assert_eq!(xkb_layout_for_lang_id(0x0807), Some("ch"));
+        assert_eq!(xkb_layout_for_lang_id(0x0422), Some("ua"));
+        assert_eq!(xkb_layout_for_lang_id(0x0000), None);
+        for (name, lang_id) in XKB_LAYOUTS {
+            assert_eq!(xkb_layout_lang_id(name), *lang_id);
+        }
+    }
+
+    #[test]
+    fn test_setxkbmap_args() {
+        let layouts: Vec<String> = ["us", "de", "ru(phonetic)"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(setxkbmap_args(&layouts), vec!["-layout", "us,de,ru", "-variant", ",,phonetic"]);
+    }
+
+    #[test]
+    fn test_xkb_variant() {
+        assert_eq!(xkb_variant("us(dvorak)").as_deref(), Some("dvorak"));
+        assert_eq!(xkb_variant("ru(phonetic)").as_deref(), Some("phonetic"));
+        assert_eq!(xkb_variant("us"), None);
+        assert_eq!(xkb_variant("rs(latin)"), None, "Serbian (Latin) is a language of its own");
+    }
+
+    #[test]
+    fn test_group_handle_round_trip() {
+        for group in 0..4 {
+            let handle = group_handle(group, "de");
+            assert_eq!(handle & 0xFFFF, 0x0407);
+            assert_eq!(handle_index(handle), Some(group));
+        }
+        assert_eq!(handle_index(0x0407), None, "Handles without a group are not XKB groups");
+    }
+
+    struct Xvfb(Child);
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    // Runs against a private Xvfb server configured with `setxkbmap`.
+    // Skipped when Xvfb or setxkbmap are not installed.
+    #[test]
+    fn test_xvfb_group_switching() {
+        if !command_exists("Xvfb") || !command_exists("setxkbmap") {
+            eprintln!("Skipping: Xvfb or setxkbmap not installed");
+            return;
+        }
+
+        let display = ":97";
+        let _server = Xvfb(Command::new("Xvfb")
+            .args([display, "-nolisten", "tcp"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start Xvfb"));
+
+        let mut configured = false;
+        for _ in 0..50 {
+            thread::sleep(Duration::from_millis(100));
+            let status = Command::new("setxkbmap")
+                .args(["-display", display, "-layout", "us,de,ru", "-variant", ",,phonetic"])
+                .stderr(Stdio::null())
+                .status();
+            if matches!(status, Ok(s) if s.success()) {
+                configured = true;
+                break;
+            }
+        }
+        assert!(configured, "setxkbmap could not configure Xvfb");
+
+        let source = XkbLayoutSource::open(Some(display)).expect("Failed to connect to Xvfb");
+        assert_eq!(source.group_layouts(), vec!["us", "de", "ru(phonetic)"]);
+
+        let handles = source.layout_handles();
+        assert_eq!(handles.len(), 3);
+        assert_eq!(source.current_layout(), Some(handles[0]));
+
+        source.activate_layout(handles[2]).unwrap();
+        assert_eq!(source.current_group(), Some(2));
+        assert_eq!(source.current_layout(), Some(handles[2]));
+        assert_eq!(source.current_layout().map(|h| h & 0xFFFF), Some(0x0419));
+
+        // Layouts added and removed through setxkbmap
+        let added = source.add_layout(0x0422).expect("Failed to add Ukrainian");
+        assert_eq!(added & 0xFFFF, 0x0422);
+        assert_eq!(source.group_layouts(), vec!["us", "de", "ru(phonetic)", "ua"]);
+        assert!(source.add_layout(0x040c).is_err(), "XKB has at most four groups");
+
+        source.remove_layout(handles[1]).expect("Failed to remove German");
+        assert_eq!(source.group_layouts(), vec!["us", "ru(phonetic)", "ua"]);
+    }
+}
@@ -0,0 +1,177 @@
+// Platform backends.
+//
+// Everything that touches the operating system (keyboard layouts, lock keys,
+// key injection, the keyboard hook, single-instance lock and auto-startup)
+// goes through the `Backend` trait. The switching logic in `keyboard_hook`,
+// `layout_manager` and `layout_indicator` only talks to this trait, so it can
+// be exercised with the in-memory `SimulatedBackend` on any platform.
+
+#[cfg(windows)]
+pub mod windows;
+#[cfg(target_os = "linux")]
+pub mod linux;
+pub mod simulated;
+
+use std::env;
+use std::sync::OnceLock;
+use std::time::Duration;
+use crate::bindings::KeyCode;
+use crate::instance::InstanceInfo;
+
+// Environment variable used to force a specific backend (e.g. "simulated")
+pub const BACKEND_ENV_VAR: &str = "CCAPS_BACKEND";
+
+// Lock keys that CCaps reads or toggles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKey {
+    CapsLock,
+    ScrollLock,
+}
+
+// Result of trying to become the single running instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockStatus {
+    Acquired,
+    AlreadyRunning,
+}
+
+// Calendar time in the user's time zone, minute precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+}
+
+pub trait Backend: Send + Sync {
+    // Short backend name shown in diagnostics
+    fn name(&self) -> &'static str;
+
+    // Keyboard layouts. Layouts are identified by a platform handle
+    // (the HKL value on Windows) whose low 16 bits hold the language ID.
+    // The foreground layout falls back to the calling thread's layout when
+    // there is no foreground window.
+    fn layout_handles(&self) -> Vec<usize>;
+    fn foreground_layout(&self) -> Option<usize>;
+
+    // Ask the foreground application to switch layout. `attempt` counts the
+    // retries after a switch that did not take effect, so backends with
+    // several ways of delivering the request can try the next one. Errors
+    // mean the request could not be delivered at all.
+    fn activate_layout(&self, handle: usize, attempt: usize) -> Result<(), String>;
+
+    // Variant of a layout (e.g. "dvorak" or "phonetic") when it is not the
+    // default layout of its language. Layouts sharing a language are told
+    // apart by it.
+    fn layout_variant(&self, _handle: usize) -> Option<String> {
+        None
+    }
+
+    // Install the default layout of a language for the user and return its
+    // handle, or remove an installed layout
+    fn add_layout(&self, _lang_id: u16) -> Result<usize, String> {
+        Err("Adding layouts is not supported by this backend".to_string())
+    }
+    fn remove_layout(&self, _handle: usize) -> Result<(), String> {
+        Err("Removing layouts is not supported by this backend".to_string())
+    }
+
+    // Set the layout indicator LED without generating key events.
+    // Returns false if the backend cannot, in which case the indicator
+    // falls back to toggling Scroll Lock.
+    fn set_indicator_led(&self, _on: bool) -> bool {
+        false
+    }
+
+    // Lock keys and key injection
+    fn sync_key_state(&self) {}
+    fn lock_key_state(&self, key: LockKey) -> bool;
+    fn inject_key_tap(&self, key: LockKey);
+    // Inject a press or release that the hook lets through, by scancode
+    // unless it is 0
+    fn inject_key(&self, _key: KeyCode, _pressed: bool) {}
+
+    // Layout picker overlay listing `items` with one highlighted. Returns
+    // false if the backend has no overlay.
+    fn show_picker(&self, _items: &[String], _highlight: usize) -> bool {
+        false
+    }
+    fn hide_picker(&self) {}
+
+    // Call `keyboard_hook::check_pending_switch` after `delay`, away from
+    // the keyboard hook, so the hook never waits for applications to take
+    // a layout. A new request replaces one that has not run yet.
+    fn schedule_switch_check(&self, delay: Duration);
+
+    // Keyboard hook and event loop
+    fn install_hook(&self) -> Result<(), String>;
+    fn uninstall_hook(&self);
+    fn run_message_loop(&self);
+    fn detach_from_console(&self) {}
+
+    // Convert a Unix timestamp to local time, None if the time zone is unknown
+    fn local_time(&self, _unix_time: u64) -> Option<LocalTime> {
+        None
+    }
+
+    // Single-instance lock and background process control.
+    // The lock owner records `info` so other invocations can report it.
+    fn acquire_instance_lock(&self, info: &InstanceInfo) -> Result<LockStatus, String>;
+    fn release_instance_lock(&self);
+    fn is_instance_running(&self) -> bool;
+    fn running_instance(&self) -> Option<InstanceInfo>;
+    // Rewrite the owner record of the lock held by this process;
+    // false if it is not held
+    fn update_instance_info(&self, _info: &InstanceInfo) -> bool {
+        false
+    }
+    fn stop_running_instance(&self) -> bool;
+    // Ask the running instance to re-read the installed layouts.
+    // Returns false if no instance is running.
+    fn refresh_running_instance(&self) -> bool {
+        false
+    }
+    fn spawn_background(&self, country_codes: &[String]) -> Result<(), String>;
+
+    // Auto-startup entry
+    fn is_in_startup(&self) -> bool;
+    fn get_startup_command(&self) -> Result<String, String>;
+    fn add_to_startup(&self, country_codes: &[String]) -> Result<(), String>;
+    fn remove_from_startup(&self) -> Result<(), String>;
+}
+
+// Get the backend for the current process.
+// The backend is chosen once: `CCAPS_BACKEND=simulated` forces the simulated
+// backend, otherwise the native backend for the platform is used.
+pub fn current() -> &'static dyn Backend {
+    static BACKEND: OnceLock<Box<dyn Backend>> = OnceLock::new();
+    BACKEND.get_or_init(select_backend).as_ref()
+}
+
+fn select_backend() -> Box<dyn Backend> {
+    if let Ok(name) = env::var(BACKEND_ENV_VAR) {
+        if name.eq_ignore_ascii_case("simulated") {
+            return Box::new(simulated::SimulatedBackend::new());
+        }
+    }
+
+    native_backend()
+}
+
+#[cfg(windows)]
+fn native_backend() -> Box<dyn Backend> {
+    Box::new(windows::WindowsBackend::new())
+}
+
+#[cfg(target_os = "linux")]
+fn native_backend() -> Box<dyn Backend> {
+    Box::new(linux::LinuxBackend::new())
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn native_backend() -> Box<dyn Backend> {
+    // No native backend for this platform yet
+    Box::new(simulated::SimulatedBackend::new())
+}
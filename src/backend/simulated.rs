@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::backend::{Backend, LockKey, LockStatus};
+use crate::instance::InstanceInfo;
+use crate::klid;
+
+// Layouts installed in a freshly created simulated backend:
+// English (United States), German and Russian
+const DEFAULT_LAYOUTS: [usize; 3] = [0x04090409, 0x04070407, 0x04190419];
+
+struct SimulatedState {
+    layouts: Vec<usize>,
+    current_layout: Option<usize>,
+    variants: HashMap<usize, String>,
+    caps_lock: bool,
+    scroll_lock: bool,
+    injected_keys: Vec<LockKey>,
+    hook_installed: bool,
+    // Activation requests the foreground application will drop
+    ignored_activations: usize,
+    activation_attempts: Vec<usize>,
+    // Delays of the switch checks the hook asked for
+    switch_checks: Vec<Duration>,
+    instance: Option<InstanceInfo>,
+    refresh_requests: usize,
+    startup_command: Option<String>,
+    // Picker entries and highlighted entry while the picker is shown
+    picker: Option<(Vec<String>, usize)>,
+    picker_overlay: bool,
+}
+
+// In-memory backend that never touches the operating system.
+// Used on platforms without a native backend, with CCAPS_BACKEND=simulated,
+// and by unit tests to exercise the switching logic end-to-end.
+pub struct SimulatedBackend {
+    state: Mutex<SimulatedState>,
+}
+
+impl SimulatedBackend {
+    pub fn new() -> Self {
+        Self::with_layouts(&DEFAULT_LAYOUTS)
+    }
+
+    pub fn with_layouts(layouts: &[usize]) -> Self {
+        SimulatedBackend {
+            state: Mutex::new(SimulatedState {
+                layouts: layouts.to_vec(),
+                current_layout: layouts.first().copied(),
+                variants: HashMap::new(),
+                caps_lock: false,
+                scroll_lock: false,
+                injected_keys: Vec::new(),
+                hook_installed: false,
+                ignored_activations: 0,
+                activation_attempts: Vec::new(),
+                switch_checks: Vec::new(),
+                instance: None,
+                refresh_requests: 0,
+                startup_command: None,
+                picker: None,
+                picker_overlay: true,
+            }),
+        }
+    }
+
+    // Change the active layout as if the user switched it outside CCaps
+    #[cfg(test)]
+    pub fn set_current_layout(&self, handle: Option<usize>) {
+        self.state.lock().unwrap().current_layout = handle;
+    }
+
+    // Name the variant of a layout whose HKL uses a 0xFnnn layout id,
+    // which Windows resolves through the registry
+    #[cfg(test)]
+    pub fn set_layout_variant(&self, handle: usize, variant: &str) {
+        self.state.lock().unwrap().variants.insert(handle, variant.to_string());
+    }
+
+    // Set a lock key state directly, without recording an injected key
+    #[cfg(test)]
+    pub fn set_lock_key_state(&self, key: LockKey, enabled: bool) {
+        let mut state = self.state.lock().unwrap();
+        match key {
+            LockKey::CapsLock => state.caps_lock = enabled,
+            LockKey::ScrollLock => state.scroll_lock = enabled,
+        }
+    }
+
+    // Drop the next `count` activation requests, like an application
+    // that ignores layout change requests
+    #[cfg(test)]
+    pub fn ignore_activations(&self, count: usize) {
+        self.state.lock().unwrap().ignored_activations = count;
+    }
+
+    // Attempt numbers of the activation requests so far, in order
+    #[cfg(test)]
+    pub fn activation_attempts(&self) -> Vec<usize> {
+        self.state.lock().unwrap().activation_attempts.clone()
+    }
+
+    // Nothing runs scheduled switch checks here; tests run them with
+    // `keyboard_hook::check_pending_switch`
+    #[cfg(test)]
+    pub fn switch_checks(&self) -> Vec<Duration> {
+        self.state.lock().unwrap().switch_checks.clone()
+    }
+
+    // Behave like a backend that cannot show the picker
+    #[cfg(test)]
+    pub fn disable_picker_overlay(&self) {
+        self.state.lock().unwrap().picker_overlay = false;
+    }
+
+    #[cfg(test)]
+    pub fn picker(&self) -> Option<(Vec<String>, usize)> {
+        self.state.lock().unwrap().picker.clone()
+    }
+
+    // Keys injected so far, in order
+    #[cfg(test)]
+    pub fn injected_keys(&self) -> Vec<LockKey> {
+        self.state.lock().unwrap().injected_keys.clone()
+    }
+
+    #[cfg(test)]
+    pub fn is_hook_installed(&self) -> bool {
+        self.state.lock().unwrap().hook_installed
+    }
+
+    // Refresh requests sent to the running instance so far
+    #[cfg(test)]
+    pub fn refresh_requests(&self) -> usize {
+        self.state.lock().unwrap().refresh_requests
+    }
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backend for SimulatedBackend {
+    fn name(&self) -> &'static str {
+        "simulated"
+    }
+
+    fn layout_handles(&self) -> Vec<usize> {
+        self.state.lock().unwrap().layouts.clone()
+    }
+
+    fn foreground_layout(&self) -> Option<usize> {
+        self.state.lock().unwrap().current_layout
+    }
+
+    fn activate_layout(&self, handle: usize, attempt: usize) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if !state.layouts.contains(&handle) {
+            return Err(format!("Layout {:08X} is not installed", handle));
+        }
+
+        state.activation_attempts.push(attempt);
+        if state.ignored_activations > 0 {
+            state.ignored_activations -= 1;
+        } else {
+            state.current_layout = Some(handle);
+        }
+        Ok(())
+    }
+
+    fn layout_variant(&self, handle: usize) -> Option<String> {
+        if let Some(variant) = self.state.lock().unwrap().variants.get(&handle) {
+            return Some(variant.clone());
+        }
+        klid::klid_from_hkl(handle).and_then(|klid| klid::klid_variant(klid, (handle & 0xFFFF) as u16))
+    }
+
+    fn add_layout(&self, lang_id: u16) -> Result<usize, String> {
+        let handle = (usize::from(lang_id) << 16) | usize::from(lang_id);
+        let mut state = self.state.lock().unwrap();
+        if !state.layouts.contains(&handle) {
+            state.layouts.push(handle);
+        }
+        Ok(handle)
+    }
+
+    fn remove_layout(&self, handle: usize) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.layouts.len() == 1 && state.layouts[0] == handle {
+            return Err("Cannot remove the only layout".to_string());
+        }
+        state.layouts.retain(|&layout| layout != handle);
+        if state.current_layout == Some(handle) {
+            state.current_layout = state.layouts.first().copied();
+        }
+        Ok(())
+    }
+
+    fn lock_key_state(&self, key: LockKey) -> bool {
+        let state = self.state.lock().unwrap();
+        match key {
+            LockKey::CapsLock => state.caps_lock,
+            LockKey::ScrollLock => state.scroll_lock,
+        }
+    }
+
+    fn inject_key_tap(&self, key: LockKey) {
+        let mut state = self.state.lock().unwrap();
+        match key {
+            LockKey::CapsLock => state.caps_lock = !state.caps_lock,
+            LockKey::ScrollLock => state.scroll_lock = !state.scroll_lock,
+        }
+        state.injected_keys.push(key);
+    }
+
+    fn show_picker(&self, items: &[String], highlight: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.picker_overlay {
+            state.picker = Some((items.to_vec(), highlight));
+        }
+        state.picker_overlay
+    }
+
+    fn hide_picker(&self) {
+        self.state.lock().unwrap().picker = None;
+    }
+
+    fn schedule_switch_check(&self, delay: Duration) {
+        self.state.lock().unwrap().switch_checks.push(delay);
+    }
+
+    fn install_hook(&self) -> Result<(), String> {
+        self.state.lock().unwrap().hook_installed = true;
+        Ok(())
+    }
+
+    fn uninstall_hook(&self) {
+        self.state.lock().unwrap().hook_installed = false;
+    }
+
+    fn run_message_loop(&self) {
+        // Nothing generates events here, so there is nothing to wait for
+    }
+
+    fn acquire_instance_lock(&self, info: &InstanceInfo) -> Result<LockStatus, String> {
+        let mut state = self.state.lock().unwrap();
+        if state.instance.is_some() {
+            return Ok(LockStatus::AlreadyRunning);
+        }
+        state.instance = Some(info.clone());
+        Ok(LockStatus::Acquired)
+    }
+
+    fn release_instance_lock(&self) {
+        self.state.lock().unwrap().instance = None;
+    }
+
+    fn is_instance_running(&self) -> bool {
+        self.state.lock().unwrap().instance.is_some()
+    }
+
+    fn running_instance(&self) -> Option<InstanceInfo> {
+        self.state.lock().unwrap().instance.clone()
+    }
+
+    fn update_instance_info(&self, info: &InstanceInfo) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.instance.is_none() {
+            return false;
+        }
+        state.instance = Some(info.clone());
+        true
+    }
+
+    fn stop_running_instance(&self) -> bool {
+        self.state.lock().unwrap().instance.take().is_some()
+    }
+
+    fn refresh_running_instance(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.instance.is_none() {
+            return false;
+        }
+        state.refresh_requests += 1;
+        true
+    }
+
+    fn spawn_background(&self, _country_codes: &[String]) -> Result<(), String> {
+        Err("Background mode is not available with the simulated backend".to_string())
+    }
+
+    fn is_in_startup(&self) -> bool {
+        self.state.lock().unwrap().startup_command.is_some()
+    }
+
+    fn get_startup_command(&self) -> Result<String, String> {
+        self.state.lock().unwrap().startup_command.clone()
+            .ok_or_else(|| "No startup entry".to_string())
+    }
+
+    fn add_to_startup(&self, country_codes: &[String]) -> Result<(), String> {
+        let mut command = "ccaps --background".to_string();
+        for code in country_codes {
+            command.push_str(&format!(" -{}", code));
+        }
+        self.state.lock().unwrap().startup_command = Some(command);
+        Ok(())
+    }
+
+    fn remove_from_startup(&self) -> Result<(), String> {
+        self.state.lock().unwrap().startup_command = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activate_unknown_layout_is_ignored() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409]);
+        assert!(backend.activate_layout(0x04070407, 0).is_err());
+        assert_eq!(backend.foreground_layout(), Some(0x04090409));
+    }
+
+    #[test]
+    fn test_instance_lock_is_exclusive() {
+        let backend = SimulatedBackend::new();
+        let first = InstanceInfo::for_current_process(&["de".to_string()]);
+        let second = InstanceInfo::for_current_process(&[]);
+        assert_eq!(backend.acquire_instance_lock(&first), Ok(LockStatus::Acquired));
+        assert_eq!(backend.acquire_instance_lock(&second), Ok(LockStatus::AlreadyRunning));
+        assert!(backend.is_instance_running());
+        assert_eq!(backend.running_instance(), Some(first), "The first owner keeps the lock");
+
+        backend.release_instance_lock();
+        assert!(!backend.is_instance_running());
+        assert_eq!(backend.running_instance(), None);
+    }
+
+    #[test]
+    fn test_hook_install_and_uninstall() {
+        let backend = SimulatedBackend::new();
+        assert!(backend.install_hook().is_ok());
+        assert!(backend.is_hook_installed());
+
+        backend.uninstall_hook();
+        assert!(!backend.is_hook_installed());
+    }
+
+    #[test]
+    fn test_startup_entry_round_trip() {
+        let backend = SimulatedBackend::new();
+        assert!(!backend.is_in_startup());
+
+        backend.add_to_startup(&["de".to_string(), "ru".to_string()]).unwrap();
+        assert!(backend.is_in_startup());
+        assert_eq!(backend.get_startup_command().unwrap(), "ccaps --background -de -ru");
+
+        backend.remove_from_startup().unwrap();
+        assert!(!backend.is_in_startup());
+    }
+}
@@ -0,0 +1,1219 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::ptr;
+use std::mem;
+use std::ffi::OsString;
+use std::os::windows::ffi::OsStrExt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use winapi::um::winuser::*;
+use winapi::um::winreg::*;
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+use winapi::um::synchapi::CreateMutexW;
+use winapi::um::errhandlingapi::GetLastError;
+use winapi::um::libloaderapi::GetModuleHandleW;
+use winapi::um::wincon::FreeConsole;
+use winapi::um::fileapi::FileTimeToLocalFileTime;
+use winapi::um::timezoneapi::FileTimeToSystemTime;
+use winapi::um::minwinbase::SYSTEMTIME;
+use winapi::um::wingdi::{GetStockObject, SelectObject, SetBkMode, SetTextColor, DEFAULT_GUI_FONT, TRANSPARENT};
+use winapi::um::winnt::{HANDLE, LONG, KEY_NOTIFY, KEY_SET_VALUE, KEY_QUERY_VALUE, KEY_READ, REG_NOTIFY_CHANGE_LAST_SET, REG_NOTIFY_CHANGE_NAME, REG_SZ};
+use winapi::shared::minwindef::*;
+use winapi::shared::windef::*;
+use winapi::shared::winerror::*;
+use crate::backend::{self, Backend, LocalTime, LockKey, LockStatus};
+use crate::bindings::{Key, KeyCode, Modifiers};
+use crate::config;
+use crate::instance::InstanceInfo;
+use crate::klid;
+use crate::keyboard_hook::{self, CCAPS_EXTRA_INFO};
+
+const MUTEX_NAME: &str = "Global\\CCapsLayoutSwitcherMutex";
+const REGISTRY_KEY: &str = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Run";
+const KEYBOARD_LAYOUTS_KEY: &str = "SYSTEM\\CurrentControlSet\\Control\\Keyboard Layouts";
+// Per-user list of installed layouts, rewritten when layouts are added or removed in Settings
+const PRELOAD_KEY: &str = "Keyboard Layout\\Preload";
+const APP_NAME: &str = "CCaps Layout Switcher";
+const WINDOW_NAME: &str = "CCaps Layout Switcher";
+const MESSAGE_CLASS_NAME: &str = "CCapsMessageWindow";
+const PICKER_CLASS_NAME: &str = "CCapsPickerWindow";
+const PICKER_WINDOW_NAME: &str = "CCaps Layout Picker";
+// Owner info of the running instance, kept next to the mutex
+const INSTANCE_FILE_NAME: &str = "ccaps-instance.json";
+
+// Posted to the message window by `ccaps -layouts`, and to the message loop
+// when the installed layouts change, to refresh the selected layouts
+const WM_CCAPS_REFRESH: UINT = WM_APP + 1;
+
+// How often the foreground layout is checked for changes made with
+// Win+Space or the language bar. Windows only reports them with
+// WM_INPUTLANGCHANGE to the window whose thread changed layout; seeing it
+// for other processes takes a WH_CALLWNDPROC hook DLL injected into each of
+// them, and no WinEvent covers it outside consoles. So the layout is polled,
+// while the user's desktop has the input.
+const LAYOUT_POLL_INTERVAL_MS: UINT = 250;
+
+// How long a window may take to handle a layout change request sent
+// directly to it; the message loop is blocked meanwhile
+const SEND_REQUEST_TIMEOUT_MS: UINT = 50;
+
+// Message window timer that runs `keyboard_hook::check_pending_switch`
+const SWITCH_CHECK_TIMER_ID: usize = 1;
+
+// Layout picker overlay size in pixels
+const PICKER_WIDTH: i32 = 360;
+const PICKER_ROW_HEIGHT: i32 = 32;
+const PICKER_PADDING: i32 = 8;
+
+// Seconds between 1601-01-01 (FILETIME epoch) and 1970-01-01
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+// Global variable to store the hook
+static mut HOOK: HHOOK = ptr::null_mut();
+
+// Foreground layout seen by the last layout check
+static LAST_FOREGROUND_LAYOUT: AtomicUsize = AtomicUsize::new(0);
+
+// Thread timer polling the foreground layout, 0 while stopped
+static LAYOUT_POLL_TIMER: AtomicUsize = AtomicUsize::new(0);
+
+// Hidden message window of the message loop thread
+static MESSAGE_WINDOW: AtomicPtr<HWND__> = AtomicPtr::new(ptr::null_mut());
+
+// Global atomic pointer to store mutex handle
+static MUTEX_HANDLE: AtomicPtr<winapi::ctypes::c_void> = AtomicPtr::new(ptr::null_mut());
+
+// Layout picker overlay, created with the message window, and the entries
+// it shows with the highlighted entry
+static PICKER_WINDOW: AtomicPtr<HWND__> = AtomicPtr::new(ptr::null_mut());
+static PICKER_ITEMS: Mutex<(Vec<String>, usize)> = Mutex::new((Vec::new(), 0));
+
+// Native Windows backend built on the Win32 API
+pub struct WindowsBackend;
+
+impl WindowsBackend {
+    pub fn new() -> Self {
+        WindowsBackend
+    }
+}
+
+impl Default for WindowsBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn to_wide(value: &str) -> Vec<u16> {
+    OsString::from(format!("{}\0", value))
+        .encode_wide()
+        .collect()
+}
+
+fn instance_file_path() -> PathBuf {
+    env::temp_dir().join(INSTANCE_FILE_NAME)
+}
+
+fn virtual_key(key: LockKey) -> i32 {
+    match key {
+        LockKey::CapsLock => VK_CAPITAL,
+        LockKey::ScrollLock => VK_SCROLL,
+    }
+}
+
+impl Backend for WindowsBackend {
+    fn name(&self) -> &'static str {
+        "windows"
+    }
+
+    fn layout_handles(&self) -> Vec<usize> {
+        unsafe {
+            // Ask for the count first; retry if a layout is added in between
+            loop {
+                let count = GetKeyboardLayoutList(0, ptr::null_mut());
+                if count <= 0 {
+                    return Vec::new();
+                }
+
+                let mut layouts: Vec<HKL> = vec![ptr::null_mut(); count as usize];
+                let written = GetKeyboardLayoutList(count, layouts.as_mut_ptr());
+                if written == count || written == 0 {
+                    layouts.truncate(written as usize);
+                    return layouts.into_iter().map(|hkl| hkl as usize).collect();
+                }
+            }
+        }
+    }
+
+    fn foreground_layout(&self) -> Option<usize> {
+        unsafe {
+            let hwnd = GetForegroundWindow();
+            if hwnd.is_null() {
+                // Fallback: get layout for current thread if no foreground window
+                return thread_layout();
+            }
+
+            let thread_id = GetWindowThreadProcessId(hwnd, ptr::null_mut());
+            let current_layout = GetKeyboardLayout(thread_id);
+            if current_layout.is_null() {
+                return None;
+            }
+
+            Some(current_layout as usize)
+        }
+    }
+
+    fn activate_layout(&self, handle: usize, attempt: usize) -> Result<(), String> {
+        unsafe {
+            let hwnd = GetForegroundWindow();
+            if hwnd.is_null() {
+                return Err("No foreground window".to_string());
+            }
+
+            let hkl = handle as HKL;
+            let delivered = match attempt {
+                0 => {
+                    // Activate new layout
+                    ActivateKeyboardLayout(hkl, 0);
+
+                    // Send layout change message to all windows
+                    PostMessageW(HWND_BROADCAST, WM_INPUTLANGCHANGEREQUEST, 0, hkl as LPARAM) != 0
+                },
+                1 => {
+                    // Console hosts and some UWP apps only act on the request
+                    // when it reaches the window that has keyboard focus
+                    PostMessageW(focus_window(hwnd), WM_INPUTLANGCHANGEREQUEST, 0, hkl as LPARAM) != 0
+                },
+                _ => {
+                    // Last resort: send it and wait until the window handled it
+                    let mut result: usize = 0;
+                    SendMessageTimeoutW(focus_window(hwnd), WM_INPUTLANGCHANGEREQUEST, 0, hkl as LPARAM,
+                                        SMTO_ABORTIFHUNG, SEND_REQUEST_TIMEOUT_MS, &mut result) != 0
+                },
+            };
+
+            if delivered {
+                Ok(())
+            } else {
+                // Typically UIPI refusing messages to elevated windows
+                Err(format!("Layout change request was not delivered (error {})", GetLastError()))
+            }
+        }
+    }
+
+    fn layout_variant(&self, handle: usize) -> Option<String> {
+        let klid = match klid::klid_from_hkl(handle) {
+            Some(klid) => klid,
+            None => find_klid_by_layout_id(klid::layout_id_from_hkl(handle)?)?,
+        };
+        klid::klid_variant(klid, (handle & 0xFFFF) as u16)
+    }
+
+    fn add_layout(&self, lang_id: u16) -> Result<usize, String> {
+        // The default KLID of a language is its LANGID, e.g. "00000407"
+        let klid_wide = to_wide(&format!("{:08X}", lang_id));
+        let hkl = unsafe { LoadKeyboardLayoutW(klid_wide.as_ptr(), KLF_SUBSTITUTE_OK) };
+        if hkl.is_null() {
+            return Err(format!("LoadKeyboardLayout failed with error {}", unsafe { GetLastError() }));
+        }
+
+        // Windows falls back to another layout when the KLID is not available
+        let handle = hkl as usize;
+        if handle & 0xFFFF != usize::from(lang_id) {
+            return Err("Windows has no keyboard layout for this language".to_string());
+        }
+        Ok(handle)
+    }
+
+    fn remove_layout(&self, handle: usize) -> Result<(), String> {
+        if unsafe { UnloadKeyboardLayout(handle as HKL) } == 0 {
+            return Err(format!("UnloadKeyboardLayout failed with error {}", unsafe { GetLastError() }));
+        }
+        Ok(())
+    }
+
+    fn sync_key_state(&self) {
+        unsafe {
+            // Synchronize the thread's key state table before querying.
+            // GetKeyState() relies on the thread's internal key state buffer,
+            // which may be stale during Windows startup before the message loop runs.
+            // GetKeyboardState() forces a synchronization with the actual key states.
+            let mut key_state: [u8; 256] = [0; 256];
+            GetKeyboardState(key_state.as_mut_ptr());
+        }
+    }
+
+    fn lock_key_state(&self, key: LockKey) -> bool {
+        unsafe { (GetKeyState(virtual_key(key)) & 1) != 0 }
+    }
+
+    fn inject_key_tap(&self, key: LockKey) {
+        unsafe {
+            let vk = virtual_key(key) as u16;
+
+            // Create array for simulating key press
+            let mut inputs: [INPUT; 2] = mem::zeroed();
+
+            // First INPUT - key press
+            inputs[0].type_ = INPUT_KEYBOARD;
+            inputs[0].u.ki_mut().wVk = vk;
+            inputs[0].u.ki_mut().dwFlags = 0;
+            inputs[0].u.ki_mut().dwExtraInfo = CCAPS_EXTRA_INFO;
+
+            // Second INPUT - key release
+            inputs[1].type_ = INPUT_KEYBOARD;
+            inputs[1].u.ki_mut().wVk = vk;
+            inputs[1].u.ki_mut().dwFlags = KEYEVENTF_KEYUP;
+            inputs[1].u.ki_mut().dwExtraInfo = CCAPS_EXTRA_INFO;
+
+            // Send press and release events
+            SendInput(2, inputs.as_mut_ptr(), mem::size_of::<INPUT>() as i32);
+        }
+    }
+
+    fn inject_key(&self, key: KeyCode, pressed: bool) {
+        unsafe {
+            let mut input: INPUT = mem::zeroed();
+            input.type_ = INPUT_KEYBOARD;
+            let keyboard_input = input.u.ki_mut();
+            if key.scancode != 0 {
+                keyboard_input.wScan = key.scancode & 0xFF;
+                keyboard_input.dwFlags = KEYEVENTF_SCANCODE;
+                if key.scancode & 0xE000 != 0 {
+                    keyboard_input.dwFlags |= KEYEVENTF_EXTENDEDKEY;
+                }
+            } else {
+                match vk_for_key(key.key) {
+                    Some(vk) => keyboard_input.wVk = vk as u16,
+                    None => return,
+                }
+            }
+            if !pressed {
+                keyboard_input.dwFlags |= KEYEVENTF_KEYUP;
+            }
+            // Marked like the Caps Lock taps, so the hook lets it through
+            keyboard_input.dwExtraInfo = CCAPS_EXTRA_INFO;
+
+            SendInput(1, &mut input, mem::size_of::<INPUT>() as i32);
+        }
+    }
+
+    // Called from the keyboard hook or the check itself, both on the message
+    // loop thread that owns the window. Setting the timer again restarts it.
+    fn schedule_switch_check(&self, delay: Duration) {
+        let window = MESSAGE_WINDOW.load(Ordering::SeqCst);
+        if !window.is_null() {
+            let delay_ms = UINT::try_from(delay.as_millis()).unwrap_or(UINT::MAX);
+            unsafe {
+                SetTimer(window, SWITCH_CHECK_TIMER_ID, delay_ms, None);
+            }
+        }
+    }
+
+    fn show_picker(&self, items: &[String], highlight: usize) -> bool {
+        unsafe {
+            let window = PICKER_WINDOW.load(Ordering::SeqCst);
+            if window.is_null() {
+                return false;
+            }
+            *PICKER_ITEMS.lock().unwrap() = (items.to_vec(), highlight);
+
+            // Centered on the monitor of the foreground window
+            let monitor = MonitorFromWindow(GetForegroundWindow(), MONITOR_DEFAULTTOPRIMARY);
+            let mut info: MONITORINFO = mem::zeroed();
+            info.cbSize = mem::size_of::<MONITORINFO>() as DWORD;
+            GetMonitorInfoW(monitor, &mut info);
+            let work = info.rcWork;
+            let height = items.len() as i32 * PICKER_ROW_HEIGHT + 2 * PICKER_PADDING;
+            let x = work.left + (work.right - work.left - PICKER_WIDTH) / 2;
+            let y = work.top + (work.bottom - work.top - height) / 2;
+
+            SetWindowPos(window, HWND_TOPMOST, x, y, PICKER_WIDTH, height, SWP_NOACTIVATE | SWP_SHOWWINDOW);
+            InvalidateRect(window, ptr::null(), TRUE);
+            UpdateWindow(window);
+            true
+        }
+    }
+
+    fn hide_picker(&self) {
+        let window = PICKER_WINDOW.load(Ordering::SeqCst);
+        if !window.is_null() {
+            unsafe {
+                ShowWindow(window, SW_HIDE);
+            }
+        }
+    }
+
+    fn install_hook(&self) -> Result<(), String> {
+        unsafe {
+            let h_mod = GetModuleHandleW(ptr::null());
+            if h_mod.is_null() {
+                return Err("Failed to get module handle".to_string());
+            }
+
+            HOOK = SetWindowsHookExW(
+                WH_KEYBOARD_LL,
+                Some(low_level_keyboard_proc),
+                h_mod,
+                0,
+            );
+
+            if HOOK.is_null() {
+                return Err("Failed to install hook".to_string());
+            }
+
+            Ok(())
+        }
+    }
+
+    fn uninstall_hook(&self) {
+        unsafe {
+            if !HOOK.is_null() {
+                UnhookWindowsHookEx(HOOK);
+                HOOK = ptr::null_mut();
+            }
+        }
+    }
+
+    fn run_message_loop(&self) {
+        unsafe {
+            // Create hidden window for message handling
+            let window = create_message_window();
+            MESSAGE_WINDOW.store(window, Ordering::SeqCst);
+            PICKER_WINDOW.store(create_picker_window(), Ordering::SeqCst);
+            watch_layout_list(GetCurrentThreadId());
+            watch_foreground_layout(window);
+
+            // Main message processing loop
+            let mut msg: MSG = mem::zeroed();
+            loop {
+                let result = GetMessageW(&mut msg, ptr::null_mut(), 0, 0);
+                if result == 0 || result == -1 {
+                    break;
+                }
+
+                // Handle quit message
+                if msg.message == WM_QUIT {
+                    break;
+                }
+
+                // Layouts were added or removed
+                if msg.message == WM_CCAPS_REFRESH {
+                    keyboard_hook::refresh_layouts(self);
+                    continue;
+                }
+
+                // Confirm or retry the last layout switch
+                if msg.message == WM_TIMER && msg.hwnd == window && msg.wParam == SWITCH_CHECK_TIMER_ID {
+                    KillTimer(window, SWITCH_CHECK_TIMER_ID);
+                    keyboard_hook::check_pending_switch(self);
+                    continue;
+                }
+
+                // Periodic check for layout changes made outside CCaps
+                if msg.message == WM_TIMER && msg.hwnd.is_null() {
+                    sync_foreground_layout(false);
+                    continue;
+                }
+
+                // Handle other system messages
+                if msg.message == WM_QUERYENDSESSION || msg.message == WM_ENDSESSION {
+                    // System shutdown - cleanup and exit gracefully
+                    break;
+                }
+
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+    }
+
+    fn detach_from_console(&self) {
+        unsafe {
+            // Unbind from the parent process console
+            // Note: We don't hide the console window because the background process
+            // is started with CREATE_NO_WINDOW flag, so it doesn't have a console window to hide
+            FreeConsole();
+        }
+    }
+
+    fn local_time(&self, unix_time: u64) -> Option<LocalTime> {
+        let ticks = unix_time.checked_add(FILETIME_UNIX_OFFSET)?.checked_mul(10_000_000)?;
+        unsafe {
+            let utc = FILETIME {
+                dwLowDateTime: ticks as DWORD,
+                dwHighDateTime: (ticks >> 32) as DWORD,
+            };
+            let mut local: FILETIME = mem::zeroed();
+            let mut time: SYSTEMTIME = mem::zeroed();
+            if FileTimeToLocalFileTime(&utc, &mut local) == 0 || FileTimeToSystemTime(&local, &mut time) == 0 {
+                return None;
+            }
+
+            Some(LocalTime {
+                year: time.wYear as i32,
+                month: time.wMonth as u32,
+                day: time.wDay as u32,
+                hour: time.wHour as u32,
+                minute: time.wMinute as u32,
+            })
+        }
+    }
+
+    fn acquire_instance_lock(&self, info: &InstanceInfo) -> Result<LockStatus, String> {
+        unsafe {
+            let mutex_name_wide = to_wide(MUTEX_NAME);
+            let mutex = CreateMutexW(
+                ptr::null_mut(),
+                TRUE,
+                mutex_name_wide.as_ptr(),
+            );
+
+            if mutex.is_null() {
+                return Err("Failed to create mutex".to_string());
+            }
+
+            // Check if another instance is already running
+            if GetLastError() == ERROR_ALREADY_EXISTS {
+                CloseHandle(mutex);
+                return Ok(LockStatus::AlreadyRunning);
+            }
+
+            // Store mutex handle in atomic pointer
+            MUTEX_HANDLE.store(mutex, Ordering::SeqCst);
+
+            // Record the owner so other invocations can report it
+            if let Err(e) = fs::write(instance_file_path(), info.to_json()) {
+                eprintln!("Warning: Could not write instance file: {}", e);
+            }
+
+            Ok(LockStatus::Acquired)
+        }
+    }
+
+    fn release_instance_lock(&self) {
+        let mutex = MUTEX_HANDLE.swap(ptr::null_mut(), Ordering::SeqCst);
+        if !mutex.is_null() {
+            let _ = fs::remove_file(instance_file_path());
+            unsafe {
+                CloseHandle(mutex);
+            }
+        }
+    }
+
+    fn is_instance_running(&self) -> bool {
+        unsafe {
+            let mutex_name_wide = to_wide(MUTEX_NAME);
+            let mutex = CreateMutexW(
+                ptr::null_mut(),
+                FALSE,
+                mutex_name_wide.as_ptr(),
+            );
+
+            if mutex.is_null() {
+                return false;
+            }
+
+            let error = GetLastError();
+            CloseHandle(mutex);
+
+            error == ERROR_ALREADY_EXISTS
+        }
+    }
+
+    fn running_instance(&self) -> Option<InstanceInfo> {
+        // The sidecar file is only trusted while the mutex exists,
+        // a crashed instance may have left it behind
+        if !self.is_instance_running() {
+            return None;
+        }
+        InstanceInfo::from_json(&fs::read_to_string(instance_file_path()).ok()?)
+    }
+
+    fn update_instance_info(&self, info: &InstanceInfo) -> bool {
+        !MUTEX_HANDLE.load(Ordering::SeqCst).is_null()
+            && fs::write(instance_file_path(), info.to_json()).is_ok()
+    }
+
+    fn stop_running_instance(&self) -> bool {
+        // Send quit message to running instance
+        unsafe {
+            let window = find_message_window();
+            if !window.is_null() {
+                PostMessageW(window, WM_QUIT, 0, 0);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn refresh_running_instance(&self) -> bool {
+        unsafe {
+            let window = find_message_window();
+            !window.is_null() && PostMessageW(window, WM_CCAPS_REFRESH, 0, 0) != 0
+        }
+    }
+
+    fn spawn_background(&self, country_codes: &[String]) -> Result<(), String> {
+        use std::process::Command;
+        use std::os::windows::process::CommandExt;
+
+        let exe_path = env::current_exe()
+            .map_err(|_| "Failed to get executable path")?;
+
+        let mut command = Command::new(&exe_path);
+        command.arg("--background");
+
+        // Add country codes to the background process
+        for code in country_codes {
+            command.arg(format!("-{}", code));
+        }
+
+        // Use CREATE_NO_WINDOW flag to prevent creating a console window
+        // This ensures the background process doesn't affect the parent terminal
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+
+        match command.spawn() {
+            Ok(child) => {
+                // Unbind the child process - don't wait for it to complete
+                mem::forget(child);
+                Ok(())
+            },
+            Err(e) => Err(format!("Failed to start process: {}", e)),
+        }
+    }
+
+    fn is_in_startup(&self) -> bool {
+        unsafe {
+            let Ok(key) = open_run_key(KEY_QUERY_VALUE) else {
+                return false;
+            };
+
+            let app_name_wide = to_wide(APP_NAME);
+            let mut value_type: DWORD = 0;
+            let mut data_size: DWORD = 0;
+
+            let result = RegQueryValueExW(
+                key,
+                app_name_wide.as_ptr(),
+                ptr::null_mut(),
+                &mut value_type,
+                ptr::null_mut(),
+                &mut data_size,
+            );
+
+            RegCloseKey(key);
+
+            result == ERROR_SUCCESS as i32 && value_type == REG_SZ && data_size > 0
+        }
+    }
+
+    fn get_startup_command(&self) -> Result<String, String> {
+        unsafe {
+            let key = open_run_key(KEY_QUERY_VALUE)?;
+
+            let app_name_wide = to_wide(APP_NAME);
+            let mut value_type: DWORD = 0;
+            let mut data_size: DWORD = 0;
+
+            // First call to get size
+            let result = RegQueryValueExW(
+                key,
+                app_name_wide.as_ptr(),
+                ptr::null_mut(),
+                &mut value_type,
+                ptr::null_mut(),
+                &mut data_size,
+            );
+
+            if result != ERROR_SUCCESS as i32 {
+                RegCloseKey(key);
+                return Err("Failed to query registry value size".to_string());
+            }
+
+            // Allocate buffer and get actual value
+            let mut buffer: Vec<u16> = vec![0; (data_size / 2) as usize];
+            let result = RegQueryValueExW(
+                key,
+                app_name_wide.as_ptr(),
+                ptr::null_mut(),
+                &mut value_type,
+                buffer.as_mut_ptr() as *mut u8,
+                &mut data_size,
+            );
+
+            RegCloseKey(key);
+
+            if result == ERROR_SUCCESS as i32 && value_type == REG_SZ {
+                // Convert wide string to String, removing null terminator
+                let end_pos = buffer.iter().position(|&x| x == 0).unwrap_or(buffer.len());
+                let path = String::from_utf16(&buffer[..end_pos])
+                    .map_err(|_| "Failed to convert path to string")?;
+                Ok(path)
+            } else {
+                Err("Failed to read registry value".to_string())
+            }
+        }
+    }
+
+    fn add_to_startup(&self, country_codes: &[String]) -> Result<(), String> {
+        // Get current executable path
+        let exe_path = env::current_exe()
+            .map_err(|_| "Failed to get executable path")?;
+
+        let mut exe_path_str = format!("\"{}\" --background", exe_path.display());
+
+        // Keep an explicit config file for the process started at login
+        if let Some(config_path) = config::config_override() {
+            exe_path_str.push_str(&format!(" --config \"{}\"", config_path.display()));
+        }
+
+        // Add country codes to the command line
+        for code in country_codes {
+            exe_path_str.push_str(&format!(" -{}", code));
+        }
+
+        unsafe {
+            let key = open_run_key(KEY_SET_VALUE)?;
+
+            let exe_path_wide = to_wide(&exe_path_str);
+            let app_name_wide = to_wide(APP_NAME);
+
+            let result = RegSetValueExW(
+                key,
+                app_name_wide.as_ptr(),
+                0,
+                REG_SZ,
+                exe_path_wide.as_ptr() as *const u8,
+                (exe_path_wide.len() * 2) as u32,
+            );
+
+            RegCloseKey(key);
+
+            if result == ERROR_SUCCESS as i32 {
+                Ok(())
+            } else {
+                Err("Failed to set registry value".to_string())
+            }
+        }
+    }
+
+    fn remove_from_startup(&self) -> Result<(), String> {
+        unsafe {
+            let key = open_run_key(KEY_SET_VALUE)?;
+
+            let app_name_wide = to_wide(APP_NAME);
+            let result = RegDeleteValueW(key, app_name_wide.as_ptr());
+            RegCloseKey(key);
+
+            if result == ERROR_SUCCESS as i32 || result == ERROR_FILE_NOT_FOUND as i32 {
+                Ok(())
+            } else {
+                Err("Failed to remove registry value".to_string())
+            }
+        }
+    }
+}
+
+// Get keyboard layout for the calling thread
+fn thread_layout() -> Option<usize> {
+    unsafe {
+        // Get keyboard layout for the current thread (0 = calling thread)
+        // This works reliably even at startup when there's no foreground window
+        let current_layout = GetKeyboardLayout(0);
+        if current_layout.is_null() {
+            return None;
+        }
+
+        Some(current_layout as usize)
+    }
+}
+
+// Open HKCU\...\Run with the requested access rights
+unsafe fn open_run_key(access: DWORD) -> Result<HKEY, String> {
+    unsafe {
+        let mut key: HKEY = ptr::null_mut();
+        let key_name_wide = to_wide(REGISTRY_KEY);
+
+        let result = RegOpenKeyExW(
+            HKEY_CURRENT_USER,
+            key_name_wide.as_ptr(),
+            0,
+            access,
+            &mut key,
+        );
+
+        if result != ERROR_SUCCESS as i32 {
+            return Err("Failed to open registry key".to_string());
+        }
+
+        Ok(key)
+    }
+}
+
+// Find the KLID whose "Layout Id" registry value matches a 0xFnnn HKL
+fn find_klid_by_layout_id(layout_id: u16) -> Option<u32> {
+    let wanted = format!("{:04x}", layout_id);
+
+    unsafe {
+        let mut layouts_key: HKEY = ptr::null_mut();
+        let key_name_wide = to_wide(KEYBOARD_LAYOUTS_KEY);
+        if RegOpenKeyExW(HKEY_LOCAL_MACHINE, key_name_wide.as_ptr(), 0, KEY_READ, &mut layouts_key) != ERROR_SUCCESS as i32 {
+            return None;
+        }
+
+        let value_name_wide = to_wide("Layout Id");
+        let mut found = None;
+        let mut index = 0;
+
+        loop {
+            let mut name = [0u16; 16];
+            let mut name_len = name.len() as DWORD;
+            let result = RegEnumKeyExW(
+                layouts_key,
+                index,
+                name.as_mut_ptr(),
+                &mut name_len,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            index += 1;
+
+            if result == ERROR_NO_MORE_ITEMS as i32 {
+                break;
+            }
+            if result != ERROR_SUCCESS as i32 {
+                continue;
+            }
+
+            let mut value = [0u16; 16];
+            let mut value_size = (value.len() * 2) as DWORD;
+            let result = RegGetValueW(
+                layouts_key,
+                name.as_ptr(),
+                value_name_wide.as_ptr(),
+                RRF_RT_REG_SZ,
+                ptr::null_mut(),
+                value.as_mut_ptr() as *mut _,
+                &mut value_size,
+            );
+            if result != ERROR_SUCCESS as i32 {
+                continue;
+            }
+
+            let end = value.iter().position(|&c| c == 0).unwrap_or(value.len());
+            if String::from_utf16_lossy(&value[..end]).eq_ignore_ascii_case(&wanted) {
+                let klid = String::from_utf16_lossy(&name[..name_len as usize]);
+                found = u32::from_str_radix(&klid, 16).ok();
+                break;
+            }
+        }
+
+        RegCloseKey(layouts_key);
+        found
+    }
+}
+
+// Callback function for handling key presses
+unsafe extern "system" fn low_level_keyboard_proc(
+    n_code: i32,
+    w_param: WPARAM,
+    l_param: LPARAM,
+) -> LRESULT {
+    unsafe {
+        if n_code >= 0 {
+            let kb_struct = *(l_param as *const KBDLLHOOKSTRUCT);
+            let vk_code = kb_struct.vkCode;
+
+            if kb_struct.flags & LLKHF_INJECTED != 0 {
+                // Allow only CCaps's own injected CapsLock events to pass through.
+                // External injected CapsLock events (from other programs or OS during startup)
+                // are blocked to prevent spontaneous CapsLock LED activation.
+                if vk_code == VK_CAPITAL as u32
+                    && !keyboard_hook::should_pass_through_capslock(kb_struct.flags, kb_struct.dwExtraInfo)
+                {
+                    return 1;
+                }
+
+                // Injected input never triggers bindings
+                return CallNextHookEx(HOOK, n_code, w_param, l_param);
+            }
+
+            // WM_SYSKEYDOWN occurs when Alt is held or when system thinks Alt is held
+            // (which can happen during Windows startup due to key state desynchronization)
+            let pressed = w_param == WM_KEYDOWN as usize || w_param == WM_SYSKEYDOWN as usize;
+
+            // Check real-time modifier state using GetAsyncKeyState
+            // This prevents desynchronization issues when modifiers change during window switching
+            let key = key_code(vk_code, kb_struct.scanCode, kb_struct.flags);
+            let time_ms = kb_struct.time as u64;
+            if keyboard_hook::handle_key_event(backend::current(), key, held_modifiers(), pressed, time_ms) {
+                // Block default processing of the bound key
+                return 1;
+            }
+        }
+
+        CallNextHookEx(HOOK, n_code, w_param, l_param)
+    }
+}
+
+// Named key and scancode of a hook event; extended keys get 0xE000 added
+fn key_code(vk_code: DWORD, scan_code: DWORD, flags: DWORD) -> KeyCode {
+    let extended = if flags & LLKHF_EXTENDED != 0 { 0xE000 } else { 0 };
+    KeyCode {
+        key: key_from_vk(vk_code),
+        scancode: (scan_code as u16 & 0xFF) | extended,
+    }
+}
+
+fn key_from_vk(vk_code: DWORD) -> Key {
+    let vk = vk_code as i32;
+    match vk {
+        VK_CAPITAL => Key::CapsLock,
+        VK_LSHIFT => Key::LeftShift,
+        VK_RSHIFT => Key::RightShift,
+        VK_LCONTROL => Key::LeftCtrl,
+        VK_RCONTROL => Key::RightCtrl,
+        VK_LMENU => Key::LeftAlt,
+        VK_RMENU => Key::RightAlt,
+        VK_LWIN => Key::LeftWin,
+        VK_RWIN => Key::RightWin,
+        VK_APPS => Key::Menu,
+        VK_SCROLL => Key::ScrollLock,
+        VK_PAUSE => Key::Pause,
+        VK_SPACE => Key::Space,
+        VK_TAB => Key::Tab,
+        VK_ESCAPE => Key::Escape,
+        VK_RETURN => Key::Enter,
+        VK_LEFT => Key::Left,
+        VK_RIGHT => Key::Right,
+        VK_UP => Key::Up,
+        VK_DOWN => Key::Down,
+        VK_F1..=VK_F24 => Key::F((vk - VK_F1 + 1) as u8),
+        // Letter and digit keys use their ASCII code
+        0x30..=0x39 | 0x41..=0x5A => Key::Char(vk as u8 as char),
+        _ => Key::Other,
+    }
+}
+
+// Virtual key of a named key, for keys injected without a scancode
+fn vk_for_key(key: Key) -> Option<DWORD> {
+    if key == Key::Other {
+        return None;
+    }
+    (1..0xFF).find(|vk| key_from_vk(*vk) == key)
+}
+
+// Modifier keys held down right now
+unsafe fn held_modifiers() -> Modifiers {
+    const MODIFIER_KEYS: [(i32, Key); 8] = [
+        (VK_LSHIFT, Key::LeftShift),
+        (VK_RSHIFT, Key::RightShift),
+        (VK_LCONTROL, Key::LeftCtrl),
+        (VK_RCONTROL, Key::RightCtrl),
+        (VK_LMENU, Key::LeftAlt),
+        (VK_RMENU, Key::RightAlt),
+        (VK_LWIN, Key::LeftWin),
+        (VK_RWIN, Key::RightWin),
+    ];
+
+    MODIFIER_KEYS.iter()
+        .filter(|(vk, _)| GetAsyncKeyState(*vk) as u16 & 0x8000 != 0)
+        .fold(Modifiers::NONE, |held, (_, key)| held.with(*key))
+}
+
+// Window with keyboard focus in the thread that owns `foreground`,
+// or `foreground` itself if it cannot be determined
+unsafe fn focus_window(foreground: HWND) -> HWND {
+    let thread_id = GetWindowThreadProcessId(foreground, ptr::null_mut());
+    let mut info: GUITHREADINFO = mem::zeroed();
+    info.cbSize = mem::size_of::<GUITHREADINFO>() as DWORD;
+    if GetGUIThreadInfo(thread_id, &mut info) != 0 && !info.hwndFocus.is_null() {
+        info.hwndFocus
+    } else {
+        foreground
+    }
+}
+
+// Post WM_CCAPS_REFRESH to the message loop thread whenever the user's
+// installed layout list changes
+fn watch_layout_list(thread_id: DWORD) {
+    thread::spawn(move || unsafe {
+        let key_name_wide = to_wide(PRELOAD_KEY);
+        let mut key: HKEY = ptr::null_mut();
+        if RegOpenKeyExW(HKEY_CURRENT_USER, key_name_wide.as_ptr(), 0, KEY_NOTIFY, &mut key) != ERROR_SUCCESS as i32 {
+            eprintln!("Warning: Cannot watch keyboard layout changes");
+            return;
+        }
+
+        // Blocks until the key changes; the watch has to be renewed after each change
+        while RegNotifyChangeKeyValue(key, TRUE, REG_NOTIFY_CHANGE_NAME | REG_NOTIFY_CHANGE_LAST_SET, ptr::null_mut(), FALSE) == ERROR_SUCCESS as i32 {
+            if PostThreadMessageW(thread_id, WM_CCAPS_REFRESH, 0, 0) == 0 {
+                break;
+            }
+        }
+        RegCloseKey(key);
+    });
+}
+
+// Re-sync the cycle index and indicator if the foreground layout changed
+// since the last check, or unconditionally when `force` is set (the system
+// may have reset the LEDs)
+fn sync_foreground_layout(force: bool) {
+    let backend = backend::current();
+    let layout = backend.foreground_layout().unwrap_or(0);
+    let previous = LAST_FOREGROUND_LAYOUT.swap(layout, Ordering::SeqCst);
+    if force || layout != previous {
+        keyboard_hook::sync_foreground_layout(backend);
+    }
+}
+
+// Called for foreground window changes and desktop switches (lock/unlock)
+unsafe extern "system" fn on_win_event(
+    _hook: HWINEVENTHOOK,
+    event: DWORD,
+    _hwnd: HWND,
+    _id_object: LONG,
+    _id_child: LONG,
+    _event_thread: DWORD,
+    _event_time: DWORD,
+) {
+    if event == EVENT_SYSTEM_DESKTOPSWITCH {
+        update_layout_poll();
+    }
+    sync_foreground_layout(event == EVENT_SYSTEM_DESKTOPSWITCH);
+}
+
+// Start polling the foreground layout, or stop while the lock screen or
+// another secure desktop has the input and the user cannot change layouts
+unsafe fn update_layout_poll() {
+    let timer = LAYOUT_POLL_TIMER.load(Ordering::SeqCst);
+    let active = input_desktop_is_default();
+    if active && timer == 0 {
+        LAYOUT_POLL_TIMER.store(SetTimer(ptr::null_mut(), 0, LAYOUT_POLL_INTERVAL_MS, None), Ordering::SeqCst);
+    } else if !active && timer != 0 {
+        KillTimer(ptr::null_mut(), timer);
+        LAYOUT_POLL_TIMER.store(0, Ordering::SeqCst);
+    }
+}
+
+// Whether the user's desktop receives input. The Winlogon desktop of the
+// lock screen cannot be opened by user processes.
+unsafe fn input_desktop_is_default() -> bool {
+    let desktop = OpenInputDesktop(0, FALSE, DESKTOP_READOBJECTS);
+    if desktop.is_null() {
+        return false;
+    }
+
+    let mut name = [0u16; 64];
+    let mut needed: DWORD = 0;
+    let found = GetUserObjectInformationW(desktop as HANDLE, UOI_NAME as i32, name.as_mut_ptr() as *mut _,
+                                          (name.len() * 2) as DWORD, &mut needed) != 0;
+    CloseDesktop(desktop);
+
+    let length = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    found && String::from_utf16_lossy(&name[..length]).eq_ignore_ascii_case("Default")
+}
+
+// Observe changes of the foreground layout: switching windows (each thread
+// has its own layout), input-language changes, session unlock and resume
+// from sleep. Events are delivered to the message loop thread.
+unsafe fn watch_foreground_layout(window: HWND) {
+    LAST_FOREGROUND_LAYOUT.store(backend::current().foreground_layout().unwrap_or(0), Ordering::SeqCst);
+
+    SetWinEventHook(EVENT_SYSTEM_FOREGROUND, EVENT_SYSTEM_FOREGROUND, ptr::null_mut(),
+                    Some(on_win_event), 0, 0, WINEVENT_OUTOFCONTEXT);
+    SetWinEventHook(EVENT_SYSTEM_DESKTOPSWITCH, EVENT_SYSTEM_DESKTOPSWITCH, ptr::null_mut(),
+                    Some(on_win_event), 0, 0, WINEVENT_OUTOFCONTEXT);
+    update_layout_poll();
+
+    // Message-only windows get no power broadcasts unless they ask for them
+    if !window.is_null() {
+        RegisterSuspendResumeNotification(window as HANDLE, DEVICE_NOTIFY_WINDOW_HANDLE);
+    }
+}
+
+// Topmost popup for the layout picker. It never takes the focus, so the
+// foreground application keeps receiving the layout switch.
+unsafe fn create_picker_window() -> HWND {
+    unsafe extern "system" fn picker_window_proc(
+        hwnd: HWND,
+        msg: UINT,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        match msg {
+            WM_PAINT => {
+                paint_picker(hwnd);
+                0
+            }
+            WM_MOUSEACTIVATE => MA_NOACTIVATE as LRESULT,
+            _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }
+
+    let class_name_wide = to_wide(PICKER_CLASS_NAME);
+    let window_name_wide = to_wide(PICKER_WINDOW_NAME);
+    let wc = WNDCLASSW {
+        style: 0,
+        lpfnWndProc: Some(picker_window_proc),
+        cbClsExtra: 0,
+        cbWndExtra: 0,
+        hInstance: GetModuleHandleW(ptr::null()),
+        hIcon: ptr::null_mut(),
+        hCursor: ptr::null_mut(),
+        hbrBackground: ptr::null_mut(),
+        lpszMenuName: ptr::null(),
+        lpszClassName: class_name_wide.as_ptr(),
+    };
+    RegisterClassW(&wc);
+
+    CreateWindowExW(
+        WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE,
+        class_name_wide.as_ptr(),
+        window_name_wide.as_ptr(),
+        WS_POPUP | WS_BORDER,
+        0, 0, 0, 0,
+        ptr::null_mut(),
+        ptr::null_mut(),
+        GetModuleHandleW(ptr::null()),
+        ptr::null_mut(),
+    )
+}
+
+// One row per layout, the highlighted one in the system selection colors
+unsafe fn paint_picker(hwnd: HWND) {
+    let mut paint: PAINTSTRUCT = mem::zeroed();
+    let dc = BeginPaint(hwnd, &mut paint);
+
+    let mut client: RECT = mem::zeroed();
+    GetClientRect(hwnd, &mut client);
+    FillRect(dc, &client, GetSysColorBrush(COLOR_WINDOW));
+    SetBkMode(dc, TRANSPARENT as i32);
+    SelectObject(dc, GetStockObject(DEFAULT_GUI_FONT as i32));
+
+    let (items, highlight) = PICKER_ITEMS.lock().unwrap().clone();
+    for (i, item) in items.iter().enumerate() {
+        let top = PICKER_PADDING + i as i32 * PICKER_ROW_HEIGHT;
+        let mut row = RECT {
+            left: PICKER_PADDING,
+            top,
+            right: client.right - PICKER_PADDING,
+            bottom: top + PICKER_ROW_HEIGHT,
+        };
+        if i == highlight {
+            FillRect(dc, &row, GetSysColorBrush(COLOR_HIGHLIGHT));
+            SetTextColor(dc, GetSysColor(COLOR_HIGHLIGHTTEXT));
+        } else {
+            SetTextColor(dc, GetSysColor(COLOR_WINDOWTEXT));
+        }
+
+        row.left += PICKER_PADDING;
+        let text = to_wide(item);
+        DrawTextW(dc, text.as_ptr(), -1, &mut row, DT_SINGLELINE | DT_VCENTER | DT_LEFT | DT_END_ELLIPSIS);
+    }
+
+    EndPaint(hwnd, &paint);
+}
+
+// Message window of the running instance. FindWindowW skips message-only
+// windows, so those are looked up among the HWND_MESSAGE children first.
+unsafe fn find_message_window() -> HWND {
+    let class_name_wide = to_wide(MESSAGE_CLASS_NAME);
+    let window_name_wide = to_wide(WINDOW_NAME);
+    let window = FindWindowExW(HWND_MESSAGE, ptr::null_mut(), class_name_wide.as_ptr(), window_name_wide.as_ptr());
+    if !window.is_null() {
+        return window;
+    }
+    // Regular hidden window created as a fallback
+    FindWindowW(class_name_wide.as_ptr(), window_name_wide.as_ptr())
+}
+
+// Improved hidden window creation function
+unsafe fn create_message_window() -> HWND {
+    let class_name_wide = to_wide(MESSAGE_CLASS_NAME);
+    let window_name_wide = to_wide(WINDOW_NAME);
+
+    // Custom window procedure for handling system messages
+    unsafe extern "system" fn window_proc(
+        hwnd: HWND,
+        msg: UINT,
+        wparam: WPARAM,
+        lparam: LPARAM,
+    ) -> LRESULT {
+        match msg {
+            WM_QUERYENDSESSION | WM_ENDSESSION => {
+                // System shutdown - cleanup and exit gracefully
+                PostQuitMessage(0);
+                return 0;
+            }
+            WM_POWERBROADCAST => {
+                if wparam == PBT_APMRESUMEAUTOMATIC {
+                    // Resumed from sleep
+                    sync_foreground_layout(true);
+                }
+                return TRUE as LRESULT;
+            }
+            WM_DESTROY => {
+                PostQuitMessage(0);
+                return 0;
+            }
+            _ => return DefWindowProcW(hwnd, msg, wparam, lparam),
+        }
+    }
+
+    // Register window class
+    let wc = WNDCLASSW {
+        style: 0,
+        lpfnWndProc: Some(window_proc),
+        cbClsExtra: 0,
+        cbWndExtra: 0,
+        hInstance: GetModuleHandleW(ptr::null()),
+        hIcon: ptr::null_mut(),
+        hCursor: ptr::null_mut(),
+        hbrBackground: ptr::null_mut(),
+        lpszMenuName: ptr::null(),
+        lpszClassName: class_name_wide.as_ptr(),
+    };
+
+    RegisterClassW(&wc);
+
+    // Create hidden window
+    let hwnd = CreateWindowExW(
+        0,
+        class_name_wide.as_ptr(),
+        window_name_wide.as_ptr(),
+        0, // No window style (completely hidden)
+        0, 0, 0, 0, // Position and size (irrelevant for hidden window)
+        HWND_MESSAGE, // Message-only window (not displayed in UI)
+        ptr::null_mut(),
+        GetModuleHandleW(ptr::null()),
+        ptr::null_mut(),
+    );
+
+    if hwnd.is_null() {
+        // Fallback: try to create a regular hidden window
+        return CreateWindowExW(
+            0,
+            class_name_wide.as_ptr(),
+            window_name_wide.as_ptr(),
+            WS_OVERLAPPEDWINDOW,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            GetModuleHandleW(ptr::null()),
+            ptr::null_mut(),
+        );
+    }
+
+    hwnd
+}
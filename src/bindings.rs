@@ -0,0 +1,1013 @@
+use std::collections::BTreeMap;
+use std::mem;
+use crate::config::DualRoleConfig;
+
+// Keys that can be named in bindings. Platform hooks translate their key
+// codes to these; keys without a name are `Other` and can still be bound
+// by scancode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    CapsLock,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    LeftWin,
+    RightWin,
+    Menu,
+    ScrollLock,
+    Pause,
+    Space,
+    Tab,
+    Escape,
+    Enter,
+    Left,
+    Right,
+    Up,
+    Down,
+    // Letter (upper case) or digit key
+    Char(char),
+    // Function key F1-F24
+    F(u8),
+    // Virtual modifier held by a dual-role key with the "Layer" role
+    Layer,
+    Other,
+}
+
+// A key event's key: its name and the hardware scancode (extended keys
+// have 0xE000 added on Windows; evdev key code on Linux)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyCode {
+    pub key: Key,
+    pub scancode: u16,
+}
+
+// Modifier keys held down, one bit per side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers(u16);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+
+    // Bit of a modifier key, 0 for other keys
+    fn bit(key: Key) -> u16 {
+        match key {
+            Key::LeftShift => 0x01,
+            Key::RightShift => 0x02,
+            Key::LeftCtrl => 0x04,
+            Key::RightCtrl => 0x08,
+            Key::LeftAlt => 0x10,
+            Key::RightAlt => 0x20,
+            Key::LeftWin => 0x40,
+            Key::RightWin => 0x80,
+            Key::Layer => 0x100,
+            _ => 0,
+        }
+    }
+
+    pub fn is_modifier(key: Key) -> bool {
+        Self::bit(key) != 0
+    }
+
+    // Add a held modifier key; other keys are ignored
+    pub fn with(self, key: Key) -> Modifiers {
+        Modifiers(self.0 | Self::bit(key))
+    }
+
+    fn without(self, key: Key) -> Modifiers {
+        Modifiers(self.0 & !Self::bit(key))
+    }
+}
+
+// One key of a binding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySpec {
+    Key(Key),
+    // Raw scancode, "sc:0x3A"
+    Scancode(u16),
+    // Modifier on either side: "Shift", "Ctrl", "Alt", "Win"
+    AnySide(Key, Key),
+}
+
+impl KeySpec {
+    fn parse(name: &str) -> Result<KeySpec, String> {
+        let lower = name.to_ascii_lowercase();
+        if let Some(code) = lower.strip_prefix("sc:") {
+            let value = match code.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => code.parse::<u16>(),
+            };
+            return value.map(KeySpec::Scancode).map_err(|_| format!("Invalid scancode '{}'", name));
+        }
+
+        let key = match lower.as_str() {
+            "shift" => return Ok(KeySpec::AnySide(Key::LeftShift, Key::RightShift)),
+            "ctrl" | "control" => return Ok(KeySpec::AnySide(Key::LeftCtrl, Key::RightCtrl)),
+            "alt" => return Ok(KeySpec::AnySide(Key::LeftAlt, Key::RightAlt)),
+            "win" | "super" | "meta" => return Ok(KeySpec::AnySide(Key::LeftWin, Key::RightWin)),
+            "caps" | "capslock" => Key::CapsLock,
+            "lshift" => Key::LeftShift,
+            "rshift" => Key::RightShift,
+            "lctrl" => Key::LeftCtrl,
+            "rctrl" => Key::RightCtrl,
+            "lalt" => Key::LeftAlt,
+            "ralt" | "altgr" => Key::RightAlt,
+            "lwin" => Key::LeftWin,
+            "rwin" => Key::RightWin,
+            "menu" | "apps" => Key::Menu,
+            "scrolllock" => Key::ScrollLock,
+            "pause" => Key::Pause,
+            "space" => Key::Space,
+            "tab" => Key::Tab,
+            "esc" | "escape" => Key::Escape,
+            "enter" | "return" => Key::Enter,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "layer" => Key::Layer,
+            _ => match function_key(&lower).or_else(|| char_key(&lower)) {
+                Some(key) => key,
+                None => return Err(format!("Unknown key '{}'", name)),
+            },
+        };
+        Ok(KeySpec::Key(key))
+    }
+
+    fn matches(&self, key: &KeyCode) -> bool {
+        match self {
+            KeySpec::Key(expected) => key.key == *expected,
+            KeySpec::Scancode(scancode) => key.scancode == *scancode,
+            KeySpec::AnySide(left, right) => key.key == *left || key.key == *right,
+        }
+    }
+
+    // Whether a held modifier satisfies this key. Only modifiers are
+    // tracked as held; another key is held only as the layer key of a
+    // dual-role key, which holds the Layer modifier.
+    fn is_held(&self, held: Modifiers) -> bool {
+        match self {
+            KeySpec::Key(key) if !Modifiers::is_modifier(*key) => held.0 & Modifiers::bit(Key::Layer) != 0,
+            KeySpec::Key(key) => held.0 & Modifiers::bit(*key) != 0,
+            KeySpec::Scancode(_) => held.0 & Modifiers::bit(Key::Layer) != 0,
+            KeySpec::AnySide(left, right) => held.0 & (Modifiers::bit(*left) | Modifiers::bit(*right)) != 0,
+        }
+    }
+
+    fn is_modifier(&self) -> bool {
+        match self {
+            KeySpec::Key(key) => Modifiers::is_modifier(*key),
+            KeySpec::Scancode(_) => false,
+            KeySpec::AnySide(..) => true,
+        }
+    }
+}
+
+// "f1".."f24"
+fn function_key(name: &str) -> Option<Key> {
+    let number: u8 = name.strip_prefix('f')?.parse().ok()?;
+    (1..=24).contains(&number).then_some(Key::F(number))
+}
+
+// "a".."z", "0".."9"
+fn char_key(name: &str) -> Option<Key> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_alphanumeric() => Some(Key::Char(c.to_ascii_uppercase())),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    // Switch to the next selected layout
+    Cycle,
+    // Switch to the previous selected layout
+    CycleBack,
+    // Toggle Caps Lock itself
+    ToggleCapsLock,
+    // Switch straight to the layout for a code
+    Layout(String),
+    // Switch to the Nth selected layout, counting from 1
+    Select(usize),
+}
+
+impl Action {
+    fn parse(name: &str) -> Result<Action, String> {
+        let name = name.trim();
+        if let Some(code) = name.strip_prefix("layout:") {
+            let code = code.trim().trim_start_matches('-');
+            if code.is_empty() {
+                return Err("Missing layout code in 'layout:'".to_string());
+            }
+            return Ok(Action::Layout(code.to_lowercase()));
+        }
+        if let Some(position) = name.strip_prefix("select:") {
+            return match position.trim().parse::<usize>() {
+                Ok(position) if position > 0 => Ok(Action::Select(position)),
+                _ => Err(format!("Invalid position in '{}' (use select:1, select:2, ...)", name)),
+            };
+        }
+
+        match name.to_ascii_lowercase().as_str() {
+            "cycle" => Ok(Action::Cycle),
+            "cycle-back" => Ok(Action::CycleBack),
+            "toggle-caps" => Ok(Action::ToggleCapsLock),
+            _ => Err(format!("Unknown action '{}' (use cycle, cycle-back, toggle-caps, layout:<code> or select:<n>)", name)),
+        }
+    }
+}
+
+// Keys pressed together ("Shift+CapsLock") and what they do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub keys: Vec<KeySpec>,
+    pub action: Action,
+}
+
+impl Binding {
+    pub fn parse(chord: &str, action: &str) -> Result<Binding, String> {
+        let keys = chord.split('+')
+            .map(|name| KeySpec::parse(name.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid binding '{}': {}", chord, e))?;
+
+        // Held keys are only tracked for modifiers and the layer key
+        if keys.iter().filter(|key| !key.is_modifier()).count() > 2 {
+            return Err(format!(
+                "Invalid binding '{}': only a layer key and one more key besides modifiers are allowed",
+                chord
+            ));
+        }
+
+        let action = Action::parse(action).map_err(|e| format!("Invalid binding '{}': {}", chord, e))?;
+        Ok(Binding { keys, action })
+    }
+
+    // First of two keys besides modifiers, as in "CapsLock+1": it has to
+    // be held down like a modifier
+    pub fn layer_key(&self) -> Option<&KeySpec> {
+        let mut keys = self.keys.iter().filter(|key| !key.is_modifier());
+        let first = keys.next();
+        keys.next().and(first)
+    }
+}
+
+// Caps Lock switches layout, Shift + Caps Lock toggles Caps Lock
+pub fn default_bindings() -> Vec<Binding> {
+    vec![
+        Binding { keys: vec![KeySpec::Key(Key::CapsLock)], action: Action::Cycle },
+        Binding {
+            keys: vec![KeySpec::AnySide(Key::LeftShift, Key::RightShift), KeySpec::Key(Key::CapsLock)],
+            action: Action::ToggleCapsLock,
+        },
+    ]
+}
+
+// Codes of the `layout:<code>` bindings, each once
+pub fn layout_codes(bindings: &[Binding]) -> Vec<String> {
+    let mut codes: Vec<String> = Vec::new();
+    for binding in bindings {
+        if let Action::Layout(code) = &binding.action {
+            if !codes.contains(code) {
+                codes.push(code.clone());
+            }
+        }
+    }
+    codes
+}
+
+// Bindings from the config file ("Shift+CapsLock": "toggle-caps"), or the
+// default bindings if there are none
+pub fn parse_bindings(config: &BTreeMap<String, String>) -> Result<Vec<Binding>, String> {
+    if config.is_empty() {
+        return Ok(default_bindings());
+    }
+
+    let mut bindings = Vec::new();
+    let mut errors = Vec::new();
+    for (chord, action) in config {
+        match Binding::parse(chord, action) {
+            Ok(binding) => bindings.push(binding),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(bindings)
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+// Dual-role key from the config file, if any. Without one, a layer key
+// used in the bindings ("CapsLock+1") becomes a dual-role key that holds
+// the Layer modifier and runs its own bindings when tapped.
+pub fn parse_dual_role(config: Option<&DualRoleConfig>, bindings: &[Binding]) -> Result<Option<DualRole>, String> {
+    let dual_role = config
+        .map(|config| DualRole::parse(&config.key, &config.hold, config.timeout_ms, config.permissive_hold))
+        .transpose()?;
+
+    let mut layer_keys = bindings.iter().filter_map(|binding| binding.layer_key());
+    let layer_key = match layer_keys.next() {
+        Some(layer_key) => layer_key,
+        None => return Ok(dual_role),
+    };
+    if layer_keys.any(|other| other != layer_key) {
+        return Err("Bindings can use only one layer key".to_string());
+    }
+
+    match dual_role {
+        None => Ok(Some(DualRole {
+            key: layer_key.clone(),
+            hold: HoldRole::Layer,
+            // Only another key tapped meanwhile makes it a hold
+            timeout_ms: u64::MAX,
+            permissive_hold: true,
+        })),
+        Some(dual_role) if dual_role.key == *layer_key && dual_role.hold == HoldRole::Layer => Ok(Some(dual_role)),
+        Some(_) => Err("The layer key of the bindings must be the dual-role key, with \"hold\": \"Layer\"".to_string()),
+    }
+}
+
+// Binding for a key press with the given modifiers held. All other keys of
+// the binding must be held; extra modifiers are allowed, and the binding
+// with the most keys wins, so Shift + Caps Lock beats Caps Lock.
+pub fn match_binding<'a>(bindings: &'a [Binding], key: &KeyCode, held: Modifiers) -> Option<&'a Binding> {
+    let held = held.without(key.key);
+
+    bindings.iter()
+        .filter(|binding| {
+            binding.keys.iter().enumerate().any(|(trigger, spec)| {
+                spec.matches(key)
+                    && binding.keys.iter().enumerate().all(|(i, other)| i == trigger || other.is_held(held))
+            })
+        })
+        .max_by_key(|binding| binding.keys.len())
+}
+
+// What holding a dual-role key does
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HoldRole {
+    // Hold these modifier keys; "Hyper" is Ctrl + Shift + Alt + Win
+    Modifiers(Vec<Key>),
+    // Hold the virtual Layer key, for bindings such as "Layer+1"
+    Layer,
+}
+
+// A key that runs its bindings when tapped and acts as `hold` while held
+// together with other keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DualRole {
+    pub key: KeySpec,
+    pub hold: HoldRole,
+    // Held longer than this, the key is a hold
+    pub timeout_ms: u64,
+    // Another key pressed and released while the key is down makes it a
+    // hold even before the timeout
+    pub permissive_hold: bool,
+}
+
+impl DualRole {
+    pub fn parse(key: &str, hold: &str, timeout_ms: u64, permissive_hold: bool) -> Result<DualRole, String> {
+        let spec = KeySpec::parse(key.trim()).map_err(|e| format!("Invalid dual-role key: {}", e))?;
+        if spec.is_modifier() {
+            return Err(format!("Invalid dual-role key: '{}' is already a modifier", key));
+        }
+        if timeout_ms == 0 {
+            return Err("Invalid dual-role timeout: must be greater than 0 ms".to_string());
+        }
+
+        let hold = match hold.trim().to_ascii_lowercase().as_str() {
+            "hyper" => HoldRole::Modifiers(vec![Key::LeftCtrl, Key::LeftShift, Key::LeftAlt, Key::LeftWin]),
+            "layer" => HoldRole::Layer,
+            name => match KeySpec::parse(name) {
+                Ok(KeySpec::AnySide(left, _)) => HoldRole::Modifiers(vec![left]),
+                Ok(KeySpec::Key(key)) if Modifiers::is_modifier(key) => HoldRole::Modifiers(vec![key]),
+                _ => return Err(format!(
+                    "Invalid dual-role hold '{}' (use Ctrl, Shift, Alt, Win, Hyper or Layer)",
+                    hold
+                )),
+            },
+        };
+
+        Ok(DualRole { key: spec, hold, timeout_ms, permissive_hold })
+    }
+
+    // Modifiers seen by bindings while the key is held
+    fn held(&self, held: Modifiers) -> Modifiers {
+        match &self.hold {
+            HoldRole::Modifiers(keys) => keys.iter().fold(held, |held, key| held.with(*key)),
+            HoldRole::Layer => held.with(Key::Layer),
+        }
+    }
+
+    // Modifier key events to inject when the hold starts or ends
+    fn hold_events(&self, pressed: bool) -> Vec<(KeyCode, bool)> {
+        let keys = match &self.hold {
+            HoldRole::Modifiers(keys) => keys.clone(),
+            HoldRole::Layer => Vec::new(),
+        };
+        let events = keys.into_iter().map(|key| (KeyCode { key, scancode: 0 }, pressed));
+        if pressed {
+            events.collect()
+        } else {
+            events.rev().collect()
+        }
+    }
+}
+
+// Steps of the layout picker, shown while a cycle binding is held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PickerEvent {
+    // A cycle binding was pressed; `reverse` for cycle-back
+    Open { reverse: bool },
+    // Still held (autorepeat): show the picker
+    Show,
+    // Move the highlight: another tap of the trigger or an arrow key
+    Move(isize),
+    // Released: switch to the highlighted layout
+    Commit,
+    // Escape: close without switching
+    Cancel,
+}
+
+// What the hook does with a key event
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct KeyOutcome {
+    // Swallow the event
+    pub block: bool,
+    // Actions to run, then key events to inject, in this order
+    pub actions: Vec<Action>,
+    pub inject: Vec<(KeyCode, bool)>,
+    // Picker steps, run after the actions
+    pub picker: Vec<PickerEvent>,
+}
+
+// Tap or hold state of the dual-role key
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DualState {
+    Idle,
+    // Down, not decided yet. Other key events are held back until it is.
+    Pending {
+        key: KeyCode,
+        held: Modifiers,
+        since: u64,
+        buffered: Vec<(KeyCode, Modifiers, bool)>,
+    },
+    Holding,
+}
+
+// A cycle binding held down with the picker enabled
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PickerSession {
+    trigger: KeyCode,
+    keys: Vec<KeySpec>,
+    step: isize,
+}
+
+// Matches key events against the bindings. Keys that triggered a binding
+// are swallowed until released, so their autorepeat and release events
+// never reach applications.
+pub struct BindingEngine {
+    bindings: Vec<Binding>,
+    dual_role: Option<DualRole>,
+    state: DualState,
+    swallowed: Vec<KeyCode>,
+    // Whether cycle bindings open the picker
+    picker: bool,
+    session: Option<PickerSession>,
+}
+
+impl BindingEngine {
+    pub const fn new(bindings: Vec<Binding>) -> Self {
+        BindingEngine {
+            bindings,
+            dual_role: None,
+            state: DualState::Idle,
+            swallowed: Vec::new(),
+            picker: false,
+            session: None,
+        }
+    }
+
+    pub fn with_dual_role(mut self, dual_role: Option<DualRole>) -> Self {
+        self.dual_role = dual_role;
+        self
+    }
+
+    pub fn with_picker(mut self, picker: bool) -> Self {
+        self.picker = picker;
+        self
+    }
+
+    pub fn layout_codes(&self) -> Vec<String> {
+        layout_codes(&self.bindings)
+    }
+
+    // Handle a key event at `time_ms`, a millisecond clock of the event source
+    pub fn handle(&mut self, key: KeyCode, held: Modifiers, pressed: bool, time_ms: u64) -> KeyOutcome {
+        if let Some(outcome) = self.handle_picker(key, pressed) {
+            return outcome;
+        }
+
+        let mut outcome = self.handle_dual_role(key, held, pressed, time_ms);
+
+        // The picker closes once no key of its binding is held any more
+        if let Some(session) = &self.session {
+            let held = match (&self.state, &self.dual_role) {
+                (DualState::Holding, Some(dual_role)) => dual_role.held(held),
+                _ => held,
+            }.without(key.key);
+            let binding_held = session.keys.iter()
+                .any(|spec| !spec.matches(&session.trigger) && spec.is_held(held));
+            if !pressed && !self.swallowed.contains(&session.trigger) && !binding_held {
+                self.session = None;
+                outcome.picker.push(PickerEvent::Commit);
+            }
+        }
+
+        outcome
+    }
+
+    // Keys with a meaning while the picker is open: the trigger and the
+    // arrow, Tab, Enter and Escape keys. Other keys and all releases are
+    // handled as usual.
+    fn handle_picker(&mut self, key: KeyCode, pressed: bool) -> Option<KeyOutcome> {
+        let session = self.session.as_ref()?;
+        if !pressed {
+            return None;
+        }
+
+        let repeat = self.swallowed.contains(&key);
+        let event = if key == session.trigger {
+            if repeat { PickerEvent::Show } else { PickerEvent::Move(session.step) }
+        } else {
+            match key.key {
+                Key::Left | Key::Up => PickerEvent::Move(-1),
+                Key::Right | Key::Down | Key::Tab => PickerEvent::Move(1),
+                Key::Enter => PickerEvent::Commit,
+                Key::Escape => PickerEvent::Cancel,
+                _ => return None,
+            }
+        };
+
+        if !repeat {
+            self.swallowed.push(key);
+        }
+        if matches!(event, PickerEvent::Commit | PickerEvent::Cancel) {
+            self.session = None;
+        }
+        Some(KeyOutcome { block: true, picker: vec![event], ..KeyOutcome::default() })
+    }
+
+    fn handle_dual_role(&mut self, key: KeyCode, held: Modifiers, pressed: bool, time_ms: u64) -> KeyOutcome {
+        let dual_role = match &self.dual_role {
+            Some(dual_role) => dual_role.clone(),
+            None => return self.handle_bound(key, held, pressed),
+        };
+        let is_dual = dual_role.key.matches(&key);
+        let mut outcome = KeyOutcome::default();
+
+        match mem::replace(&mut self.state, DualState::Idle) {
+            DualState::Idle if is_dual => {
+                // A release without a press comes from a key held at startup
+                outcome.block = true;
+                if pressed {
+                    self.state = DualState::Pending { key, held, since: time_ms, buffered: Vec::new() };
+                }
+            },
+            DualState::Idle => outcome = self.handle_bound(key, held, pressed),
+            DualState::Pending { key: dual_key, held: dual_held, since, mut buffered } => {
+                let timed_out = time_ms.saturating_sub(since) >= dual_role.timeout_ms;
+                outcome.block = true;
+
+                if is_dual && !pressed {
+                    if timed_out {
+                        // Held alone: the modifiers are pressed and released
+                        self.start_hold(&dual_role, buffered, &mut outcome);
+                        outcome.inject.extend(dual_role.hold_events(false));
+                        self.state = DualState::Idle;
+                    } else {
+                        self.tap(dual_key, dual_held, buffered, &mut outcome);
+                    }
+                } else if timed_out {
+                    self.start_hold(&dual_role, buffered, &mut outcome);
+                    if !is_dual {
+                        self.replay(key, dual_role.held(held), pressed, &mut outcome);
+                    }
+                } else if is_dual {
+                    // Autorepeat
+                    self.state = DualState::Pending { key: dual_key, held: dual_held, since, buffered };
+                } else if !pressed && !buffered.iter().any(|(buffered_key, _, down)| *buffered_key == key && *down) {
+                    // Release of a key pressed before the dual-role key
+                    self.state = DualState::Pending { key: dual_key, held: dual_held, since, buffered };
+                    outcome = self.handle_bound(key, held, pressed);
+                } else {
+                    buffered.push((key, held, pressed));
+                    if !pressed && dual_role.permissive_hold {
+                        // A key tapped inside the dual-role key
+                        self.start_hold(&dual_role, buffered, &mut outcome);
+                    } else {
+                        self.state = DualState::Pending { key: dual_key, held: dual_held, since, buffered };
+                    }
+                }
+            },
+            DualState::Holding if is_dual => {
+                outcome.block = true;
+                if pressed {
+                    self.state = DualState::Holding;
+                } else {
+                    outcome.inject.extend(dual_role.hold_events(false));
+                }
+            },
+            DualState::Holding => {
+                self.state = DualState::Holding;
+                outcome = self.handle_bound(key, dual_role.held(held), pressed);
+            },
+        }
+
+        outcome
+    }
+
+    // Decided as a tap: run the dual-role key's own binding, then let the
+    // held back keys through
+    fn tap(&mut self, key: KeyCode, held: Modifiers, buffered: Vec<(KeyCode, Modifiers, bool)>, outcome: &mut KeyOutcome) {
+        match match_binding(&self.bindings, &key, held) {
+            Some(binding) => outcome.actions.push(binding.action.clone()),
+            None => outcome.inject.extend([(key, true), (key, false)]),
+        }
+
+        for (key, held, pressed) in buffered {
+            self.replay(key, held, pressed, outcome);
+        }
+    }
+
+    // Decided as a hold: press the modifiers, then replay the held back keys
+    // with them
+    fn start_hold(&mut self, dual_role: &DualRole, buffered: Vec<(KeyCode, Modifiers, bool)>, outcome: &mut KeyOutcome) {
+        outcome.inject.extend(dual_role.hold_events(true));
+        for (key, held, pressed) in buffered {
+            self.replay(key, dual_role.held(held), pressed, outcome);
+        }
+        self.state = DualState::Holding;
+    }
+
+    // A held back key event: bindings still apply, anything else is injected
+    fn replay(&mut self, key: KeyCode, held: Modifiers, pressed: bool, outcome: &mut KeyOutcome) {
+        let event = self.handle_bound(key, held, pressed);
+        outcome.actions.extend(event.actions);
+        outcome.picker.extend(event.picker);
+        if !event.block {
+            outcome.inject.push((key, pressed));
+        }
+    }
+
+    fn handle_bound(&mut self, key: KeyCode, held: Modifiers, pressed: bool) -> KeyOutcome {
+        let position = self.swallowed.iter().position(|swallowed| *swallowed == key);
+
+        if !pressed {
+            return match position {
+                Some(index) => {
+                    self.swallowed.remove(index);
+                    KeyOutcome { block: true, ..KeyOutcome::default() }
+                },
+                None => KeyOutcome::default(),
+            };
+        }
+
+        if position.is_some() {
+            // Autorepeat of a key that triggered a binding
+            return KeyOutcome { block: true, ..KeyOutcome::default() };
+        }
+
+        let binding = match match_binding(&self.bindings, &key, held) {
+            Some(binding) => binding,
+            None => return KeyOutcome::default(),
+        };
+        self.swallowed.push(key);
+
+        let reverse = match binding.action {
+            Action::Cycle if self.picker => false,
+            Action::CycleBack if self.picker => true,
+            _ => return KeyOutcome { block: true, actions: vec![binding.action.clone()], ..KeyOutcome::default() },
+        };
+        self.session = Some(PickerSession {
+            trigger: key,
+            keys: binding.keys.clone(),
+            step: if reverse { -1 } else { 1 },
+        });
+        KeyOutcome { block: true, picker: vec![PickerEvent::Open { reverse }], ..KeyOutcome::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPS: KeyCode = KeyCode { key: Key::CapsLock, scancode: 0x3A };
+    const LSHIFT: KeyCode = KeyCode { key: Key::LeftShift, scancode: 0x2A };
+    const LCTRL: KeyCode = KeyCode { key: Key::LeftCtrl, scancode: 0x1D };
+    const RALT: KeyCode = KeyCode { key: Key::RightAlt, scancode: 0xE038 };
+
+    fn bindings(entries: &[(&str, &str)]) -> Vec<Binding> {
+        let config: BTreeMap<String, String> = entries.iter()
+            .map(|(chord, action)| (chord.to_string(), action.to_string()))
+            .collect();
+        parse_bindings(&config).unwrap()
+    }
+
+    fn action(bindings: &[Binding], key: KeyCode, held: Modifiers) -> Option<Action> {
+        match_binding(bindings, &key, held).map(|binding| binding.action.clone())
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(KeySpec::parse("CapsLock"), Ok(KeySpec::Key(Key::CapsLock)));
+        assert_eq!(KeySpec::parse("altgr"), Ok(KeySpec::Key(Key::RightAlt)));
+        assert_eq!(KeySpec::parse("F13"), Ok(KeySpec::Key(Key::F(13))));
+        assert_eq!(KeySpec::parse("q"), Ok(KeySpec::Key(Key::Char('Q'))));
+        assert_eq!(KeySpec::parse("sc:0x3a"), Ok(KeySpec::Scancode(0x3A)));
+        assert_eq!(KeySpec::parse("sc:58"), Ok(KeySpec::Scancode(58)));
+        assert!(KeySpec::parse("F25").is_err());
+        assert!(KeySpec::parse("Hyper").is_err());
+    }
+
+    #[test]
+    fn test_parse_binding_errors() {
+        assert!(Binding::parse("Ctrl+Alt+1", "layout:-de").is_ok());
+        assert_eq!(Binding::parse("Ctrl+Alt+1", "layout:-de").unwrap().action, Action::Layout("de".to_string()));
+
+        let error = Binding::parse("Hyper+Caps", "cycle").unwrap_err();
+        assert_eq!(error, "Invalid binding 'Hyper+Caps': Unknown key 'Hyper'");
+        assert!(Binding::parse("A+B+C", "cycle").unwrap_err().contains("only a layer key and one more key"));
+        assert!(Binding::parse("Caps", "jump").unwrap_err().contains("Unknown action 'jump'"));
+        assert!(Binding::parse("Caps", "layout:").is_err());
+        assert_eq!(Binding::parse("Caps+3", "select:3").unwrap().action, Action::Select(3));
+        assert!(Binding::parse("Caps+3", "select:0").is_err());
+        assert!(Binding::parse("Caps+3", "select:x").is_err());
+    }
+
+    #[test]
+    fn test_layer_key_bindings() {
+        let bindings = bindings(&[("Caps", "cycle"), ("Shift+Caps", "toggle-caps"), ("Caps+1", "select:1"), ("Caps+Q", "layout:de")]);
+        assert_eq!(bindings.iter().filter_map(Binding::layer_key).count(), 2);
+
+        let dual_role = parse_dual_role(None, &bindings).unwrap().unwrap();
+        assert_eq!((dual_role.key.clone(), dual_role.hold.clone()), (KeySpec::Key(Key::CapsLock), HoldRole::Layer));
+        let mut engine = BindingEngine::new(bindings.clone()).with_dual_role(Some(dual_role));
+        let one = KeyCode { key: Key::Char('1'), scancode: 0x02 };
+
+        // Caps Lock + 1 selects, Caps Lock alone still cycles on release
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), blocked());
+        assert_eq!(engine.handle(one, Modifiers::NONE, true, 50), blocked());
+        assert_eq!(engine.handle(one, Modifiers::NONE, false, 80), run(Action::Select(1)));
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 100), blocked());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 1000), blocked());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 5000), run(Action::Cycle), "A long press alone is a tap");
+
+        // A configured dual-role key must provide the layer
+        let config = DualRoleConfig { key: "CapsLock".to_string(), hold: "Ctrl".to_string(), timeout_ms: 200, permissive_hold: false };
+        assert!(parse_dual_role(Some(&config), &bindings).is_err());
+        let config = DualRoleConfig { hold: "Layer".to_string(), ..config };
+        assert_eq!(parse_dual_role(Some(&config), &bindings).unwrap().unwrap().timeout_ms, 200);
+
+        let mixed = self::bindings(&[("Caps+1", "select:1"), ("F13+2", "select:2")]);
+        assert_eq!(parse_dual_role(None, &mixed), Err("Bindings can use only one layer key".to_string()));
+        assert_eq!(parse_dual_role(None, &default_bindings()), Ok(None));
+    }
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        assert_eq!(parse_bindings(&BTreeMap::new()), Ok(default_bindings()));
+    }
+
+    #[test]
+    fn test_layout_codes() {
+        let config = BTreeMap::from([
+            ("Ctrl+1".to_string(), "layout:de".to_string()),
+            ("Ctrl+2".to_string(), "layout:-DE".to_string()),
+            ("Ctrl+3".to_string(), "layout:ru".to_string()),
+        ]);
+        assert_eq!(layout_codes(&parse_bindings(&config).unwrap()), vec!["de".to_string(), "ru".to_string()]);
+        assert!(layout_codes(&default_bindings()).is_empty());
+    }
+
+    #[test]
+    fn test_default_bindings() {
+        let bindings = default_bindings();
+        assert_eq!(action(&bindings, CAPS, Modifiers::NONE), Some(Action::Cycle));
+        assert_eq!(action(&bindings, CAPS, Modifiers::NONE.with(Key::RightShift)), Some(Action::ToggleCapsLock));
+        assert_eq!(action(&bindings, CAPS, Modifiers::NONE.with(Key::LeftCtrl)), Some(Action::Cycle),
+                   "Extra modifiers should not stop Caps Lock from switching");
+        assert_eq!(action(&bindings, LSHIFT, Modifiers::NONE), None);
+    }
+
+    #[test]
+    fn test_modifier_sides() {
+        let bindings = bindings(&[("RAlt", "cycle"), ("LShift+Caps", "toggle-caps")]);
+        assert_eq!(action(&bindings, RALT, Modifiers::NONE), Some(Action::Cycle));
+        assert_eq!(action(&bindings, CAPS, Modifiers::NONE.with(Key::LeftShift)), Some(Action::ToggleCapsLock));
+        assert_eq!(action(&bindings, CAPS, Modifiers::NONE.with(Key::RightShift)), None,
+                   "Right Shift does not satisfy LShift");
+    }
+
+    #[test]
+    fn test_modifier_chord_in_any_order() {
+        let bindings = bindings(&[("LCtrl+LShift", "cycle")]);
+        assert_eq!(action(&bindings, LSHIFT, Modifiers::NONE.with(Key::LeftCtrl)), Some(Action::Cycle));
+        assert_eq!(action(&bindings, LCTRL, Modifiers::NONE.with(Key::LeftShift)), Some(Action::Cycle));
+        assert_eq!(action(&bindings, LCTRL, Modifiers::NONE), None);
+
+        // The pressed key itself may already show up as held
+        assert_eq!(action(&bindings, LCTRL, Modifiers::NONE.with(Key::LeftCtrl)), None);
+    }
+
+    #[test]
+    fn test_scancode_binding() {
+        // Caps Lock remapped to F13 in the registry still has its own scancode
+        let bindings = bindings(&[("sc:0x64", "cycle")]);
+        assert_eq!(action(&bindings, KeyCode { key: Key::F(13), scancode: 0x64 }, Modifiers::NONE), Some(Action::Cycle));
+        assert_eq!(action(&bindings, CAPS, Modifiers::NONE), None);
+    }
+
+    fn blocked() -> KeyOutcome {
+        KeyOutcome { block: true, ..KeyOutcome::default() }
+    }
+
+    fn run(action: Action) -> KeyOutcome {
+        KeyOutcome { block: true, actions: vec![action], ..KeyOutcome::default() }
+    }
+
+    fn injected(events: &[(KeyCode, bool)]) -> KeyOutcome {
+        KeyOutcome { block: true, inject: events.to_vec(), ..KeyOutcome::default() }
+    }
+
+    const A: KeyCode = KeyCode { key: Key::Char('A'), scancode: 0x1E };
+    const CTRL: KeyCode = KeyCode { key: Key::LeftCtrl, scancode: 0 };
+
+    fn dual_role_engine(hold: &str, permissive_hold: bool) -> BindingEngine {
+        let dual_role = DualRole::parse("CapsLock", hold, 200, permissive_hold).unwrap();
+        BindingEngine::new(default_bindings()).with_dual_role(Some(dual_role))
+    }
+
+    #[test]
+    fn test_engine_swallows_until_release() {
+        let mut engine = BindingEngine::new(default_bindings());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), run(Action::Cycle));
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 30), blocked(), "Autorepeat is swallowed");
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 60), blocked());
+
+        assert_eq!(engine.handle(LSHIFT, Modifiers::NONE, true, 90), KeyOutcome::default());
+        assert_eq!(engine.handle(LSHIFT, Modifiers::NONE, false, 120), KeyOutcome::default());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 150), KeyOutcome::default(),
+                   "A release without a swallowed press passes");
+    }
+
+    #[test]
+    fn test_parse_dual_role() {
+        let dual_role = DualRole::parse("Caps", "ctrl", 150, true).unwrap();
+        assert_eq!(dual_role.key, KeySpec::Key(Key::CapsLock));
+        assert_eq!(dual_role.hold, HoldRole::Modifiers(vec![Key::LeftCtrl]));
+        assert_eq!(DualRole::parse("Caps", "RAlt", 150, true).unwrap().hold, HoldRole::Modifiers(vec![Key::RightAlt]));
+        assert_eq!(DualRole::parse("Caps", "Hyper", 150, true).unwrap().hold,
+                   HoldRole::Modifiers(vec![Key::LeftCtrl, Key::LeftShift, Key::LeftAlt, Key::LeftWin]));
+        assert_eq!(DualRole::parse("Caps", "Layer", 150, true).unwrap().hold, HoldRole::Layer);
+
+        assert!(DualRole::parse("Caps", "A", 150, true).unwrap_err().contains("Invalid dual-role hold 'A'"));
+        assert!(DualRole::parse("Shift", "Ctrl", 150, true).unwrap_err().contains("already a modifier"));
+        assert!(DualRole::parse("Caps", "Ctrl", 0, true).is_err());
+    }
+
+    #[test]
+    fn test_dual_role_tap() {
+        let mut engine = dual_role_engine("Ctrl", false);
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), blocked());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 50), blocked(), "Autorepeat is swallowed");
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 100), run(Action::Cycle));
+
+        // Shift held at the press still toggles Caps Lock
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE.with(Key::LeftShift), true, 1000), blocked());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 1100), run(Action::ToggleCapsLock));
+    }
+
+    #[test]
+    fn test_dual_role_hold_with_other_key() {
+        let mut engine = dual_role_engine("Ctrl", false);
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 250), injected(&[(CTRL, true), (A, true)]));
+        assert_eq!(engine.handle(A, Modifiers::NONE, false, 300), KeyOutcome::default(),
+                   "Once held, other keys pass with the modifier down");
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 400), injected(&[(CTRL, false)]));
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 500), KeyOutcome::default());
+    }
+
+    #[test]
+    fn test_dual_role_roll_over_is_a_tap() {
+        // Caps Lock released before the timeout while typing on
+        let mut engine = dual_role_engine("Ctrl", false);
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 50), blocked());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 100),
+                   KeyOutcome { inject: vec![(A, true)], ..run(Action::Cycle) });
+        assert_eq!(engine.handle(A, Modifiers::NONE, false, 150), KeyOutcome::default());
+
+        // Without permissive hold a nested tap is still a tap
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 1000), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 1050), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, false, 1080), blocked());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 1100),
+                   KeyOutcome { inject: vec![(A, true), (A, false)], ..run(Action::Cycle) });
+    }
+
+    #[test]
+    fn test_dual_role_permissive_hold() {
+        let mut engine = dual_role_engine("Ctrl", true);
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 50), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, false, 80), injected(&[(CTRL, true), (A, true), (A, false)]));
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 100), injected(&[(CTRL, false)]));
+
+        // A key pressed before Caps Lock is released normally
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 1000), KeyOutcome::default());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 1050), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, false, 1080), KeyOutcome::default());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 1100), run(Action::Cycle));
+    }
+
+    #[test]
+    fn test_dual_role_held_alone() {
+        let mut engine = dual_role_engine("Ctrl", true);
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), blocked());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 500), injected(&[(CTRL, true)]));
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 600), injected(&[(CTRL, false)]));
+    }
+
+    #[test]
+    fn test_dual_role_layer() {
+        let bindings = bindings(&[("Caps", "cycle"), ("Layer+1", "layout:de")]);
+        let dual_role = DualRole::parse("Caps", "Layer", 200, true).unwrap();
+        let mut engine = BindingEngine::new(bindings).with_dual_role(Some(dual_role));
+        let one = KeyCode { key: Key::Char('1'), scancode: 0x02 };
+
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), blocked());
+        assert_eq!(engine.handle(one, Modifiers::NONE, true, 50), blocked());
+        assert_eq!(engine.handle(one, Modifiers::NONE, false, 80), run(Action::Layout("de".to_string())));
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 100), KeyOutcome::default(),
+                   "Unbound keys pass on the layer");
+        assert_eq!(engine.handle(A, Modifiers::NONE, false, 120), KeyOutcome::default());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 150), blocked());
+
+        assert_eq!(engine.handle(one, Modifiers::NONE, true, 1000), KeyOutcome::default(),
+                   "Layer bindings need the layer key");
+    }
+
+    fn picker(events: &[PickerEvent]) -> KeyOutcome {
+        KeyOutcome { block: true, picker: events.to_vec(), ..KeyOutcome::default() }
+    }
+
+    #[test]
+    fn test_picker_held_trigger() {
+        let mut engine = BindingEngine::new(default_bindings()).with_picker(true);
+        let right = KeyCode { key: Key::Right, scancode: 0xE04D };
+
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 0), picker(&[PickerEvent::Open { reverse: false }]));
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, true, 500), picker(&[PickerEvent::Show]));
+        assert_eq!(engine.handle(right, Modifiers::NONE, true, 600), picker(&[PickerEvent::Move(1)]));
+        assert_eq!(engine.handle(right, Modifiers::NONE, true, 650), picker(&[PickerEvent::Move(1)]), "Arrows repeat");
+        assert_eq!(engine.handle(right, Modifiers::NONE, false, 700), blocked());
+        assert_eq!(engine.handle(A, Modifiers::NONE, true, 750), KeyOutcome::default(), "Other keys pass");
+        assert_eq!(engine.handle(A, Modifiers::NONE, false, 760), KeyOutcome::default());
+        assert_eq!(engine.handle(CAPS, Modifiers::NONE, false, 800), picker(&[PickerEvent::Commit]));
+
+        // Closed: arrows are ordinary keys again
+        assert_eq!(engine.handle(right, Modifiers::NONE, true, 900), KeyOutcome::default());
+    }
+
+    #[test]
+    fn test_picker_chord_and_cancel() {
+        let bindings = bindings(&[("Alt+Q", "cycle"), ("Alt+Shift+Q", "cycle-back")]);
+        let mut engine = BindingEngine::new(bindings).with_picker(true);
+        let q = KeyCode { key: Key::Char('Q'), scancode: 0x10 };
+        let lalt = KeyCode { key: Key::LeftAlt, scancode: 0x38 };
+        let escape = KeyCode { key: Key::Escape, scancode: 0x01 };
+        let alt = Modifiers::NONE.with(Key::LeftAlt);
+
+        // Like Alt+Tab: taps of Q while Alt is held move on, releasing Alt commits
+        assert_eq!(engine.handle(q, alt, true, 0), picker(&[PickerEvent::Open { reverse: false }]));
+        assert_eq!(engine.handle(q, alt, false, 50), blocked());
+        assert_eq!(engine.handle(q, alt, true, 100), picker(&[PickerEvent::Move(1)]));
+        assert_eq!(engine.handle(q, alt, false, 150), blocked());
+        assert_eq!(engine.handle(lalt, alt, false, 200),
+                   KeyOutcome { picker: vec![PickerEvent::Commit], ..KeyOutcome::default() },
+                   "The modifier release still reaches applications");
+
+        assert_eq!(engine.handle(q, alt.with(Key::LeftShift), true, 1000), picker(&[PickerEvent::Open { reverse: true }]));
+        assert_eq!(engine.handle(escape, alt, true, 1050), picker(&[PickerEvent::Cancel]));
+        assert_eq!(engine.handle(escape, alt, false, 1100), blocked());
+        assert_eq!(engine.handle(q, alt, false, 1150), blocked());
+        assert_eq!(engine.handle(lalt, alt, false, 1200), KeyOutcome::default());
+    }
+}
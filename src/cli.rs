@@ -1,23 +1,10 @@
 use std::env;
-use std::ptr;
-use std::ffi::OsString;
-use std::os::windows::ffi::OsStrExt;
 use std::io::{self, Write};
-use winapi::um::winuser::*;
-use winapi::um::winreg::*;
-use winapi::um::handleapi::CloseHandle;
-use winapi::um::synchapi::CreateMutexW;
-use winapi::um::errhandlingapi::GetLastError;
-use winapi::um::winnt::{KEY_SET_VALUE, KEY_QUERY_VALUE, REG_SZ, HANDLE};
-use winapi::shared::minwindef::*;
-use winapi::shared::winerror::*;
-use crate::layout_manager;
+use crate::backend::{self, Backend};
+use crate::bindings;
+use crate::layout_manager::{self, LayoutChange};
 use crate::config;
 
-const MUTEX_NAME: &str = "Global\\CCapsLayoutSwitcherMutex";
-const REGISTRY_KEY: &str = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Run";
-const APP_NAME: &str = "CCaps Layout Switcher";
-
 pub enum CliCommand {
     Start(Vec<String>), // Modified to include country codes
     Stop,
@@ -26,13 +13,67 @@ pub enum CliCommand {
     Run(Vec<String>), // Modified to include country codes
     Menu, // Interactive menu (no parameters)
     Background(Vec<String>), // Internal command for background process with country codes
+    Layouts(LayoutAction, Vec<String>), // Install or remove system keyboard layouts
     Help,
     Version,
     Unknown(String),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutAction {
+    Add,
+    Remove,
+}
+
+// Global option selecting the config file, accepted before or after the command
+const CONFIG_OPTION: &str = "--config";
+
+// Remove `--config <path>` / `--config=<path>` from the arguments and return the path
+fn take_config_option(args: &mut Vec<String>) -> Result<Option<String>, String> {
+    let mut config_path = None;
+    let mut index = 1;
+
+    while index < args.len() {
+        if args[index] == CONFIG_OPTION {
+            if index + 1 >= args.len() {
+                return Err(format!("{} requires a file path", CONFIG_OPTION));
+            }
+            config_path = Some(args.remove(index + 1));
+            args.remove(index);
+        } else if let Some(path) = args[index].strip_prefix("--config=") {
+            config_path = Some(path.to_string());
+            args.remove(index);
+        } else {
+            index += 1;
+        }
+    }
+
+    Ok(config_path)
+}
+
+// Process arguments without the global options
+fn command_args() -> Vec<String> {
+    let mut args: Vec<String> = env::args().collect();
+    let _ = take_config_option(&mut args);
+    args
+}
+
 pub fn parse_args() -> CliCommand {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    match take_config_option(&mut args) {
+        Ok(Some(path)) => {
+            if let Err(e) = config::set_config_override(&path) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Ok(None) => {},
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
     
     if args.len() < 2 {
         // No arguments provided - show interactive menu
@@ -67,6 +108,18 @@ pub fn parse_args() -> CliCommand {
                 .collect();
             CliCommand::Background(country_codes)
         },
+        "-layouts" => {
+            // Codes after add/remove, with or without a leading dash
+            let codes: Vec<String> = args.iter().skip(3)
+                .map(|arg| arg.trim_start_matches('-').to_string())
+                .filter(|code| !code.is_empty())
+                .collect();
+            match args.get(2).map(String::as_str) {
+                Some("add") => CliCommand::Layouts(LayoutAction::Add, codes),
+                Some("remove") => CliCommand::Layouts(LayoutAction::Remove, codes),
+                _ => CliCommand::Unknown(format!("{} (use -layouts add|remove <codes>)", args[1..].join(" "))),
+            }
+        },
         "-help" | "--help" | "-h" | "/?" => CliCommand::Help,
         "-v" | "--version" => CliCommand::Version,
         _ => CliCommand::Unknown(args[1].clone()),
@@ -81,6 +134,7 @@ pub fn execute_command(command: CliCommand) -> (i32, Vec<String>) {
         CliCommand::Status => (handle_status(), vec![]),
         CliCommand::Background(country_codes) => (handle_background(&country_codes), country_codes),
         CliCommand::Run(country_codes) => (0, country_codes), // Continue normal execution
+        CliCommand::Layouts(action, codes) => (handle_layouts(action, &codes), vec![]),
         CliCommand::Menu => (0, vec![]), // This should not be called directly
         CliCommand::Help => {
             show_help();
@@ -109,9 +163,10 @@ fn handle_background(country_codes: &[String]) -> i32 {
     
     // When running in the background, we only check autoload
     // but we don't install it again
-    if !is_in_startup() {
+    let backend = backend::current();
+    if !backend.is_in_startup() {
         // If for some reason the autoload is missing, we add it
-        if let Err(e) = add_to_startup(&final_country_codes) {
+        if let Err(e) = backend.add_to_startup(&final_country_codes) {
             eprintln!("Warning: Could not ensure startup entry: {}", e);
         }
     }
@@ -125,16 +180,24 @@ fn handle_status() -> i32 {
     println!("╞══════════════════════════════════════════════════════════════╡");
     
     // Check if running in background
-    let is_running = is_already_running();
+    let backend = backend::current();
+    println!("Platform backend:   {}", backend.name());
+    let is_running = backend.is_instance_running();
     println!("Background process: {}", if is_running { "RUNNING ✓" } else { "NOT RUNNING ✗" });
+    if let Some(instance) = backend.running_instance() {
+        println!("Instance:           {}", instance.summary(backend));
+        if let Some(error) = instance.switch_error_summary(backend) {
+            println!("Last switch error:  {}", error);
+        }
+    }
     
     // Check startup entry
-    let in_startup = is_in_startup();
+    let in_startup = backend.is_in_startup();
     println!("Auto-startup:       {}", if in_startup { "ENABLED ✓" } else { "DISABLED ✗" });
     
     // Show startup path if enabled
     if in_startup {
-        if let Ok(startup_path) = get_startup_path() {
+        if let Ok(startup_path) = backend.get_startup_command() {
             println!("Startup command:    {}", startup_path);
         }
     }
@@ -151,33 +214,47 @@ fn handle_status() -> i32 {
     } else {
         println!("Saved country codes: [All layouts]");
     }
+    println!("Home layout:        {}", layout_manager::home_layout().describe(backend));
+    if !config.aliases.is_empty() {
+        println!("Aliases:");
+        for (name, target) in &config.aliases {
+            match layout_manager::resolve_alias(backend, name, target) {
+                Ok(layout) => println!("  -{:<16} → {} ({})", name, layout.short_code, layout.name),
+                Err(error) => println!("  -{:<16} ✗ {}", name, error),
+            }
+        }
+    }
+    if !config.bindings.is_empty() {
+        println!("Key bindings:");
+        for (chord, action) in &config.bindings {
+            match bindings::Binding::parse(chord, action) {
+                Ok(_) => println!("  {:<17} → {}", chord, action),
+                Err(error) => println!("  {:<17} ✗ {}", chord, error),
+            }
+        }
+    }
+    let key_bindings = bindings::parse_bindings(&config.bindings).unwrap_or_else(|_| bindings::default_bindings());
+    match (bindings::parse_dual_role(config.dual_role.as_ref(), &key_bindings), &config.dual_role) {
+        (Err(error), _) => println!("Dual-role key: ✗ {}", error),
+        (Ok(_), Some(dual_role)) => println!("Dual-role key: {} (held: {}, timeout {} ms{})", dual_role.key, dual_role.hold,
+            dual_role.timeout_ms, if dual_role.permissive_hold { ", permissive hold" } else { "" }),
+        (Ok(Some(_)), None) => println!("Dual-role key: layer key of the key bindings"),
+        (Ok(None), None) => {},
+    }
+    if config.switch_mode == config::SwitchMode::Mru {
+        println!("Switch mode: most recently used");
+    }
+    if config.picker {
+        println!("Layout picker: shown while the cycle key is held");
+    }
     
     println!();
     
     // Show available layouts
-    let layouts = layout_manager::get_all_keyboard_layouts();
+    let layouts = layout_manager::get_all_keyboard_layouts(backend);
+    let current_layout = layout_manager::get_current_layout(backend);
     println!("Available keyboard layouts:");
-    println!("┌─────┬──────────────────────────────────────┬─────────────────┐");
-    println!("│ Code│ Language                             │ Status          │");
-    println!("├─────┼──────────────────────────────────────┼─────────────────┤");
-    
-    let current_layout = layout_manager::get_current_layout();
-    
-    for layout in &layouts {
-        let status = if let Some(ref current) = current_layout {
-            if current.hkl == layout.hkl {
-                "CURRENT ✓"
-            } else {
-                "Available"
-            }
-        } else {
-            "Available"
-        };
-        
-        println!("│ -{:<2} │ {:<36} │ {:<15} │", 
-                layout.short_code, layout.name, status);
-    }
-    println!("└─────┴──────────────────────────────────────┴─────────────────┘");
+    print_layout_table(&layouts, current_layout.as_ref());
     
     println!();
     println!("Usage examples:");
@@ -212,16 +289,22 @@ fn handle_start(country_codes: &[String]) -> i32 {
     println!("Starting CCaps Layout Switcher...");
     
     // Check if already running
-    if is_already_running() {
+    let backend = backend::current();
+    if backend.is_instance_running() {
         println!("The program is already running in the background.");
         return 1;
     }
     
-    // Validate country codes if provided
+    // Validate country codes if provided, with aliases expanded.
+    // The codes are saved as given, so alias changes apply on the next start.
+    let saved_config = config::load_config();
     if !country_codes.is_empty() {
-        if let Err(error) = layout_manager::validate_country_codes(
-            &country_codes.iter().map(|s| s.as_str()).collect::<Vec<_>>()
-        ) {
+        if let Err(error) = layout_manager::expand_aliases(backend, country_codes, &saved_config.aliases)
+            .and_then(|codes| layout_manager::validate_country_codes(
+                backend,
+                &codes.iter().map(|s| s.as_str()).collect::<Vec<_>>()
+            ))
+        {
             eprintln!("Error: {}", error);
             return 1;
         }
@@ -230,8 +313,8 @@ fn handle_start(country_codes: &[String]) -> i32 {
         println!("Using all available layouts");
     }
     
-    // Save configuration
-    let config = config::Config::with_country_codes(country_codes.to_vec());
+    // Save configuration, keeping the other settings
+    let config = config::Config::with_country_codes_from(country_codes.to_vec(), saved_config);
     if let Err(e) = config::save_config(&config) {
         eprintln!("Warning: Could not save configuration: {}", e);
     } else {
@@ -239,12 +322,12 @@ fn handle_start(country_codes: &[String]) -> i32 {
     }
 
     // Add to startup with country codes
-    let already_in_startup = is_in_startup();
+    let already_in_startup = backend.is_in_startup();
     if already_in_startup {
         println!("Already in system startup. Updating configuration...");
     }
 
-    if let Err(e) = add_to_startup(country_codes) {
+    if let Err(e) = backend.add_to_startup(country_codes) {
         eprintln!("Warning: Could not add to startup: {}", e);
     } else {
         if already_in_startup {
@@ -255,7 +338,7 @@ fn handle_start(country_codes: &[String]) -> i32 {
     }
     
     // Start in background (completely detached process)
-    if let Err(e) = start_background_process(country_codes) {
+    if let Err(e) = backend.spawn_background(country_codes) {
         eprintln!("Failed to start background process: {}", e);
         return 1;
     }
@@ -264,11 +347,57 @@ fn handle_start(country_codes: &[String]) -> i32 {
     0
 }
 
+fn handle_layouts(action: LayoutAction, codes: &[String]) -> i32 {
+    change_layouts(backend::current(), action, codes)
+}
+
+fn change_layouts(backend: &dyn Backend, action: LayoutAction, codes: &[String]) -> i32 {
+    if codes.is_empty() {
+        eprintln!("Error: No layout codes given, e.g. 'ccaps -layouts add de ru'");
+        return 1;
+    }
+
+    let mut changed = false;
+    let mut failed = false;
+
+    for code in codes {
+        let result = match action {
+            LayoutAction::Add => layout_manager::add_layout(backend, code),
+            LayoutAction::Remove => layout_manager::remove_layout(backend, code),
+        };
+
+        match result {
+            Ok(LayoutChange::Added(layout)) => {
+                println!("Added:             -{} ({})", layout.short_code, layout.name);
+                changed = true;
+            },
+            Ok(LayoutChange::AlreadyInstalled(layout)) => {
+                println!("Already installed: -{} ({})", layout.short_code, layout.name);
+            },
+            Ok(LayoutChange::Removed(layout)) => {
+                println!("Removed:           -{} ({})", layout.short_code, layout.name);
+                changed = true;
+            },
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                failed = true;
+            }
+        }
+    }
+
+    // The running instance re-reads its layouts before the next switch
+    if changed && backend.refresh_running_instance() {
+        println!("Running instance refreshed.");
+    }
+
+    if failed { 1 } else { 0 }
+}
+
 fn ask_confirmation(prompt: &str) -> bool {
     ask_confirmation_with_reader(prompt, &mut io::stdin().lock())
 }
 
-fn ask_confirmation_with_reader<R: io::BufRead>(prompt: &str, reader: &mut R) -> bool {
+pub fn ask_confirmation_with_reader<R: io::BufRead>(prompt: &str, reader: &mut R) -> bool {
     print!("{} [y/n]: ", prompt);
     io::stdout().flush().unwrap();
 
@@ -292,7 +421,8 @@ fn handle_stop() -> i32 {
     }
 
     // Remove from startup
-    if let Err(e) = remove_from_startup() {
+    let backend = backend::current();
+    if let Err(e) = backend.remove_from_startup() {
         eprintln!("Warning: Could not remove from startup: {}", e);
     } else {
         println!("Removed from system startup.");
@@ -306,7 +436,7 @@ fn handle_stop() -> i32 {
     }
 
     // Stop running process
-    if stop_background_process() {
+    if backend.stop_running_instance() {
         println!("Background process stopped.");
     } else {
         println!("No background process was running.");
@@ -324,7 +454,7 @@ fn handle_exit() -> i32 {
         return 0;
     }
 
-    if stop_background_process() {
+    if backend::current().stop_running_instance() {
         println!("Background process stopped.");
     } else {
         println!("No background process was running.");
@@ -337,6 +467,47 @@ fn show_version() {
     println!("CCaps Layout Switcher v{}", env!("CARGO_PKG_VERSION"));
 }
 
+// Table of layouts with their CLI code, BCP-47 tag and name from the locale catalog
+fn print_layout_table(layouts: &[layout_manager::LayoutInfo], current_layout: Option<&layout_manager::LayoutInfo>) {
+    // Codes outside the catalog can be longer than the usual two letters
+    let code_width = layouts.iter().map(|l| l.short_code.chars().count() + 1).max().unwrap_or(0).max(4);
+    let tag_width = layouts.iter().map(|l| l.tag.chars().count()).max().unwrap_or(0).max(3);
+    let line = |left: &str, middle: &str, right: &str| {
+        format!("{}{}{}{}{}{}{}{}{}", left, "─".repeat(code_width + 2), middle, "─".repeat(tag_width + 2),
+                middle, "─".repeat(38), middle, "─".repeat(17), right)
+    };
+
+    println!("{}", line("┌", "┬", "┐"));
+    println!("│ {:<code_width$} │ {:<tag_width$} │ {:<36} │ {:<15} │", "Code", "Tag", "Language", "Status");
+    println!("{}", line("├", "┼", "┤"));
+
+    for layout in layouts {
+        let status = match current_layout {
+            Some(current) if current.hkl == layout.hkl => "CURRENT ✓",
+            _ => "Available",
+        };
+
+        println!("│ {:<code_width$} │ {:<tag_width$} │ {:<36} │ {:<15} │",
+                format!("-{}", layout.short_code), layout.tag, layout.name, status);
+    }
+    println!("{}", line("└", "┴", "┘"));
+}
+
+// Key bindings from the config file, or the default ones
+pub fn print_key_bindings(config: &config::Config) {
+    if config.bindings.is_empty() {
+        println!("  {:<22} - Switch keyboard layout", "Caps Lock");
+        println!("  {:<22} - Toggle Caps Lock", "Shift + Caps Lock");
+    } else {
+        for (chord, action) in &config.bindings {
+            println!("  {:<22} - {}", chord, action);
+        }
+    }
+    if let Some(dual_role) = &config.dual_role {
+        println!("  {:<22} - {} when held with other keys", dual_role.key, dual_role.hold);
+    }
+}
+
 fn show_help() {
     println!("CCaps Layout Switcher v{}", env!("CARGO_PKG_VERSION"));
     println!("Keyboard layout switcher using Caps Lock key");
@@ -351,317 +522,81 @@ fn show_help() {
     println!("  ccaps -stop        - Stop background process and remove from startup");
     println!("  ccaps -quit        - Stop background process only");
     println!("  ccaps -status      - Show current status and available language codes");
+    println!("  ccaps -layouts add de ru   - Install keyboard layouts");
+    println!("  ccaps -layouts remove de   - Remove an installed keyboard layout");
     println!("  ccaps -help        - Show this help");
     println!("  ccaps -v           - Show version information");
     println!();
-    println!("Key bindings:");
-    println!("  Caps Lock              - Switch keyboard layout");
-    println!("  Shift + Caps Lock      - Toggle Caps Lock");
-    println!("  Scroll Lock indicator  - Shows current layout (OFF=English, ON=Non-English)");
+    println!("Options:");
+    println!("  --config <path>    - Use this configuration file (also CCAPS_CONFIG)");
+    println!();
+    println!("Key bindings (\"bindings\" in the configuration file):");
+    print_key_bindings(&config::load_config());
+    println!("  Scroll Lock indicator  - Shows current layout (OFF=home layout, ON=any other)");
     println!();
     println!("Configuration:");
     println!("  Settings are automatically saved when using -start with country codes");
-    println!("  Configuration file: %localappdata%\\CCaps\\ccaps-config.json");
+    if cfg!(windows) {
+        println!("  Configuration file: %localappdata%\\CCaps\\ccaps-config.json");
+    } else {
+        println!("  Configuration file: $XDG_CONFIG_HOME/ccaps/ccaps-config.json");
+    }
     println!("  Use 'ccaps -status' to see all available language codes");
+    println!("  Home layout (default English): \"home_layout\": \"ua\" in the file, or CCAPS_HOME_LAYOUT");
     println!();
 }
 
-fn is_already_running() -> bool {
-    unsafe {
-        let mutex_name = format!("{}\0", MUTEX_NAME);
-        let mutex_name_wide: Vec<u16> = OsString::from(mutex_name)
-            .encode_wide()
-            .collect();
-        
-        let mutex = CreateMutexW(
-            ptr::null_mut(),
-            FALSE,
-            mutex_name_wide.as_ptr(),
-        );
-        
-        if mutex.is_null() {
-            return false;
-        }
-        
-        let error = GetLastError();
-        CloseHandle(mutex);
-        
-        error == ERROR_ALREADY_EXISTS
-    }
-}
-
-fn is_in_startup() -> bool {
-    unsafe {
-        let mut key: HKEY = ptr::null_mut();
-        let key_name = format!("{}\0", REGISTRY_KEY);
-        let key_name_wide: Vec<u16> = OsString::from(key_name)
-            .encode_wide()
-            .collect();
-        
-        let result = RegOpenKeyExW(
-            HKEY_CURRENT_USER,
-            key_name_wide.as_ptr(),
-            0,
-            KEY_QUERY_VALUE,
-            &mut key,
-        );
-        
-        if result != ERROR_SUCCESS as i32 {
-            return false;
-        }
-        
-        let app_name_wide: Vec<u16> = OsString::from(format!("{}\0", APP_NAME))
-            .encode_wide()
-            .collect();
-        
-        let mut value_type: DWORD = 0;
-        let mut data_size: DWORD = 0;
-        
-        let result = RegQueryValueExW(
-            key,
-            app_name_wide.as_ptr(),
-            ptr::null_mut(),
-            &mut value_type,
-            ptr::null_mut(),
-            &mut data_size,
-        );
-        
-        RegCloseKey(key);
-        
-        result == ERROR_SUCCESS as i32 && value_type == REG_SZ && data_size > 0
-    }
-}
-
-fn get_startup_path() -> Result<String, String> {
-    unsafe {
-        let mut key: HKEY = ptr::null_mut();
-        let key_name = format!("{}\0", REGISTRY_KEY);
-        let key_name_wide: Vec<u16> = OsString::from(key_name)
-            .encode_wide()
-            .collect();
-        
-        let result = RegOpenKeyExW(
-            HKEY_CURRENT_USER,
-            key_name_wide.as_ptr(),
-            0,
-            KEY_QUERY_VALUE,
-            &mut key,
-        );
-        
-        if result != ERROR_SUCCESS as i32 {
-            return Err("Failed to open registry key".to_string());
-        }
-        
-        let app_name_wide: Vec<u16> = OsString::from(format!("{}\0", APP_NAME))
-            .encode_wide()
-            .collect();
-        
-        let mut value_type: DWORD = 0;
-        let mut data_size: DWORD = 0;
-        
-        // First call to get size
-        let result = RegQueryValueExW(
-            key,
-            app_name_wide.as_ptr(),
-            ptr::null_mut(),
-            &mut value_type,
-            ptr::null_mut(),
-            &mut data_size,
-        );
-        
-        if result != ERROR_SUCCESS as i32 {
-            RegCloseKey(key);
-            return Err("Failed to query registry value size".to_string());
-        }
-        
-        // Allocate buffer and get actual value
-        let mut buffer: Vec<u16> = vec![0; (data_size / 2) as usize];
-        let result = RegQueryValueExW(
-            key,
-            app_name_wide.as_ptr(),
-            ptr::null_mut(),
-            &mut value_type,
-            buffer.as_mut_ptr() as *mut u8,
-            &mut data_size,
-        );
-        
-        RegCloseKey(key);
-        
-        if result == ERROR_SUCCESS as i32 && value_type == REG_SZ {
-            // Convert wide string to String, removing null terminator
-            let end_pos = buffer.iter().position(|&x| x == 0).unwrap_or(buffer.len());
-            let path = String::from_utf16(&buffer[..end_pos])
-                .map_err(|_| "Failed to convert path to string")?;
-            Ok(path)
-        } else {
-            Err("Failed to read registry value".to_string())
-        }
-    }
+pub fn should_run_in_background() -> bool {
+    let args = command_args();
+    args.len() > 1 && (args[1] == "--background")
 }
 
-fn add_to_startup(country_codes: &[String]) -> Result<(), String> {
-    unsafe {
-        let mut key: HKEY = ptr::null_mut();
-        let key_name = format!("{}\0", REGISTRY_KEY);
-        let key_name_wide: Vec<u16> = OsString::from(key_name)
-            .encode_wide()
-            .collect();
-        
-        let result = RegOpenKeyExW(
-            HKEY_CURRENT_USER,
-            key_name_wide.as_ptr(),
-            0,
-            KEY_SET_VALUE,
-            &mut key,
-        );
-        
-        if result != ERROR_SUCCESS as i32 {
-            return Err("Failed to open registry key".to_string());
-        }
-        
-        // Get current executable path
-        let exe_path = env::current_exe()
-            .map_err(|_| "Failed to get executable path")?;
-        
-        let mut exe_path_str = format!("\"{}\" --background", exe_path.display());
-        
-        // Add country codes to the command line
-        for code in country_codes {
-            exe_path_str.push_str(&format!(" -{}", code));
-        }
-        exe_path_str.push('\0');
-        
-        let exe_path_wide: Vec<u16> = OsString::from(exe_path_str)
-            .encode_wide()
-            .collect();
-        
-        let app_name_wide: Vec<u16> = OsString::from(format!("{}\0", APP_NAME))
-            .encode_wide()
-            .collect();
-        
-        let result = RegSetValueExW(
-            key,
-            app_name_wide.as_ptr(),
-            0,
-            REG_SZ,
-            exe_path_wide.as_ptr() as *const u8,
-            (exe_path_wide.len() * 2) as u32,
-        );
-        
-        RegCloseKey(key);
-        
-        if result == ERROR_SUCCESS as i32 {
-            Ok(())
-        } else {
-            Err("Failed to set registry value".to_string())
-        }
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::simulated::SimulatedBackend;
+    use crate::instance::InstanceInfo;
+    use std::io::Cursor;
 
-fn remove_from_startup() -> Result<(), String> {
-    unsafe {
-        let mut key: HKEY = ptr::null_mut();
-        let key_name = format!("{}\0", REGISTRY_KEY);
-        let key_name_wide: Vec<u16> = OsString::from(key_name)
-            .encode_wide()
-            .collect();
-        
-        let result = RegOpenKeyExW(
-            HKEY_CURRENT_USER,
-            key_name_wide.as_ptr(),
-            0,
-            KEY_SET_VALUE,
-            &mut key,
-        );
-        
-        if result != ERROR_SUCCESS as i32 {
-            return Err("Failed to open registry key".to_string());
-        }
-        
-        let app_name_wide: Vec<u16> = OsString::from(format!("{}\0", APP_NAME))
-            .encode_wide()
-            .collect();
-        
-        let result = RegDeleteValueW(key, app_name_wide.as_ptr());
-        RegCloseKey(key);
-        
-        if result == ERROR_SUCCESS as i32 || result == ERROR_FILE_NOT_FOUND as i32 {
-            Ok(())
-        } else {
-            Err("Failed to remove registry value".to_string())
-        }
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
     }
-}
 
-fn start_background_process(country_codes: &[String]) -> Result<(), String> {
-    use std::process::Command;
-    use std::os::windows::process::CommandExt;
+    #[test]
+    fn test_take_config_option() {
+        let mut before = args(&["ccaps", "--config", "my.json", "-run", "-de"]);
+        assert_eq!(take_config_option(&mut before), Ok(Some("my.json".to_string())));
+        assert_eq!(before, args(&["ccaps", "-run", "-de"]));
 
-    let exe_path = env::current_exe()
-        .map_err(|_| "Failed to get executable path")?;
+        let mut after = args(&["ccaps", "--background", "-de", "--config=/etc/ccaps.json"]);
+        assert_eq!(take_config_option(&mut after), Ok(Some("/etc/ccaps.json".to_string())));
+        assert_eq!(after, args(&["ccaps", "--background", "-de"]));
 
-    let mut command = Command::new(&exe_path);
-    command.arg("--background");
+        let mut none = args(&["ccaps", "-status"]);
+        assert_eq!(take_config_option(&mut none), Ok(None));
+        assert_eq!(none, args(&["ccaps", "-status"]));
 
-    // Add country codes to the background process
-    for code in country_codes {
-        command.arg(&format!("-{}", code));
+        assert!(take_config_option(&mut args(&["ccaps", "-status", "--config"])).is_err());
     }
 
-    // Use CREATE_NO_WINDOW flag to prevent creating a console window
-    // This ensures the background process doesn't affect the parent terminal
-    const CREATE_NO_WINDOW: u32 = 0x08000000;
-    command.creation_flags(CREATE_NO_WINDOW);
-
-    match command.spawn() {
-        Ok(child) => {
-            // Get the PID of the child process
-            let _pid = child.id();
+    #[test]
+    fn test_change_layouts_refreshes_running_instance() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409]);
+        backend.acquire_instance_lock(&InstanceInfo::for_current_process(&[])).unwrap();
 
-            // Unbind the child process - don't wait for it to complete
-            std::mem::forget(child);
-            Ok(())
-        },
-        Err(e) => Err(format!("Failed to start process: {}", e)),
-    }
-}
+        assert_eq!(change_layouts(&backend, LayoutAction::Add, &args(&["de", "ru", "us"])), 0);
+        assert_eq!(backend.refresh_requests(), 1);
+        assert!(layout_manager::validate_country_codes(&backend, &["de", "ru"]).is_ok());
 
-fn stop_background_process() -> bool {
-    // Send quit message to running instance
-    unsafe {
-        let window = FindWindowA(ptr::null(), b"CCaps Layout Switcher\0".as_ptr() as *const i8);
-        if !window.is_null() {
-            PostMessageA(window, WM_QUIT, 0, 0);
-            return true;
-        }
-    }
-    false
-}
+        // Nothing changed, nothing to refresh
+        assert_eq!(change_layouts(&backend, LayoutAction::Add, &args(&["us"])), 0);
+        assert_eq!(backend.refresh_requests(), 1);
 
-pub fn create_mutex() -> HANDLE {
-    unsafe {
-        let mutex_name = format!("{}\0", MUTEX_NAME);
-        let mutex_name_wide: Vec<u16> = OsString::from(mutex_name)
-            .encode_wide()
-            .collect();
-        
-        CreateMutexW(
-            ptr::null_mut(),
-            TRUE,
-            mutex_name_wide.as_ptr(),
-        )
+        assert_eq!(change_layouts(&backend, LayoutAction::Remove, &args(&["de", "fr"])), 1, "Unknown layouts fail");
+        assert_eq!(backend.refresh_requests(), 2);
+        assert!(layout_manager::validate_country_codes(&backend, &["de"]).is_err());
+        assert_eq!(change_layouts(&backend, LayoutAction::Add, &[]), 1);
     }
-}
-
-pub fn should_run_in_background() -> bool {
-    let args: Vec<String> = env::args().collect();
-    args.len() > 1 && (args[1] == "--background")
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
 
     #[test]
     fn test_ask_confirmation_yes() {
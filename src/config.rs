@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
 use std::env;
@@ -10,42 +11,168 @@ const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub struct Config {
     pub country_codes: Vec<String>,
     pub version: String,
+    // Layout code of the home layout, English layouts if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub home_layout: Option<String>,
+    // User-defined names for layouts, e.g. "work": "de-CH"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+    // Key bindings, e.g. "Shift+CapsLock": "toggle-caps"; defaults if empty
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bindings: BTreeMap<String, String>,
+    // Key that runs its bindings when tapped and acts as a modifier when held
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dual_role: Option<DualRoleConfig>,
+    // Which layout Caps Lock switches to: the next one, or the last used
+    #[serde(default, skip_serializing_if = "SwitchMode::is_cycle")]
+    pub switch_mode: SwitchMode,
+    // Holding a cycle binding opens the layout picker (more than two layouts)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub picker: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SwitchMode {
+    // Round-robin through the selected layouts
+    #[default]
+    Cycle,
+    // Back to the previously used layout, like Alt+Tab
+    Mru,
+}
+
+impl SwitchMode {
+    fn is_cycle(&self) -> bool {
+        *self == SwitchMode::Cycle
+    }
+}
+
+// "dual_role": {"hold": "Ctrl"} makes Caps Lock act as Ctrl while held
+// with other keys
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DualRoleConfig {
+    #[serde(default = "default_dual_role_key")]
+    pub key: String,
+    // Ctrl, Shift, Alt, Win (optionally with side, e.g. "RAlt"), Hyper or Layer
+    pub hold: String,
+    #[serde(default = "default_tap_timeout")]
+    pub timeout_ms: u64,
+    // A key tapped while the dual-role key is down makes it a hold
+    // even before the timeout
+    #[serde(default)]
+    pub permissive_hold: bool,
+}
+
+fn default_dual_role_key() -> String {
+    "CapsLock".to_string()
+}
+
+fn default_tap_timeout() -> u64 {
+    200
 }
 
 impl Config {
     pub fn new() -> Self {
+        Config::with_country_codes(Vec::new())
+    }
+
+    pub fn with_country_codes(country_codes: Vec<String>) -> Self {
         Config {
-            country_codes: Vec::new(),
+            country_codes,
             version: CURRENT_VERSION.to_string(),
+            ..Config::default()
         }
     }
 
-    pub fn with_country_codes(country_codes: Vec<String>) -> Self {
+    // Other country codes with every other setting of a saved config
+    pub fn with_country_codes_from(country_codes: Vec<String>, saved: Config) -> Self {
         Config {
             country_codes,
             version: CURRENT_VERSION.to_string(),
+            ..saved
         }
     }
+
+    // Home layout code: CCAPS_HOME_LAYOUT overrides the config file
+    pub fn home_layout_code(&self) -> Option<String> {
+        home_layout_code(self, |name| env::var(name).ok())
+    }
 }
 
-// Get the path to the configuration file in AppData
-pub fn get_config_path() -> Result<PathBuf, String> {
-    // Use LOCALAPPDATA for application-specific data
-    let app_data = env::var("LOCALAPPDATA")
-        .or_else(|_| env::var("APPDATA"))
-        .map_err(|_| "Cannot determine AppData directory".to_string())?;
+// Home layout for this process, overriding `home_layout` in the config file
+pub const HOME_LAYOUT_ENV_VAR: &str = "CCAPS_HOME_LAYOUT";
 
-    let config_dir = PathBuf::from(app_data).join("CCaps");
+fn home_layout_code(config: &Config, get_var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    get_var(HOME_LAYOUT_ENV_VAR)
+        .or_else(|| config.home_layout.clone())
+        .map(|code| code.trim().to_string())
+        .filter(|code| !code.is_empty())
+}
 
-    // Create the directory if it doesn't exist
-    if !config_dir.exists() {
-        fs::create_dir_all(&config_dir)
-            .map_err(|e| format!("Cannot create config directory: {}", e))?;
+// Explicit config file path; `--config <path>` sets it for the process and
+// the background process it spawns
+pub const CONFIG_ENV_VAR: &str = "CCAPS_CONFIG";
+
+// Config file path set with CCAPS_CONFIG or --config, if any.
+// Relative paths are resolved against the current directory, since the
+// background process and startup entries run from a different one.
+pub fn config_override() -> Option<PathBuf> {
+    env::var_os(CONFIG_ENV_VAR)
+        .filter(|path| !path.is_empty())
+        .map(|path| absolute_path(PathBuf::from(path)))
+}
+
+fn absolute_path(path: PathBuf) -> PathBuf {
+    if path.is_absolute() {
+        return path;
+    }
+    let relative = path.strip_prefix(".").map(PathBuf::from).unwrap_or(path);
+    match env::current_dir() {
+        Ok(dir) => dir.join(relative),
+        Err(_) => relative,
+    }
+}
+
+// Use `path` as the config file for this process and its children
+pub fn set_config_override(path: &str) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("Empty config path".to_string());
     }
+    env::set_var(CONFIG_ENV_VAR, absolute_path(PathBuf::from(path)));
+    Ok(())
+}
+
+// Default config file location for the platform:
+// %LOCALAPPDATA%\CCaps on Windows, $XDG_CONFIG_HOME/ccaps elsewhere
+fn default_config_path(get_var: impl Fn(&str) -> Option<String>) -> Result<PathBuf, String> {
+    let get_var = |name: &str| get_var(name).filter(|value| !value.is_empty());
+
+    let config_dir = if cfg!(windows) {
+        // Use LOCALAPPDATA for application-specific data
+        let app_data = get_var("LOCALAPPDATA")
+            .or_else(|| get_var("APPDATA"))
+            .ok_or_else(|| "Cannot determine AppData directory".to_string())?;
+        PathBuf::from(app_data).join("CCaps")
+    } else {
+        let config_home = get_var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| get_var("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .ok_or_else(|| "Cannot determine the config directory (HOME is not set)".to_string())?;
+        config_home.join("ccaps")
+    };
 
     Ok(config_dir.join(CONFIG_FILE_NAME))
 }
 
+// Get the path to the configuration file. This is a pure lookup,
+// the directory is only created when the config is saved.
+pub fn get_config_path() -> Result<PathBuf, String> {
+    match config_override() {
+        Some(path) => Ok(path),
+        None => default_config_path(|name| env::var(name).ok()),
+    }
+}
+
 // Load configuration from file
 pub fn load_config() -> Config {
     match get_config_path() {
@@ -87,6 +214,14 @@ pub fn save_config(config: &Config) -> Result<(), String> {
     
     let json_content = serde_json::to_string_pretty(config)
         .map_err(|e| format!("Cannot serialize config: {}", e))?;
+
+    // Create the directory if it doesn't exist
+    if let Some(config_dir) = config_path.parent() {
+        if !config_dir.as_os_str().is_empty() && !config_dir.exists() {
+            fs::create_dir_all(config_dir)
+                .map_err(|e| format!("Cannot create config directory: {}", e))?;
+        }
+    }
     
     fs::write(&config_path, json_content)
         .map_err(|e| format!("Cannot write config file: {}", e))?;
@@ -118,4 +253,119 @@ pub fn get_config_info() -> (bool, Option<String>) {
         },
         Err(_) => (false, None),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars<'a>(pairs: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| pairs.iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.to_string())
+    }
+
+    #[test]
+    fn test_with_country_codes_from() {
+        let mut saved = Config::with_country_codes(vec!["de".to_string()]);
+        saved.version = "0.9.0".to_string();
+        saved.home_layout = Some("ua".to_string());
+        saved.picker = true;
+
+        let config = Config::with_country_codes_from(vec!["ru".to_string()], saved);
+        assert_eq!(config.country_codes, vec!["ru".to_string()]);
+        assert_eq!(config.version, CURRENT_VERSION);
+        assert_eq!(config.home_layout.as_deref(), Some("ua"));
+        assert!(config.picker, "Other settings should be kept");
+    }
+
+    #[test]
+    fn test_home_layout_code() {
+        let mut config = Config::new();
+        assert_eq!(home_layout_code(&config, vars(&[])), None);
+
+        config.home_layout = Some("ua".to_string());
+        assert_eq!(home_layout_code(&config, vars(&[])).as_deref(), Some("ua"));
+        assert_eq!(home_layout_code(&config, vars(&[(HOME_LAYOUT_ENV_VAR, " de ")])).as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn test_old_config_uses_defaults() {
+        // Configs written by older versions have none of the optional
+        // settings, and settings left at their default are not written
+        let config: Config = serde_json::from_str(r#"{"country_codes": ["de"], "version": "0.9.0"}"#).unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+        let fields = [
+            ("home_layout", config.home_layout.is_none()),
+            ("aliases", config.aliases.is_empty()),
+            ("bindings", config.bindings.is_empty()),
+            ("dual_role", config.dual_role.is_none()),
+            ("switch_mode", config.switch_mode == SwitchMode::Cycle),
+            ("picker", !config.picker),
+        ];
+        for (field, is_default) in fields {
+            assert!(is_default, "'{}' should have its default", field);
+            assert!(!json.contains(field), "'{}' should not be written", field);
+        }
+    }
+
+    #[test]
+    fn test_aliases() {
+        let config: Config = serde_json::from_str(r#"{"country_codes": [], "version": "0.10.0", "aliases": {"work": "de-CH"}}"#).unwrap();
+        assert_eq!(config.aliases.get("work").map(String::as_str), Some("de-CH"));
+
+        let saved: Config = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(saved.aliases, config.aliases, "Aliases should survive saving");
+    }
+
+    #[test]
+    fn test_dual_role() {
+        let config: Config = serde_json::from_str(r#"{"country_codes": [], "version": "0.10.0", "dual_role": {"hold": "Ctrl"}}"#).unwrap();
+        let dual_role = config.dual_role.clone().unwrap();
+        assert_eq!((dual_role.key.as_str(), dual_role.timeout_ms, dual_role.permissive_hold), ("CapsLock", 200, false));
+
+        let saved: Config = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(saved.dual_role, Some(dual_role), "The dual-role key should survive saving");
+    }
+
+    #[test]
+    fn test_switch_mode() {
+        let config: Config = serde_json::from_str(r#"{"country_codes": [], "version": "0.10.0", "switch_mode": "mru"}"#).unwrap();
+        assert_eq!(config.switch_mode, SwitchMode::Mru);
+
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains(r#""switch_mode":"mru""#));
+        assert_eq!(serde_json::from_str::<Config>(&json).unwrap().switch_mode, SwitchMode::Mru);
+    }
+
+    #[test]
+    fn test_picker() {
+        let mut config = Config::new();
+        config.picker = true;
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains(r#""picker":true"#));
+        assert!(serde_json::from_str::<Config>(&json).unwrap().picker);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_default_path_follows_xdg() {
+        let path = default_config_path(vars(&[("XDG_CONFIG_HOME", "/xdg"), ("HOME", "/home/u")])).unwrap();
+        assert_eq!(path, PathBuf::from("/xdg/ccaps/ccaps-config.json"));
+
+        let path = default_config_path(vars(&[("XDG_CONFIG_HOME", ""), ("HOME", "/home/u")])).unwrap();
+        assert_eq!(path, PathBuf::from("/home/u/.config/ccaps/ccaps-config.json"));
+
+        assert!(default_config_path(vars(&[])).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_default_path_uses_local_app_data() {
+        let path = default_config_path(vars(&[("LOCALAPPDATA", "C:\\Local"), ("APPDATA", "C:\\Roaming")])).unwrap();
+        assert_eq!(path, PathBuf::from("C:\\Local\\CCaps\\ccaps-config.json"));
+
+        let path = default_config_path(vars(&[("APPDATA", "C:\\Roaming")])).unwrap();
+        assert_eq!(path, PathBuf::from("C:\\Roaming\\CCaps\\ccaps-config.json"));
+    }
+}
@@ -0,0 +1,211 @@
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::backend::{Backend, LocalTime};
+
+const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Owner of the single-instance lock, stored next to the lock so other
+// invocations (-status, -stop) can tell which process holds it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InstanceInfo {
+    pub pid: u32,
+    // Start time in seconds since the Unix epoch
+    pub started_at: u64,
+    pub version: String,
+    pub args: Vec<String>,
+    // Country codes in effect, including ones loaded from the config file
+    pub country_codes: Vec<String>,
+    // Last layout switch that did not take effect, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub switch_error: Option<SwitchError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SwitchError {
+    // Time in seconds since the Unix epoch
+    pub at: u64,
+    pub message: String,
+}
+
+impl InstanceInfo {
+    pub fn for_current_process(country_codes: &[String]) -> Self {
+        InstanceInfo {
+            pid: std::process::id(),
+            started_at: unix_time_now(),
+            version: CURRENT_VERSION.to_string(),
+            args: env::args().skip(1).collect(),
+            country_codes: country_codes.to_vec(),
+            switch_error: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn from_json(content: &str) -> Option<Self> {
+        serde_json::from_str(content.trim()).ok()
+    }
+
+    // One-line description for -status,
+    // e.g. "running since 09:12, pid 4312, v0.10.0, codes de"
+    pub fn summary(&self, backend: &dyn Backend) -> String {
+        let started = format_start_time(
+            backend.local_time(self.started_at),
+            backend.local_time(unix_time_now()),
+            self.started_at,
+        );
+
+        let codes = if self.country_codes.is_empty() {
+            "all layouts".to_string()
+        } else {
+            format!("codes {}", self.country_codes.join(", "))
+        };
+
+        format!("running since {}, pid {}, v{}, {}", started, self.pid, self.version, codes)
+    }
+
+    // Last failed switch for -status, e.g. "No foreground window (at 09:14)"
+    pub fn switch_error_summary(&self, backend: &dyn Backend) -> Option<String> {
+        let error = self.switch_error.as_ref()?;
+        let at = format_start_time(backend.local_time(error.at), backend.local_time(unix_time_now()), error.at);
+        Some(format!("{} (at {})", error.message, at))
+    }
+}
+
+// Record the last failed layout switch in the info of the instance lock held
+// by this process, or clear it with None
+pub fn record_switch_error(backend: &dyn Backend, message: Option<&str>) {
+    let Some(mut info) = backend.running_instance() else {
+        return;
+    };
+    if info.pid != std::process::id() {
+        return;
+    }
+
+    info.switch_error = message.map(|message| SwitchError {
+        at: unix_time_now(),
+        message: message.to_string(),
+    });
+    backend.update_instance_info(&info);
+}
+
+pub fn unix_time_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// UTC calendar time, used when the backend cannot convert to local time
+pub fn utc_time(unix_time: u64) -> LocalTime {
+    let days = (unix_time / 86_400) as i64;
+    let seconds = unix_time % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (year_of_era + era * 400 + i64::from(month <= 2)) as i32;
+
+    LocalTime {
+        year,
+        month,
+        day,
+        hour: (seconds / 3600) as u32,
+        minute: (seconds % 3600 / 60) as u32,
+    }
+}
+
+// "09:12" for today, "2026-10-16 09:12" for earlier days; UTC is marked
+// as such when the local time is unknown
+fn format_start_time(started: Option<LocalTime>, now: Option<LocalTime>, started_at: u64) -> String {
+    let (started, now, suffix) = match (started, now) {
+        (Some(started), Some(now)) => (started, now, ""),
+        _ => (utc_time(started_at), utc_time(unix_time_now()), " UTC"),
+    };
+
+    let same_day = (started.year, started.month, started.day) == (now.year, now.month, now.day);
+    if same_day {
+        format!("{:02}:{:02}{}", started.hour, started.minute, suffix)
+    } else {
+        format!("{}-{:02}-{:02} {:02}:{:02}{}",
+                started.year, started.month, started.day, started.hour, started.minute, suffix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::simulated::SimulatedBackend;
+
+    fn time(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> LocalTime {
+        LocalTime { year, month, day, hour, minute }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let info = InstanceInfo {
+            pid: 4312,
+            started_at: 1_700_000_000,
+            version: "0.10.0".to_string(),
+            args: vec!["--background".to_string(), "-de".to_string()],
+            country_codes: vec!["de".to_string()],
+            switch_error: Some(SwitchError { at: 1_700_000_100, message: "No foreground window".to_string() }),
+        };
+
+        assert_eq!(InstanceInfo::from_json(&info.to_json()), Some(info));
+        assert_eq!(InstanceInfo::from_json("4312\n"), None, "A bare PID is not owner info");
+    }
+
+    #[test]
+    fn test_utc_time() {
+        assert_eq!(utc_time(0), time(1970, 1, 1, 0, 0));
+        assert_eq!(utc_time(951_782_400), time(2000, 2, 29, 0, 0));
+        assert_eq!(utc_time(1_700_000_000), time(2023, 11, 14, 22, 13));
+    }
+
+    #[test]
+    fn test_format_start_time() {
+        let started = time(2026, 10, 17, 9, 12);
+        assert_eq!(format_start_time(Some(started), Some(time(2026, 10, 17, 18, 0)), 0), "09:12");
+        assert_eq!(format_start_time(Some(started), Some(time(2026, 10, 18, 8, 0)), 0), "2026-10-17 09:12");
+        assert!(format_start_time(None, None, 0).ends_with(" UTC"));
+    }
+
+    #[test]
+    fn test_summary() {
+        let backend = SimulatedBackend::new();
+        let mut info = InstanceInfo::for_current_process(&["de".to_string()]);
+        info.pid = 4312;
+        info.version = "0.10.0".to_string();
+
+        let summary = info.summary(&backend);
+        assert!(summary.starts_with("running since "));
+        assert!(summary.ends_with(", pid 4312, v0.10.0, codes de"), "Unexpected summary: {}", summary);
+
+        info.country_codes.clear();
+        assert!(info.summary(&backend).ends_with(", all layouts"));
+    }
+
+    #[test]
+    fn test_record_switch_error() {
+        let backend = SimulatedBackend::new();
+        let info = InstanceInfo::for_current_process(&[]);
+        assert!(!info.to_json().contains("switch_error"), "No error is not written out");
+        backend.acquire_instance_lock(&info).unwrap();
+
+        record_switch_error(&backend, Some("No foreground window"));
+        let summary = backend.running_instance().unwrap().switch_error_summary(&backend).unwrap();
+        assert!(summary.starts_with("No foreground window (at "), "Unexpected summary: {}", summary);
+
+        record_switch_error(&backend, None);
+        assert_eq!(backend.running_instance(), Some(info));
+    }
+}
@@ -1,5 +1,8 @@
+use std::collections::BTreeMap;
 use std::io::{self, Write};
-use crate::cli::{execute_command, CliCommand};
+use crate::cli::{ask_confirmation_with_reader, execute_command, print_key_bindings, CliCommand};
+use crate::backend::{self, Backend};
+use crate::config;
 use crate::layout_manager;
 
 pub fn show_interactive_menu() -> (i32, Vec<String>) {
@@ -116,10 +119,9 @@ fn show_menu() {
     println!("│  exit (e)      - Exit this menu                                            │");
     println!("└────────────────────────────────────────────────────────────────────────────┘");
     println!();
-    println!("Key bindings when running:");
-    println!("  Caps Lock              - Switch keyboard layout");
-    println!("  Shift + Caps Lock      - Toggle Caps Lock");
-    println!("  Scroll Lock indicator  - Shows current layout (OFF=English, ON=Non-English)");
+    println!("Key bindings when running (from the configuration file):");
+    print_key_bindings(&config::load_config());
+    println!("  Scroll Lock indicator  - Shows current layout (OFF=home layout, ON=any other)");
     println!();
 }
 
@@ -139,26 +141,12 @@ fn parse_menu_command(input: &str) -> CliCommand {
                 .collect();
             
             // Validate country codes if provided
-            if !country_codes.is_empty() {
-                match layout_manager::validate_country_codes(
-                    &country_codes.iter().map(|s| s.as_str()).collect::<Vec<_>>()
-                ) {
-                    Ok(_) => {
-                        println!();
-                        println!("✓ Validated country codes: {}", country_codes.join(", "));
-                    },
-                    Err(error) => {
-                        println!();
-                        println!("✗ Error: {}", error);
-                        return CliCommand::Unknown(format!("Invalid codes: {}", input));
-                    }
-                }
-            } else {
-                println!();
-                println!("✓ Using all available layouts");
+            println!();
+            let aliases = config::load_config().aliases;
+            match validate_menu_codes(backend::current(), country_codes, &aliases, &mut io::stdin().lock()) {
+                Some(country_codes) => CliCommand::Run(country_codes),
+                None => CliCommand::Unknown(format!("Invalid codes: {}", input)),
             }
-            
-            CliCommand::Run(country_codes)
         },
         "start" => {
             // Parse country codes after start command
@@ -168,23 +156,11 @@ fn parse_menu_command(input: &str) -> CliCommand {
                 .collect();
             
             // Validate country codes if provided
-            if !country_codes.is_empty() {
-                match layout_manager::validate_country_codes(
-                    &country_codes.iter().map(|s| s.as_str()).collect::<Vec<_>>()
-                ) {
-                    Ok(_) => {
-                        println!("✓ Validated country codes: {}", country_codes.join(", "));
-                    },
-                    Err(error) => {
-                        println!("✗ Error: {}", error);
-                        return CliCommand::Unknown(format!("Invalid codes: {}", input));
-                    }
-                }
-            } else {
-                println!("✓ Using all available layouts");
+            let aliases = config::load_config().aliases;
+            match validate_menu_codes(backend::current(), country_codes, &aliases, &mut io::stdin().lock()) {
+                Some(country_codes) => CliCommand::Start(country_codes),
+                None => CliCommand::Unknown(format!("Invalid codes: {}", input)),
             }
-            
-            CliCommand::Start(country_codes)
         },
         "stop" => CliCommand::Stop,
         "quit" => CliCommand::Exit,
@@ -194,4 +170,91 @@ fn parse_menu_command(input: &str) -> CliCommand {
         "exit" | "e" => CliCommand::Unknown("exit".to_string()),
         _ => CliCommand::Unknown(input.to_string()),
     }
-}
\ No newline at end of file
+}
+
+// Validate codes typed in the menu, with aliases expanded. For an unknown
+// code with a close match ("germn"), offer to use the suggested code in its
+// place. Aliases are returned as typed, so -start saves them like on the
+// command line.
+fn validate_menu_codes<R: io::BufRead>(
+    backend: &dyn Backend,
+    mut country_codes: Vec<String>,
+    aliases: &BTreeMap<String, String>,
+    reader: &mut R,
+) -> Option<Vec<String>> {
+    if country_codes.is_empty() {
+        println!("✓ Using all available layouts");
+        return Some(country_codes);
+    }
+
+    let mut expanded = match layout_manager::expand_aliases(backend, &country_codes, aliases) {
+        Ok(codes) => codes,
+        Err(error) => {
+            println!("✗ Error: {}", error);
+            return None;
+        }
+    };
+
+    for (code, expanded) in country_codes.iter_mut().zip(expanded.iter_mut()) {
+        if let Some(layout) = layout_manager::suggest_layout_code(backend, expanded) {
+            let prompt = format!("Unknown code '{}'. Use '{}' ({}) instead?", code, layout.short_code, layout.name);
+            if ask_confirmation_with_reader(&prompt, reader) {
+                *code = layout.short_code.clone();
+                *expanded = layout.short_code;
+            }
+        }
+    }
+
+    match layout_manager::validate_country_codes(
+        backend,
+        &expanded.iter().map(|s| s.as_str()).collect::<Vec<_>>()
+    ) {
+        Ok(_) => {
+            println!("✓ Validated country codes: {}", country_codes.join(", "));
+            Some(country_codes)
+        },
+        Err(error) => {
+            println!("✗ Error: {}", error);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::simulated::SimulatedBackend;
+    use std::io::Cursor;
+
+    fn codes(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_menu_accepts_suggested_code() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407, 0x04190419]);
+
+        let aliases = BTreeMap::new();
+
+        let accepted = validate_menu_codes(&backend, codes(&["germn", "ru"]), &aliases, &mut Cursor::new("y\n"));
+        assert_eq!(accepted, Some(codes(&["de", "ru"])));
+
+        let declined = validate_menu_codes(&backend, codes(&["germn"]), &aliases, &mut Cursor::new("n\n"));
+        assert_eq!(declined, None, "A declined suggestion leaves the code invalid");
+
+        let unknown = validate_menu_codes(&backend, codes(&["zz"]), &aliases, &mut Cursor::new(""));
+        assert_eq!(unknown, None);
+    }
+
+    #[test]
+    fn test_menu_keeps_aliases() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407, 0x04190419]);
+        let aliases = BTreeMap::from([("work".to_string(), "de".to_string())]);
+
+        let accepted = validate_menu_codes(&backend, codes(&["work", "ru"]), &aliases, &mut Cursor::new(""));
+        assert_eq!(accepted, Some(codes(&["work", "ru"])), "Aliases are saved by name");
+
+        let missing = BTreeMap::from([("work".to_string(), "fr".to_string())]);
+        assert_eq!(validate_menu_codes(&backend, codes(&["work"]), &missing, &mut Cursor::new("")), None);
+    }
+}
@@ -1,62 +1,421 @@
-use std::ptr;
-use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
-use winapi::um::winuser::*;
-use winapi::um::libloaderapi::GetModuleHandleW;
-use winapi::shared::minwindef::*;
-use winapi::shared::windef::HHOOK;
+use std::time::Duration;
+use crate::backend::{Backend, LockKey};
+use crate::bindings::{Action, BindingEngine, KeyCode, KeyOutcome, Modifiers, PickerEvent};
+use crate::config::SwitchMode;
+use crate::instance;
 use crate::layout_indicator;
-use crate::layout_manager::{self, LayoutInfo};
-
-// Global variable to store the hook
-static mut HOOK: HHOOK = ptr::null_mut();
+use crate::layout_manager::{self, HomeLayout, LayoutInfo, PendingSwitch, SwitchCheck};
 
 // Structure for passing data to the hook
 struct HookData {
     selected_layouts: Vec<LayoutInfo>,
     current_layout_index: usize,
+    country_codes: Vec<String>,
+    home: HomeLayout,
+    // Installed layout handles when the layouts were last selected
+    known_handles: Vec<usize>,
+    // Switch waiting to be confirmed, see `check_pending_switch`
+    pending_switch: Option<PendingSwitch>,
+    // Whether the last switch failed, so the recorded error can be cleared
+    switch_failed: bool,
+    // Change to the recorded switch error not written to the instance info
+    // yet; the file is written off the keyboard hook
+    error_report: Option<Option<String>>,
+    switch_mode: SwitchMode,
+    // Handles of the selected layouts, most recently used first
+    recent: Vec<usize>,
+    // Codes of the `layout:<code>` bindings and the layouts they were
+    // resolved to when the layouts were selected
+    binding_codes: Vec<String>,
+    bound_layouts: Vec<(String, LayoutInfo)>,
+    picker: Option<Picker>,
+}
+
+// Layout picker, open while a cycle binding is held
+struct Picker {
+    // Indices of the selected layouts as listed, the current one first
+    order: Vec<usize>,
+    // Highlighted entry of `order`
+    position: usize,
+    origin: usize,
+    // Shown as an overlay; without one the highlighted layout is activated
+    // right away as a preview
+    overlay: bool,
 }
 
 // Use a Mutex to protect the hook data
-static HOOK_DATA: Mutex<HookData> = Mutex::new(HookData {
-    selected_layouts: Vec::new(),
-    current_layout_index: 0,
-});
+static HOOK_DATA: Mutex<HookData> = Mutex::new(HookData::new());
 
-// Initialize layout switching with specific country codes
-pub fn initialize_layout_switching(country_codes: &[String]) {
-    let mut hook_data = HOOK_DATA.lock().unwrap();
-    
-    if country_codes.is_empty() {
-        // Use all available layouts
-        hook_data.selected_layouts = layout_manager::get_all_keyboard_layouts();
-    } else {
-        // Find layouts by country codes
-        let codes: Vec<&str> = country_codes.iter().map(|s| s.as_str()).collect();
-        hook_data.selected_layouts = layout_manager::find_layouts_by_codes(&codes);
-        
-        // If no layouts found by codes or only one layout found, 
-        // try to add English layout for better switching experience
-        if hook_data.selected_layouts.len() <= 1 {
-            if let Some(english_layout) = layout_manager::get_english_layout() {
-                // Add English layout if not already present
-                let has_english = hook_data.selected_layouts.iter()
-                    .any(|l| l.hkl == english_layout.hkl);
-                
-                if !has_english {
-                    hook_data.selected_layouts.insert(0, english_layout);
+// Key bindings, set at startup from the config file
+static BINDINGS: Mutex<BindingEngine> = Mutex::new(BindingEngine::new(Vec::new()));
+
+// Set when the installed layouts changed (`ccaps -layouts add/remove`);
+// the selected layouts are re-read before the next switch
+static REFRESH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+impl HookData {
+    const fn new() -> Self {
+        HookData {
+            selected_layouts: Vec::new(),
+            current_layout_index: 0,
+            country_codes: Vec::new(),
+            home: HomeLayout::English,
+            known_handles: Vec::new(),
+            pending_switch: None,
+            switch_failed: false,
+            error_report: None,
+            switch_mode: SwitchMode::Cycle,
+            recent: Vec::new(),
+            binding_codes: Vec::new(),
+            bound_layouts: Vec::new(),
+            picker: None,
+        }
+    }
+
+    // Select layouts for the given country codes and find the current one
+    fn initialize(&mut self, backend: &dyn Backend, country_codes: &[String], home: HomeLayout) {
+        self.country_codes = country_codes.to_vec();
+        self.home = home;
+        self.select_layouts(backend);
+
+        // Find current layout index
+        if let Some(current) = layout_manager::get_current_layout(backend) {
+            self.current_layout_index = self.selected_layouts
+                .iter()
+                .position(|l| l.hkl == current.hkl)
+                .unwrap_or(0);
+        }
+        if let Some(layout) = self.selected_layouts.get(self.current_layout_index) {
+            self.mark_used(layout.hkl);
+        }
+    }
+
+    // Select layouts for the stored country codes from the installed layouts
+    fn select_layouts(&mut self, backend: &dyn Backend) {
+        let country_codes = &self.country_codes;
+        self.known_handles = backend.layout_handles();
+
+        if country_codes.is_empty() {
+            // Use all available layouts
+            self.selected_layouts = layout_manager::get_all_keyboard_layouts_with_home(backend, &self.home);
+        } else {
+            // Find layouts by country codes
+            let codes: Vec<&str> = country_codes.iter().map(|s| s.as_str()).collect();
+            self.selected_layouts = layout_manager::find_layouts_by_codes(backend, &codes);
+            
+            // If no layouts found by codes or only one layout found, 
+            // try to add the home layout for better switching experience
+            if self.selected_layouts.len() <= 1 {
+                if let Some(home_layout) = layout_manager::get_home_layout(backend, &self.home) {
+                    // Add the home layout if not already present
+                    let has_home = self.selected_layouts.iter()
+                        .any(|l| l.hkl == home_layout.hkl);
+                    
+                    if !has_home {
+                        self.selected_layouts.insert(0, home_layout);
+                    }
                 }
             }
         }
+
+        // Resolved here so the hook only looks the layouts up
+        self.bound_layouts = self.binding_codes.iter()
+            .filter_map(|code| {
+                let layout = layout_manager::find_layouts_by_codes(backend, &[code.as_str()]).into_iter().next()?;
+                Some((code.clone(), layout))
+            })
+            .collect();
     }
-    
-    // Find current layout index
-    if let Some(current) = layout_manager::get_current_layout() {
-        hook_data.current_layout_index = hook_data.selected_layouts
-            .iter()
-            .position(|l| l.hkl == current.hkl)
+
+    // Select layouts again with the same codes, after layouts were installed or removed.
+    // The index stays on the same layout; if that layout is gone it moves to the
+    // current one.
+    fn refresh(&mut self, backend: &dyn Backend) {
+        // The picker lists the old selection
+        if self.picker.take().is_some() {
+            backend.hide_picker();
+        }
+
+        let previous = self.selected_layouts.get(self.current_layout_index).map(|l| l.hkl);
+        self.select_layouts(backend);
+
+        let position = |hkl: usize| self.selected_layouts.iter().position(|l| l.hkl == hkl);
+        self.current_layout_index = previous.and_then(position)
+            .or_else(|| layout_manager::get_current_layout(backend).and_then(|current| position(current.hkl)))
             .unwrap_or(0);
     }
+
+    // Whether layouts were installed or removed since the layouts were selected
+    fn layouts_changed(&self, backend: &dyn Backend) -> bool {
+        backend.layout_handles() != self.known_handles
+    }
+
+    // Move to the next selected layout and update the indicator
+    fn switch_to_next(&mut self, backend: &dyn Backend) -> Result<(), String> {
+        if self.selected_layouts.is_empty() {
+            return Ok(());
+        }
+        
+        // With a single layout this activates it again
+        let next_index = self.next_index(false);
+        self.switch_to_index(backend, next_index)
+    }
+
+    // Move back to the previous selected layout
+    fn switch_to_previous(&mut self, backend: &dyn Backend) -> Result<(), String> {
+        if self.selected_layouts.is_empty() {
+            return Ok(());
+        }
+
+        let previous_index = self.next_index(true);
+        self.switch_to_index(backend, previous_index)
+    }
+
+    // Index of the layout a cycle step switches to: the one after the
+    // current layout in the switch order, or the last one for a reverse step.
+    // In MRU mode a step goes back to the previously used layout, and a
+    // reverse step to the least recently used one, so repeated reverse
+    // steps reach every layout.
+    fn next_index(&self, reverse: bool) -> usize {
+        let order = self.switch_order();
+        if reverse {
+            order[order.len() - 1]
+        } else {
+            order[1 % order.len()]
+        }
+    }
+
+    // Indices of the selected layouts starting with the current one, in
+    // selection or MRU order
+    fn switch_order(&self) -> Vec<usize> {
+        let count = self.selected_layouts.len();
+        match self.switch_mode {
+            SwitchMode::Cycle => (0..count).map(|i| (self.current_layout_index + i) % count).collect(),
+            SwitchMode::Mru => self.recent_order(),
+        }
+    }
+
+    // Indices of the selected layouts, the current one first, then by last
+    // use; layouts never used follow in selection order
+    fn recent_order(&self) -> Vec<usize> {
+        let mut order = vec![self.current_layout_index];
+        let used = self.recent.iter()
+            .filter_map(|hkl| self.selected_layouts.iter().position(|l| l.hkl == *hkl));
+        for index in used.chain(0..self.selected_layouts.len()) {
+            if !order.contains(&index) {
+                order.push(index);
+            }
+        }
+        order
+    }
+
+    fn mark_used(&mut self, hkl: usize) {
+        self.recent.retain(|used| *used != hkl);
+        self.recent.insert(0, hkl);
+    }
+
+    // Switch to a selected layout and update the indicator. The switch is
+    // confirmed later; if it does not take effect, the index and indicator
+    // follow the layout that is actually active instead.
+    fn switch_to_index(&mut self, backend: &dyn Backend, index: usize) -> Result<(), String> {
+        let layout = self.selected_layouts[index].clone();
+        self.request_switch(backend, &layout);
+        self.current_layout_index = index;
+        layout_indicator::show_layout(backend, &layout, &self.home);
+        self.mark_used(layout.hkl);
+        Ok(())
+    }
+
+    // Ask for a layout and have the backend check on it once the
+    // foreground application had time to apply it
+    fn request_switch(&mut self, backend: &dyn Backend, layout: &LayoutInfo) {
+        self.pending_switch = Some(layout_manager::request_switch(backend, layout));
+        backend.schedule_switch_check(layout_manager::SWITCH_CONFIRM_DELAY);
+    }
+
+    // Confirm the pending switch, retrying it if the foreground application
+    // ignored it. Returns None while there is nothing to report yet.
+    fn check_switch(&mut self, backend: &dyn Backend) -> Option<Result<(), String>> {
+        let pending = self.pending_switch.as_mut()?;
+        if let Some(remaining) = pending.remaining() {
+            backend.schedule_switch_check(remaining);
+            return None;
+        }
+
+        match layout_manager::check_switch(backend, pending) {
+            SwitchCheck::Retrying => {
+                backend.schedule_switch_check(layout_manager::SWITCH_CONFIRM_DELAY);
+                None
+            },
+            SwitchCheck::Done => {
+                self.pending_switch = None;
+                Some(Ok(()))
+            },
+            SwitchCheck::Failed(e) => {
+                self.pending_switch = None;
+                match backend.foreground_layout() {
+                    Some(current) => {
+                        self.sync_to_layout(current);
+                        layout_indicator::show_layout(backend, &LayoutInfo::from_handle(backend, current), &self.home);
+                    },
+                    None => layout_indicator::update_layout_indicator(backend),
+                }
+                Some(Err(e))
+            },
+        }
+    }
+
+    // Keep track of failed switches. Failures go to the log and, for
+    // -status, to the instance info, which `check_pending_switch` writes.
+    fn note_switch_result(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(()) if self.switch_failed => {
+                self.switch_failed = false;
+                self.error_report = Some(None);
+            },
+            Ok(()) => {},
+            Err(e) => {
+                eprintln!("Warning: {}", e);
+                self.switch_failed = true;
+                self.error_report = Some(Some(e));
+            },
+        }
+    }
+
+    // Switch straight to the layout for a code. Layouts outside the
+    // selection can be switched to as well; the cycle index stays put then.
+    fn switch_to_code(&mut self, backend: &dyn Backend, code: &str) -> Result<(), String> {
+        let layout = self.bound_layouts.iter()
+            .find(|(bound, _)| bound == code)
+            .map(|(_, layout)| layout.clone())
+            .ok_or_else(|| format!("No installed layout matches '{}'", code))?;
+
+        match self.selected_layouts.iter().position(|l| l.hkl == layout.hkl) {
+            Some(index) => self.switch_to_index(backend, index),
+            None => {
+                self.request_switch(backend, &layout);
+                layout_indicator::show_layout(backend, &layout, &self.home);
+                Ok(())
+            },
+        }
+    }
+
+    fn run_picker(&mut self, backend: &dyn Backend, event: PickerEvent) -> Result<(), String> {
+        match event {
+            PickerEvent::Open { reverse } => self.open_picker(backend, reverse),
+            PickerEvent::Show => self.show_picker(backend),
+            PickerEvent::Move(step) => {
+                if let Some(picker) = &mut self.picker {
+                    let count = picker.order.len() as isize;
+                    picker.position = (picker.position as isize + step).rem_euclid(count) as usize;
+                }
+                self.show_picker(backend)
+            },
+            PickerEvent::Commit => self.close_picker(backend, true),
+            PickerEvent::Cancel => self.close_picker(backend, false),
+        }
+    }
+
+    // Open the picker on the layout a cycle step would switch to. With two
+    // layouts or fewer there is nothing to pick, so this is a cycle step.
+    fn open_picker(&mut self, backend: &dyn Backend, reverse: bool) -> Result<(), String> {
+        if self.selected_layouts.len() <= 2 {
+            return if reverse { self.switch_to_previous(backend) } else { self.switch_to_next(backend) };
+        }
+
+        let order = self.switch_order();
+        let position = if reverse { order.len() - 1 } else { 1 };
+        self.picker = Some(Picker { order, position, origin: self.current_layout_index, overlay: false });
+        Ok(())
+    }
+
+    // Show the picker with the highlighted layout, or activate it if the
+    // backend has no overlay
+    fn show_picker(&mut self, backend: &dyn Backend) -> Result<(), String> {
+        let (_, names) = self.switching_status();
+        let (index, overlay) = match &mut self.picker {
+            Some(picker) => {
+                let items: Vec<String> = picker.order.iter().map(|index| names[*index].clone()).collect();
+                picker.overlay = backend.show_picker(&items, picker.position);
+                (picker.order[picker.position], picker.overlay)
+            },
+            None => return Ok(()),
+        };
+
+        if overlay || index == self.current_layout_index {
+            return Ok(());
+        }
+        self.switch_to_index(backend, index)
+    }
+
+    // Close the picker, switching to the highlighted layout or back to
+    // the one it was opened on
+    fn close_picker(&mut self, backend: &dyn Backend, commit: bool) -> Result<(), String> {
+        let picker = match self.picker.take() {
+            Some(picker) => picker,
+            None => return Ok(()),
+        };
+        backend.hide_picker();
+
+        let index = if commit { picker.order[picker.position] } else { picker.origin };
+        if index != self.current_layout_index {
+            self.switch_to_index(backend, index)?;
+        }
+
+        // Previewed layouts do not count as used
+        let origin = self.selected_layouts[picker.origin].hkl;
+        let current = self.selected_layouts[self.current_layout_index].hkl;
+        self.mark_used(origin);
+        self.mark_used(current);
+        Ok(())
+    }
+
+    // Current index and names of the selected layouts
+    fn switching_status(&self) -> (usize, Vec<String>) {
+        let layout_names: Vec<String> = self.selected_layouts
+            .iter()
+            .map(|l| format!("{} ({})", l.name, l.short_code))
+            .collect();
+        (self.current_layout_index, layout_names)
+    }
+
+    // Switch to the Nth selected layout, counting from 1
+    fn switch_to_position(&mut self, backend: &dyn Backend, position: usize) -> Result<(), String> {
+        if position == 0 || position > self.selected_layouts.len() {
+            return Err(format!("No layout {} in the selection ({} selected)", position, self.selected_layouts.len()));
+        }
+        self.switch_to_index(backend, position - 1)
+    }
+
+    // Point the cycle index at a layout activated outside CCaps.
+    // Returns false if the layout is not one of the selected layouts.
+    fn sync_to_layout(&mut self, hkl: usize) -> bool {
+        match self.selected_layouts.iter().position(|l| l.hkl == hkl) {
+            Some(index) => {
+                self.current_layout_index = index;
+                self.mark_used(hkl);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Follow a layout activated outside CCaps, re-reading the selected
+    // layouts first if it may have just been installed
+    fn follow_layout(&mut self, backend: &dyn Backend, hkl: usize) {
+        if !self.sync_to_layout(hkl) && self.layouts_changed(backend) {
+            self.refresh(backend);
+            self.sync_to_layout(hkl);
+        }
+    }
+}
+
+// Initialize layout switching with specific country codes
+pub fn initialize_layout_switching(backend: &dyn Backend, country_codes: &[String]) {
+    let mut hook_data = HOOK_DATA.lock().unwrap();
+    hook_data.initialize(backend, country_codes, layout_manager::home_layout());
     
     println!("Initialized with {} layout(s):", hook_data.selected_layouts.len());
     for (i, layout) in hook_data.selected_layouts.iter().enumerate() {
@@ -65,169 +424,158 @@ pub fn initialize_layout_switching(country_codes: &[String]) {
     }
 }
 
+// Ask for the selected layouts to be re-read. Only sets a flag, so it is
+// safe to call from a signal handler.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub fn request_refresh() {
+    REFRESH_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Re-read the selected layouts now, after the platform reported that the
+// installed layouts changed
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn refresh_layouts(backend: &dyn Backend) {
+    if let Ok(mut hook_data) = HOOK_DATA.lock() {
+        hook_data.refresh(backend);
+    }
+}
+
+// Re-sync the cycle index and indicator after the layout was changed
+// outside CCaps (reported by the platform backend)
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
+pub fn sync_current_layout(backend: &dyn Backend, hkl: usize) {
+    if let Ok(mut hook_data) = HOOK_DATA.lock() {
+        hook_data.follow_layout(backend, hkl);
+    }
+    layout_indicator::update_layout_indicator_with_layout(backend, hkl);
+}
+
+// Re-sync with whatever layout is active now, after the foreground window,
+// the session or the power state changed
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn sync_foreground_layout(backend: &dyn Backend) {
+    match backend.foreground_layout() {
+        Some(hkl) => sync_current_layout(backend, hkl),
+        None => layout_indicator::update_layout_indicator(backend),
+    }
+}
+
 // Flag indicating injected event (from SendInput)
+#[cfg_attr(not(windows), allow(dead_code))]
 const LLKHF_INJECTED: u32 = 0x00000010;
 
 // Unique marker value set in dwExtraInfo for all CCaps SendInput calls.
 // This allows the hook to distinguish CCaps's own injected CapsLock events
 // from external injected events (e.g., from other programs during OS startup).
+// On Linux the same value identifies CCaps's uinput virtual keyboard.
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
 pub const CCAPS_EXTRA_INFO: usize = 0xCCA95;
 
-// Callback function for handling key presses
-unsafe extern "system" fn low_level_keyboard_proc(
-    n_code: i32,
-    w_param: WPARAM,
-    l_param: LPARAM,
-) -> LRESULT {
-    unsafe {
-        if n_code >= 0 {
-            let kb_struct = *(l_param as *const KBDLLHOOKSTRUCT);
-            let vk_code = kb_struct.vkCode;
-
-            // Check if this is CapsLock
-            if vk_code == VK_CAPITAL as u32 {
-                // Allow only CCaps's own injected CapsLock events to pass through.
-                // External injected CapsLock events (from other programs or OS during startup)
-                // are blocked to prevent spontaneous CapsLock LED activation.
-                if (kb_struct.flags & LLKHF_INJECTED) != 0 && kb_struct.dwExtraInfo == CCAPS_EXTRA_INFO {
-                    return CallNextHookEx(HOOK, n_code, w_param, l_param);
-                }
-
-                // Handle Caps Lock press (both normal and system key events)
-                // WM_SYSKEYDOWN occurs when Alt is held or when system thinks Alt is held
-                // (which can happen during Windows startup due to key state desynchronization)
-                if w_param == WM_KEYDOWN as usize || w_param == WM_SYSKEYDOWN as usize {
-                    // Check real-time Shift key state using GetAsyncKeyState
-                    // This prevents desynchronization issues when Shift state changes during window switching
-                    let shift_state = GetAsyncKeyState(VK_SHIFT);
-                    let is_shift_pressed = (shift_state & 0x8000u16 as i16) != 0;
-
-                    if is_shift_pressed {
-                        // Shift + Caps Lock: toggle Caps Lock functionality
-                        toggle_caps_lock();
-                    } else {
-                        // Caps Lock only: switch keyboard layout
-                        switch_keyboard_layout();
-                    }
-
-                    // Block default Caps Lock processing
-                    return 1;
-                }
-
-                // Also block Caps Lock key release to prevent any residual toggle
-                // This ensures complete blocking of CapsLock functionality
-                if w_param == WM_KEYUP as usize || w_param == WM_SYSKEYUP as usize {
-                    return 1;
-                }
-
-                // Catch-all: block any remaining CapsLock event with an unexpected w_param.
-                // Prevents fallthrough to CallNextHookEx for non-standard message types that
-                // some programs or drivers might inject (e.g., Chrome accessibility layer).
-                return 1;
-            }
-        }
+// Helper to check if a CapsLock event should be passed through the hook.
+// Extracted from hook logic so it can be unit-tested without a real hook.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn should_pass_through_capslock(flags: u32, dw_extra_info: usize) -> bool {
+    (flags & LLKHF_INJECTED) != 0 && dw_extra_info == CCAPS_EXTRA_INFO
+}
 
-        CallNextHookEx(HOOK, n_code, w_param, l_param)
+// Choose between round-robin and most-recently-used switching
+pub fn set_switch_mode(mode: SwitchMode) {
+    if let Ok(mut hook_data) = HOOK_DATA.lock() {
+        hook_data.switch_mode = mode;
     }
 }
 
-// Function to switch keyboard layout
-unsafe fn switch_keyboard_layout() {
+// Replace the key bindings and dual-role key. Set before the layouts are
+// selected, which resolves the codes of the `layout:<code>` bindings.
+pub fn set_bindings(engine: BindingEngine) {
     if let Ok(mut hook_data) = HOOK_DATA.lock() {
-        if hook_data.selected_layouts.is_empty() {
-            return;
-        }
-        
-        if hook_data.selected_layouts.len() == 1 {
-            // Only one layout available, just activate it
-            layout_manager::switch_to_layout(&hook_data.selected_layouts[0]);
-            layout_indicator::update_layout_indicator_with_layout(hook_data.selected_layouts[0].get_hkl());
-            return;
-        }
-        
-        // Move to next layout
-        hook_data.current_layout_index = (hook_data.current_layout_index + 1) % hook_data.selected_layouts.len();
-        let next_layout = &hook_data.selected_layouts[hook_data.current_layout_index];
-        
-        // Switch to the new layout
-        layout_manager::switch_to_layout(next_layout);
-        
-        // Update Scroll Lock indicator
-        layout_indicator::update_layout_indicator_with_layout(next_layout.get_hkl());
+        hook_data.binding_codes = engine.layout_codes();
     }
+    *BINDINGS.lock().unwrap() = engine;
 }
 
-// Function to toggle Caps Lock state
-unsafe fn toggle_caps_lock() {
-    unsafe {
-        // Create array for simulating key press
-        let mut inputs: [INPUT; 2] = mem::zeroed();
-
-        // First INPUT - Caps Lock press
-        inputs[0].type_ = INPUT_KEYBOARD;
-        inputs[0].u.ki_mut().wVk = VK_CAPITAL as u16;
-        inputs[0].u.ki_mut().dwFlags = 0;
-        inputs[0].u.ki_mut().dwExtraInfo = CCAPS_EXTRA_INFO;
-
-        // Second INPUT - Caps Lock release
-        inputs[1].type_ = INPUT_KEYBOARD;
-        inputs[1].u.ki_mut().wVk = VK_CAPITAL as u16;
-        inputs[1].u.ki_mut().dwFlags = KEYEVENTF_KEYUP;
-        inputs[1].u.ki_mut().dwExtraInfo = CCAPS_EXTRA_INFO;
-
-        // Send press and release events
-        SendInput(2, inputs.as_mut_ptr(), mem::size_of::<INPUT>() as i32);
+// Handle a physical key event reported by the platform hook; `time_ms` is
+// the event's timestamp. Returns true if the event is consumed and must not
+// reach applications.
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
+pub fn handle_key_event(backend: &dyn Backend, key: KeyCode, held: Modifiers, pressed: bool, time_ms: u64) -> bool {
+    // Decide first, the bindings are not locked while the actions run
+    let outcome = match BINDINGS.lock() {
+        Ok(mut engine) => engine.handle(key, held, pressed, time_ms),
+        Err(_) => KeyOutcome::default(),
+    };
+
+    for action in &outcome.actions {
+        run_action(backend, action);
+    }
+    for event in outcome.picker {
+        switch_keyboard_layout(backend, |hook_data| hook_data.run_picker(backend, event));
+    }
+    // Keys held back by a dual-role key, and its modifiers
+    for (key, pressed) in outcome.inject {
+        backend.inject_key(key, pressed);
     }
+    outcome.block
 }
 
-// Public function to install the hook
-pub unsafe fn install_hook() -> Result<(), &'static str> {
-    unsafe {
-        let h_mod = GetModuleHandleW(ptr::null());
-        if h_mod.is_null() {
-            return Err("Failed to get module handle");
+fn run_action(backend: &dyn Backend, action: &Action) {
+    match action {
+        Action::Cycle => switch_keyboard_layout(backend, |hook_data| hook_data.switch_to_next(backend)),
+        Action::CycleBack => switch_keyboard_layout(backend, |hook_data| hook_data.switch_to_previous(backend)),
+        Action::ToggleCapsLock => toggle_caps_lock(backend),
+        Action::Layout(code) => switch_keyboard_layout(backend, |hook_data| hook_data.switch_to_code(backend, code)),
+        Action::Select(position) => switch_keyboard_layout(backend, |hook_data| hook_data.switch_to_position(backend, *position)),
+    }
+}
+
+// Function to switch keyboard layout. Runs inside the keyboard hook, so it
+// only requests the switch; confirming it and recording errors happen in
+// `check_pending_switch`.
+fn switch_keyboard_layout(backend: &dyn Backend, switch: impl FnOnce(&mut HookData) -> Result<(), String>) {
+    if let Ok(mut hook_data) = HOOK_DATA.lock() {
+        // Layouts are only re-read when the platform reported a change;
+        // enumerating them on every key press would slow down the hook
+        if REFRESH_REQUESTED.swap(false, Ordering::SeqCst) {
+            hook_data.refresh(backend);
         }
-        
-        HOOK = SetWindowsHookExW(
-            WH_KEYBOARD_LL,
-            Some(low_level_keyboard_proc),
-            h_mod,
-            0,
-        );
-        
-        if HOOK.is_null() {
-            return Err("Failed to install hook");
+
+        if let Err(e) = switch(&mut hook_data) {
+            hook_data.note_switch_result(Err(e));
+            backend.schedule_switch_check(Duration::ZERO);
         }
-        
-        Ok(())
     }
 }
 
-// Public function to uninstall the hook
-pub unsafe fn uninstall_hook() {
-    unsafe {
-        if !HOOK.is_null() {
-            UnhookWindowsHookEx(HOOK);
-            HOOK = ptr::null_mut();
-        }
+// Confirm or retry the pending layout switch and write switch errors to the
+// instance info. Called by the backend away from the keyboard hook, after
+// `Backend::schedule_switch_check`.
+#[cfg_attr(not(any(windows, target_os = "linux")), allow(dead_code))]
+pub fn check_pending_switch(backend: &dyn Backend) {
+    let report = match HOOK_DATA.lock() {
+        Ok(mut hook_data) => {
+            if let Some(result) = hook_data.check_switch(backend) {
+                hook_data.note_switch_result(result);
+            }
+            hook_data.error_report.take()
+        },
+        Err(_) => return,
+    };
+
+    if let Some(error) = report {
+        instance::record_switch_error(backend, error.as_deref());
     }
 }
 
-// Helper to check if a CapsLock event should be passed through the hook.
-// Extracted from hook logic so it can be unit-tested without a real hook.
-#[cfg(test)]
-fn should_pass_through_capslock(flags: u32, dw_extra_info: usize) -> bool {
-    (flags & LLKHF_INJECTED) != 0 && dw_extra_info == CCAPS_EXTRA_INFO
+// Function to toggle Caps Lock state
+fn toggle_caps_lock(backend: &dyn Backend) {
+    // Send press and release events, marked as CCaps's own input
+    backend.inject_key_tap(LockKey::CapsLock);
 }
 
 // Function to get current layout switching status (for debugging)
 pub fn get_switching_status() -> (usize, Vec<String>) {
     if let Ok(hook_data) = HOOK_DATA.lock() {
-        let layout_names: Vec<String> = hook_data.selected_layouts
-            .iter()
-            .map(|l| format!("{} ({})", l.name, l.short_code))
-            .collect();
-        (hook_data.current_layout_index, layout_names)
+        hook_data.switching_status()
     } else {
         (0, vec!["Error: Could not access layout data".to_string()])
     }
@@ -236,6 +584,7 @@ pub fn get_switching_status() -> (usize, Vec<String>) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::simulated::SimulatedBackend;
 
     #[test]
     fn test_ccaps_extra_info_is_nonzero() {
@@ -294,4 +643,392 @@ mod tests {
         );
     }
 
-}
\ No newline at end of file
+    const US: usize = 0x04090409;
+    const DE: usize = 0x04070407;
+    const RU: usize = 0x04190419;
+
+    fn codes(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_initialize_with_all_layouts() {
+        let backend = SimulatedBackend::with_layouts(&[RU, US, DE]);
+        backend.set_current_layout(Some(DE));
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        let selected: Vec<&str> = hook_data.selected_layouts.iter().map(|l| l.short_code.as_str()).collect();
+        assert_eq!(selected, vec!["us", "de", "ru"], "English should come first, then alphabetical order");
+        assert_eq!(hook_data.current_layout_index, 1, "Current index should point at German");
+    }
+
+    #[test]
+    fn test_initialize_adds_english_for_single_code() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &codes(&["de"]), HomeLayout::English);
+
+        let selected: Vec<usize> = hook_data.selected_layouts.iter().map(|l| l.hkl).collect();
+        assert_eq!(selected, vec![US, DE], "English should be inserted before a single selected layout");
+    }
+
+    #[test]
+    fn test_initialize_keeps_requested_codes_without_english() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &codes(&["de", "ru"]), HomeLayout::English);
+
+        let selected: Vec<usize> = hook_data.selected_layouts.iter().map(|l| l.hkl).collect();
+        assert_eq!(selected, vec![DE, RU], "Two requested layouts should be used as-is");
+    }
+
+    #[test]
+    fn test_initialize_with_configured_home_layout() {
+        const UA: usize = 0x04220422;
+        let backend = SimulatedBackend::with_layouts(&[US, DE, UA]);
+        let home = HomeLayout::Code("ua".to_string());
+
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], home.clone());
+        let selected: Vec<usize> = hook_data.selected_layouts.iter().map(|l| l.hkl).collect();
+        assert_eq!(selected, vec![UA, US, DE], "The home layout should come first");
+
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &codes(&["de"]), home);
+        let selected: Vec<usize> = hook_data.selected_layouts.iter().map(|l| l.hkl).collect();
+        assert_eq!(selected, vec![UA, DE], "The home layout should be inserted before a single selected layout");
+
+        hook_data.switch_to_next(&backend).unwrap();
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be ON for German");
+        hook_data.switch_to_next(&backend).unwrap();
+        assert!(!backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be OFF for the home layout");
+    }
+
+    #[test]
+    fn test_switch_cycles_layouts_and_indicator() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be ON for German");
+
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(RU));
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should stay ON for Russian");
+
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(US));
+        assert!(!backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be OFF for English");
+        assert_eq!(hook_data.current_layout_index, 0, "Index should wrap around");
+    }
+
+    #[test]
+    fn test_refresh_picks_up_added_layouts() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &codes(&["de", "ru"]), HomeLayout::English);
+        assert_eq!(hook_data.selected_layouts.iter().map(|l| l.hkl).collect::<Vec<_>>(), vec![US, DE],
+                   "Russian is not installed yet, English fills in");
+
+        backend.add_layout(0x0419).unwrap();
+        assert!(hook_data.layouts_changed(&backend));
+        hook_data.refresh(&backend);
+        assert_eq!(hook_data.selected_layouts.iter().map(|l| l.hkl).collect::<Vec<_>>(), vec![DE, RU]);
+        assert!(!hook_data.layouts_changed(&backend));
+    }
+
+    #[test]
+    fn test_refresh_keeps_index_on_same_layout() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        backend.set_current_layout(Some(RU));
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+        assert_eq!(hook_data.current_layout_index, 2);
+
+        // German goes away; the index follows Russian to its new position
+        backend.remove_layout(DE).unwrap();
+        hook_data.refresh(&backend);
+        assert_eq!(hook_data.selected_layouts.iter().map(|l| l.hkl).collect::<Vec<_>>(), vec![US, RU]);
+        assert_eq!(hook_data.current_layout_index, 1);
+    }
+
+    #[test]
+    fn test_refresh_drops_removed_current_layout() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        backend.set_current_layout(Some(RU));
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        // The current layout itself is removed; the system falls back to English
+        backend.remove_layout(RU).unwrap();
+        hook_data.refresh(&backend);
+        assert_eq!(hook_data.selected_layouts.iter().map(|l| l.hkl).collect::<Vec<_>>(), vec![US, DE]);
+        assert_eq!(hook_data.current_layout_index, 0);
+    }
+
+    #[test]
+    fn test_sync_to_external_layout_change() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        // Layout changed to Russian outside CCaps; the next press goes on from there
+        assert!(hook_data.sync_to_layout(RU));
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(US));
+
+        assert!(!hook_data.sync_to_layout(0x040c040c), "Unselected layouts should not move the index");
+        assert_eq!(hook_data.current_layout_index, 0);
+    }
+
+    #[test]
+    fn test_follow_newly_installed_layout() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        // Russian was installed and activated outside CCaps
+        let ru = backend.add_layout(0x0419).unwrap();
+        hook_data.follow_layout(&backend, ru);
+        assert_eq!(hook_data.selected_layouts.len(), 3);
+        assert_eq!(hook_data.selected_layouts[hook_data.current_layout_index].hkl, ru);
+
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(US), "The next press goes on from Russian");
+    }
+
+    #[test]
+    fn test_failed_switch_follows_active_layout() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+        backend.set_lock_key_state(LockKey::ScrollLock, true);
+
+        // The application ignores every request; English stays active
+        backend.ignore_activations(usize::MAX);
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(hook_data.current_layout_index, 1, "The index moves before the switch is confirmed");
+        assert_eq!(backend.switch_checks(), vec![layout_manager::SWITCH_CONFIRM_DELAY]);
+        assert_eq!(hook_data.check_switch(&backend), None, "The check waits for the application");
+        assert!(matches!(finish_switch(&mut hook_data, &backend), Some(Err(_))));
+        assert_eq!(hook_data.current_layout_index, 0);
+        assert!(!backend.lock_key_state(LockKey::ScrollLock), "The indicator should show English");
+
+        hook_data.note_switch_result(Err("ignored".to_string()));
+        assert_eq!(hook_data.error_report.take(), Some(Some("ignored".to_string())));
+
+        backend.ignore_activations(0);
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE), "The next press tries German again");
+        let result = finish_switch(&mut hook_data, &backend).unwrap();
+        hook_data.note_switch_result(result);
+        assert_eq!(hook_data.error_report.take(), Some(None), "The recorded error should be cleared");
+    }
+
+    // Run the checks of the pending switch until it is done or failed
+    fn finish_switch(hook_data: &mut HookData, backend: &SimulatedBackend) -> Option<Result<(), String>> {
+        loop {
+            hook_data.pending_switch.as_mut()?.expire();
+            if let Some(result) = hook_data.check_switch(backend) {
+                return Some(result);
+            }
+        }
+    }
+
+    #[test]
+    fn test_switch_to_code() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.binding_codes = codes(&["ru", "us", "fr"]);
+        hook_data.initialize(&backend, &codes(&["de", "ru"]), HomeLayout::English);
+        assert_eq!(hook_data.bound_layouts.len(), 2, "Only installed layouts are resolved");
+
+        hook_data.switch_to_code(&backend, "ru").unwrap();
+        assert_eq!((backend.foreground_layout(), hook_data.current_layout_index), (Some(RU), 1));
+
+        // English is not selected: it is activated, the cycle stays on Russian
+        hook_data.switch_to_code(&backend, "us").unwrap();
+        assert_eq!((backend.foreground_layout(), hook_data.current_layout_index), (Some(US), 1));
+
+        assert_eq!(hook_data.switch_to_code(&backend, "fr"), Err("No installed layout matches 'fr'".to_string()));
+    }
+
+    #[test]
+    fn test_cycle_back() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        hook_data.switch_to_previous(&backend).unwrap();
+        assert_eq!((backend.foreground_layout(), hook_data.current_layout_index), (Some(RU), 2));
+        hook_data.switch_to_previous(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+    }
+
+    #[test]
+    fn test_mru_switches_back_to_previous_layout() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.switch_mode = SwitchMode::Mru;
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        // Nothing used yet besides English: the next layout in the selection
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+
+        hook_data.switch_to_position(&backend, 3).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(RU));
+
+        // Taps bounce between the last two layouts
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(RU));
+
+        // Reverse steps go to the least recently used layout
+        hook_data.switch_to_previous(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(US));
+        hook_data.switch_to_previous(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+
+        // Layouts activated outside CCaps count as used
+        backend.set_current_layout(Some(RU));
+        assert!(hook_data.sync_to_layout(RU));
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+    }
+
+    #[test]
+    fn test_recent_order() {
+        const PL: usize = 0x04150415;
+        let mut hook_data = HookData::new();
+        hook_data.selected_layouts = [US, DE, RU, PL].iter()
+            .map(|hkl| LayoutInfo::with_variant(*hkl, None))
+            .collect();
+        hook_data.current_layout_index = 2;
+        assert_eq!(hook_data.recent_order(), vec![2, 0, 1, 3]);
+
+        hook_data.mark_used(DE);
+        hook_data.mark_used(RU);
+        hook_data.mark_used(PL);
+        hook_data.mark_used(RU);
+        assert_eq!(hook_data.recent_order(), vec![2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn test_picker_overlay() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        hook_data.run_picker(&backend, PickerEvent::Open { reverse: false }).unwrap();
+        assert_eq!(backend.picker(), None, "A quick tap shows nothing");
+        hook_data.run_picker(&backend, PickerEvent::Show).unwrap();
+        let (items, highlight) = backend.picker().unwrap();
+        assert_eq!(items.len(), 3);
+        assert!(items[0].contains("(us)") && items[1].contains("(de)"), "{:?}", items);
+        assert_eq!(highlight, 1);
+
+        hook_data.run_picker(&backend, PickerEvent::Move(1)).unwrap();
+        assert_eq!(backend.picker().unwrap().1, 2);
+        assert_eq!(backend.foreground_layout(), Some(US), "The overlay does not switch yet");
+
+        hook_data.run_picker(&backend, PickerEvent::Commit).unwrap();
+        assert_eq!(backend.picker(), None);
+        assert_eq!((backend.foreground_layout(), hook_data.current_layout_index), (Some(RU), 2));
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be ON for Russian");
+
+        // Opened again from Russian, moving back wraps around to German
+        hook_data.run_picker(&backend, PickerEvent::Open { reverse: false }).unwrap();
+        hook_data.run_picker(&backend, PickerEvent::Move(-1)).unwrap();
+        hook_data.run_picker(&backend, PickerEvent::Move(-1)).unwrap();
+        assert_eq!(backend.picker().unwrap().1, 2);
+        hook_data.run_picker(&backend, PickerEvent::Commit).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+    }
+
+    #[test]
+    fn test_picker_preview_and_cancel() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        backend.disable_picker_overlay();
+        let mut hook_data = HookData::new();
+        hook_data.switch_mode = SwitchMode::Mru;
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        // Without an overlay the highlighted layout is activated as a preview
+        hook_data.run_picker(&backend, PickerEvent::Open { reverse: false }).unwrap();
+        hook_data.run_picker(&backend, PickerEvent::Show).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+        hook_data.run_picker(&backend, PickerEvent::Move(1)).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(RU));
+
+        hook_data.run_picker(&backend, PickerEvent::Cancel).unwrap();
+        assert_eq!((backend.foreground_layout(), hook_data.current_layout_index), (Some(US), 0));
+
+        // Committing a previewed layout leaves the opening layout as the previous one
+        hook_data.run_picker(&backend, PickerEvent::Open { reverse: true }).unwrap();
+        hook_data.run_picker(&backend, PickerEvent::Show).unwrap();
+        hook_data.run_picker(&backend, PickerEvent::Move(-1)).unwrap();
+        hook_data.run_picker(&backend, PickerEvent::Commit).unwrap();
+        let current = backend.foreground_layout();
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(US));
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), current);
+    }
+
+    #[test]
+    fn test_picker_with_two_layouts_cycles() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        hook_data.run_picker(&backend, PickerEvent::Open { reverse: false }).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+        hook_data.run_picker(&backend, PickerEvent::Show).unwrap();
+        hook_data.run_picker(&backend, PickerEvent::Commit).unwrap();
+        assert_eq!((backend.picker(), backend.foreground_layout()), (None, Some(DE)));
+    }
+
+    #[test]
+    fn test_switch_to_position() {
+        let backend = SimulatedBackend::with_layouts(&[US, DE, RU]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+
+        hook_data.switch_to_position(&backend, 3).unwrap();
+        assert_eq!((backend.foreground_layout(), hook_data.current_layout_index), (Some(RU), 2));
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be ON for Russian");
+
+        hook_data.switch_to_position(&backend, 1).unwrap();
+        assert_eq!((backend.foreground_layout(), hook_data.current_layout_index), (Some(US), 0));
+        assert!(!backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be OFF for English");
+
+        // Cycling goes on from the selected layout
+        hook_data.switch_to_next(&backend).unwrap();
+        assert_eq!(backend.foreground_layout(), Some(DE));
+
+        assert_eq!(hook_data.switch_to_position(&backend, 4), Err("No layout 4 in the selection (3 selected)".to_string()));
+        assert_eq!(backend.foreground_layout(), Some(DE));
+    }
+
+    #[test]
+    fn test_switch_with_no_layouts_does_nothing() {
+        let backend = SimulatedBackend::with_layouts(&[]);
+        let mut hook_data = HookData::new();
+        hook_data.initialize(&backend, &[], HomeLayout::English);
+        hook_data.switch_to_next(&backend).unwrap();
+        assert!(backend.injected_keys().is_empty());
+    }
+
+    #[test]
+    fn test_toggle_caps_lock_injects_caps() {
+        let backend = SimulatedBackend::new();
+        toggle_caps_lock(&backend);
+        assert!(backend.lock_key_state(LockKey::CapsLock), "Shift + Caps Lock should turn CapsLock on");
+        assert_eq!(backend.injected_keys(), vec![LockKey::CapsLock]);
+    }
+}
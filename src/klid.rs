@@ -0,0 +1,123 @@
+// Keyboard layout identifiers (KLIDs) and layout variants.
+//
+// A Windows HKL keeps the input language in its low word and the physical
+// layout in its high word. The high word is either the LANGID of the
+// layout (KLID 0000xxxx, e.g. 0x04520809 is "United Kingdom Extended" used
+// for English (UK)), or 0xFnnn, a "Layout Id" that has to be resolved to
+// its KLID through the registry (e.g. 0xF0020409 is US-Dvorak, KLID
+// 00010409). Layouts other than the default one for their language get a
+// variant name, which becomes part of their code ("us-dvorak").
+
+// KLID of an HKL whose high word names the layout directly.
+// Returns None for 0xFnnn layout ids, which need a registry lookup.
+pub fn klid_from_hkl(hkl: usize) -> Option<u32> {
+    let high_word = ((hkl >> 16) & 0xFFFF) as u32;
+    if high_word == 0 || high_word & 0xF000 == 0xF000 {
+        return None;
+    }
+    Some(high_word)
+}
+
+// Layout id of an HKL with a 0xFnnn high word
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn layout_id_from_hkl(hkl: usize) -> Option<u16> {
+    let high_word = ((hkl >> 16) & 0xFFFF) as u16;
+    (high_word & 0xF000 == 0xF000).then_some(high_word & 0x0FFF)
+}
+
+// Variant name of a layout, None for the default layout of the language
+pub fn klid_variant(klid: u32, lang_id: u16) -> Option<String> {
+    if klid == u32::from(lang_id) {
+        return None;
+    }
+
+    let variant = match klid {
+        // English
+        0x0001_0409 => "dvorak",
+        0x0002_0409 => "intl",
+        0x0003_0409 => "dvorak-left",
+        0x0004_0409 => "dvorak-right",
+        0x0000_0452 => "extended",
+        0x0001_1009 => "multilingual",
+
+        // Cyrillic languages
+        0x0001_0419 => "typewriter",
+        0x0002_0419 => "phonetic",
+        0x0002_0422 => "enhanced",
+        0x0001_0402 => "latin",
+        0x0002_0402 => "phonetic",
+        0x0003_0402 => "standard",
+        0x0004_0402 => "phonetic-traditional",
+
+        // Central and Eastern European
+        0x0001_0405 => "qwerty",
+        0x0002_0405 => "programmers",
+        0x0001_041b => "qwerty",
+        0x0001_0415 => "214",
+        0x0001_040e => "101",
+        0x0001_0418 => "standard",
+        0x0002_0418 => "programmers",
+        0x0001_0426 => "qwerty",
+        0x0002_0426 => "standard",
+        0x0002_0427 => "standard",
+
+        // Western European
+        0x0001_0407 => "ibm",
+        0x0001_0410 => "142",
+        0x0001_040a => "variation",
+        0x0001_0416 => "abnt2",
+        0x0001_041f => "f",
+
+        // Other
+        0x0001_0408 => "220",
+        0x0002_0408 => "319",
+        0x0003_0408 => "220-latin",
+        0x0004_0408 => "319-latin",
+        0x0005_0408 => "latin",
+        0x0006_0408 => "polytonic",
+        0x0002_040d => "standard",
+        0x0001_042f => "standard",
+        0x0001_0437 => "qwerty",
+        0x0002_0437 => "ergonomic",
+
+        // Some other language's default layout, e.g. French (Canada) on
+        // an English input language; name it after that language
+        klid if klid <= 0xFFFF => {
+            return Some(crate::locale::lookup(klid as u16)
+                .map(|locale| locale.code.to_string())
+                .unwrap_or_else(|| format!("{:08x}", klid)));
+        },
+        _ => return Some(format!("{:08x}", klid)),
+    };
+
+    Some(variant.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_klid_from_hkl() {
+        assert_eq!(klid_from_hkl(0x04090409), Some(0x0409));
+        assert_eq!(klid_from_hkl(0x04520809), Some(0x0452));
+        assert_eq!(klid_from_hkl(0xF0020409), None, "Layout ids need a registry lookup");
+        assert_eq!(klid_from_hkl(0x0409), None);
+    }
+
+    #[test]
+    fn test_layout_id_from_hkl() {
+        assert_eq!(layout_id_from_hkl(0xF0020409), Some(0x0002));
+        assert_eq!(layout_id_from_hkl(0x04090409), None);
+    }
+
+    #[test]
+    fn test_klid_variant() {
+        assert_eq!(klid_variant(0x0409, 0x0409), None, "Default layouts have no variant");
+        assert_eq!(klid_variant(0x0001_0409, 0x0409).as_deref(), Some("dvorak"));
+        assert_eq!(klid_variant(0x0002_0419, 0x0419).as_deref(), Some("phonetic"));
+        assert_eq!(klid_variant(0x0452, 0x0809).as_deref(), Some("extended"));
+        assert_eq!(klid_variant(0x0407, 0x0409).as_deref(), Some("de"), "Another language's layout");
+        assert_eq!(klid_variant(0x0009_0409, 0x0409).as_deref(), Some("00090409"));
+    }
+}
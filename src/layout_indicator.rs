@@ -1,109 +1,39 @@
-use std::ptr;
-use std::mem;
-use winapi::um::winuser::*;
-use winapi::shared::minwindef::HKL;
-use crate::keyboard_hook::CCAPS_EXTRA_INFO;
-
-// Function to check if given layout is English
-unsafe fn is_english_layout_hkl(layout: HKL) -> bool {
-    // Extract language ID from layout handle
-    // Lower 16 bits contain the language identifier
-    let lang_id = (layout as usize) & 0xFFFF;
-    
-    // English language IDs:
-    // 0x0409 - English (United States)
-    // 0x0809 - English (United Kingdom)
-    // 0x0c09 - English (Australia)
-    // 0x1009 - English (Canada)
-    // 0x1409 - English (New Zealand)
-    // 0x1809 - English (Ireland)
-    // 0x1c09 - English (South Africa)
-    // 0x2009 - English (Jamaica)
-    // 0x2409 - English (Caribbean)
-    // 0x2809 - English (Belize)
-    // 0x2c09 - English (Trinidad)
-    // 0x3009 - English (Zimbabwe)
-    // 0x3409 - English (Philippines)
-    match lang_id {
-        0x0409 | 0x0809 | 0x0c09 | 0x1009 | 0x1409 | 
-        0x1809 | 0x1c09 | 0x2009 | 0x2409 | 0x2809 | 
-        0x2c09 | 0x3009 | 0x3409 => true,
-        _ => false,
-    }
-}
+use crate::backend::{Backend, LockKey};
+use crate::layout_manager::{self, HomeLayout, LayoutInfo};
 
 // Function to set Scroll Lock state
-unsafe fn set_scroll_lock_state(enabled: bool) {
-    unsafe {
-        // Get current Scroll Lock state
-        let current_state = GetKeyState(VK_SCROLL) & 1;
-        let is_currently_on = current_state != 0;
-        
-        // Only change if state is different
-        if is_currently_on != enabled {
-            // Create input for toggling Scroll Lock
-            let mut inputs: [INPUT; 2] = mem::zeroed();
-            
-            // First INPUT - Scroll Lock press
-            inputs[0].type_ = INPUT_KEYBOARD;
-            inputs[0].u.ki_mut().wVk = VK_SCROLL as u16;
-            inputs[0].u.ki_mut().dwFlags = 0;
-            
-            // Second INPUT - Scroll Lock release
-            inputs[1].type_ = INPUT_KEYBOARD;
-            inputs[1].u.ki_mut().wVk = VK_SCROLL as u16;
-            inputs[1].u.ki_mut().dwFlags = KEYEVENTF_KEYUP;
-            
-            // Send press and release events
-            SendInput(2, inputs.as_mut_ptr(), mem::size_of::<INPUT>() as i32);
-        }
+fn set_scroll_lock_state(backend: &dyn Backend, enabled: bool) {
+    // Prefer a direct LED driver, it leaves the Scroll Lock key state alone
+    if backend.set_indicator_led(enabled) {
+        return;
     }
-}
-
-// Function to check if current layout is English
-unsafe fn is_english_layout() -> bool {
-    unsafe {
-        // Get active window
-        let hwnd = GetForegroundWindow();
-
-        // Get current layout handle
-        let current_layout = if hwnd.is_null() {
-            // Fallback: get layout for current thread if no foreground window
-            // This is more reliable during program startup
-            GetKeyboardLayout(0)
-        } else {
-            // Get window thread ID and its layout
-            let thread_id = GetWindowThreadProcessId(hwnd, ptr::null_mut());
-            GetKeyboardLayout(thread_id)
-        };
-
-        if current_layout.is_null() {
-            return false;
-        }
 
-        is_english_layout_hkl(current_layout)
+    // Only change if state is different
+    if backend.lock_key_state(LockKey::ScrollLock) != enabled {
+        // Toggle Scroll Lock with a press and release
+        backend.inject_key_tap(LockKey::ScrollLock);
     }
 }
 
+// Show a layout on the indicator.
+// Home layout: Scroll Lock OFF
+// Any other layout: Scroll Lock ON
+pub fn show_layout(backend: &dyn Backend, layout: &LayoutInfo, home: &HomeLayout) {
+    set_scroll_lock_state(backend, !home.contains(layout));
+}
+
 // Public function to update Scroll Lock indicator with specific layout
-pub unsafe fn update_layout_indicator_with_layout(layout: HKL) {
-    unsafe {
-        let is_english = is_english_layout_hkl(layout);
-        
-        // English layout: Scroll Lock OFF
-        // Non-English layout: Scroll Lock ON
-        set_scroll_lock_state(!is_english);
-    }
+pub fn update_layout_indicator_with_layout(backend: &dyn Backend, layout: usize) {
+    show_layout(backend, &LayoutInfo::from_handle(backend, layout), &layout_manager::home_layout());
 }
 
 // Public function to update Scroll Lock indicator based on current layout
-pub unsafe fn update_layout_indicator() {
-    unsafe {
-        let is_english = is_english_layout();
-
-        // English layout: Scroll Lock OFF
-        // Non-English layout: Scroll Lock ON
-        set_scroll_lock_state(!is_english);
+pub fn update_layout_indicator(backend: &dyn Backend) {
+    // The backend falls back to the current thread's layout if there is
+    // no foreground window, which is more reliable during program startup
+    match layout_manager::get_current_layout(backend) {
+        Some(current_layout) => show_layout(backend, &current_layout, &layout_manager::home_layout()),
+        None => set_scroll_lock_state(backend, true),
     }
 }
 
@@ -113,166 +43,174 @@ pub unsafe fn update_layout_indicator() {
 // Only sends key events if CapsLock is actually on, avoiding
 // unnecessary toggles that can cause LED desynchronization during
 // Windows startup due to unreliable SendInput timing.
-pub unsafe fn ensure_caps_lock_off() {
-    unsafe {
-        // Synchronize the thread's key state table before querying.
-        // GetKeyState() relies on the thread's internal key state buffer,
-        // which may be stale during Windows startup before the message loop runs.
-        // GetKeyboardState() forces a synchronization with the actual key states.
-        let mut key_state: [u8; 256] = [0; 256];
-        GetKeyboardState(key_state.as_mut_ptr());
-
-        let caps_state = GetKeyState(VK_CAPITAL) & 1;
-        if caps_state != 0 {
-            // CapsLock is on, toggle it off with a single press+release
-            let mut inputs: [INPUT; 2] = std::mem::zeroed();
-
-            inputs[0].type_ = INPUT_KEYBOARD;
-            inputs[0].u.ki_mut().wVk = VK_CAPITAL as u16;
-            inputs[0].u.ki_mut().dwFlags = 0;
-            inputs[0].u.ki_mut().dwExtraInfo = CCAPS_EXTRA_INFO;
-
-            inputs[1].type_ = INPUT_KEYBOARD;
-            inputs[1].u.ki_mut().wVk = VK_CAPITAL as u16;
-            inputs[1].u.ki_mut().dwFlags = KEYEVENTF_KEYUP;
-            inputs[1].u.ki_mut().dwExtraInfo = CCAPS_EXTRA_INFO;
-
-            SendInput(2, inputs.as_mut_ptr(), std::mem::size_of::<INPUT>() as i32);
-        }
+pub fn ensure_caps_lock_off(backend: &dyn Backend) {
+    // Synchronize the key state table before querying, it may be stale
+    // during startup before the message loop runs
+    backend.sync_key_state();
+
+    if backend.lock_key_state(LockKey::CapsLock) {
+        // CapsLock is on, toggle it off with a single press+release
+        backend.inject_key_tap(LockKey::CapsLock);
     }
 }
 
 // Public function to get current layout information (for debugging)
 #[allow(dead_code)]
-pub unsafe fn get_current_layout_info() -> (String, bool) {
-    unsafe {
-        let hwnd = GetForegroundWindow();
-        if hwnd.is_null() {
-            return ("Unknown".to_string(), false);
-        }
+pub fn get_current_layout_info(backend: &dyn Backend) -> (String, bool) {
+    let Some(current_layout) = layout_manager::get_current_layout(backend) else {
+        return ("Unknown".to_string(), false);
+    };
 
-        let thread_id = GetWindowThreadProcessId(hwnd, ptr::null_mut());
-        let current_layout = GetKeyboardLayout(thread_id);
-        let lang_id = (current_layout as usize) & 0xFFFF;
-        let is_english = is_english_layout_hkl(current_layout);
-
-        let layout_name = match lang_id {
-            0x0409 => "English (US)",
-            0x0809 => "English (UK)",
-            0x0419 => "Russian",
-            0x0422 => "Ukrainian",
-            0x0407 => "German",
-            0x040C => "French",
-            0x0410 => "Italian",
-            0x040A => "Spanish",
-            0x0415 => "Polish",
-            _ => "Other",
-        };
-
-        (format!("{} (0x{:04X})", layout_name, lang_id), is_english)
-    }
+    let is_home = layout_manager::home_layout().contains(&current_layout);
+
+    (format!("{} (0x{:04X})", current_layout.name, current_layout.lang_id), is_home)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::simulated::SimulatedBackend;
 
     // Helper function to create a fake HKL from a language ID
-    fn create_test_hkl(lang_id: usize) -> HKL {
-        lang_id as HKL
+    fn create_test_hkl(lang_id: usize) -> usize {
+        lang_id
+    }
+
+    fn is_english_layout_hkl(hkl: usize) -> bool {
+        HomeLayout::English.contains(&LayoutInfo::new(hkl))
     }
 
     #[test]
     fn test_english_us_layout_detection() {
-        unsafe {
-            let hkl = create_test_hkl(0x0409);
-            assert!(is_english_layout_hkl(hkl), "English (US) should be detected as English");
-        }
+        let hkl = create_test_hkl(0x0409);
+        assert!(is_english_layout_hkl(hkl), "English (US) should be detected as English");
     }
 
     #[test]
     fn test_english_uk_layout_detection() {
-        unsafe {
-            let hkl = create_test_hkl(0x0809);
-            assert!(is_english_layout_hkl(hkl), "English (UK) should be detected as English");
-        }
+        let hkl = create_test_hkl(0x0809);
+        assert!(is_english_layout_hkl(hkl), "English (UK) should be detected as English");
     }
 
     #[test]
     fn test_english_variants_detection() {
-        unsafe {
-            // Test various English language variants
-            let english_variants = vec![
-                (0x0409, "US"),
-                (0x0809, "UK"),
-                (0x0c09, "Australia"),
-                (0x1009, "Canada"),
-                (0x1409, "New Zealand"),
-                (0x1809, "Ireland"),
-                (0x1c09, "South Africa"),
-            ];
-
-            for (lang_id, country) in english_variants {
-                let hkl = create_test_hkl(lang_id);
-                assert!(
-                    is_english_layout_hkl(hkl),
-                    "English ({}) layout 0x{:04X} should be detected as English",
-                    country,
-                    lang_id
-                );
-            }
+        // Test various English language variants
+        let english_variants = vec![
+            (0x0409, "US"),
+            (0x0809, "UK"),
+            (0x0c09, "Australia"),
+            (0x1009, "Canada"),
+            (0x1409, "New Zealand"),
+            (0x1809, "Ireland"),
+            (0x1c09, "South Africa"),
+        ];
+
+        for (lang_id, country) in english_variants {
+            let hkl = create_test_hkl(lang_id);
+            assert!(
+                is_english_layout_hkl(hkl),
+                "English ({}) layout 0x{:04X} should be detected as English",
+                country,
+                lang_id
+            );
         }
     }
 
     #[test]
     fn test_non_english_layout_detection() {
-        unsafe {
-            // Test that non-English layouts are correctly identified
-            let non_english_layouts = vec![
-                (0x0419, "Russian"),
-                (0x0407, "German"),
-                (0x040c, "French"),
-                (0x0410, "Italian"),
-                (0x040a, "Spanish"),
-                (0x0415, "Polish"),
-                (0x0422, "Ukrainian"),
-            ];
-
-            for (lang_id, language) in non_english_layouts {
-                let hkl = create_test_hkl(lang_id);
-                assert!(
-                    !is_english_layout_hkl(hkl),
-                    "{} layout 0x{:04X} should NOT be detected as English",
-                    language,
-                    lang_id
-                );
-            }
+        // Test that non-English layouts are correctly identified
+        let non_english_layouts = vec![
+            (0x0419, "Russian"),
+            (0x0407, "German"),
+            (0x040c, "French"),
+            (0x0410, "Italian"),
+            (0x040a, "Spanish"),
+            (0x0415, "Polish"),
+            (0x0422, "Ukrainian"),
+        ];
+
+        for (lang_id, language) in non_english_layouts {
+            let hkl = create_test_hkl(lang_id);
+            assert!(
+                !is_english_layout_hkl(hkl),
+                "{} layout 0x{:04X} should NOT be detected as English",
+                language,
+                lang_id
+            );
         }
     }
 
     #[test]
     fn test_layout_hkl_language_id_extraction() {
-        unsafe {
-            // Test that language ID is correctly extracted from HKL
-            // HKL format: lower 16 bits = language ID, upper 16 bits = device handle
-
-            // Create HKL with device handle in upper bits
-            let lang_id = 0x0409; // English (US)
-            let device_handle = 0xABCD;
-            let hkl = ((device_handle << 16) | lang_id) as HKL;
-
-            // Language ID should still be correctly extracted
-            let extracted_lang_id = (hkl as usize) & 0xFFFF;
-            assert_eq!(extracted_lang_id, lang_id, "Language ID should be correctly extracted from HKL");
-            assert!(is_english_layout_hkl(hkl), "English layout should be detected even with device handle");
-        }
+        // Test that language ID is correctly extracted from HKL
+        // HKL format: lower 16 bits = language ID, upper 16 bits = device handle
+
+        // Create HKL with device handle in upper bits
+        let lang_id = 0x0409; // English (US)
+        let device_handle = 0xABCD;
+        let hkl = (device_handle << 16) | lang_id;
+
+        // Language ID should still be correctly extracted
+        let extracted_lang_id = hkl & 0xFFFF;
+        assert_eq!(extracted_lang_id, lang_id, "Language ID should be correctly extracted from HKL");
+        assert!(is_english_layout_hkl(hkl), "English layout should be detected even with device handle");
     }
 
     #[test]
     fn test_zero_hkl() {
-        unsafe {
-            let hkl = create_test_hkl(0x0000);
-            assert!(!is_english_layout_hkl(hkl), "Zero HKL should not be detected as English");
-        }
+        let hkl = create_test_hkl(0x0000);
+        assert!(!is_english_layout_hkl(hkl), "Zero HKL should not be detected as English");
+    }
+
+    #[test]
+    fn test_indicator_on_for_non_english_layout() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04190419]);
+        update_layout_indicator_with_layout(&backend, 0x04190419);
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be ON for Russian");
+        assert_eq!(backend.injected_keys(), vec![LockKey::ScrollLock]);
+    }
+
+    #[test]
+    fn test_indicator_not_toggled_when_already_in_sync() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04190419]);
+        update_layout_indicator(&backend);
+        assert!(!backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should stay OFF for English");
+        assert!(backend.injected_keys().is_empty(), "No key should be injected when the LED is already correct");
+    }
+
+    #[test]
+    fn test_indicator_follows_current_layout() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407]);
+        backend.set_lock_key_state(LockKey::ScrollLock, true);
+        update_layout_indicator(&backend);
+        assert!(!backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be turned OFF for English");
+
+        backend.set_current_layout(Some(0x04070407));
+        update_layout_indicator(&backend);
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be turned ON for German");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_indicator_off_for_configured_home_layout() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04220422, 0x04070407]);
+        let home = HomeLayout::Code("ua".to_string());
+
+        show_layout(&backend, &LayoutInfo::new(0x04090409), &home);
+        assert!(backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be ON for English when home is Ukrainian");
+
+        show_layout(&backend, &LayoutInfo::new(0x04220422), &home);
+        assert!(!backend.lock_key_state(LockKey::ScrollLock), "Scroll Lock should be OFF for the home layout");
+    }
+
+    #[test]
+    fn test_ensure_caps_lock_off_only_when_on() {
+        let backend = SimulatedBackend::new();
+        ensure_caps_lock_off(&backend);
+        assert!(backend.injected_keys().is_empty(), "CapsLock should not be toggled when already off");
+
+        backend.set_lock_key_state(LockKey::CapsLock, true);
+        ensure_caps_lock_off(&backend);
+        assert!(!backend.lock_key_state(LockKey::CapsLock), "CapsLock should be turned off");
+        assert_eq!(backend.injected_keys(), vec![LockKey::CapsLock]);
+    }
+}
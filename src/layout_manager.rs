@@ -1,94 +1,160 @@
-use std::ptr;
-use std::mem;
-use winapi::um::winuser::*;
-use winapi::shared::minwindef::{HKL, LPARAM};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::backend::Backend;
+use crate::locale;
+
+// Primary language ID shared by all English locales
+const LANG_ENGLISH: u16 = 0x09;
 
 #[derive(Debug, Clone)]
 pub struct LayoutInfo {
-    pub hkl: usize, // Changed from HKL to usize for Send + Sync
-    #[allow(dead_code)]
+    pub hkl: usize, // Platform layout handle (HKL on Windows), usize for Send + Sync
     pub lang_id: u32,
     pub name: String,
     pub short_code: String,
+    pub tag: String, // BCP-47 language tag, empty if unknown
+    pub language: String, // ISO 639 language, empty if unknown
+    pub region: String, // ISO 3166 region, empty if none
+    pub variant: Option<String>, // Layout variant, None for the language's default layout
     pub is_english: bool,
 }
 
 impl LayoutInfo {
-    pub fn new(hkl: HKL) -> Self {
-        let lang_id = (hkl as usize) & 0xFFFF;
-        let (name, short_code, is_english) = get_layout_details(lang_id as u32);
-        
+    // Default layout of the handle's language, ignoring any variant
+    #[cfg(test)]
+    pub fn new(hkl: usize) -> Self {
+        Self::with_variant(hkl, None)
+    }
+
+    // Layout of a language other than its default one, e.g. US-Dvorak:
+    // the variant is appended to the name and the code ("us-dvorak")
+    pub fn with_variant(hkl: usize, variant: Option<&str>) -> Self {
+        let lang_id = hkl & 0xFFFF;
+        let mut details = get_layout_details(lang_id as u32);
+
+        if let Some(variant) = variant {
+            details.name = format!("{} - {}", details.name, variant);
+            details.short_code = format!("{}-{}", details.short_code, variant);
+        }
+
         LayoutInfo {
-            hkl: hkl as usize, // Convert HKL to usize
+            hkl,
             lang_id: lang_id as u32,
-            name,
-            short_code,
-            is_english,
+            name: details.name,
+            short_code: details.short_code,
+            tag: details.tag.to_string(),
+            language: details.language.to_string(),
+            region: details.region.to_string(),
+            variant: variant.map(str::to_string),
+            is_english: locale::primary_language(lang_id as u16) == LANG_ENGLISH,
         }
     }
-    
-    pub fn get_hkl(&self) -> HKL {
-        self.hkl as HKL // Convert back to HKL when needed
-    }
-}
-
-pub fn get_all_keyboard_layouts() -> Vec<LayoutInfo> {
-    unsafe {
-        let mut layouts: [HKL; 20] = mem::zeroed();
-        let layout_count = GetKeyboardLayoutList(20, layouts.as_mut_ptr());
-        
-        let mut layout_infos = Vec::new();
-        for i in 0..layout_count as usize {
-            layout_infos.push(LayoutInfo::new(layouts[i]));
-        }
-        
-        // Sort layouts: English first, then alphabetically
-        layout_infos.sort_by(|a, b| {
-            match (a.is_english, b.is_english) {
-                (true, false) => std::cmp::Ordering::Less,
-                (false, true) => std::cmp::Ordering::Greater,
-                _ => a.name.cmp(&b.name),
-            }
-        });
-        
-        layout_infos
+
+    // Layout info for a backend layout handle, including its variant
+    pub fn from_handle(backend: &dyn Backend, hkl: usize) -> Self {
+        Self::with_variant(hkl, backend.layout_variant(hkl).as_deref())
     }
 }
 
-pub fn get_current_layout() -> Option<LayoutInfo> {
-    unsafe {
-        let hwnd = GetForegroundWindow();
-        if hwnd.is_null() {
-            // Fallback: get layout for current thread if no foreground window
-            return get_current_thread_layout();
+// The "home" layout: the Scroll Lock indicator shows it as OFF, it is added
+// when a single code is given and it is listed first. English by default,
+// configurable with `home_layout` in the config file or CCAPS_HOME_LAYOUT.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HomeLayout {
+    #[default]
+    English, // Any English layout
+    Code(String), // One layout, by its resolved layout code
+}
+
+impl HomeLayout {
+    // Resolve a configured code (any form accepted by -run) against the
+    // installed layouts; None keeps English
+    pub fn resolve(backend: &dyn Backend, code: Option<&str>) -> Result<Self, String> {
+        let Some(code) = code.map(str::trim).filter(|code| !code.is_empty()) else {
+            return Ok(HomeLayout::English);
+        };
+
+        let all_layouts = get_all_keyboard_layouts_with_home(backend, &HomeLayout::English);
+        match match_layout_code(&all_layouts, code) {
+            CodeMatch::Unique(layout) => Ok(HomeLayout::Code(layout.short_code.clone())),
+            CodeMatch::Ambiguous(candidates) => Err(format!(
+                "Ambiguous home layout '{}' matches several layouts: {}. Use one of these codes instead.",
+                code, describe_candidates(&candidates)
+            )),
+            CodeMatch::Unknown => Err(format!(
+                "Unknown home layout: {}. Use 'ccaps -status' to see available codes.", code
+            )),
         }
+    }
 
-        let thread_id = GetWindowThreadProcessId(hwnd, ptr::null_mut());
-        let current_layout = GetKeyboardLayout(thread_id);
+    // Whether a layout is the home layout. Every home layout decision
+    // (indicator, auto-added layout, sort order) goes through this.
+    pub fn contains(&self, layout: &LayoutInfo) -> bool {
+        match self {
+            HomeLayout::English => layout.is_english,
+            HomeLayout::Code(code) => layout.short_code == *code,
+        }
+    }
 
-        Some(LayoutInfo::new(current_layout))
+    // "English" or "ua (Ukrainian (Ukraine))", for status output
+    pub fn describe(&self, backend: &dyn Backend) -> String {
+        match self {
+            HomeLayout::English => "English".to_string(),
+            HomeLayout::Code(code) => match get_all_keyboard_layouts_with_home(backend, self).iter().find(|l| self.contains(l)) {
+                Some(layout) => format!("{} ({})", code, layout.name),
+                None => code.clone(),
+            },
+        }
     }
 }
 
-pub fn get_current_thread_layout() -> Option<LayoutInfo> {
-    unsafe {
-        // Get keyboard layout for the current thread (0 = calling thread)
-        // This works reliably even at startup when there's no foreground window
-        let current_layout = GetKeyboardLayout(0);
-        if current_layout.is_null() {
-            return None;
-        }
+// Home layout of this process, set once at startup
+static HOME_LAYOUT: Mutex<HomeLayout> = Mutex::new(HomeLayout::English);
 
-        Some(LayoutInfo::new(current_layout))
+pub fn set_home_layout(home: HomeLayout) {
+    if let Ok(mut current) = HOME_LAYOUT.lock() {
+        *current = home;
     }
 }
 
-pub fn find_layouts_by_codes(codes: &[&str]) -> Vec<LayoutInfo> {
-    let all_layouts = get_all_keyboard_layouts();
+pub fn home_layout() -> HomeLayout {
+    HOME_LAYOUT.lock().map(|home| home.clone()).unwrap_or_default()
+}
+
+pub fn get_all_keyboard_layouts(backend: &dyn Backend) -> Vec<LayoutInfo> {
+    get_all_keyboard_layouts_with_home(backend, &home_layout())
+}
+
+pub fn get_all_keyboard_layouts_with_home(backend: &dyn Backend, home: &HomeLayout) -> Vec<LayoutInfo> {
+    let mut layout_infos: Vec<LayoutInfo> = backend.layout_handles()
+        .into_iter()
+        .map(|hkl| LayoutInfo::from_handle(backend, hkl))
+        .collect();
+    
+    // Sort layouts: home layout first, then alphabetically
+    layout_infos.sort_by(|a, b| {
+        match (home.contains(a), home.contains(b)) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            _ => a.name.cmp(&b.name),
+        }
+    });
+    
+    layout_infos
+}
+
+pub fn get_current_layout(backend: &dyn Backend) -> Option<LayoutInfo> {
+    backend.foreground_layout().map(|hkl| LayoutInfo::from_handle(backend, hkl))
+}
+
+pub fn find_layouts_by_codes(backend: &dyn Backend, codes: &[&str]) -> Vec<LayoutInfo> {
+    let all_layouts = get_all_keyboard_layouts_with_home(backend, &HomeLayout::English);
     let mut selected_layouts = Vec::new();
     
     for code in codes {
-        if let Some(layout) = all_layouts.iter().find(|l| l.short_code == *code) {
+        if let CodeMatch::Unique(layout) = match_layout_code(&all_layouts, code) {
             selected_layouts.push(layout.clone());
         }
     }
@@ -96,132 +162,651 @@ pub fn find_layouts_by_codes(codes: &[&str]) -> Vec<LayoutInfo> {
     selected_layouts
 }
 
-pub fn get_english_layout() -> Option<LayoutInfo> {
-    let all_layouts = get_all_keyboard_layouts();
-    all_layouts.into_iter().find(|l| l.is_english)
-}
-
-pub fn switch_to_layout(layout: &LayoutInfo) {
-    unsafe {
-        let hwnd = GetForegroundWindow();
-        if hwnd.is_null() {
-            return;
-        }
-        
-        let hkl = layout.get_hkl();
-        
-        // Activate new layout
-        ActivateKeyboardLayout(hkl, 0);
-        
-        // Send layout change message to all windows
-        PostMessageW(
-            HWND_BROADCAST,
-            WM_INPUTLANGCHANGEREQUEST,
-            0,
-            hkl as LPARAM,
-        );
-    }
-}
-
-fn get_layout_details(lang_id: u32) -> (String, String, bool) {
-    match lang_id {
-        // English variants
-        0x0409 => ("English (United States)".to_string(), "us".to_string(), true),
-        0x0809 => ("English (United Kingdom)".to_string(), "gb".to_string(), true),
-        0x0c09 => ("English (Australia)".to_string(), "au".to_string(), true),
-        0x1009 => ("English (Canada)".to_string(), "ca".to_string(), true),
-        0x1409 => ("English (New Zealand)".to_string(), "nz".to_string(), true),
-        0x1809 => ("English (Ireland)".to_string(), "ie".to_string(), true),
-        0x1c09 => ("English (South Africa)".to_string(), "za".to_string(), true),
-        
-        // Cyrillic languages
-        0x0419 => ("Russian".to_string(), "ru".to_string(), false),
-        0x0422 => ("Ukrainian".to_string(), "ua".to_string(), false),
-        0x0423 => ("Belarusian".to_string(), "by".to_string(), false),
-        0x0402 => ("Bulgarian".to_string(), "bg".to_string(), false),
-        0x041a => ("Croatian".to_string(), "hr".to_string(), false),
-        0x0405 => ("Czech".to_string(), "cz".to_string(), false),
-        0x081a => ("Serbian (Latin)".to_string(), "rs".to_string(), false),
-        0x0c1a => ("Serbian (Cyrillic)".to_string(), "sr".to_string(), false),
-        0x041f => ("Turkish".to_string(), "tr".to_string(), false),
-        
-        // Western European
-        0x0407 => ("German".to_string(), "de".to_string(), false),
-        0x040c => ("French".to_string(), "fr".to_string(), false),
-        0x0410 => ("Italian".to_string(), "it".to_string(), false),
-        0x040a => ("Spanish".to_string(), "es".to_string(), false),
-        0x0413 => ("Dutch".to_string(), "nl".to_string(), false),
-        0x0414 => ("Norwegian".to_string(), "no".to_string(), false),
-        0x041d => ("Swedish".to_string(), "se".to_string(), false),
-        0x0406 => ("Danish".to_string(), "dk".to_string(), false),
-        0x040b => ("Finnish".to_string(), "fi".to_string(), false),
-        0x0816 => ("Portuguese".to_string(), "pt".to_string(), false),
-        0x0416 => ("Portuguese (Brazil)".to_string(), "br".to_string(), false),
-        
-        // Eastern European
-        0x0415 => ("Polish".to_string(), "pl".to_string(), false),
-        0x040e => ("Hungarian".to_string(), "hu".to_string(), false),
-        0x0418 => ("Romanian".to_string(), "ro".to_string(), false),
-        0x041b => ("Slovak".to_string(), "sk".to_string(), false),
-        0x0424 => ("Slovenian".to_string(), "si".to_string(), false),
-        0x0425 => ("Estonian".to_string(), "ee".to_string(), false),
-        0x0426 => ("Latvian".to_string(), "lv".to_string(), false),
-        0x0427 => ("Lithuanian".to_string(), "lt".to_string(), false),
-        
-        // Asian languages
-        0x0411 => ("Japanese".to_string(), "jp".to_string(), false),
-        0x0412 => ("Korean".to_string(), "kr".to_string(), false),
-        0x0404 => ("Chinese (Traditional)".to_string(), "tw".to_string(), false),
-        0x0804 => ("Chinese (Simplified)".to_string(), "cn".to_string(), false),
-        0x041e => ("Thai".to_string(), "th".to_string(), false),
-        0x042a => ("Vietnamese".to_string(), "vn".to_string(), false),
-        
-        // Middle Eastern
-        0x040d => ("Hebrew".to_string(), "he".to_string(), false),
-        0x0401 => ("Arabic".to_string(), "ar".to_string(), false),
-        0x0429 => ("Farsi".to_string(), "fa".to_string(), false),
-        
-        // Other
-        0x040f => ("Icelandic".to_string(), "is".to_string(), false),
-        0x0408 => ("Greek".to_string(), "gr".to_string(), false),
-        0x041c => ("Albanian".to_string(), "al".to_string(), false),
-        0x042f => ("Macedonian".to_string(), "mk".to_string(), false),
-        
-        // Default case
-        _ => {
-            // Try to determine if it's English based on primary language
-            let primary_lang = lang_id & 0x3FF;
-            let is_english = primary_lang == 0x09; // LANG_ENGLISH
-            let name = format!("Unknown Language (0x{:04X})", lang_id);
-            let code = format!("{:02x}", (lang_id & 0xFF) as u8);
-            (name, code, is_english)
-        }
-    }
-}
-
-pub fn validate_country_codes(codes: &[&str]) -> Result<Vec<String>, String> {
-    let all_layouts = get_all_keyboard_layouts();
+pub fn get_home_layout(backend: &dyn Backend, home: &HomeLayout) -> Option<LayoutInfo> {
+    let all_layouts = get_all_keyboard_layouts_with_home(backend, home);
+    all_layouts.into_iter().find(|l| home.contains(l))
+}
+
+// Attempts at switching layout, each with the backend's next way of
+// delivering the request
+const SWITCH_ATTEMPTS: usize = 3;
+
+// How long the foreground application gets to apply a switch before it is
+// checked. Checks run off the keyboard hook, see `Backend::schedule_switch_check`.
+pub const SWITCH_CONFIRM_DELAY: Duration = Duration::from_millis(50);
+
+// A layout switch that was requested but not confirmed yet
+#[derive(Debug, Clone)]
+pub struct PendingSwitch {
+    pub layout: LayoutInfo,
+    attempt: usize,
+    // Why the last request could not be delivered
+    error: Option<String>,
+    due: Instant,
+}
+
+impl PendingSwitch {
+    // Time left until the switch should be checked, None once it is due
+    pub fn remaining(&self) -> Option<Duration> {
+        let remaining = self.due.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    // Make the switch due for checking right away
+    #[cfg(test)]
+    pub fn expire(&mut self) {
+        self.due = Instant::now();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwitchCheck {
+    // Requested again, check again later
+    Retrying,
+    Done,
+    Failed(String),
+}
+
+// Ask the foreground application to switch layout. Returns at once; the
+// switch is confirmed later with `check_switch`.
+pub fn request_switch(backend: &dyn Backend, layout: &LayoutInfo) -> PendingSwitch {
+    PendingSwitch {
+        layout: layout.clone(),
+        attempt: 0,
+        error: backend.activate_layout(layout.hkl, 0).err(),
+        due: Instant::now() + SWITCH_CONFIRM_DELAY,
+    }
+}
+
+// Check whether the foreground application took a requested layout. If it
+// did not, the request is repeated with the backend's next way of
+// delivering it, until the attempts run out.
+pub fn check_switch(backend: &dyn Backend, pending: &mut PendingSwitch) -> SwitchCheck {
+    if backend.foreground_layout() == Some(pending.layout.hkl) {
+        return SwitchCheck::Done;
+    }
+
+    let error = pending.error.take()
+        .unwrap_or_else(|| "the foreground application ignored the request".to_string());
+    pending.attempt += 1;
+    if pending.attempt >= SWITCH_ATTEMPTS {
+        return SwitchCheck::Failed(format!(
+            "Could not switch to {} ({}): {}",
+            pending.layout.name, pending.layout.short_code, error
+        ));
+    }
+
+    pending.error = backend.activate_layout(pending.layout.hkl, pending.attempt).err();
+    pending.due = Instant::now() + SWITCH_CONFIRM_DELAY;
+    SwitchCheck::Retrying
+}
+
+struct LayoutDetails {
+    name: String,
+    short_code: String,
+    tag: &'static str,
+    language: &'static str,
+    region: &'static str,
+}
+
+// Name, CLI code and locale of a LANGID, from the locale catalog
+fn get_layout_details(lang_id: u32) -> LayoutDetails {
+    let lang_id = lang_id as u16;
+
+    if let Some(locale) = locale::lookup(lang_id) {
+        return LayoutDetails {
+            name: locale.name.to_string(),
+            short_code: locale.code.to_string(),
+            tag: locale.tag,
+            language: locale.language,
+            region: locale.region,
+        };
+    }
+
+    // Regional variant missing from the catalog: name it after its language
+    // and give it a code that is still unique, e.g. "en-5c09"
+    if let Some(locale) = locale::lookup_primary(lang_id) {
+        let language = locale.name.split(" (").next().unwrap_or(locale.name);
+        return LayoutDetails {
+            name: format!("{} (0x{:04X})", language, lang_id),
+            short_code: format!("{}-{:04x}", locale.language, lang_id),
+            tag: locale.language,
+            language: locale.language,
+            region: "",
+        };
+    }
+
+    LayoutDetails {
+        name: format!("Unknown Language (0x{:04X})", lang_id),
+        short_code: format!("{:04x}", lang_id),
+        tag: "",
+        language: "",
+        region: "",
+    }
+}
+
+// How a code given on the command line matched the installed layouts
+#[derive(Debug)]
+enum CodeMatch<'a> {
+    Unique(&'a LayoutInfo),
+    Ambiguous(Vec<&'a LayoutInfo>),
+    Unknown,
+}
+
+// Several layouts fit: pick the language's default layout if it is the only
+// one without a variant (en-US over en-US Dvorak), otherwise it's ambiguous
+fn pick_candidate(candidates: Vec<&LayoutInfo>) -> CodeMatch<'_> {
+    let defaults: Vec<&LayoutInfo> = candidates.iter().copied().filter(|l| l.variant.is_none()).collect();
+    match (candidates.len(), defaults.len()) {
+        (0, _) => CodeMatch::Unknown,
+        (1, _) => CodeMatch::Unique(candidates[0]),
+        (_, 1) => CodeMatch::Unique(defaults[0]),
+        _ => CodeMatch::Ambiguous(candidates),
+    }
+}
+
+// Match a code against the layouts. Accepted forms, case-insensitive:
+// - layout codes as shown by -status ("us", "de-ch", "ru-phonetic")
+// - BCP-47 tags, optionally with a variant ("en-CA", "sv-SE", "en-US-dvorak")
+// - ISO 639 languages ("uk", "sv") and ISO 3166 regions ("il")
+// A legacy country code that is also another layout's language ("ca" for
+// English (Canada) vs Catalan, "se" for Swedish vs Northern Sami) is ambiguous.
+fn match_layout_code<'a>(layouts: &'a [LayoutInfo], code: &str) -> CodeMatch<'a> {
+    let code = code.trim().replace('_', "-").to_lowercase();
+
+    let by_code: Vec<&LayoutInfo> = layouts.iter().filter(|l| l.short_code == code).collect();
+    if !by_code.is_empty() {
+        let by_language: Vec<&LayoutInfo> = layouts.iter()
+            .filter(|l| l.language == code && !by_code.iter().any(|c| c.hkl == l.hkl))
+            .collect();
+        if by_language.is_empty() || by_code.iter().all(|l| l.language == code) {
+            return pick_candidate(by_code);
+        }
+        return CodeMatch::Ambiguous(by_code.into_iter().chain(by_language).collect());
+    }
+
+    let by_tag: Vec<&LayoutInfo> = layouts.iter()
+        .filter(|l| {
+            let tag = l.tag.to_lowercase();
+            tag == code || l.variant.as_ref().is_some_and(|variant| format!("{}-{}", tag, variant) == code)
+        })
+        .collect();
+    if !by_tag.is_empty() {
+        return pick_candidate(by_tag);
+    }
+
+    let by_language: Vec<&LayoutInfo> = layouts.iter().filter(|l| l.language == code).collect();
+    if !by_language.is_empty() {
+        return pick_candidate(by_language);
+    }
+
+    pick_candidate(layouts.iter().filter(|l| l.region.to_lowercase() == code).collect())
+}
+
+// "ca (English (Canada), en-CA), ca-es (Catalan (Spain), ca-ES)"
+fn describe_candidates(candidates: &[&LayoutInfo]) -> String {
+    candidates.iter()
+        .map(|l| format!("{} ({}, {})", l.short_code, l.name, l.tag))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Edit distance between two strings, counted in characters
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// Words a layout can be looked up by: its code, tag, English and native
+// language names
+fn suggestion_keys(layout: &LayoutInfo) -> Vec<String> {
+    let mut keys = vec![layout.short_code.clone(), layout.tag.to_lowercase()];
+    if let Some(locale) = locale::lookup(layout.lang_id as u16) {
+        keys.push(locale.language_name().to_lowercase());
+        keys.push(locale.name.to_lowercase());
+    }
+    if let Some(native) = locale::native_name(&layout.language) {
+        keys.push(native.to_lowercase());
+    }
+    keys.retain(|key| !key.is_empty());
+    keys
+}
+
+// Closest layout to a code that matched nothing, by edit distance against
+// the suggestion keys. A key starting with the input counts as one edit
+// ("germ" -> German). Short inputs allow fewer edits, so "zz" suggests nothing.
+fn closest_layout<'a>(layouts: &'a [LayoutInfo], code: &str) -> Option<&'a LayoutInfo> {
+    let code = code.trim().replace('_', "-").to_lowercase();
+    let max_distance = match code.chars().count() {
+        0 => return None,
+        1..=4 => 1,
+        5..=8 => 2,
+        _ => 3,
+    };
+
+    layouts.iter()
+        .filter_map(|layout| {
+            let distance = suggestion_keys(layout).iter()
+                .map(|key| match edit_distance(&code, key) {
+                    0 => 0,
+                    _ if code.chars().count() >= 3 && key.starts_with(&code) => 1,
+                    distance => distance,
+                })
+                .min()?;
+            (distance <= max_distance).then_some((distance, layout))
+        })
+        // Keep the first layout of equally close ones, the home layout comes first
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, layout)| layout)
+}
+
+// Suggested layout for an unknown code, e.g. "de" for "germn" or "deutsch"
+pub fn suggest_layout_code(backend: &dyn Backend, code: &str) -> Option<LayoutInfo> {
+    let all_layouts = get_all_keyboard_layouts(backend);
+    if !matches!(match_layout_code(&all_layouts, code), CodeMatch::Unknown) {
+        return None;
+    }
+    closest_layout(&all_layouts, code).cloned()
+}
+
+// Layout an alias from the config file stands for. The alias may not
+// shadow a layout code and its target must be an installed layout.
+pub fn resolve_alias(backend: &dyn Backend, name: &str, target: &str) -> Result<LayoutInfo, String> {
+    let all_layouts = get_all_keyboard_layouts_with_home(backend, &HomeLayout::English);
+    check_alias(&all_layouts, name, target).cloned()
+}
+
+fn check_alias<'a>(layouts: &'a [LayoutInfo], name: &str, target: &str) -> Result<&'a LayoutInfo, String> {
+    let normalized = name.trim().replace('_', "-").to_lowercase();
+    let shadowed = match match_layout_code(layouts, &normalized) {
+        CodeMatch::Unique(layout) => Some(layout.short_code.as_str()),
+        CodeMatch::Ambiguous(candidates) => Some(candidates[0].short_code.as_str()),
+        CodeMatch::Unknown => locale::LOCALES.iter()
+            .find(|locale| locale.code == normalized || locale.tag.eq_ignore_ascii_case(&normalized))
+            .map(|locale| locale.code),
+    };
+    if let Some(code) = shadowed {
+        return Err(format!("Alias '{}' shadows the layout code '{}'. Rename the alias in the config file.", name, code));
+    }
+
+    match match_layout_code(layouts, target) {
+        CodeMatch::Unique(layout) => Ok(layout),
+        CodeMatch::Ambiguous(candidates) => Err(format!(
+            "Alias '{}' points at '{}', which matches several layouts: {}. Use one of these codes instead.",
+            name, target, describe_candidates(&candidates)
+        )),
+        CodeMatch::Unknown => Err(format!(
+            "Alias '{}' points at '{}', which is not an installed layout. Use 'ccaps -status' to see available codes.",
+            name, target
+        )),
+    }
+}
+
+// Replace aliases (config "aliases", e.g. "work": "de-CH") with the codes
+// of their layouts. Codes that are not aliases are kept as they are.
+pub fn expand_aliases(backend: &dyn Backend, codes: &[String], aliases: &BTreeMap<String, String>) -> Result<Vec<String>, String> {
+    if aliases.is_empty() {
+        return Ok(codes.to_vec());
+    }
+
+    let all_layouts = get_all_keyboard_layouts_with_home(backend, &HomeLayout::English);
+    codes.iter()
+        .map(|code| match aliases.iter().find(|(name, _)| name.eq_ignore_ascii_case(code.trim())) {
+            Some((name, target)) => check_alias(&all_layouts, name, target).map(|layout| layout.short_code.clone()),
+            None => Ok(code.clone()),
+        })
+        .collect()
+}
+
+// Outcome of `ccaps -layouts add/remove` for one code
+#[derive(Debug, Clone)]
+pub enum LayoutChange {
+    Added(LayoutInfo),
+    AlreadyInstalled(LayoutInfo),
+    Removed(LayoutInfo),
+}
+
+// Install the layout for a code. Codes are looked up in the locale catalog,
+// since the layout is not installed yet ("de", "de-CH", "uk").
+pub fn add_layout(backend: &dyn Backend, code: &str) -> Result<LayoutChange, String> {
+    let all_layouts = get_all_keyboard_layouts_with_home(backend, &HomeLayout::English);
+    if let CodeMatch::Unique(layout) = match_layout_code(&all_layouts, code) {
+        return Ok(LayoutChange::AlreadyInstalled(layout.clone()));
+    }
+
+    let locale = locale::find(code)
+        .ok_or_else(|| format!("Unknown layout code '{}'. Use a code or tag such as 'de' or 'de-CH'.", code))?;
+    let handle = backend.add_layout(locale.lang_id)
+        .map_err(|e| format!("Cannot add {} ({}): {}", locale.code, locale.name, e))?;
+    Ok(LayoutChange::Added(LayoutInfo::from_handle(backend, handle)))
+}
+
+// Remove the installed layout for a code
+pub fn remove_layout(backend: &dyn Backend, code: &str) -> Result<LayoutChange, String> {
+    let all_layouts = get_all_keyboard_layouts_with_home(backend, &HomeLayout::English);
+    let layout = match match_layout_code(&all_layouts, code) {
+        CodeMatch::Unique(layout) => layout,
+        CodeMatch::Ambiguous(candidates) => return Err(format!(
+            "Ambiguous code '{}' matches several layouts: {}. Use one of these codes instead.",
+            code, describe_candidates(&candidates)
+        )),
+        CodeMatch::Unknown => return Err(format!(
+            "No installed layout matches '{}'. Use 'ccaps -status' to see installed layouts.", code
+        )),
+    };
+
+    backend.remove_layout(layout.hkl)
+        .map_err(|e| format!("Cannot remove {} ({}): {}", layout.short_code, layout.name, e))?;
+    Ok(LayoutChange::Removed(layout.clone()))
+}
+
+// Check codes against the installed layouts. Returns the matching layout codes.
+pub fn validate_country_codes(backend: &dyn Backend, codes: &[&str]) -> Result<Vec<String>, String> {
+    let all_layouts = get_all_keyboard_layouts_with_home(backend, &HomeLayout::English);
     let mut valid_codes = Vec::new();
     let mut invalid_codes = Vec::new();
-    
+    let mut suggestions = Vec::new();
+    let mut ambiguous = Vec::new();
+
     for code in codes {
-        if all_layouts.iter().any(|l| l.short_code == *code) {
-            valid_codes.push(code.to_string());
-        } else {
-            invalid_codes.push(code.to_string());
+        match match_layout_code(&all_layouts, code) {
+            CodeMatch::Unique(layout) => valid_codes.push(layout.short_code.clone()),
+            CodeMatch::Ambiguous(candidates) => ambiguous.push(format!(
+                "Ambiguous code '{}' matches several layouts: {}. Use one of these codes instead.",
+                code, describe_candidates(&candidates)
+            )),
+            CodeMatch::Unknown => {
+                invalid_codes.push(code.to_string());
+                if let Some(layout) = closest_layout(&all_layouts, code) {
+                    suggestions.push(format!("Did you mean '{}' ({}) instead of '{}'?", layout.short_code, layout.name, code));
+                }
+            },
         }
     }
-    
+
+    let mut errors = Vec::new();
     if !invalid_codes.is_empty() {
-        return Err(format!(
+        errors.push(format!(
             "Unknown country codes: {}. Use 'ccaps -status' to see available codes.",
             invalid_codes.join(", ")
         ));
     }
-    
+    errors.extend(suggestions);
+    errors.extend(ambiguous);
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
     if valid_codes.is_empty() {
         return Err("No valid country codes provided.".to_string());
     }
-    
+
     Ok(valid_codes)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::simulated::SimulatedBackend;
+
+    #[test]
+    fn test_layout_info_from_catalog() {
+        let georgian = LayoutInfo::new(0x04370437);
+        assert_eq!((georgian.name.as_str(), georgian.short_code.as_str(), georgian.tag.as_str()),
+                   ("Georgian (Georgia)", "ge", "ka-GE"));
+        assert!(!georgian.is_english);
+
+        let german = LayoutInfo::new(0x04070407);
+        assert_eq!((german.short_code.as_str(), german.tag.as_str()), ("de", "de-DE"));
+        assert!(LayoutInfo::new(0x08090809).is_english);
+    }
+
+    #[test]
+    fn test_variants_are_separate_layouts() {
+        let us = LayoutInfo::new(0x04090409);
+        let dvorak = LayoutInfo::with_variant(0xF0020409, Some("dvorak"));
+        assert_eq!(dvorak.short_code, "us-dvorak");
+        assert_eq!(dvorak.name, "English (United States) - dvorak");
+        assert_eq!(dvorak.tag, us.tag);
+        assert!(dvorak.is_english);
+
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0xF0020409, 0x04190419, 0xF0030419]);
+        backend.set_layout_variant(0xF0020409, "dvorak");
+        backend.set_layout_variant(0xF0030419, "phonetic");
+
+        let codes: Vec<String> = get_all_keyboard_layouts(&backend).into_iter().map(|l| l.short_code).collect();
+        assert_eq!(codes, vec!["us", "us-dvorak", "ru", "ru-phonetic"]);
+
+        let selected = find_layouts_by_codes(&backend, &["ru-phonetic", "us-dvorak"]);
+        assert_eq!(selected.iter().map(|l| l.hkl).collect::<Vec<_>>(), vec![0xF0030419, 0xF0020409]);
+        assert!(validate_country_codes(&backend, &["ru", "ru-phonetic"]).is_ok());
+    }
+
+    fn codes(backend: &SimulatedBackend, input: &[&str]) -> Result<Vec<String>, String> {
+        validate_country_codes(backend, input)
+    }
+
+    #[test]
+    fn test_tags_and_legacy_codes() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x10091009, 0x041d041d, 0x04220422, 0x040d040d]);
+
+        assert_eq!(codes(&backend, &["ca", "en-CA", "EN_ca"]), Ok(vec!["ca".to_string(); 3]));
+        assert_eq!(codes(&backend, &["se", "sv-SE", "sv"]), Ok(vec!["se".to_string(); 3]));
+        assert_eq!(codes(&backend, &["ua", "uk-UA", "uk"]), Ok(vec!["ua".to_string(); 3]));
+        assert_eq!(codes(&backend, &["he", "he-IL", "il"]), Ok(vec!["he".to_string(); 3]));
+        assert!(codes(&backend, &["fr-FR"]).unwrap_err().starts_with("Unknown country codes: fr-FR."));
+    }
+
+    #[test]
+    fn test_ambiguous_codes_list_candidates() {
+        // English (Canada) and Catalan: "ca" is a country code and a language
+        let backend = SimulatedBackend::with_layouts(&[0x10091009, 0x04030403, 0x04090409, 0x08090809]);
+
+        let error = codes(&backend, &["ca"]).unwrap_err();
+        assert!(error.contains("Ambiguous code 'ca'"), "{}", error);
+        assert!(error.contains("ca (English (Canada), en-CA)"), "{}", error);
+        assert!(error.contains("ca-es (Catalan (Spain), ca-ES)"), "{}", error);
+
+        // Full tags are never ambiguous
+        assert_eq!(codes(&backend, &["en-CA", "ca-ES"]), Ok(vec!["ca".to_string(), "ca-es".to_string()]));
+
+        // "en" fits three English layouts
+        let error = codes(&backend, &["en"]).unwrap_err();
+        assert!(error.contains("us (English (United States), en-US)"), "{}", error);
+        assert!(find_layouts_by_codes(&backend, &["en", "us"]).iter().map(|l| l.hkl).eq([0x04090409]));
+    }
+
+    #[test]
+    fn test_tag_prefers_default_layout_over_variants() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0xF0020409, 0x04070407]);
+        backend.set_layout_variant(0xF0020409, "dvorak");
+
+        assert_eq!(codes(&backend, &["en-US", "en-US-dvorak", "de"]),
+                   Ok(vec!["us".to_string(), "us-dvorak".to_string(), "de".to_string()]));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("german", "german"), 0);
+        assert_eq!(edit_distance("germn", "german"), 1);
+        assert_eq!(edit_distance("deutsh", "deutsch"), 1);
+        assert_eq!(edit_distance("zz", "de"), 2);
+        assert_eq!(edit_distance("", "us"), 2);
+        assert_eq!(edit_distance("руский", "русский"), 1);
+    }
+
+    #[test]
+    fn test_suggestions_for_unknown_codes() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407, 0x04190419, 0x04220422, 0x040c040c]);
+        let suggest = |code: &str| suggest_layout_code(&backend, code).map(|l| l.short_code);
+
+        // Codes and tags
+        assert_eq!(suggest("dee").as_deref(), Some("de"));
+        assert_eq!(suggest("ru-RUS").as_deref(), Some("ru"));
+        // English and native language names
+        assert_eq!(suggest("german").as_deref(), Some("de"));
+        assert_eq!(suggest("germ").as_deref(), Some("de"));
+        assert_eq!(suggest("deutsch").as_deref(), Some("de"));
+        assert_eq!(suggest("francais").as_deref(), Some("fr"));
+        assert_eq!(suggest("українська").as_deref(), Some("ua"));
+        assert_eq!(suggest("ukrainan").as_deref(), Some("ua"));
+        // Nothing close, or the code is fine as it is
+        assert_eq!(suggest("zz"), None);
+        assert_eq!(suggest("de"), None);
+
+        let error = validate_country_codes(&backend, &["germn", "zz"]).unwrap_err();
+        assert_eq!(error, "Unknown country codes: germn, zz. Use 'ccaps -status' to see available codes.\n\
+                           Did you mean 'de' (German (Germany)) instead of 'germn'?");
+    }
+
+    #[test]
+    fn test_aliases() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x08070807, 0x04220422, 0x10091009, 0x04030403]);
+        let aliases: BTreeMap<String, String> = [("work", "de-CH"), ("Home", "uk")].iter()
+            .map(|(name, target)| (name.to_string(), target.to_string()))
+            .collect();
+
+        let expanded = expand_aliases(&backend, &["work".to_string(), "home".to_string(), "us".to_string()], &aliases);
+        assert_eq!(expanded, Ok(vec!["de-ch".to_string(), "ua".to_string(), "us".to_string()]));
+        assert_eq!(resolve_alias(&backend, "work", "de-CH").map(|l| l.hkl), Ok(0x08070807));
+
+        // Aliases may not shadow codes, tags or languages, even of layouts that aren't installed
+        for name in ["us", "en-US", "de", "uk", "fr"] {
+            let error = resolve_alias(&backend, name, "de-CH").unwrap_err();
+            assert!(error.starts_with(&format!("Alias '{}' shadows the layout code", name)), "{}", error);
+        }
+
+        let error = resolve_alias(&backend, "office", "fr-FR").unwrap_err();
+        assert!(error.contains("which is not an installed layout"), "{}", error);
+        let error = resolve_alias(&backend, "office", "ca").unwrap_err();
+        assert!(error.contains("matches several layouts"), "{}", error);
+
+        let bad: BTreeMap<String, String> = [("office".to_string(), "fr".to_string())].into();
+        assert!(expand_aliases(&backend, &["office".to_string()], &bad).is_err());
+        assert_eq!(expand_aliases(&backend, &["us".to_string()], &bad), Ok(vec!["us".to_string()]), "Unused aliases are not checked");
+    }
+
+    #[test]
+    fn test_add_and_remove_layouts() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407]);
+
+        match add_layout(&backend, "de-CH") {
+            Ok(LayoutChange::Added(layout)) => assert_eq!(layout.short_code, "de-ch"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(matches!(add_layout(&backend, "de"), Ok(LayoutChange::AlreadyInstalled(_))));
+        assert!(add_layout(&backend, "zz").unwrap_err().starts_with("Unknown layout code 'zz'"));
+        assert!(validate_country_codes(&backend, &["de-ch"]).is_ok());
+
+        match remove_layout(&backend, "de") {
+            Ok(LayoutChange::Removed(layout)) => assert_eq!(layout.hkl, 0x04070407),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(remove_layout(&backend, "fr").unwrap_err().starts_with("No installed layout matches 'fr'"));
+
+        let codes: Vec<String> = get_all_keyboard_layouts(&backend).into_iter().map(|l| l.short_code).collect();
+        assert_eq!(codes, vec!["us", "de-ch"]);
+    }
+
+    #[test]
+    fn test_home_layout_classification() {
+        let english = HomeLayout::English;
+        for hkl in [0x04090409, 0x08090809, 0x0c090c09, 0x10091009, 0x34093409, 0x5c095c09] {
+            assert!(english.contains(&LayoutInfo::new(hkl)), "0x{:08X} should be English", hkl);
+        }
+        for hkl in [0x04190419, 0x04070407, 0x04220422, 0x0000] {
+            assert!(!english.contains(&LayoutInfo::new(hkl)), "0x{:08X} should not be English", hkl);
+        }
+
+        let ukrainian = HomeLayout::Code("ua".to_string());
+        assert!(ukrainian.contains(&LayoutInfo::new(0x04220422)));
+        assert!(!ukrainian.contains(&LayoutInfo::new(0x04090409)));
+    }
+
+    #[test]
+    fn test_home_layout_resolve_and_sort() {
+        let backend = SimulatedBackend::with_layouts(&[0x04070407, 0x04090409, 0x04220422, 0x10091009, 0x04030403]);
+
+        assert_eq!(HomeLayout::resolve(&backend, None), Ok(HomeLayout::English));
+        assert_eq!(HomeLayout::resolve(&backend, Some("uk-UA")), Ok(HomeLayout::Code("ua".to_string())));
+        assert!(HomeLayout::resolve(&backend, Some("fr")).unwrap_err().starts_with("Unknown home layout: fr."));
+        assert!(HomeLayout::resolve(&backend, Some("ca")).unwrap_err().contains("ca-es (Catalan (Spain), ca-ES)"));
+
+        let home = HomeLayout::Code("ua".to_string());
+        let codes: Vec<String> = get_all_keyboard_layouts_with_home(&backend, &home).into_iter().map(|l| l.short_code).collect();
+        assert_eq!(codes, vec!["ua", "ca-es", "ca", "us", "de"]);
+        assert_eq!(get_home_layout(&backend, &home).map(|l| l.hkl), Some(0x04220422));
+        assert_eq!(home.describe(&backend), "ua (Ukrainian (Ukraine))");
+    }
+
+    #[test]
+    fn test_layout_info_outside_catalog() {
+        let variant = LayoutInfo::new(0x5c09);
+        assert_eq!((variant.name.as_str(), variant.short_code.as_str()), ("English (0x5C09)", "en-5c09"));
+        assert!(variant.is_english);
+
+        let unknown = LayoutInfo::new(0x00ff);
+        assert_eq!((unknown.name.as_str(), unknown.short_code.as_str()), ("Unknown Language (0x00FF)", "00ff"));
+    }
+
+    #[test]
+    fn test_check_switch_confirms_switch() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407]);
+        let german = LayoutInfo::from_handle(&backend, 0x04070407);
+        let mut pending = request_switch(&backend, &german);
+        assert!(pending.remaining().is_some(), "The check waits for the application");
+        assert_eq!(check_switch(&backend, &mut pending), SwitchCheck::Done);
+        assert_eq!(backend.foreground_layout(), Some(0x04070407));
+        assert_eq!(backend.activation_attempts(), vec![0]);
+    }
+
+    #[test]
+    fn test_check_switch_retries_ignored_request() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407]);
+        backend.ignore_activations(1);
+        let german = LayoutInfo::from_handle(&backend, 0x04070407);
+        let mut pending = request_switch(&backend, &german);
+        assert_eq!(check_switch(&backend, &mut pending), SwitchCheck::Retrying);
+        assert_eq!(check_switch(&backend, &mut pending), SwitchCheck::Done);
+        assert_eq!(backend.activation_attempts(), vec![0, 1], "The retry should use the next delivery");
+    }
+
+    #[test]
+    fn test_check_switch_reports_failure() {
+        let backend = SimulatedBackend::with_layouts(&[0x04090409, 0x04070407]);
+        backend.ignore_activations(SWITCH_ATTEMPTS);
+        let german = LayoutInfo::from_handle(&backend, 0x04070407);
+        let error = match finish_switch(&backend, &german) {
+            SwitchCheck::Failed(error) => error,
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        assert!(error.starts_with("Could not switch to German (Germany) (de): "), "Unexpected error: {}", error);
+        assert_eq!(backend.foreground_layout(), Some(0x04090409));
+        assert_eq!(backend.activation_attempts().len(), SWITCH_ATTEMPTS);
+
+        let missing = LayoutInfo::from_handle(&backend, 0x04190419);
+        assert!(matches!(finish_switch(&backend, &missing), SwitchCheck::Failed(error) if error.contains("is not installed")));
+    }
+
+    // Request a switch and check it until it is done or failed
+    fn finish_switch(backend: &SimulatedBackend, layout: &LayoutInfo) -> SwitchCheck {
+        let mut pending = request_switch(backend, layout);
+        loop {
+            match check_switch(backend, &mut pending) {
+                SwitchCheck::Retrying => continue,
+                check => return check,
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+// Locale catalog for Windows language identifiers (LANGIDs).
+//
+// The table is generated by build.rs from data/locales.tsv, one entry per
+// LANGID with its BCP-47 tag, ISO 639 language, ISO 3166 region, English
+// display name and the CLI code used with `-run -xx`. Native language
+// names come from data/languages.tsv.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale {
+    pub lang_id: u16,
+    pub tag: &'static str,
+    pub language: &'static str,
+    pub region: &'static str,
+    pub name: &'static str,
+    pub code: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/locale_catalog.rs"));
+
+// Primary language bits of a LANGID (e.g. 0x09 for every English locale)
+pub fn primary_language(lang_id: u16) -> u16 {
+    lang_id & 0x3FF
+}
+
+// Catalog entry for a LANGID
+pub fn lookup(lang_id: u16) -> Option<&'static Locale> {
+    LOCALES.binary_search_by_key(&lang_id, |locale| locale.lang_id)
+        .ok()
+        .map(|index| &LOCALES[index])
+}
+
+// Entry for the default sublanguage of the LANGID's primary language,
+// used for LANGIDs missing from the catalog (e.g. new regional variants)
+pub fn lookup_primary(lang_id: u16) -> Option<&'static Locale> {
+    lookup((1 << 10) | primary_language(lang_id))
+        .or_else(|| LOCALES.iter().find(|locale| primary_language(locale.lang_id) == primary_language(lang_id)))
+}
+
+impl Locale {
+    // English name of the language without the region, e.g. "German"
+    pub fn language_name(&self) -> &'static str {
+        self.name.split(" (").next().unwrap_or(self.name)
+    }
+}
+
+// Catalog entry for a layout code, a BCP-47 tag or a bare language
+// ("de", "de-CH", "uk"); a language stands for its default sublanguage
+pub fn find(code: &str) -> Option<&'static Locale> {
+    let code = code.trim().replace('_', "-").to_lowercase();
+    LOCALES.iter().find(|locale| locale.code == code)
+        .or_else(|| LOCALES.iter().find(|locale| locale.tag.to_lowercase() == code))
+        .or_else(|| {
+            let first = LOCALES.iter().find(|locale| locale.language == code)?;
+            lookup_primary(first.lang_id)
+        })
+}
+
+// Name of an ISO 639 language in that language, e.g. "Deutsch" for "de"
+pub fn native_name(language: &str) -> Option<&'static str> {
+    NATIVE_NAMES.binary_search_by_key(&language, |(code, _)| code)
+        .ok()
+        .map(|index| NATIVE_NAMES[index].1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_is_sorted_and_codes_are_unique() {
+        assert!(LOCALES.windows(2).all(|pair| pair[0].lang_id < pair[1].lang_id));
+
+        let mut codes: Vec<&str> = LOCALES.iter().map(|locale| locale.code).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), LOCALES.len());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let georgian = lookup(0x0437).unwrap();
+        assert_eq!((georgian.tag, georgian.language, georgian.region), ("ka-GE", "ka", "GE"));
+        assert_eq!(georgian.name, "Georgian (Georgia)");
+
+        for (lang_id, code) in [(0x042b, "am"), (0x043f, "kz"), (0x0439, "in"), (0x0409, "us"), (0x0419, "ru")] {
+            assert_eq!(lookup(lang_id).map(|locale| locale.code), Some(code), "LANGID 0x{:04x}", lang_id);
+        }
+
+        assert_eq!(lookup(0x0000), None);
+    }
+
+    #[test]
+    fn test_every_language_has_a_native_name() {
+        for locale in LOCALES {
+            assert!(native_name(locale.language).is_some(), "No native name for '{}'", locale.language);
+        }
+        assert_eq!(native_name("de"), Some("Deutsch"));
+        assert_eq!(native_name("uk"), Some("українська"));
+        assert_eq!(lookup(0x0804).map(|locale| locale.language_name()), Some("Chinese"));
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find("de").map(|locale| locale.lang_id), Some(0x0407));
+        assert_eq!(find("de-CH").map(|locale| locale.lang_id), Some(0x0807));
+        assert_eq!(find("sv_se").map(|locale| locale.lang_id), Some(0x041d));
+        assert_eq!(find("uk").map(|locale| locale.lang_id), Some(0x0422));
+        assert_eq!(find("zz"), None);
+    }
+
+    #[test]
+    fn test_lookup_primary_falls_back_to_default_sublanguage() {
+        // English (Ghana) is not in the catalog
+        assert_eq!(lookup_primary(0x5c09).map(|locale| locale.tag), Some("en-US"));
+        assert_eq!(lookup_primary(0x00ff), None);
+    }
+}
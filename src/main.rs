@@ -1,39 +1,40 @@
+mod backend;
+mod bindings;
 mod keyboard_hook;
+mod klid;
 mod layout_indicator;
 mod layout_manager;
+mod locale;
 mod cli;
 mod interactive_menu;
 mod config;
+mod instance;
 
-use std::ptr;
-use std::mem;
-use std::ffi::OsString;
-use std::os::windows::ffi::OsStrExt;
-use std::sync::atomic::{AtomicPtr, Ordering};
-use winapi::um::winuser::*;
-use winapi::um::handleapi::CloseHandle;
-use winapi::um::wincon::*;
-use winapi::um::errhandlingapi::GetLastError;
-use winapi::shared::minwindef::*;
-use winapi::shared::windef::*;
-use keyboard_hook::{install_hook, uninstall_hook, initialize_layout_switching};
-use cli::{parse_args, execute_command, CliCommand, create_mutex, should_run_in_background};
+use backend::LockStatus;
+use instance::InstanceInfo;
+use keyboard_hook::initialize_layout_switching;
+use cli::{parse_args, execute_command, print_key_bindings, CliCommand, should_run_in_background};
 use interactive_menu::show_interactive_menu;
 
-// Global atomic pointer to store mutex handle
-static MUTEX_HANDLE: AtomicPtr<winapi::ctypes::c_void> = AtomicPtr::new(ptr::null_mut());
-
 fn main() {
     // Parse command line arguments
     let command = parse_args();
     
     // Handle CLI commands that don't require running the main loop
     match command {
-        CliCommand::Start(_) | CliCommand::Stop | CliCommand::Exit | CliCommand::Status | CliCommand::Help | CliCommand::Version | CliCommand::Unknown(_) => {
+        CliCommand::Start(_) | CliCommand::Stop | CliCommand::Exit | CliCommand::Layouts(..)
+        | CliCommand::Help | CliCommand::Version | CliCommand::Unknown(_) => {
+            let (exit_code, _) = execute_command(command);
+            std::process::exit(exit_code);
+        }
+        CliCommand::Status => {
+            resolve_home_layout();
             let (exit_code, _) = execute_command(command);
             std::process::exit(exit_code);
         }
         CliCommand::Background(country_codes) => {
+            resolve_home_layout();
+
             // Execute background-specific logic and get country codes
             let (_, final_country_codes) = execute_command(CliCommand::Background(country_codes.clone()));
             
@@ -45,38 +46,28 @@ fn main() {
                 final_country_codes
             };
             
-            // Validate country codes if provided
-            if !country_codes_to_use.is_empty() {
-                if let Err(error) = layout_manager::validate_country_codes(
-                    &country_codes_to_use.iter().map(|s| s.as_str()).collect::<Vec<_>>()
-                ) {
-                    eprintln!("Error: {}", error);
-                    std::process::exit(1);
-                }
-            }
+            // Expand aliases and validate country codes if provided
+            let country_codes_to_use = resolve_country_codes(country_codes_to_use);
             
             // Continue with normal execution after background setup
             run_main_loop(country_codes_to_use);
         }
         CliCommand::Menu => {
+            resolve_home_layout();
+
             // Show interactive menu when no parameters provided
             let (menu_result, country_codes) = show_interactive_menu();
             if menu_result != 0 {
                 std::process::exit(menu_result);
             }
             // If menu_result is 0, continue with normal execution using country codes from menu
-            run_main_loop(country_codes);
+            run_main_loop(resolve_country_codes(country_codes));
         }
         CliCommand::Run(country_codes) => {
-            // Validate country codes if provided
-            if !country_codes.is_empty() {
-                if let Err(error) = layout_manager::validate_country_codes(
-                    &country_codes.iter().map(|s| s.as_str()).collect::<Vec<_>>()
-                ) {
-                    eprintln!("Error: {}", error);
-                    std::process::exit(1);
-                }
-            }
+            resolve_home_layout();
+
+            // Expand aliases and validate country codes if provided
+            let country_codes = resolve_country_codes(country_codes);
             
             // Direct run mode - continue with normal execution
             run_main_loop(country_codes);
@@ -84,239 +75,182 @@ fn main() {
     }
 }
 
-fn run_main_loop(country_codes: Vec<String>) {
-    // Create mutex to prevent multiple instances
-    let mutex = create_mutex();
-    if mutex.is_null() {
-        eprintln!("Failed to create mutex");
-        return;
+// Resolve the home layout from the config file before anything classifies
+// layouts. Only done by the commands that show or switch layouts, since it
+// may connect to the session's input method.
+fn resolve_home_layout() {
+    let home_code = config::load_config().home_layout_code();
+    match layout_manager::HomeLayout::resolve(backend::current(), home_code.as_deref()) {
+        Ok(home) => layout_manager::set_home_layout(home),
+        Err(error) => {
+            eprintln!("Warning: {}", error);
+            eprintln!("Using English as the home layout.");
+        }
     }
-    
-    // Store mutex handle in atomic pointer
-    MUTEX_HANDLE.store(mutex, Ordering::SeqCst);
-    
-    // Check if another instance is already running
-    unsafe {
-        if GetLastError() == winapi::shared::winerror::ERROR_ALREADY_EXISTS {
+}
+
+// Expand aliases from the config file and validate the codes, exits on errors
+fn resolve_country_codes(country_codes: Vec<String>) -> Vec<String> {
+    if country_codes.is_empty() {
+        return country_codes;
+    }
+
+    let backend = backend::current();
+    let result = layout_manager::expand_aliases(backend, &country_codes, &config::load_config().aliases)
+        .and_then(|codes| {
+            layout_manager::validate_country_codes(backend, &codes.iter().map(|s| s.as_str()).collect::<Vec<_>>())
+                .map(|_| codes)
+        });
+
+    match result {
+        Ok(codes) => codes,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_main_loop(country_codes: Vec<String>) {
+    let backend = backend::current();
+
+    // Take the single-instance lock to prevent multiple instances
+    match backend.acquire_instance_lock(&InstanceInfo::for_current_process(&country_codes)) {
+        Ok(LockStatus::Acquired) => {},
+        Ok(LockStatus::AlreadyRunning) => {
+            // Another instance is already running
             if !should_run_in_background() {
                 println!("The program is already running in the background.");
             }
-            CloseHandle(mutex);
+            return;
+        },
+        Err(e) => {
+            eprintln!("{}", e);
             return;
         }
     }
     
+    // Set up the key bindings from the config file
+    let config = config::load_config();
+    let key_bindings = match bindings::parse_bindings(&config.bindings).and_then(|key_bindings| {
+        // `layout:<code>` must match exactly one installed layout
+        let codes = bindings::layout_codes(&key_bindings);
+        if !codes.is_empty() {
+            layout_manager::validate_country_codes(backend, &codes.iter().map(|s| s.as_str()).collect::<Vec<_>>())?;
+        }
+        Ok(key_bindings)
+    }) {
+        Ok(key_bindings) => key_bindings,
+        Err(error) => {
+            eprintln!("Warning: {}", error);
+            eprintln!("Using the default key bindings.");
+            bindings::default_bindings()
+        }
+    };
+    let dual_role = bindings::parse_dual_role(config.dual_role.as_ref(), &key_bindings).unwrap_or_else(|error| {
+        eprintln!("Warning: {}", error);
+        eprintln!("No key acts as a modifier when held.");
+        None
+    });
+    keyboard_hook::set_bindings(bindings::BindingEngine::new(key_bindings)
+        .with_dual_role(dual_role)
+        .with_picker(config.picker));
+    keyboard_hook::set_switch_mode(config.switch_mode);
+
     let is_background = should_run_in_background();
     
     if is_background {
         // Detaching from the parent process in the background
-        unsafe {
-            detach_from_console();
-        }
+        backend.detach_from_console();
     } else {
         // Show startup message in foreground mode
         println!();
         println!("═══════════════════════════════════════════════════");
         println!("       Caps Lock Layout Switcher started!          ");
         println!("═══════════════════════════════════════════════════");
-        println!("Shift + Caps Lock - toggle Caps Lock");
+        print_key_bindings(&config);
         println!("Scroll Lock indicator shows current layout:");
-        println!("  OFF = English layout");
-        println!("  ON  = Non-English layout");
+        println!("  OFF = Home layout ({})", layout_manager::home_layout().describe(backend));
+        println!("  ON  = Any other layout");
         println!();
     }
     
-    unsafe {
-        // Initialize layout switching with country codes
-        initialize_layout_switching(&country_codes);
-        
-        if !is_background {
-            // Show current layout info only in foreground mode
-            if let Some(current_layout) = layout_manager::get_current_layout() {
-                println!("Current layout: {} ({})", current_layout.name, current_layout.short_code);
-                println!("Setting Scroll Lock to: {}", if current_layout.is_english { "OFF" } else { "ON" });
-            } else {
-                println!("Could not detect current layout");
-            }
-            println!();
+    // Initialize layout switching with country codes
+    initialize_layout_switching(backend, &country_codes);
+    
+    if !is_background {
+        // Show current layout info only in foreground mode
+        if let Some(current_layout) = layout_manager::get_current_layout(backend) {
+            println!("Current layout: {} ({})", current_layout.name, current_layout.short_code);
+            let is_home = layout_manager::home_layout().contains(&current_layout);
+            println!("Setting Scroll Lock to: {}", if is_home { "OFF" } else { "ON" });
+        } else {
+            println!("Could not detect current layout");
         }
-        
-        // Set initial Scroll Lock state based on current layout
-        layout_indicator::update_layout_indicator();
-        
-        // Install the hook
-        match install_hook() {
-            Ok(()) => {
-                // Ensure CapsLock is off at startup since the key is
-                // repurposed for layout switching
-                layout_indicator::ensure_caps_lock_off();
+        println!();
+    }
+    
+    // Set initial Scroll Lock state based on current layout
+    layout_indicator::update_layout_indicator(backend);
+    
+    // Install the hook
+    match backend.install_hook() {
+        Ok(()) => {
+            // Ensure CapsLock is off at startup since the key is
+            // repurposed for layout switching
+            layout_indicator::ensure_caps_lock_off(backend);
 
-                if !is_background {
-                    println!("Hook installed successfully");
-                    println!("Layout switcher is now active!");
-                    
-                    // Show switching configuration
-                    let (current_index, layout_names) = keyboard_hook::get_switching_status();
-                    if !layout_names.is_empty() {
-                        println!("Switching between {} layout(s):", layout_names.len());
-                        for (i, name) in layout_names.iter().enumerate() {
-                            let marker = if i == current_index { " [CURRENT]" } else { "" };
-                            println!("  {}{}", name, marker);
-                        }
+            if !is_background {
+                println!("Hook installed successfully");
+                println!("Layout switcher is now active!");
+                
+                // Show switching configuration
+                let (current_index, layout_names) = keyboard_hook::get_switching_status();
+                if !layout_names.is_empty() {
+                    println!("Switching between {} layout(s):", layout_names.len());
+                    for (i, name) in layout_names.iter().enumerate() {
+                        let marker = if i == current_index { " [CURRENT]" } else { "" };
+                        println!("  {}{}", name, marker);
                     }
-                    
-                    println!();
-                    println!("Press Ctrl+C to exit");
-                    println!();
-                }
-            },
-            Err(e) => {
-                if !is_background {
-                    eprintln!("Hook installation error: {}", e);
                 }
-                cleanup_and_exit();
-                return;
+                
+                println!();
+                println!("Press Ctrl+C to exit");
+                println!();
             }
-        }
-        
-        // Ctrl+C handler for proper shutdown (only for foreground mode)
-        if !is_background {
-            // Try to set up Ctrl+C handler, but don't panic if it fails
-            if let Err(e) = ctrlc::set_handler(move || {
-                println!("\nShutting down...");
-                cleanup_and_exit();
-                std::process::exit(0);
-            }) {
-                println!("Warning: Could not set Ctrl+C handler: {}", e);
-                println!("You may need to close the console window manually to exit.");
+        },
+        Err(e) => {
+            if !is_background {
+                eprintln!("Hook installation error: {}", e);
             }
+            cleanup_and_exit();
+            return;
         }
-        
-        // Create hidden window for message handling
-        create_message_window();
-        
-        // Main message processing loop
-        let mut msg: MSG = mem::zeroed();
-        loop {
-            let result = GetMessageW(&mut msg, ptr::null_mut(), 0, 0);
-            if result == 0 || result == -1 {
-                break;
-            }
-            
-            // Handle quit message
-            if msg.message == WM_QUIT {
-                break;
-            }
-            
-            // Handle other system messages
-            if msg.message == WM_QUERYENDSESSION || msg.message == WM_ENDSESSION {
-                // System shutdown - cleanup and exit gracefully
-                break;
-            }
-            
-            TranslateMessage(&msg);
-            DispatchMessageW(&msg);
+    }
+    
+    // Ctrl+C handler for proper shutdown (only for foreground mode)
+    if !is_background {
+        // Try to set up Ctrl+C handler, but don't panic if it fails
+        if let Err(e) = ctrlc::set_handler(move || {
+            println!("\nShutting down...");
+            cleanup_and_exit();
+            std::process::exit(0);
+        }) {
+            println!("Warning: Could not set Ctrl+C handler: {}", e);
+            println!("You may need to close the console window manually to exit.");
         }
-        
-        // Cleanup
-        cleanup_and_exit();
     }
-}
-
-// Simplified function to detach from console
-unsafe fn detach_from_console() {
-    // Unbind from the parent process console
-    // Note: We don't hide the console window because the background process
-    // is started with CREATE_NO_WINDOW flag, so it doesn't have a console window to hide
-    FreeConsole();
+    
+    // Main message processing loop, returns on quit or system shutdown
+    backend.run_message_loop();
+    
+    // Cleanup
+    cleanup_and_exit();
 }
 
 // Helper function to cleanup resources
 fn cleanup_and_exit() {
-    unsafe {
-        uninstall_hook();
-        let mutex = MUTEX_HANDLE.swap(ptr::null_mut(), Ordering::SeqCst);
-        if !mutex.is_null() {
-            CloseHandle(mutex);
-        }
-    }
+    let backend = backend::current();
+    backend.uninstall_hook();
+    backend.release_instance_lock();
 }
-
-// Improved hidden window creation function
-unsafe fn create_message_window() {
-    use winapi::um::libloaderapi::GetModuleHandleW;
-
-    let class_name = "CCapsMessageWindow\0";
-    let class_name_wide: Vec<u16> = OsString::from(class_name).encode_wide().collect();
-
-    let window_name = "CCaps Layout Switcher\0";
-    let window_name_wide: Vec<u16> = OsString::from(window_name).encode_wide().collect();
-
-    // Custom window procedure for handling system messages
-    unsafe extern "system" fn window_proc(
-        hwnd: HWND,
-        msg: UINT,
-        wparam: WPARAM,
-        lparam: LPARAM,
-    ) -> LRESULT {
-        match msg {
-            WM_QUERYENDSESSION | WM_ENDSESSION => {
-                // System shutdown - cleanup and exit gracefully
-                PostQuitMessage(0);
-                return 0;
-            }
-            WM_DESTROY => {
-                PostQuitMessage(0);
-                return 0;
-            }
-            _ => return DefWindowProcW(hwnd, msg, wparam, lparam),
-        }
-    }
-    
-    // Register window class
-    let wc = WNDCLASSW {
-        style: 0,
-        lpfnWndProc: Some(window_proc),
-        cbClsExtra: 0,
-        cbWndExtra: 0,
-        hInstance: GetModuleHandleW(ptr::null()),
-        hIcon: ptr::null_mut(),
-        hCursor: ptr::null_mut(),
-        hbrBackground: ptr::null_mut(),
-        lpszMenuName: ptr::null(),
-        lpszClassName: class_name_wide.as_ptr(),
-    };
-    
-    RegisterClassW(&wc);
-    
-    // Create hidden window
-    let hwnd = CreateWindowExW(
-        0,
-        class_name_wide.as_ptr(),
-        window_name_wide.as_ptr(),
-        0, // No window style (completely hidden)
-        0, 0, 0, 0, // Position and size (irrelevant for hidden window)
-        HWND_MESSAGE, // Message-only window (not displayed in UI)
-        ptr::null_mut(),
-        GetModuleHandleW(ptr::null()),
-        ptr::null_mut(),
-    );
-    
-    if hwnd.is_null() {
-        // Fallback: try to create a regular hidden window
-        CreateWindowExW(
-            0,
-            class_name_wide.as_ptr(),
-            window_name_wide.as_ptr(),
-            WS_OVERLAPPEDWINDOW,
-            CW_USEDEFAULT,
-            CW_USEDEFAULT,
-            CW_USEDEFAULT,
-            CW_USEDEFAULT,
-            ptr::null_mut(),
-            ptr::null_mut(),
-            GetModuleHandleW(ptr::null()),
-            ptr::null_mut(),
-        );
-    }
-}
\ No newline at end of file